@@ -0,0 +1,184 @@
+//! Audio captured next to the video: PCM chunks stamped on the session
+//! timeline, and what the recorders need to line them up with frames.
+
+use crate::pipewire_stream::Target;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// Captured audio is always converted to this by PipeWire.
+pub const SAMPLE_RATE: u32 = 48000;
+pub const CHANNELS: u32 = 2;
+
+/// Where to capture audio from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AudioSource {
+    /// Monitor of the default output, i.e. what the desktop plays.
+    DesktopMonitor,
+    Node(Target),
+}
+
+impl std::str::FromStr for AudioSource {
+    type Err = std::convert::Infallible;
+
+    /// `monitor` or a node id or name.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "monitor" => AudioSource::DesktopMonitor,
+            _ => AudioSource::Node(s.parse()?),
+        })
+    }
+}
+
+/// Interleaved signed 16 bit samples at [`SAMPLE_RATE`] with [`CHANNELS`]
+/// channels.
+#[derive(Debug, Clone)]
+pub struct AudioChunk {
+    /// When the first sample was captured, on the stream's
+    /// [`SessionClock`](crate::clock::SessionClock).
+    pub pts: Duration,
+    pub samples: Vec<i16>,
+}
+
+impl AudioChunk {
+    pub fn frames(&self) -> usize {
+        self.samples.len() / CHANNELS as usize
+    }
+}
+
+fn frames_in(duration: Duration) -> u64 {
+    (duration.as_secs_f64() * SAMPLE_RATE as f64).round() as u64
+}
+
+/// Lines audio up with a video timeline starting at `start`: gaps in the
+/// capture are filled with silence and audio from before `start` or
+/// overlapping what was already written is dropped.
+pub struct AudioAligner {
+    start: Duration,
+    /// Frames (samples per channel) written so far.
+    written: u64,
+}
+
+impl AudioAligner {
+    /// Timestamp jitter that is tolerated before padding or dropping.
+    const TOLERANCE: Duration = Duration::from_millis(20);
+
+    pub fn new(start: Duration) -> Self {
+        Self { start, written: 0 }
+    }
+
+    /// Appends the samples of `chunk` that belong to the timeline to `out`.
+    pub fn align(&mut self, chunk: &AudioChunk, out: &mut Vec<i16>) {
+        let channels = CHANNELS as usize;
+        let expected =
+            self.start + Duration::from_secs_f64(self.written as f64 / SAMPLE_RATE as f64);
+        let mut skip = 0;
+        if chunk.pts > expected + Self::TOLERANCE {
+            let silence = frames_in(chunk.pts - expected);
+            out.resize(out.len() + silence as usize * channels, 0);
+            self.written += silence;
+        } else if chunk.pts + Self::TOLERANCE < expected {
+            skip = (frames_in(expected - chunk.pts) as usize).min(chunk.frames());
+        }
+        out.extend_from_slice(&chunk.samples[skip * channels..]);
+        self.written += (chunk.frames() - skip) as u64;
+    }
+
+    /// Takes back the last `frames` aligned, for output that was dropped
+    /// rather than written. The next chunk pads their time with silence.
+    pub fn rewind(&mut self, frames: u64) {
+        self.written = self.written.saturating_sub(frames);
+    }
+}
+
+/// 16 bit PCM WAV file, the sizes are filled in by [`WavWriter::finish`].
+pub struct WavWriter {
+    file: std::io::BufWriter<std::fs::File>,
+    data_bytes: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        let block_align = CHANNELS * 2;
+        file.write_all(b"RIFF\0\0\0\0WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        // PCM
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&(CHANNELS as u16).to_le_bytes())?;
+        file.write_all(&SAMPLE_RATE.to_le_bytes())?;
+        file.write_all(&(SAMPLE_RATE * block_align).to_le_bytes())?;
+        file.write_all(&(block_align as u16).to_le_bytes())?;
+        file.write_all(&16u16.to_le_bytes())?;
+        file.write_all(b"data\0\0\0\0")?;
+        Ok(Self {
+            file,
+            data_bytes: 0,
+        })
+    }
+
+    pub fn write(&mut self, samples: &[i16]) -> std::io::Result<()> {
+        let bytes = samples
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect::<Vec<_>>();
+        self.file.write_all(&bytes)?;
+        self.data_bytes += bytes.len() as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(36 + self.data_bytes).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_bytes.to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AudioAligner, AudioChunk, WavWriter};
+    use std::time::Duration;
+
+    #[test]
+    fn align_pads_and_trims() {
+        let start = Duration::from_secs(1);
+        let mut aligner = AudioAligner::new(start);
+        let mut out = Vec::new();
+
+        // 40 ms starting 30 ms before the video
+        let chunk = AudioChunk {
+            pts: start - Duration::from_millis(30),
+            samples: vec![1; 1920 * 2],
+        };
+        aligner.align(&chunk, &mut out);
+        assert_eq!(out, vec![1; 480 * 2]);
+
+        // 100 ms gap after the 10 ms written so far
+        out.clear();
+        let chunk = AudioChunk {
+            pts: start + Duration::from_millis(110),
+            samples: vec![2; 4],
+        };
+        aligner.align(&chunk, &mut out);
+        assert_eq!(out.len(), 4800 * 2 + 4);
+        assert!(out[..9600].iter().all(|&s| s == 0));
+        assert_eq!(&out[9600..], [2; 4]);
+    }
+
+    #[test]
+    fn wav_sizes() {
+        let path = std::env::temp_dir().join(format!("screencast-{}.wav", std::process::id()));
+        let mut wav = WavWriter::create(&path).unwrap();
+        wav.write(&[1, -1, 2, -2]).unwrap();
+        wav.finish().unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[4..8], &44u32.to_le_bytes());
+        assert_eq!(&data[40..44], &8u32.to_le_bytes());
+        assert_eq!(&data[44..], &[1, 0, 0xff, 0xff, 2, 0, 0xfe, 0xff]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,58 +1,96 @@
-use std::os::fd::AsRawFd;
+use clap::Parser;
+use screencast::dma_buf_share::{self, DmaBufClient};
+use std::path::PathBuf;
+use std::time::Duration;
 
-use gbm;
-
-const WIDTH: u32 = 4;
-const HEIGHT: u32 = 4;
-
-fn main() {
-    // let Some(fd) = std::env::args().nth(1) else {
-    //     println!("Expected fd");
-    //     return;
-    // };
+/// Imports the DMA-BUFs shared by dma_buf_sender or `screencast share` with
+/// GBM and prints a checksum of their contents.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Socket to connect to, $XDG_RUNTIME_DIR/screencast-dma-buf.sock by
+    /// default
+    #[arg(long)]
+    socket: Option<PathBuf>,
+    /// DRM device, the first of /dev/dri/renderD128, card0 and card1 by
+    /// default
+    #[arg(long)]
+    device: Option<PathBuf>,
+    /// Frames to import before exiting
+    #[arg(long, default_value_t = 1)]
+    frames: u32,
+    /// Checksum printed by the sender, exits with an error if a frame
+    /// differs
+    #[arg(long, value_parser = parse_checksum)]
+    expect: Option<u32>,
+    /// Seconds to wait for a frame
+    #[arg(long, default_value_t = 5)]
+    timeout: u64,
+}
 
-    let fd = read_fd();
-    println!("Received fd: {fd}");
+fn parse_checksum(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 16).map_err(|_| format!("Invalid checksum '{s}', expected hex"))
+}
 
-    // let buffer_fd = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd.parse::<i32>().unwrap()) };
-    let buffer_fd = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) };
+fn run(args: Args) -> Result<bool, Box<dyn std::error::Error>> {
+    let drm_fd = dma_buf_share::open_drm_device(args.device.as_deref())?;
+    let gbm = gbm::Device::new(drm_fd)?;
+    println!("device: {}", gbm.backend_name());
 
-    let mut options = std::fs::OpenOptions::new();
-    options.read(true).write(true);
-    let drm_fd = options
-        .open("/dev/dri/card0")
-        .or(options.open("/dev/dri/card1"))
-        .unwrap();
-    let gbm = gbm::Device::new(drm_fd).unwrap();
+    let path = args
+        .socket
+        .unwrap_or_else(dma_buf_share::default_socket_path);
+    let mut client = DmaBufClient::connect(&path)?;
+    client.set_timeout(Some(Duration::from_secs(args.timeout)))?;
 
-    let bo = gbm
-        .import_buffer_object_from_dma_buf_with_modifiers::<()>(
-            1,
-            [Some(buffer_fd), None, None, None],
-            WIDTH,
-            HEIGHT,
-            gbm::Format::Argb8888,
-            gbm::BufferObjectFlags::empty(),
-            [256; 4],
-            [0; 4],
-            gbm::Modifier::Linear,
-        )
-        .unwrap();
+    let mut matched = true;
+    for _ in 0..args.frames {
+        let Some(frame) = client.recv()? else {
+            println!("sender closed the connection");
+            break;
+        };
+        let format = gbm::Format::try_from(frame.fourcc)?;
+        println!(
+            "frame {}: {}x{}, format: {format}, modifier: {:?}, planes: {}",
+            frame.sequence,
+            frame.width,
+            frame.height,
+            gbm::Modifier::from(frame.modifier),
+            frame.planes.len()
+        );
+        for plane in &frame.planes {
+            println!("plane: offset {}, stride {}", plane.offset, plane.stride);
+        }
 
-    let _ = bo
-        .map(&gbm, 0, 0, WIDTH, HEIGHT, |mbo| {
-            println!("Buffer size: {}", mbo.buffer().len());
-            println!("Buffer: {:?}", mbo.buffer());
-        })
-        .unwrap()
-        .unwrap();
+        let bo = frame.import(&gbm)?;
+        let Some(bpp) = dma_buf_share::bytes_per_pixel(format) else {
+            println!("imported, no checksum for {format}");
+            continue;
+        };
+        let row = (frame.width * bpp) as usize;
+        let checksum = bo.map(&gbm, 0, 0, frame.width, frame.height, |mbo| {
+            dma_buf_share::checksum(
+                mbo.buffer(),
+                mbo.stride() as usize,
+                row,
+                frame.height as usize,
+            )
+        })??;
+        println!("checksum: {checksum:08x}");
+        if args.expect.is_some_and(|expected| expected != checksum) {
+            println!("checksum mismatch");
+            matched = false;
+        }
+    }
+    Ok(matched)
 }
 
-fn read_fd() -> std::os::fd::RawFd {
-    use passfd::FdPassingExt;
-    use std::os::unix::net::UnixStream;
-
-    let stream = UnixStream::connect("/tmp/dma_buf_transfer.sock").unwrap();
-    let fd = stream.recv_fd().unwrap();
-    fd
+fn main() {
+    match run(Args::parse()) {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(2),
+        Err(e) => {
+            println!("{e}");
+            std::process::exit(1);
+        }
+    }
 }
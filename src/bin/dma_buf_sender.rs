@@ -1,10 +1,69 @@
-use std::os::fd::AsRawFd;
-
+use clap::Parser;
 use drm::Device;
-use gbm;
+use screencast::dma_buf_share::{self, DmaBufFrame, DmaBufPlane, DmaBufServer};
+use screencast::test_pattern::{Canvas, ChannelOrder, Pattern};
+use std::os::unix::io::{AsFd, BorrowedFd};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Allocates a DMA-BUF with GBM, fills it and shares it on a Unix socket
+/// until killed, to check DMA-BUF interop with dma_buf_receiver.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Buffer size as WIDTHxHEIGHT
+    #[arg(long, default_value = "64x64", value_parser = parse_size)]
+    size: (u32, u32),
+    /// DRM fourcc, e.g. AR24 for ARGB8888 or XB24 for XBGR8888
+    #[arg(long, default_value = "AR24", value_parser = dma_buf_share::parse_fourcc)]
+    fourcc: gbm::Format,
+    /// Modifiers GBM may pick from: linear, invalid or numbers
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "linear",
+        value_parser = dma_buf_share::parse_modifier
+    )]
+    modifiers: Vec<gbm::Modifier>,
+    /// stripes, ramp, or bars, box or counter for 8 bit RGB formats
+    #[arg(long, default_value = "stripes")]
+    fill: Fill,
+    /// Socket to listen on, $XDG_RUNTIME_DIR/screencast-dma-buf.sock by
+    /// default
+    #[arg(long)]
+    socket: Option<PathBuf>,
+    /// DRM device, the first of /dev/dri/renderD128, card0 and card1 by
+    /// default
+    #[arg(long)]
+    device: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Fill {
+    /// Rows of 0x00 and 0xff bytes in turn.
+    Stripes,
+    /// Bytes counting up along each row.
+    Ramp,
+    Pattern(Pattern),
+}
+
+impl std::str::FromStr for Fill {
+    type Err = String;
 
-use std::os::unix::io::AsFd;
-use std::os::unix::io::BorrowedFd;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stripes" => Ok(Fill::Stripes),
+            "ramp" => Ok(Fill::Ramp),
+            _ => s.parse().map(Fill::Pattern),
+        }
+    }
+}
+
+fn parse_size(s: &str) -> Result<(u32, u32), String> {
+    s.split_once('x')
+        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+        .filter(|&(w, h)| w > 0 && h > 0)
+        .ok_or_else(|| format!("Invalid size '{s}', expected WIDTHxHEIGHT"))
+}
 
 #[derive(Debug)]
 /// A simple wrapper for a device node.
@@ -22,101 +81,142 @@ impl AsFd for Card {
 /// With [`AsFd`] implemented, we can now implement [`drm::Device`].
 impl Device for Card {}
 
-impl Card {
-    /// Simple helper method for opening a [`Card`].
-    fn open() -> Self {
-        let mut options = std::fs::OpenOptions::new();
-        options.read(true);
-        options.write(true);
-
-        // The normal location of the primary device node on Linux
-        let fd = options
-            .open("/dev/dri/card0")
-            .or(options.open("/dev/dri/card1"))
-            .unwrap();
-        Card(fd)
+/// Draws `fill` into a mapped buffer, `row` bytes of every line.
+fn draw(
+    fill: Fill,
+    format: gbm::Format,
+    data: &mut [u8],
+    stride: usize,
+    row: usize,
+    size: (u32, u32),
+) {
+    let lines = data.chunks_mut(stride).take(size.1 as usize);
+    match fill {
+        Fill::Stripes => {
+            for (y, line) in lines.enumerate() {
+                line[..row].fill(if y % 2 == 0 { 0 } else { 255 });
+            }
+        }
+        Fill::Ramp => {
+            for (y, line) in lines.enumerate() {
+                for (x, byte) in line[..row].iter_mut().enumerate() {
+                    *byte = (x + y) as u8;
+                }
+            }
+        }
+        Fill::Pattern(pattern) => {
+            let order = match format {
+                gbm::Format::Abgr8888 | gbm::Format::Xbgr8888 => ChannelOrder::Rgba,
+                _ => ChannelOrder::Bgra,
+            };
+            Canvas {
+                data,
+                width: size.0,
+                height: size.1,
+                stride,
+                order,
+            }
+            .draw(pattern, 0);
+        }
     }
 }
 
-fn main() {
-    let drm_fd = Card::open();
-    let gbm = gbm::Device::new(drm_fd).unwrap();
-
-    const WIDTH: u32 = 4;
-    const HEIGHT: u32 = 4;
+fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let (width, height) = args.size;
+    let format = args.fourcc;
+    let bpp = dma_buf_share::bytes_per_pixel(format)
+        .ok_or_else(|| format!("Cannot fill {format}, only single plane formats"))?;
+    if matches!(args.fill, Fill::Pattern(_))
+        && !matches!(
+            format,
+            gbm::Format::Argb8888
+                | gbm::Format::Xrgb8888
+                | gbm::Format::Abgr8888
+                | gbm::Format::Xbgr8888
+        )
+    {
+        return Err(format!("Test patterns need AR24, XR24, AB24 or XB24, not {format}").into());
+    }
+    let row = (width * bpp) as usize;
 
+    let card = Card(dma_buf_share::open_drm_device(args.device.as_deref())?);
+    let gbm = gbm::Device::new(card)?;
+    println!("device: {}", gbm.backend_name());
     println!(
         "prime: {}",
-        gbm.get_driver_capability(drm::DriverCapability::Prime)
-            .unwrap()
+        gbm.get_driver_capability(drm::DriverCapability::Prime)?
     );
-
-    let flags = {
-        use gbm::BufferObjectFlags as f;
-        /*f::CURSOR | f::WRITE | f::SCANOUT*/
-        f::empty()
-    };
-
-    for format in [gbm::Format::Bgra8888, gbm::Format::Argb8888] {
-        println!("{format}: {}", gbm.is_format_supported(format, flags));
-    }
-
-    let modifers = &[
-        gbm::Modifier::Linear,
-        gbm::Modifier::Nvidia_16bx2_block_four_gob,
-    ];
-    let mut bo = gbm
-        .create_buffer_object_with_modifiers2::<()>(
-            WIDTH,
-            HEIGHT,
-            gbm::Format::Argb8888,
-            modifers.iter().cloned(),
-            flags,
-        )
-        .unwrap();
-
+    let flags = gbm::BufferObjectFlags::empty();
     println!(
-        "plane_count: {}\nstride: {}\noffset: {}",
-        bo.plane_count().unwrap(),
-        bo.stride().unwrap(),
-        bo.offset(0).unwrap()
+        "{format} supported: {}",
+        gbm.is_format_supported(format, flags)
     );
 
-    let _ = bo
-        .map_mut(&gbm, 0, 0, WIDTH, HEIGHT, |mbo| {
-            let b = mbo.buffer_mut();
-            for i in 0..WIDTH {
-                for j in 0..HEIGHT {
-                    let idx = (i * WIDTH + j) as usize;
-                    b[idx] = if i % 2 == 0 { 0 } else { 255 };
-                }
-            }
-            println!("buf size: {}", b.len());
-            // println!("send: {:?}", b);
+    let mut bo = gbm.create_buffer_object_with_modifiers2::<()>(
+        width,
+        height,
+        format,
+        args.modifiers.iter().copied(),
+        flags,
+    )?;
+    let planes = bo.plane_count()?;
+    println!("size: {width}x{height}, format: {format}");
+    println!("modifier: {:?}, planes: {planes}", bo.modifier()?);
+
+    bo.map_mut(&gbm, 0, 0, width, height, |mbo| {
+        let stride = mbo.stride() as usize;
+        draw(
+            args.fill,
+            format,
+            mbo.buffer_mut(),
+            stride,
+            row,
+            (width, height),
+        );
+    })??;
+    // Read back rather than trust what was written
+    let checksum = bo.map(&gbm, 0, 0, width, height, |mbo| {
+        dma_buf_share::checksum(mbo.buffer(), mbo.stride() as usize, row, height as usize)
+    })??;
+
+    let planes = (0..planes as i32)
+        .map(|plane| {
+            let plane = DmaBufPlane {
+                fd: bo.fd_for_plane(plane)?,
+                offset: bo.offset(plane)?,
+                stride: bo.stride_for_plane(plane)?,
+            };
+            println!("plane: offset {}, stride {}", plane.offset, plane.stride);
+            Ok(plane)
         })
-        .unwrap()
-        .unwrap();
-
-    let export_fd = bo.fd().unwrap();
-
-    println!(
-        "fd: {}, modifier: {:?}",
-        export_fd.as_raw_fd(),
-        bo.modifier().unwrap()
-    );
-
-    use passfd::FdPassingExt;
-    use std::os::unix::io::AsRawFd;
-    use std::os::unix::net::UnixListener;
-
-    let _ = std::fs::remove_file("/tmp/dma_buf_transfer.sock");
-    let listener = UnixListener::bind("/tmp/dma_buf_transfer.sock").unwrap();
-
+        .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+
+    let server = DmaBufServer::bind(
+        args.socket
+            .unwrap_or_else(dma_buf_share::default_socket_path),
+    )?;
+    // Clients connecting later get the latest frame, this one
+    server.publish(DmaBufFrame {
+        width,
+        height,
+        fourcc: format as u32,
+        modifier: bo.modifier()?.into(),
+        planes,
+        sequence: 0,
+        pts: Duration::ZERO,
+    })?;
+    println!("checksum: {checksum:08x}");
+    println!("sharing on {}", server.path().display());
+
+    // The buffer has to stay alive for the receivers
     loop {
-        let (stream, _) = listener.accept().unwrap();
-        stream.send_fd(export_fd.as_raw_fd()).unwrap();
+        std::thread::sleep(Duration::from_secs(60));
     }
+}
 
-    // println!("sleeping...");
-    // std::thread::sleep(std::time::Duration::from_secs(60 * 60 * 13));
+fn main() {
+    if let Err(e) = run(Args::parse()) {
+        println!("{e}");
+        std::process::exit(1);
+    }
 }
@@ -1,20 +1,26 @@
-use portal_screencast::*;
+use screencast::portal::*;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut screen_cast = ScreenCast::new()?;
+    let portal = Portal::new()?;
+    println!(
+        "portal version: {}, source types: {:?}, cursor modes: {:?}",
+        portal.version()?,
+        portal.available_source_types()?,
+        portal.available_cursor_modes()?
+    );
+
+    let session = portal.create_session()?;
     // Set which source types to allow, and enable multiple items to be shared.
-    screen_cast.set_source_types(SourceType::MONITOR | SourceType::WINDOW);
-    screen_cast.enable_multiple();
+    session.select_sources(&SourceOptions {
+        types: SourceType::MONITOR | SourceType::WINDOW,
+        multiple: true,
+        ..Default::default()
+    })?;
     // If you have a window handle you can tie the dialog to it
-    let screen_cast = screen_cast.start(None)?;
+    let started = session.start("")?;
+    let _pipewire_fd = session.open_pipewire_remote()?;
 
-    println!(
-        "pipewire node: {:?}",
-        screen_cast
-            .streams()
-            .map(|s| s.pipewire_node())
-            .collect::<Vec<_>>()
-    );
+    println!("streams: {:#?}", started.streams);
 
     std::thread::sleep(std::time::Duration::from_secs(60 * 60 * 12));
     Ok(())
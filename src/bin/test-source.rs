@@ -0,0 +1,66 @@
+use clap::Parser;
+use pipewire::spa::param::video::VideoFormat;
+use screencast::test_pattern::Pattern;
+use screencast::test_source::{TestSource, TestSourceConfig};
+use std::path::PathBuf;
+
+/// Publishes a synthetic video source on the PipeWire daemon.
+#[derive(Parser, Debug)]
+struct Args {
+    /// bars, box or counter
+    #[arg(long, default_value = "counter")]
+    pattern: Pattern,
+    /// BGRx, BGRA, RGBx, RGBA, or YUY2 like a camera, only in memory
+    /// buffers
+    #[arg(long, default_value = "BGRx", value_parser = parse_format)]
+    format: VideoFormat,
+    #[arg(long, default_value_t = 320)]
+    width: u32,
+    #[arg(long, default_value_t = 240)]
+    height: u32,
+    /// Extra bytes after every row of MemFd buffers
+    #[arg(long, default_value_t = 0)]
+    stride_padding: u32,
+    #[arg(long, default_value_t = 30)]
+    fps: u32,
+    /// Node name
+    #[arg(long, default_value = "screencast-test-source")]
+    name: String,
+    /// DRM device to allocate DMA-BUF buffers from, e.g. /dev/dri/renderD128
+    #[arg(long)]
+    dma_buf: Option<PathBuf>,
+}
+
+fn parse_format(s: &str) -> Result<VideoFormat, String> {
+    match s {
+        "BGRx" => Ok(VideoFormat::BGRx),
+        "BGRA" => Ok(VideoFormat::BGRA),
+        "RGBx" => Ok(VideoFormat::RGBx),
+        "RGBA" => Ok(VideoFormat::RGBA),
+        "YUY2" => Ok(VideoFormat::YUY2),
+        _ => Err(format!("Unsupported format '{s}'")),
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let source = TestSource::start(TestSourceConfig {
+        name: args.name,
+        pattern: args.pattern,
+        format: args.format,
+        width: args.width,
+        height: args.height,
+        stride_padding: args.stride_padding,
+        fps: args.fps,
+        dma_buf_device: args.dma_buf,
+    });
+
+    match source {
+        Ok(source) => {
+            println!("node id: {}", source.node_id());
+            source.wait();
+        }
+        Err(e) => println!("Failed to start test source: {e}"),
+    }
+}
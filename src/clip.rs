@@ -0,0 +1,608 @@
+//! Short animated clips for bug reports, written as GIF or APNG. Identical
+//! frames are merged and only the changed part of each frame is stored.
+
+use crate::export::{self, ImageView, PixelLayout};
+use crate::frame_transform::{self, Rect};
+use crate::pipewire_stream::{self, Frame, TimedFrame};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Display time of the last frame when nothing else says how long to show it.
+const DEFAULT_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipFormat {
+    /// 256 colour palette shared by all frames.
+    Gif,
+    Apng,
+}
+
+impl ClipFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "gif" => Some(ClipFormat::Gif),
+            "png" | "apng" => Some(ClipFormat::Apng),
+            _ => None,
+        }
+    }
+}
+
+struct ClipFrame {
+    frame: Frame,
+    pts: Duration,
+}
+
+/// Buffered frames of a clip, all scaled to the size of the first one.
+#[derive(Default)]
+pub struct Clip {
+    frames: Vec<ClipFrame>,
+    end: Duration,
+}
+
+impl Clip {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a frame shown from `pts` on. A frame identical to the previous
+    /// one only extends how long that is shown.
+    pub fn push(&mut self, frame: Frame, pts: Duration) {
+        self.end = self.end.max(pts);
+        let frame = match self.frames.first() {
+            Some(first)
+                if (first.frame.width(), first.frame.height())
+                    != (frame.width(), frame.height()) =>
+            {
+                frame_transform::scale(&frame, first.frame.width(), first.frame.height())
+            }
+            _ => frame,
+        };
+        if let Some(last) = self.frames.last() {
+            if last.frame.as_slice() == frame.as_slice() {
+                return;
+            }
+        }
+        self.frames.push(ClipFrame { frame, pts });
+    }
+
+    /// Shows the last frame until `end`.
+    pub fn extend_to(&mut self, end: Duration) {
+        self.end = self.end.max(end);
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn duration(&self) -> Duration {
+        let start = self.frames.first().map(|f| f.pts).unwrap_or_default();
+        self.end.saturating_sub(start)
+    }
+
+    /// Display times in units of `1 / denominator` seconds, rounded so that
+    /// the error does not add up over the clip.
+    fn delays(&self, denominator: u32) -> Vec<u32> {
+        let ticks = |t: Duration| (t.as_secs_f64() * denominator as f64).round() as u32;
+        let mut delays = self
+            .frames
+            .windows(2)
+            .map(|pair| ticks(pair[1].pts).saturating_sub(ticks(pair[0].pts)))
+            .collect::<Vec<_>>();
+        if let Some(last) = self.frames.last() {
+            let delay = match ticks(self.end).saturating_sub(ticks(last.pts)) {
+                0 => delays.last().copied().unwrap_or(ticks(DEFAULT_DELAY)),
+                delay => delay,
+            };
+            delays.push(delay);
+        }
+        delays
+    }
+
+    /// Part of each frame that differs from the previous one, the whole
+    /// first frame.
+    fn regions(&self) -> impl Iterator<Item = (&Frame, Rect)> {
+        self.frames.iter().enumerate().map(|(i, f)| {
+            let full = Rect {
+                x: 0,
+                y: 0,
+                width: f.frame.width(),
+                height: f.frame.height(),
+            };
+            let rect = match i {
+                0 => full,
+                _ => changed_rect(&self.frames[i - 1].frame, &f.frame).unwrap_or(Rect {
+                    width: 1,
+                    height: 1,
+                    ..full
+                }),
+            };
+            (&f.frame, rect)
+        })
+    }
+
+    pub fn write_gif(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let (width, height) = self.size();
+        let mut quantizer = Quantizer::new(median_cut(self.color_samples(), 256));
+
+        writer.write_all(b"GIF89a")?;
+        writer.write_all(&(width as u16).to_le_bytes())?;
+        writer.write_all(&(height as u16).to_le_bytes())?;
+        // Global colour table of 256 entries, 8 bit colour resolution
+        writer.write_all(&[0xf7, 0, 0])?;
+        let mut table = [0; 768];
+        for (entry, color) in table.chunks_exact_mut(3).zip(&quantizer.palette) {
+            entry.copy_from_slice(color);
+        }
+        writer.write_all(&table)?;
+        // Loop forever
+        writer.write_all(b"\x21\xff\x0bNETSCAPE2.0\x03\x01\x00\x00\x00")?;
+
+        // Browsers show delays below 2/100 s as 1/10 s
+        let delays = self
+            .delays(100)
+            .into_iter()
+            .map(|d| d.clamp(2, u16::MAX as u32));
+        for ((frame, rect), delay) in self.regions().zip(delays) {
+            // Graphic control extension: leave the frame in place, no transparency
+            writer.write_all(&[0x21, 0xf9, 4, 1 << 2])?;
+            writer.write_all(&(delay as u16).to_le_bytes())?;
+            writer.write_all(&[0, 0])?;
+
+            writer.write_all(&[0x2c])?;
+            for value in [rect.x, rect.y, rect.width, rect.height] {
+                writer.write_all(&(value as u16).to_le_bytes())?;
+            }
+            writer.write_all(&[0])?;
+
+            let cropped = frame_transform::crop(frame, rect);
+            let indices = cropped
+                .as_slice()
+                .iter()
+                .map(|p| quantizer.index([p.r, p.g, p.b]))
+                .collect::<Vec<_>>();
+            writer.write_all(&[8])?;
+            for block in lzw_encode(&indices, 8).chunks(255) {
+                writer.write_all(&[block.len() as u8])?;
+                writer.write_all(block)?;
+            }
+            writer.write_all(&[0])?;
+        }
+        writer.write_all(&[0x3b])
+    }
+
+    pub fn write_apng(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let (width, height) = self.size();
+        writer.write_all(&export::PNG_SIGNATURE)?;
+        export::write_chunk(writer, b"IHDR", &export::png_header(width, height))?;
+        let mut control = Vec::with_capacity(8);
+        control.extend_from_slice(&(self.frames.len() as u32).to_be_bytes());
+        // Loop forever
+        control.extend_from_slice(&0u32.to_be_bytes());
+        export::write_chunk(writer, b"acTL", &control)?;
+
+        let delays = self.delays(1000);
+        let mut sequence = 0u32;
+        for (i, ((frame, rect), delay)) in self.regions().zip(delays).enumerate() {
+            let mut control = Vec::with_capacity(26);
+            for value in [sequence, rect.width, rect.height, rect.x, rect.y] {
+                control.extend_from_slice(&value.to_be_bytes());
+            }
+            control.extend_from_slice(&(delay.min(u16::MAX as u32) as u16).to_be_bytes());
+            control.extend_from_slice(&1000u16.to_be_bytes());
+            // Dispose op none, blend op source
+            control.extend_from_slice(&[0, 0]);
+            export::write_chunk(writer, b"fcTL", &control)?;
+            sequence += 1;
+
+            let offset = (rect.y as usize * frame.width() as usize + rect.x as usize) * 4;
+            let data = export::png_image_data(&ImageView {
+                data: &frame.as_bytes()[offset..],
+                width: rect.width,
+                height: rect.height,
+                stride: frame.width() as usize * 4,
+                layout: PixelLayout::Rgba,
+            });
+            if i == 0 {
+                export::write_chunk(writer, b"IDAT", &data)?;
+            } else {
+                let mut chunk = Vec::with_capacity(data.len() + 4);
+                chunk.extend_from_slice(&sequence.to_be_bytes());
+                chunk.extend_from_slice(&data);
+                export::write_chunk(writer, b"fdAT", &chunk)?;
+                sequence += 1;
+            }
+        }
+        export::write_chunk(writer, b"IEND", &[])
+    }
+
+    /// Writes the clip to `path` as GIF or APNG, by extension.
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let path = path.as_ref();
+        let format = ClipFormat::from_path(path).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unknown clip format of {}", path.display()),
+            )
+        })?;
+        if self.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Clip has no frames",
+            ));
+        }
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+        match format {
+            ClipFormat::Gif => self.write_gif(&mut writer)?,
+            ClipFormat::Apng => self.write_apng(&mut writer)?,
+        }
+        writer.flush()
+    }
+
+    fn size(&self) -> (u32, u32) {
+        self.frames
+            .first()
+            .map(|f| (f.frame.width(), f.frame.height()))
+            .unwrap_or_default()
+    }
+
+    /// Evenly spread pixels of all frames, enough to pick a palette.
+    fn color_samples(&self) -> Vec<[u8; 3]> {
+        const MAX_SAMPLES: usize = 1 << 16;
+        let total = self
+            .frames
+            .iter()
+            .map(|f| f.frame.as_slice().len())
+            .sum::<usize>();
+        let step = total.div_ceil(MAX_SAMPLES).max(1);
+        self.frames
+            .iter()
+            .flat_map(|f| f.frame.as_slice().iter().step_by(step))
+            .map(|p| [p.r, p.g, p.b])
+            .collect()
+    }
+}
+
+/// Buffers frames until `duration` has passed since the first one, the
+/// channel closes or `stop` returns true.
+pub fn capture(
+    frames: &async_channel::Receiver<TimedFrame>,
+    duration: Duration,
+    stop: impl Fn() -> bool,
+) -> Clip {
+    let mut clip = Clip::new();
+    // Idle screens send no frames, so the end is found with the wall clock
+    let start = Cell::new(None::<(Instant, Duration)>);
+    let done = || {
+        stop()
+            || start
+                .get()
+                .is_some_and(|(start, _)| start.elapsed() >= duration)
+    };
+    let _ = pipewire_stream::for_each_frame(frames, done, |TimedFrame { frame, pts, .. }| {
+        if frame.width() > 0 && frame.height() > 0 {
+            let first = start.get().unwrap_or_else(|| (Instant::now(), pts));
+            start.set(Some(first));
+            clip.push(frame, pts.saturating_sub(first.1));
+        }
+        Ok(())
+    });
+    if let Some((start, _)) = start.get() {
+        clip.extend_to(start.elapsed().min(duration));
+    }
+    clip
+}
+
+/// Bounding box of the pixels that differ between two frames of equal size.
+fn changed_rect(previous: &Frame, frame: &Frame) -> Option<Rect> {
+    let width = frame.width() as usize;
+    let rows = previous
+        .as_slice()
+        .chunks_exact(width)
+        .zip(frame.as_slice().chunks_exact(width));
+    let mut bounds: Option<(usize, usize, usize, usize)> = None;
+    for (y, (a, b)) in rows.enumerate() {
+        if a == b {
+            continue;
+        }
+        let first = a.iter().zip(b).position(|(p, q)| p != q).unwrap();
+        let last = a.iter().zip(b).rposition(|(p, q)| p != q).unwrap();
+        let (x0, y0, x1, _) = bounds.unwrap_or((first, y, last, y));
+        bounds = Some((x0.min(first), y0, x1.max(last), y));
+    }
+    bounds.map(|(x0, y0, x1, y1)| Rect {
+        x: x0 as u32,
+        y: y0 as u32,
+        width: (x1 - x0 + 1) as u32,
+        height: (y1 - y0 + 1) as u32,
+    })
+}
+
+/// Splits the colour space at the median of the widest channel until there
+/// are `colors` boxes, and returns their average colours.
+fn median_cut(samples: Vec<[u8; 3]>, colors: usize) -> Vec<[u8; 3]> {
+    let widest_channel = |colors: &[[u8; 3]]| {
+        (0..3)
+            .map(|c| {
+                let (min, max) = colors.iter().fold((255, 0), |(min, max), color| {
+                    (color[c].min(min), color[c].max(max))
+                });
+                (max.saturating_sub(min), c)
+            })
+            .max()
+            .unwrap()
+    };
+
+    let mut boxes = vec![samples];
+    while boxes.len() < colors {
+        let Some((range, index, channel)) = boxes
+            .iter()
+            .enumerate()
+            .map(|(i, b)| {
+                let (range, channel) = widest_channel(b);
+                (range, i, channel)
+            })
+            .max()
+        else {
+            break;
+        };
+        if range == 0 {
+            break;
+        }
+        let mut lower = boxes.swap_remove(index);
+        lower.sort_unstable_by_key(|color| color[channel]);
+        let upper = lower.split_off(lower.len() / 2);
+        boxes.push(lower);
+        boxes.push(upper);
+    }
+
+    boxes
+        .iter()
+        .filter(|b| !b.is_empty())
+        .map(|b| {
+            let mut sum = [0usize; 3];
+            for color in b {
+                for c in 0..3 {
+                    sum[c] += color[c] as usize;
+                }
+            }
+            sum.map(|s| (s / b.len()) as u8)
+        })
+        .collect()
+}
+
+/// Nearest palette entry lookup, cached per 15 bit colour.
+struct Quantizer {
+    palette: Vec<[u8; 3]>,
+    cache: Vec<Option<u8>>,
+}
+
+impl Quantizer {
+    fn new(palette: Vec<[u8; 3]>) -> Self {
+        Self {
+            palette,
+            cache: vec![None; 1 << 15],
+        }
+    }
+
+    fn index(&mut self, color: [u8; 3]) -> u8 {
+        let key =
+            (color[0] as usize >> 3) << 10 | (color[1] as usize >> 3) << 5 | color[2] as usize >> 3;
+        if let Some(index) = self.cache[key] {
+            return index;
+        }
+        let distance = |entry: &[u8; 3]| {
+            (0..3)
+                .map(|c| (entry[c] as i32 - color[c] as i32).pow(2))
+                .sum::<i32>()
+        };
+        let index = (0..self.palette.len())
+            .min_by_key(|&i| distance(&self.palette[i]))
+            .unwrap_or(0) as u8;
+        self.cache[key] = Some(index);
+        index
+    }
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u8) {
+        self.buffer |= (code as u32) << self.count;
+        self.count += size as u32;
+        while self.count >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.buffer as u8);
+        }
+        self.out
+    }
+}
+
+/// GIF flavoured LZW: variable code size up to 12 bits, LSB first.
+fn lzw_encode(indices: &[u8], min_code_size: u8) -> Vec<u8> {
+    const MAX_CODES: u16 = 4096;
+    let clear = 1u16 << min_code_size;
+    let end = clear + 1;
+    let mut codes = HashMap::<(u16, u8), u16>::new();
+    let mut next = end + 1;
+    let mut code_size = min_code_size + 1;
+    let mut bits = BitWriter {
+        out: Vec::new(),
+        buffer: 0,
+        count: 0,
+    };
+
+    bits.write(clear, code_size);
+    let mut prefix = None::<u16>;
+    for &index in indices {
+        let Some(current) = prefix else {
+            prefix = Some(index as u16);
+            continue;
+        };
+        if let Some(&code) = codes.get(&(current, index)) {
+            prefix = Some(code);
+            continue;
+        }
+        bits.write(current, code_size);
+        if next < MAX_CODES {
+            codes.insert((current, index), next);
+            next += 1;
+            // The decoder adds its entries one code later, so it switches
+            // code size one code later too
+            if next > 1 << code_size && code_size < 12 {
+                code_size += 1;
+            }
+        } else {
+            bits.write(clear, code_size);
+            codes.clear();
+            next = end + 1;
+            code_size = min_code_size + 1;
+        }
+        prefix = Some(index as u16);
+    }
+    if let Some(current) = prefix {
+        bits.write(current, code_size);
+    }
+    bits.write(end, code_size);
+    bits.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{lzw_encode, Clip};
+    use crate::pipewire_stream::Frame;
+    use std::time::Duration;
+
+    fn lzw_decode(data: &[u8], min_code_size: u8) -> Vec<u8> {
+        let clear = 1usize << min_code_size;
+        let mut table: Vec<Vec<u8>> = Vec::new();
+        let reset = |table: &mut Vec<Vec<u8>>| {
+            table.clear();
+            table.extend((0..clear + 2).map(|i| vec![i as u8]));
+        };
+        reset(&mut table);
+        let (mut code_size, mut position) = (min_code_size as usize + 1, 0);
+        let mut previous: Option<Vec<u8>> = None;
+        let mut out = Vec::new();
+        loop {
+            let mut code = 0;
+            for bit in 0..code_size {
+                let i = position + bit;
+                code |= ((data[i / 8] >> (i % 8)) as usize & 1) << bit;
+            }
+            position += code_size;
+            if code == clear {
+                reset(&mut table);
+                code_size = min_code_size as usize + 1;
+                previous = None;
+                continue;
+            }
+            if code == clear + 1 {
+                return out;
+            }
+            let entry = match (table.get(code), &previous) {
+                (Some(entry), _) => entry.clone(),
+                (None, Some(p)) => [p.clone(), vec![p[0]]].concat(),
+                (None, None) => panic!("Invalid code {code}"),
+            };
+            if let Some(p) = previous {
+                if table.len() < 4096 {
+                    table.push([p, vec![entry[0]]].concat());
+                    if table.len() == 1 << code_size && code_size < 12 {
+                        code_size += 1;
+                    }
+                }
+            }
+            out.extend_from_slice(&entry);
+            previous = Some(entry);
+        }
+    }
+
+    #[test]
+    fn lzw_round_trip() {
+        // Enough varied input to fill the code table several times
+        let mut state = 1u32;
+        let indices = (0..100_000)
+            .map(|i| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                if i % 7 < 3 {
+                    (state >> 16) as u8
+                } else {
+                    (i / 50) as u8
+                }
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(lzw_decode(&lzw_encode(&indices, 8), 8), indices);
+        assert_eq!(lzw_decode(&lzw_encode(&[3; 10], 2), 2), [3; 10]);
+    }
+
+    #[test]
+    fn dedup_and_delays() {
+        let frame = |value: u8| {
+            let mut frame = Frame::new(4, 4);
+            frame.make_mut_slice()[5] = slint::Rgba8Pixel::new(value, 0, 0, 255);
+            frame
+        };
+        let mut clip = Clip::new();
+        clip.push(frame(0), Duration::from_millis(0));
+        clip.push(frame(0), Duration::from_millis(40));
+        clip.push(frame(1), Duration::from_millis(80));
+        clip.push(frame(1), Duration::from_millis(120));
+        clip.extend_to(Duration::from_millis(130));
+        assert_eq!(clip.len(), 2);
+        assert_eq!(clip.delays(100), [8, 5]);
+
+        let mut gif = Vec::new();
+        clip.write_gif(&mut gif).unwrap();
+        assert!(gif.starts_with(b"GIF89a\x04\x00\x04\x00"));
+        assert_eq!(gif.last(), Some(&0x3b));
+        // Second frame only covers the changed pixel at (1, 1)
+        let descriptors = gif
+            .windows(10)
+            .filter(|w| w[0] == 0x2c && w[9] == 0)
+            .map(|w| w[1..9].to_vec())
+            .collect::<Vec<_>>();
+        assert!(descriptors.contains(&vec![1, 0, 1, 0, 1, 0, 1, 0]));
+
+        let mut apng = Vec::new();
+        clip.write_apng(&mut apng).unwrap();
+        let count = |kind: &[u8]| apng.windows(4).filter(|w| *w == kind).count();
+        assert_eq!(
+            (
+                count(b"acTL"),
+                count(b"fcTL"),
+                count(b"IDAT"),
+                count(b"fdAT")
+            ),
+            (1, 2, 1, 1)
+        );
+    }
+
+    #[test]
+    fn out_of_order_pts() {
+        let mut frame = Frame::new(2, 2);
+        let mut clip = Clip::new();
+        clip.push(frame.clone(), Duration::from_millis(80));
+        frame.make_mut_slice()[0] = slint::Rgba8Pixel::new(255, 0, 0, 255);
+        clip.push(frame, Duration::from_millis(40));
+        assert_eq!(clip.delays(100), [0, 4]);
+        assert_eq!(clip.duration(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,248 @@
+//! Session timeline shared by video and audio: PipeWire buffer timestamps
+//! mapped to the time since the capture started, so recorders can stamp
+//! frames and audio with the same clock.
+
+use std::time::Duration;
+
+/// Nanoseconds on `CLOCK_MONOTONIC`, the clock of the PipeWire graph.
+pub fn monotonic_now() -> i64 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    Duration::new(now.tv_sec as u64, now.tv_nsec as u32).as_nanos() as i64
+}
+
+/// The timeline of a capture session, starting when it was created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionClock {
+    /// `CLOCK_MONOTONIC` nanoseconds at the start of the session.
+    origin: i64,
+}
+
+impl Default for SessionClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionClock {
+    pub fn new() -> Self {
+        Self::starting_at(monotonic_now())
+    }
+
+    pub fn starting_at(origin: i64) -> Self {
+        Self { origin }
+    }
+
+    pub fn now(&self) -> Duration {
+        self.at(monotonic_now())
+    }
+
+    /// Session time of a `CLOCK_MONOTONIC` timestamp, zero for times before
+    /// the session started.
+    pub fn at(&self, monotonic: i64) -> Duration {
+        Duration::from_nanos((monotonic - self.origin).max(0) as u64)
+    }
+}
+
+/// How the timestamps of one stream relate to the graph clock.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DriftStats {
+    /// Buffers mapped since the stream (re)connected.
+    pub samples: u64,
+    /// Graph time minus buffer timestamp of the latest buffer, in
+    /// nanoseconds. The latency for producers stamping with
+    /// `CLOCK_MONOTONIC`.
+    pub offset_ns: i64,
+    /// How fast the offset grows, in parts per million: positive when the
+    /// producer's clock runs slower than the graph clock.
+    pub drift_ppm: f64,
+    /// Largest distance of an offset from the fitted drift line.
+    pub max_jitter: Duration,
+}
+
+/// Drift of the video and, when captured, the audio stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ClockStats {
+    pub video: DriftStats,
+    pub audio: Option<DriftStats>,
+}
+
+/// Least squares line through (graph time, offset), relative to the first
+/// sample to keep the sums small.
+#[derive(Debug, Clone, Default)]
+struct LinearFit {
+    origin: Option<(i64, i64)>,
+    n: f64,
+    sum_x: f64,
+    sum_y: f64,
+    sum_xx: f64,
+    sum_xy: f64,
+}
+
+impl LinearFit {
+    /// Point relative to the origin, x in seconds and y in nanoseconds.
+    fn point(&mut self, now: i64, offset: i64) -> (f64, f64) {
+        let (now0, offset0) = *self.origin.get_or_insert((now, offset));
+        ((now - now0) as f64 / 1e9, (offset - offset0) as f64)
+    }
+
+    fn add(&mut self, now: i64, offset: i64) {
+        let (x, y) = self.point(now, offset);
+        self.n += 1.0;
+        self.sum_x += x;
+        self.sum_y += y;
+        self.sum_xx += x * x;
+        self.sum_xy += x * y;
+    }
+
+    /// Nanoseconds per second.
+    fn slope(&self) -> f64 {
+        let denominator = self.n * self.sum_xx - self.sum_x * self.sum_x;
+        if self.n < 2.0 || denominator.abs() < f64::EPSILON {
+            return 0.0;
+        }
+        (self.n * self.sum_xy - self.sum_x * self.sum_y) / denominator
+    }
+
+    /// Fitted offset at graph time `now`.
+    fn predict(&mut self, now: i64) -> i64 {
+        if self.n == 0.0 {
+            return 0;
+        }
+        let (x, _) = self.point(now, 0);
+        let slope = self.slope();
+        let intercept = (self.sum_y - slope * self.sum_x) / self.n;
+        self.origin.unwrap().1 + (intercept + slope * x).round() as i64
+    }
+}
+
+/// Maps the buffer timestamps of one stream onto a [`SessionClock`].
+///
+/// Buffers carry the producer's `spa_meta_header.pts`, which compositors
+/// take from `CLOCK_MONOTONIC` but other producers count from their own
+/// start. Those are moved onto the graph clock (`pw_stream_get_time`) by the
+/// fitted offset between the two, which also gives the drift statistics.
+#[derive(Debug, Clone)]
+pub struct StreamClock {
+    clock: SessionClock,
+    fit: LinearFit,
+    stats: DriftStats,
+    last: Option<Duration>,
+}
+
+impl StreamClock {
+    /// Timestamps closer than this to the graph time are taken to be on
+    /// `CLOCK_MONOTONIC` already.
+    const SAME_CLOCK: i64 = 1_000_000_000;
+
+    pub fn new(clock: SessionClock) -> Self {
+        Self {
+            clock,
+            fit: LinearFit::default(),
+            stats: DriftStats::default(),
+            last: None,
+        }
+    }
+
+    pub fn session(&self) -> SessionClock {
+        self.clock
+    }
+
+    /// Forgets the producer's clock, e.g. after reconnecting to another node.
+    /// The session time keeps going.
+    pub fn reset(&mut self) {
+        self.fit = LinearFit::default();
+        self.stats = DriftStats::default();
+    }
+
+    /// Session time of a buffer stamped `pts` by its producer, if it was,
+    /// that reached the graph at `now` (both in nanoseconds). The result
+    /// never goes backwards.
+    pub fn map(&mut self, pts: Option<i64>, now: i64) -> Duration {
+        let monotonic = match pts {
+            Some(pts) => {
+                let offset = now - pts;
+                self.fit.add(now, offset);
+                let fitted = self.fit.predict(now);
+                let jitter = Duration::from_nanos((offset - fitted).unsigned_abs());
+                self.stats = DriftStats {
+                    samples: self.stats.samples + 1,
+                    offset_ns: offset,
+                    drift_ppm: self.fit.slope() / 1000.0,
+                    max_jitter: self.stats.max_jitter.max(jitter),
+                };
+                if offset.abs() < Self::SAME_CLOCK {
+                    pts
+                } else {
+                    pts + fitted
+                }
+            }
+            None => {
+                self.stats.samples += 1;
+                now
+            }
+        };
+        let time = self.clock.at(monotonic).max(self.last.unwrap_or_default());
+        self.last = Some(time);
+        time
+    }
+
+    pub fn stats(&self) -> DriftStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SessionClock, StreamClock};
+    use std::time::Duration;
+
+    const MS: i64 = 1_000_000;
+
+    #[test]
+    fn monotonic_pts_are_kept() {
+        let session = SessionClock::starting_at(1000 * MS);
+        let mut clock = StreamClock::new(session);
+        // Compositor timestamps, arriving 3 to 5 ms later
+        for (i, latency) in [3, 5, 4, 3].into_iter().enumerate() {
+            let pts = 1100 * MS + i as i64 * 16 * MS;
+            let time = clock.map(Some(pts), pts + latency * MS);
+            assert_eq!(time, Duration::from_millis(100 + i as u64 * 16));
+        }
+        let stats = clock.stats();
+        assert_eq!(stats.samples, 4);
+        assert_eq!(stats.offset_ns, 3 * MS);
+        assert!(stats.max_jitter <= Duration::from_millis(2));
+
+        // Unstamped buffers use the graph time, but never go backwards
+        assert_eq!(clock.map(None, 1120 * MS), Duration::from_millis(148));
+        assert_eq!(clock.map(None, 1200 * MS), Duration::from_millis(200));
+    }
+
+    #[test]
+    fn foreign_clock_drift() {
+        let session = SessionClock::starting_at(5000 * MS);
+        let mut clock = StreamClock::new(session);
+        // A producer counting from zero whose clock runs 100 ppm slow
+        let mut last = Duration::ZERO;
+        for i in 0..100i64 {
+            let now = 5000 * MS + i * 40 * MS;
+            let pts = i * 40 * MS - i * 40 * MS / 10_000;
+            let time = clock.map(Some(pts), now);
+            let expected = Duration::from_millis(i as u64 * 40);
+            assert!(time.abs_diff(expected) < Duration::from_micros(10));
+            assert!(time >= last);
+            last = time;
+        }
+        let stats = clock.stats();
+        assert!((stats.drift_ppm - 100.0).abs() < 1.0, "{stats:?}");
+        assert!(stats.max_jitter < Duration::from_micros(10));
+
+        clock.reset();
+        assert_eq!(clock.stats().samples, 0);
+        assert!(clock.map(Some(0), 5000 * MS) >= last);
+    }
+}
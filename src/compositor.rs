@@ -0,0 +1,376 @@
+use crate::pipewire_stream::{Frame, TimedFrame};
+use crate::portal::StreamDescriptor;
+use std::collections::BTreeMap;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Placement of a single monitor in the desktop layout, in logical pixels,
+/// as reported by the portal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Placement {
+    pub position: (i32, i32),
+    pub size: (u32, u32),
+}
+
+impl Placement {
+    /// Where the portal put a shared monitor. `None` for windows and other
+    /// sources without a position.
+    pub fn from_descriptor(stream: &StreamDescriptor) -> Option<Self> {
+        let (width, height) = stream.size?;
+        Some(Self {
+            position: stream.position?,
+            size: (width.try_into().ok()?, height.try_into().ok()?),
+        })
+    }
+}
+
+struct Output {
+    placement: Placement,
+    frame: Option<Frame>,
+}
+
+/// Places per-monitor frames onto a single canvas covering the bounding box
+/// of all known monitors.
+pub struct Compositor {
+    background: slint::Rgba8Pixel,
+    outputs: BTreeMap<u32, Output>,
+}
+
+impl Compositor {
+    pub fn new(background: slint::Rgba8Pixel) -> Self {
+        Self {
+            background,
+            outputs: BTreeMap::new(),
+        }
+    }
+
+    pub fn set_background(&mut self, background: slint::Rgba8Pixel) {
+        self.background = background;
+    }
+
+    pub fn add_output(&mut self, id: u32, placement: Placement) {
+        self.outputs.insert(
+            id,
+            Output {
+                placement,
+                frame: None,
+            },
+        );
+    }
+
+    pub fn remove_output(&mut self, id: u32) {
+        self.outputs.remove(&id);
+    }
+
+    pub fn update_frame(&mut self, id: u32, frame: Frame) {
+        if let Some(output) = self.outputs.get_mut(&id) {
+            output.frame = Some(frame);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outputs.is_empty()
+    }
+
+    /// Returns the origin and size of the canvas.
+    pub fn canvas(&self) -> ((i32, i32), (u32, u32)) {
+        let mut placements = self.outputs.values().map(|o| o.placement);
+        let Some(first) = placements.next() else {
+            return ((0, 0), (0, 0));
+        };
+
+        let right = |p: Placement| p.position.0 + p.size.0 as i32;
+        let bottom = |p: Placement| p.position.1 + p.size.1 as i32;
+
+        let (mut x0, mut y0) = first.position;
+        let (mut x1, mut y1) = (right(first), bottom(first));
+        for p in placements {
+            x0 = x0.min(p.position.0);
+            y0 = y0.min(p.position.1);
+            x1 = x1.max(right(p));
+            y1 = y1.max(bottom(p));
+        }
+
+        ((x0, y0), ((x1 - x0) as u32, (y1 - y0) as u32))
+    }
+
+    /// Builds the composed frame. Monitors without a frame yet are left with
+    /// the background colour. Frames whose size differs from the logical
+    /// placement (e.g. scaled outputs) are resampled with nearest neighbour.
+    pub fn compose(&self) -> Frame {
+        let (origin, size) = self.canvas();
+        let mut canvas = Frame::new(size.0, size.1);
+        canvas.make_mut_slice().fill(self.background);
+
+        let canvas_width = size.0 as usize;
+        let pixels = canvas.make_mut_slice();
+
+        for output in self.outputs.values() {
+            let Some(frame) = &output.frame else {
+                continue;
+            };
+            let placement = output.placement;
+            if frame.width() == 0 || frame.height() == 0 {
+                continue;
+            }
+
+            let x0 = (placement.position.0 - origin.0) as usize;
+            let y0 = (placement.position.1 - origin.1) as usize;
+            let src = frame.as_slice();
+            let src_width = frame.width() as usize;
+
+            for y in 0..placement.size.1 as usize {
+                let src_y = y * frame.height() as usize / placement.size.1 as usize;
+                let dst_row = (y0 + y) * canvas_width + x0;
+                for x in 0..placement.size.0 as usize {
+                    let src_x = x * src_width / placement.size.0 as usize;
+                    pixels[dst_row + x] = src[src_y * src_width + src_x];
+                }
+            }
+        }
+
+        canvas
+    }
+}
+
+enum Event {
+    Add(u32, Placement),
+    Frame(u32, TimedFrame),
+    Remove(u32),
+    SetBackground(slint::Rgba8Pixel),
+}
+
+/// Compositor stage running on its own thread. Each monitor is fed by the
+/// frame receiver of its `PipewireStream`; a monitor is removed from the
+/// layout when its receiver is closed or `remove_monitor` is called.
+/// Composed frames carry the timestamp of the newest monitor frame.
+pub struct DesktopStitcher {
+    event_sender: async_channel::Sender<Event>,
+    frame_sender: async_channel::Sender<TimedFrame>,
+    thread_handle: Option<JoinHandle<()>>,
+}
+
+impl DesktopStitcher {
+    pub fn start(background: slint::Rgba8Pixel) -> (Self, async_channel::Receiver<TimedFrame>) {
+        let (event_sender, event_receiver) = async_channel::unbounded();
+        let (frame_sender, frame_receiver) = async_channel::bounded(10);
+
+        let thread_handle = std::thread::spawn({
+            let frame_sender = frame_sender.clone();
+            move || {
+                let mut compositor = Compositor::new(background);
+                let mut pts = Duration::ZERO;
+                while let Ok(event) = event_receiver.recv_blocking() {
+                    match event {
+                        Event::Add(id, placement) => compositor.add_output(id, placement),
+                        Event::Frame(id, frame) => {
+                            pts = pts.max(frame.pts);
+                            compositor.update_frame(id, frame.frame);
+                        }
+                        Event::Remove(id) => compositor.remove_output(id),
+                        Event::SetBackground(background) => compositor.set_background(background),
+                    }
+                    // Skip intermediate states while more events are queued
+                    if !event_receiver.is_empty() {
+                        continue;
+                    }
+                    let frame = TimedFrame {
+                        frame: compositor.compose(),
+                        pts,
+                        damage: None,
+                    };
+                    if frame_sender.send_blocking(frame).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        (
+            Self {
+                event_sender,
+                frame_sender,
+                thread_handle: Some(thread_handle),
+            },
+            frame_receiver,
+        )
+    }
+
+    /// Adds a monitor at `placement` and forwards frames from `frames` to
+    /// the compositor until the receiver is closed.
+    pub fn add_monitor(
+        &self,
+        id: u32,
+        placement: Placement,
+        frames: async_channel::Receiver<TimedFrame>,
+    ) {
+        let event_sender = self.event_sender.clone();
+        let _ = event_sender.send_blocking(Event::Add(id, placement));
+        std::thread::spawn(move || {
+            while let Ok(frame) = frames.recv_blocking() {
+                if event_sender.send_blocking(Event::Frame(id, frame)).is_err() {
+                    return;
+                }
+            }
+            let _ = event_sender.send_blocking(Event::Remove(id));
+        });
+    }
+
+    pub fn remove_monitor(&self, id: u32) {
+        let _ = self.event_sender.send_blocking(Event::Remove(id));
+    }
+
+    pub fn set_background(&self, background: slint::Rgba8Pixel) {
+        let _ = self
+            .event_sender
+            .send_blocking(Event::SetBackground(background));
+    }
+
+    pub fn stop(&mut self) {
+        // Wakes the thread if it waits for room in a full frame channel
+        self.frame_sender.close();
+        self.event_sender.close();
+        if let Some(handle) = self.thread_handle.take() {
+            handle.join().unwrap();
+        }
+    }
+}
+
+impl Drop for DesktopStitcher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Compositor, DesktopStitcher, Placement};
+    use crate::pipewire_stream::{Frame, TimedFrame};
+    use crate::portal::StreamDescriptor;
+    use std::time::{Duration, Instant};
+
+    const RED: slint::Rgba8Pixel = slint::Rgba8Pixel::new(255, 0, 0, 255);
+    const BLACK: slint::Rgba8Pixel = slint::Rgba8Pixel::new(0, 0, 0, 255);
+
+    fn solid(width: u32, height: u32, color: slint::Rgba8Pixel) -> Frame {
+        let mut frame = Frame::new(width, height);
+        frame.make_mut_slice().fill(color);
+        frame
+    }
+
+    #[test]
+    fn canvas_covers_all_outputs() {
+        let mut compositor = Compositor::new(BLACK);
+        compositor.add_output(
+            1,
+            Placement {
+                position: (-4, 0),
+                size: (4, 2),
+            },
+        );
+        compositor.add_output(
+            2,
+            Placement {
+                position: (0, 1),
+                size: (2, 2),
+            },
+        );
+        assert_eq!(compositor.canvas(), ((-4, 0), (6, 3)));
+
+        compositor.remove_output(1);
+        assert_eq!(compositor.canvas(), ((0, 1), (2, 2)));
+    }
+
+    #[test]
+    fn compose_places_and_scales_frames() {
+        let mut compositor = Compositor::new(BLACK);
+        compositor.add_output(
+            1,
+            Placement {
+                position: (0, 0),
+                size: (2, 2),
+            },
+        );
+        compositor.add_output(
+            2,
+            Placement {
+                position: (2, 0),
+                size: (1, 1),
+            },
+        );
+        // Output 1 is a HiDPI monitor: physical frame twice the logical size
+        compositor.update_frame(1, solid(4, 4, RED));
+
+        let canvas = compositor.compose();
+        assert_eq!((canvas.width(), canvas.height()), (3, 2));
+        let pixel = |x: usize, y: usize| canvas.as_slice()[y * 3 + x];
+        assert_eq!(pixel(0, 0), RED);
+        assert_eq!(pixel(1, 1), RED);
+        // No frame yet for output 2, and the gap below it
+        assert_eq!(pixel(2, 0), BLACK);
+        assert_eq!(pixel(2, 1), BLACK);
+    }
+
+    #[test]
+    fn placement_of_monitors_only() {
+        let mut stream = StreamDescriptor {
+            node_id: 42,
+            id: None,
+            position: Some((-1920, 0)),
+            size: Some((1920, 1080)),
+            source_type: None,
+            mapping_id: None,
+        };
+        assert_eq!(
+            Placement::from_descriptor(&stream),
+            Some(Placement {
+                position: (-1920, 0),
+                size: (1920, 1080),
+            })
+        );
+        stream.position = None;
+        assert_eq!(Placement::from_descriptor(&stream), None);
+    }
+
+    #[test]
+    fn stop_with_full_output() {
+        let (mut stitcher, stitched) = DesktopStitcher::start(BLACK);
+        let (sender, frames) = async_channel::unbounded();
+        stitcher.add_monitor(
+            1,
+            Placement {
+                position: (0, 0),
+                size: (2, 2),
+            },
+            frames,
+        );
+        // Paced so each one is composed, nobody takes the output
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut pts = Duration::ZERO;
+        while !stitched.is_full() {
+            assert!(Instant::now() < deadline, "output never filled up");
+            pts += Duration::from_millis(10);
+            let frame = TimedFrame {
+                frame: solid(2, 2, RED),
+                pts,
+                damage: None,
+            };
+            sender.send_blocking(frame).unwrap();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        sender
+            .send_blocking(TimedFrame {
+                frame: solid(2, 2, RED),
+                pts,
+                damage: None,
+            })
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+
+        stitcher.stop();
+        // What was composed before stopping is still delivered
+        assert!(stitched.recv_blocking().is_ok());
+        while stitched.try_recv().is_ok() {}
+        assert!(stitched.is_closed());
+    }
+}
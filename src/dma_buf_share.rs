@@ -0,0 +1,681 @@
+//! Zero-copy frame sharing with other local processes: the DMA-BUF file
+//! descriptors of each captured buffer go over a Unix socket, with a header
+//! describing their layout.
+//!
+//! Every message is one `sendmsg` of a [`HEADER_SIZE`] byte header with the
+//! plane descriptors attached as `SCM_RIGHTS`, all integers little-endian:
+//!
+//! | Offset | Field                                        |
+//! |--------|----------------------------------------------|
+//! | 0      | Magic `SCDB`                                 |
+//! | 4      | Protocol version, u32                        |
+//! | 8      | Sequence number, u64                         |
+//! | 16     | Capture time in ns, u64                      |
+//! | 24     | Width, height, DRM fourcc, plane count, u32s |
+//! | 40     | DRM modifier, u64                            |
+//! | 48     | Offset and stride of planes 0 to 3, u32s     |
+//!
+//! The buffers stay the producer's: a frame's contents are only valid until
+//! it captures the next one, so clients should import and use them promptly.
+
+use crate::pipewire_stream;
+use std::io::Read;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+pub const MAGIC: [u8; 4] = *b"SCDB";
+pub const VERSION: u32 = 1;
+pub const HEADER_SIZE: usize = 80;
+pub const MAX_PLANES: usize = 4;
+
+/// Room for the descriptors of every plane.
+const CONTROL_SIZE: usize =
+    unsafe { libc::CMSG_SPACE((MAX_PLANES * std::mem::size_of::<RawFd>()) as u32) } as usize;
+
+/// `$XDG_RUNTIME_DIR/screencast-dma-buf.sock`, in the temporary directory
+/// without a runtime directory.
+pub fn default_socket_path() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("screencast-dma-buf.sock")
+}
+
+/// Opens `path` read-write, or the first of /dev/dri/renderD128, card0 and
+/// card1, e.g. to create a GBM device to import frames with.
+pub fn open_drm_device(path: Option<&Path>) -> std::io::Result<std::fs::File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.read(true).write(true);
+    match path {
+        Some(path) => options.open(path),
+        None => options
+            .open("/dev/dri/renderD128")
+            .or_else(|_| options.open("/dev/dri/card0"))
+            .or_else(|_| options.open("/dev/dri/card1")),
+    }
+}
+
+#[derive(Debug)]
+pub struct DmaBufPlane {
+    pub fd: OwnedFd,
+    pub offset: u32,
+    pub stride: u32,
+}
+
+/// One captured buffer as its DMA-BUF planes.
+#[derive(Debug)]
+pub struct DmaBufFrame {
+    pub width: u32,
+    pub height: u32,
+    /// DRM format, e.g. `XR24` for BGRx.
+    pub fourcc: u32,
+    pub modifier: u64,
+    pub planes: Vec<DmaBufPlane>,
+    /// Counts the frames published by the server, from 1.
+    pub sequence: u64,
+    /// Capture time on the session clock.
+    pub pts: Duration,
+}
+
+impl DmaBufFrame {
+    fn header(&self) -> [u8; HEADER_SIZE] {
+        let mut header = [0; HEADER_SIZE];
+        header[0..4].copy_from_slice(&MAGIC);
+        header[4..8].copy_from_slice(&VERSION.to_le_bytes());
+        header[8..16].copy_from_slice(&self.sequence.to_le_bytes());
+        header[16..24].copy_from_slice(&(self.pts.as_nanos() as u64).to_le_bytes());
+        for (i, value) in [
+            self.width,
+            self.height,
+            self.fourcc,
+            self.planes.len() as u32,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            header[24 + i * 4..][..4].copy_from_slice(&value.to_le_bytes());
+        }
+        header[40..48].copy_from_slice(&self.modifier.to_le_bytes());
+        for (i, plane) in self.planes.iter().enumerate() {
+            header[48 + i * 8..][..4].copy_from_slice(&plane.offset.to_le_bytes());
+            header[52 + i * 8..][..4].copy_from_slice(&plane.stride.to_le_bytes());
+        }
+        header
+    }
+
+    /// The frame `header` describes, with the descriptors that came with it.
+    fn parse(header: &[u8; HEADER_SIZE], fds: Vec<OwnedFd>) -> std::io::Result<Self> {
+        let u32_at = |offset: usize| u32::from_le_bytes(header[offset..][..4].try_into().unwrap());
+        let u64_at = |offset: usize| u64::from_le_bytes(header[offset..][..8].try_into().unwrap());
+        if header[0..4] != MAGIC || u32_at(4) != VERSION {
+            return Err(invalid_data("Not a DMA-BUF frame of a known version"));
+        }
+        if u32_at(36) as usize != fds.len() {
+            return Err(invalid_data("Plane count does not match the descriptors"));
+        }
+        Ok(Self {
+            width: u32_at(24),
+            height: u32_at(28),
+            fourcc: u32_at(32),
+            modifier: u64_at(40),
+            planes: fds
+                .into_iter()
+                .enumerate()
+                .map(|(i, fd)| DmaBufPlane {
+                    fd,
+                    offset: u32_at(48 + i * 8),
+                    stride: u32_at(52 + i * 8),
+                })
+                .collect(),
+            sequence: u64_at(8),
+            pts: Duration::from_nanos(u64_at(16)),
+        })
+    }
+
+    /// Imports the planes as a GBM buffer object, to map or hand to EGL.
+    pub fn import<T: AsFd>(
+        &self,
+        device: &gbm::Device<T>,
+    ) -> std::io::Result<gbm::BufferObject<()>> {
+        let format =
+            gbm::Format::try_from(self.fourcc).map_err(|_| invalid_data("Unknown DRM format"))?;
+        let mut fds = [None; MAX_PLANES];
+        let mut strides = [0; MAX_PLANES];
+        let mut offsets = [0; MAX_PLANES];
+        for (i, plane) in self.planes.iter().enumerate() {
+            fds[i] = Some(plane.fd.as_fd());
+            strides[i] = plane.stride as i32;
+            offsets[i] = plane.offset as i32;
+        }
+        device.import_buffer_object_from_dma_buf_with_modifiers(
+            self.planes.len() as u32,
+            fds,
+            self.width,
+            self.height,
+            format,
+            gbm::BufferObjectFlags::empty(),
+            strides,
+            offsets,
+            gbm::Modifier::from(self.modifier),
+        )
+    }
+}
+
+/// A DRM format from its four character code, e.g. `AR24` for ARGB8888.
+pub fn parse_fourcc(s: &str) -> Result<gbm::Format, String> {
+    let code: [u8; 4] = s
+        .as_bytes()
+        .try_into()
+        .map_err(|_| format!("Expected four characters, got '{s}'"))?;
+    gbm::Format::try_from(u32::from_le_bytes(code)).map_err(|_| format!("Unknown DRM format '{s}'"))
+}
+
+/// `linear`, `invalid` or the modifier's number, e.g. `0x300000000606014`.
+pub fn parse_modifier(s: &str) -> Result<gbm::Modifier, String> {
+    let value = match s {
+        "linear" => return Ok(gbm::Modifier::Linear),
+        "invalid" => return Ok(gbm::Modifier::Invalid),
+        _ => match s.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => s.parse(),
+        },
+    };
+    value
+        .map(gbm::Modifier::from)
+        .map_err(|_| format!("Invalid modifier '{s}', expected linear, invalid or a number"))
+}
+
+/// Bytes per pixel of the common single plane formats.
+pub fn bytes_per_pixel(format: gbm::Format) -> Option<u32> {
+    use gbm::Format as F;
+    match format {
+        F::R8 => Some(1),
+        F::Rg88 | F::Gr88 | F::Rgb565 | F::Bgr565 => Some(2),
+        F::Rgb888 | F::Bgr888 => Some(3),
+        F::Argb8888
+        | F::Xrgb8888
+        | F::Abgr8888
+        | F::Xbgr8888
+        | F::Rgba8888
+        | F::Rgbx8888
+        | F::Bgra8888
+        | F::Bgrx8888
+        | F::Argb2101010
+        | F::Xrgb2101010
+        | F::Abgr2101010
+        | F::Xbgr2101010 => Some(4),
+        F::Abgr16161616f | F::Xbgr16161616f => Some(8),
+        _ => None,
+    }
+}
+
+/// CRC-32 of the first `row` bytes of `height` rows `stride` apart, which
+/// both ends of a transfer agree on whatever padding their mappings have.
+pub fn checksum(data: &[u8], stride: usize, row: usize, height: usize) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for y in 0..height {
+        hasher.update(&data[y * stride..][..row]);
+    }
+    hasher.finalize()
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn send_frame(stream: &UnixStream, frame: &DmaBufFrame) -> std::io::Result<()> {
+    let fds = frame
+        .planes
+        .iter()
+        .map(|plane| plane.fd.as_raw_fd())
+        .collect::<Vec<_>>();
+    send_with_fds(stream, &frame.header(), &fds)
+}
+
+/// The next frame, `None` once the server closed the connection.
+fn recv_frame(stream: &UnixStream) -> std::io::Result<Option<DmaBufFrame>> {
+    let mut header = [0; HEADER_SIZE];
+    let Some(fds) = recv_with_fds(stream, &mut header)? else {
+        return Ok(None);
+    };
+    DmaBufFrame::parse(&header, fds).map(Some)
+}
+
+/// Sends `data` with up to [`MAX_PLANES`] descriptors attached to its first
+/// byte as `SCM_RIGHTS`.
+pub(crate) fn send_with_fds(
+    stream: &UnixStream,
+    data: &[u8],
+    fds: &[RawFd],
+) -> std::io::Result<()> {
+    assert!(fds.len() <= MAX_PLANES);
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut _,
+        iov_len: data.len(),
+    };
+    // u64s to align the cmsghdr
+    let mut control = [0u64; CONTROL_SIZE.div_ceil(8)];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    let sent = unsafe {
+        let data_len = std::mem::size_of_val(fds) as u32;
+        msg.msg_controllen = libc::CMSG_SPACE(data_len) as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(data_len) as _;
+        std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg).cast(), fds.len());
+        libc::sendmsg(stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL)
+    };
+    if sent < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // The descriptors went with the first byte, the rest is plain data
+    let mut stream = stream;
+    std::io::Write::write_all(&mut stream, &data[sent as usize..])
+}
+
+/// Fills `data` from a message sent by [`send_with_fds`] and returns the
+/// descriptors attached, `None` if the peer closed the connection first.
+pub(crate) fn recv_with_fds(
+    stream: &UnixStream,
+    data: &mut [u8],
+) -> std::io::Result<Option<Vec<OwnedFd>>> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr().cast(),
+        iov_len: data.len(),
+    };
+    let mut control = [0u64; CONTROL_SIZE.div_ceil(8)];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+    let received = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if received < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    // Owned right away, so that they are closed on every error below
+    let mut fds = Vec::new();
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                    / std::mem::size_of::<RawFd>();
+                for i in 0..count {
+                    fds.push(OwnedFd::from_raw_fd(data.add(i).read_unaligned()));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if received == 0 {
+        return Ok(None);
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(invalid_data("More descriptors than supported"));
+    }
+    let mut stream = stream;
+    stream.read_exact(&mut data[received as usize..])?;
+    Ok(Some(fds))
+}
+
+/// The latest frame, sent to every client that has not had it yet.
+#[derive(Default)]
+struct Latest {
+    frame: Option<Arc<DmaBufFrame>>,
+    sequence: u64,
+}
+
+struct Shared {
+    latest: Mutex<Latest>,
+    published: Condvar,
+    stopped: AtomicBool,
+    clients: AtomicUsize,
+}
+
+impl Shared {
+    /// The first frame after `sequence`, waiting up to `timeout` for it.
+    fn frame_after(&self, sequence: u64, timeout: Duration) -> Option<Arc<DmaBufFrame>> {
+        let latest = self.latest.lock().unwrap();
+        let (latest, _) = self
+            .published
+            .wait_timeout_while(latest, timeout, |latest| {
+                latest.sequence <= sequence && !self.stopped.load(Ordering::Relaxed)
+            })
+            .unwrap();
+        latest
+            .frame
+            .clone()
+            .filter(|frame| frame.sequence > sequence)
+    }
+}
+
+/// Publishes DMA-BUF frames on a Unix socket.
+pub struct DmaBufServer {
+    shared: Arc<Shared>,
+    path: PathBuf,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DmaBufServer {
+    /// Listens on `path`, replacing a socket left behind by a server that is
+    /// gone but not one that still answers.
+    pub fn bind(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        if UnixStream::connect(&path).is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                format!("{} is served by another process", path.display()),
+            ));
+        }
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        // Polled so that stop() does not have to wake a blocked accept()
+        listener.set_nonblocking(true)?;
+        let shared = Arc::new(Shared {
+            latest: Mutex::new(Latest::default()),
+            published: Condvar::new(),
+            stopped: AtomicBool::new(false),
+            clients: AtomicUsize::new(0),
+        });
+        let thread = std::thread::spawn({
+            let shared = Arc::clone(&shared);
+            move || accept(listener, shared)
+        });
+        Ok(Self {
+            shared,
+            path,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Clients currently connected, as of the last frame sent to them.
+    pub fn clients(&self) -> usize {
+        self.shared.clients.load(Ordering::Relaxed)
+    }
+
+    /// Makes `frame` the latest one and returns its sequence number. Clients
+    /// still busy with an older frame skip to it.
+    pub fn publish(&self, mut frame: DmaBufFrame) -> std::io::Result<u64> {
+        if frame.planes.is_empty() || frame.planes.len() > MAX_PLANES {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Cannot share {} planes", frame.planes.len()),
+            ));
+        }
+        let mut latest = self.shared.latest.lock().unwrap();
+        latest.sequence += 1;
+        frame.sequence = latest.sequence;
+        latest.frame = Some(Arc::new(frame));
+        let sequence = latest.sequence;
+        drop(latest);
+        self.shared.published.notify_all();
+        Ok(sequence)
+    }
+
+    /// Disconnects the clients and removes the socket.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+        self.shared.published.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl Drop for DmaBufServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn accept(listener: UnixListener, shared: Arc<Shared>) {
+    while !shared.stopped.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let shared = Arc::clone(&shared);
+                std::thread::spawn(move || {
+                    shared.clients.fetch_add(1, Ordering::Relaxed);
+                    let result = send_frames(stream, &shared);
+                    shared.clients.fetch_sub(1, Ordering::Relaxed);
+                    if let Err(e) = result {
+                        if e.kind() != std::io::ErrorKind::BrokenPipe
+                            && e.kind() != std::io::ErrorKind::ConnectionReset
+                        {
+                            println!("DMA-BUF client failed: {e}");
+                        }
+                    }
+                });
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(20))
+            }
+            Err(e) => {
+                println!("DMA-BUF server failed: {e}");
+                return;
+            }
+        }
+    }
+}
+
+fn send_frames(stream: UnixStream, shared: &Shared) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    // New clients start with the latest frame
+    let mut sequence = 0;
+    while !shared.stopped.load(Ordering::Relaxed) {
+        let Some(frame) = shared.frame_after(sequence, Duration::from_millis(500)) else {
+            continue;
+        };
+        sequence = frame.sequence;
+        send_frame(&stream, &frame)?;
+    }
+    Ok(())
+}
+
+/// Receives the frames of a [`DmaBufServer`].
+pub struct DmaBufClient {
+    stream: UnixStream,
+}
+
+impl DmaBufClient {
+    pub fn connect(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self {
+            stream: UnixStream::connect(path)?,
+        })
+    }
+
+    /// How long [`recv`](Self::recv) waits, `None` for ever.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    /// Waits for the next frame, `None` once the server stopped.
+    pub fn recv(&mut self) -> std::io::Result<Option<DmaBufFrame>> {
+        recv_frame(&self.stream)
+    }
+}
+
+/// Publishes frames to `server` until the channel closes or `stop` returns
+/// true.
+pub fn serve(
+    frames: &async_channel::Receiver<DmaBufFrame>,
+    server: &DmaBufServer,
+    stop: impl Fn() -> bool,
+) {
+    let _ = pipewire_stream::for_each_frame(frames, stop, |frame| {
+        if let Err(e) = server.publish(frame) {
+            println!("Failed to share frame: {e}");
+        }
+        Ok(())
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        checksum, parse_fourcc, parse_modifier, DmaBufClient, DmaBufFrame, DmaBufPlane,
+        DmaBufServer,
+    };
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("screencast-{}-{name}.sock", std::process::id()))
+    }
+
+    /// A memfd holding `contents`, standing in for a DMA-BUF.
+    fn memfd(contents: &[u8]) -> OwnedFd {
+        let fd = unsafe { libc::memfd_create(c"plane".as_ptr(), libc::MFD_CLOEXEC) };
+        assert!(fd >= 0);
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let written =
+            unsafe { libc::write(fd.as_raw_fd(), contents.as_ptr().cast(), contents.len()) };
+        assert_eq!(written, contents.len() as isize);
+        fd
+    }
+
+    fn read_plane(plane: &DmaBufPlane) -> Vec<u8> {
+        let mut contents = vec![0; 16];
+        let read = unsafe {
+            libc::pread(
+                plane.fd.as_raw_fd(),
+                contents.as_mut_ptr().cast(),
+                contents.len(),
+                0,
+            )
+        };
+        contents.truncate(read.max(0) as usize);
+        contents
+    }
+
+    fn frame(planes: &[&[u8]]) -> DmaBufFrame {
+        DmaBufFrame {
+            width: 64,
+            height: 32,
+            fourcc: u32::from_le_bytes(*b"XR24"),
+            modifier: 0x0100_0000_0000_0001,
+            planes: planes
+                .iter()
+                .enumerate()
+                .map(|(i, contents)| DmaBufPlane {
+                    fd: memfd(contents),
+                    offset: i as u32 * 4096,
+                    stride: 256 / (i as u32 + 1),
+                })
+                .collect(),
+            sequence: 0,
+            pts: Duration::from_millis(1500),
+        }
+    }
+
+    fn wait_for_clients(server: &DmaBufServer, count: usize) {
+        for _ in 0..250 {
+            if server.clients() == count {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        panic!("{count} clients did not connect");
+    }
+
+    #[test]
+    fn frames_reach_every_client() {
+        let path = socket_path("share");
+        let server = DmaBufServer::bind(&path).unwrap();
+        let mut clients = [(); 2].map(|_| DmaBufClient::connect(&path).unwrap());
+        for client in &clients {
+            client.set_timeout(Some(Duration::from_secs(5))).unwrap();
+        }
+        wait_for_clients(&server, 2);
+
+        assert_eq!(server.publish(frame(&[b"luma", b"chroma"])).unwrap(), 1);
+        for client in &mut clients {
+            let received = client.recv().unwrap().unwrap();
+            assert_eq!((received.width, received.height), (64, 32));
+            assert_eq!(&received.fourcc.to_le_bytes(), b"XR24");
+            assert_eq!(received.modifier, 0x0100_0000_0000_0001);
+            assert_eq!(
+                (received.sequence, received.pts),
+                (1, Duration::from_millis(1500))
+            );
+            assert_eq!(received.planes.len(), 2);
+            assert_eq!(read_plane(&received.planes[0]), b"luma");
+            assert_eq!(read_plane(&received.planes[1]), b"chroma");
+            assert_eq!(
+                (received.planes[1].offset, received.planes[1].stride),
+                (4096, 128)
+            );
+        }
+
+        // Clients joining later start with the latest frame
+        server.publish(frame(&[b"second"])).unwrap();
+        server.publish(frame(&[b"third"])).unwrap();
+        let mut late = DmaBufClient::connect(&path).unwrap();
+        late.set_timeout(Some(Duration::from_secs(5))).unwrap();
+        let latest = late.recv().unwrap().unwrap();
+        assert_eq!(latest.sequence, 3);
+        assert_eq!(read_plane(&latest.planes[0]), b"third");
+        // Those behind skip frames rather than queueing them
+        while clients[0].recv().unwrap().unwrap().sequence < 3 {}
+
+        assert!(server.publish(frame(&[])).is_err());
+        server.stop();
+        assert!(clients[0].recv().unwrap().is_none());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn stale_sockets_are_replaced() {
+        let path = socket_path("stale");
+        // Left behind as if its server had crashed
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let server = DmaBufServer::bind(&path).unwrap();
+        DmaBufClient::connect(&path).unwrap();
+
+        let error = DmaBufServer::bind(&path).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::AddrInUse);
+        server.stop();
+    }
+
+    #[test]
+    fn diagnostic_options() {
+        assert_eq!(parse_fourcc("AR24"), Ok(gbm::Format::Argb8888));
+        assert_eq!(parse_fourcc("XB24"), Ok(gbm::Format::Xbgr8888));
+        assert!(parse_fourcc("ARGB8888").is_err());
+        assert!(parse_fourcc("ZZZZ").is_err());
+
+        assert_eq!(parse_modifier("linear"), Ok(gbm::Modifier::Linear));
+        assert_eq!(
+            parse_modifier("0x300000000606014").map(u64::from),
+            Ok(0x300000000606014)
+        );
+        assert_eq!(parse_modifier("0").map(u64::from), Ok(0));
+        assert!(parse_modifier("tiled").is_err());
+
+        // Padding after each row does not count
+        let padded = [1, 2, 9, 9, 3, 4, 9, 9];
+        assert_eq!(checksum(&padded, 4, 2, 2), checksum(&[1, 2, 3, 4], 2, 2, 2));
+        assert_ne!(checksum(&padded, 4, 2, 2), checksum(&[1, 2, 3, 5], 2, 2, 2));
+    }
+}
@@ -333,7 +333,7 @@ impl EglDmaBuf {
     }
 }
 
-fn spa_pixel_format_to_drm_format(spa_format: VideoFormat) -> Option<i32> {
+pub(crate) fn spa_pixel_format_to_drm_format(spa_format: VideoFormat) -> Option<i32> {
     use drm::buffer::DrmFourcc::*;
     match spa_format {
         VideoFormat::RGBA => Some(Abgr8888 as i32),
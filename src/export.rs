@@ -0,0 +1,562 @@
+//! Image file encoders (PNG, QOI, PPM/PAM, JPEG through [`crate::jpeg`]) and
+//! the matching QOI and PNM decoders, e.g. for golden-image comparisons in
+//! tests.
+
+use crate::pipewire_stream::Frame;
+use std::io::{BufRead, Read, Write};
+use std::path::Path;
+
+/// Byte order of 4 byte pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelLayout {
+    Rgba,
+    Bgra,
+    /// RGB with an unused fourth byte, written as opaque.
+    Rgbx,
+    Bgrx,
+}
+
+/// Borrowed image with rows `stride` bytes apart, e.g. a mapped PipeWire
+/// buffer with padded rows.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageView<'a> {
+    pub data: &'a [u8],
+    pub width: u32,
+    pub height: u32,
+    pub stride: usize,
+    pub layout: PixelLayout,
+}
+
+impl<'a> From<&'a Frame> for ImageView<'a> {
+    fn from(frame: &'a Frame) -> Self {
+        Self {
+            data: frame.as_bytes(),
+            width: frame.width(),
+            height: frame.height(),
+            stride: frame.width() as usize * 4,
+            layout: PixelLayout::Rgba,
+        }
+    }
+}
+
+impl ImageView<'_> {
+    /// Row `y` as RGBA, into `out`.
+    pub(crate) fn row_rgba(&self, y: u32, out: &mut Vec<u8>) {
+        let start = y as usize * self.stride;
+        let row = &self.data[start..start + self.width as usize * 4];
+        out.clear();
+        match self.layout {
+            PixelLayout::Rgba => out.extend_from_slice(row),
+            PixelLayout::Bgra => {
+                out.extend(row.chunks_exact(4).flat_map(|p| [p[2], p[1], p[0], p[3]]))
+            }
+            PixelLayout::Rgbx => {
+                out.extend(row.chunks_exact(4).flat_map(|p| [p[0], p[1], p[2], 255]))
+            }
+            PixelLayout::Bgrx => {
+                out.extend(row.chunks_exact(4).flat_map(|p| [p[2], p[1], p[0], 255]))
+            }
+        }
+    }
+
+    /// Copies the image into a tightly packed RGBA frame.
+    pub fn to_frame(&self) -> Frame {
+        let mut frame = Frame::new(self.width, self.height);
+        let mut row = Vec::with_capacity(self.width as usize * 4);
+        let row_len = self.width as usize * 4;
+        let bytes = frame.make_mut_bytes();
+        for y in 0..self.height {
+            self.row_rgba(y, &mut row);
+            let start = y as usize * row_len;
+            bytes[start..start + row_len].copy_from_slice(&row);
+        }
+        frame
+    }
+}
+
+/// File formats, picked from the file extension by [`save`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Qoi,
+    /// Binary RGB PPM (`P6`), alpha is dropped.
+    Ppm,
+    /// `P7` PAM with `RGB_ALPHA` tuples.
+    Pam,
+    /// Baseline JPEG at quality 90, alpha is dropped.
+    Jpeg,
+}
+
+impl ImageFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "png" => Some(ImageFormat::Png),
+            "qoi" => Some(ImageFormat::Qoi),
+            "ppm" => Some(ImageFormat::Ppm),
+            "pam" => Some(ImageFormat::Pam),
+            "jpg" | "jpeg" => Some(ImageFormat::Jpeg),
+            _ => None,
+        }
+    }
+
+    pub fn write<'a>(
+        self,
+        writer: &mut impl Write,
+        image: impl Into<ImageView<'a>>,
+    ) -> std::io::Result<()> {
+        match self {
+            ImageFormat::Png => write_png(writer, image),
+            ImageFormat::Qoi => write_qoi(writer, image),
+            ImageFormat::Ppm => write_ppm(writer, image),
+            ImageFormat::Pam => write_pam(writer, image),
+            ImageFormat::Jpeg => crate::jpeg::JpegEncoder::new(90).write(writer, image),
+        }
+    }
+}
+
+/// Writes `image` to `path` in the format matching its extension.
+pub fn save<'a>(path: impl AsRef<Path>, image: impl Into<ImageView<'a>>) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let format = ImageFormat::from_path(path).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Unknown image format of {}", path.display()),
+        )
+    })?;
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+    format.write(&mut writer, image)?;
+    writer.flush()
+}
+
+/// Reads an image written by [`save`] in one of the formats with a reader:
+/// QOI, PPM or PAM.
+pub fn load(path: impl AsRef<Path>) -> std::io::Result<Frame> {
+    let path = path.as_ref();
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    match ImageFormat::from_path(path) {
+        Some(ImageFormat::Qoi) => read_qoi(&mut reader),
+        Some(ImageFormat::Ppm | ImageFormat::Pam) => read_pnm(&mut reader),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Cannot read {}, only QOI, PPM and PAM", path.display()),
+        )),
+    }
+}
+
+pub(crate) const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+pub(crate) fn write_chunk(
+    writer: &mut impl Write,
+    kind: &[u8; 4],
+    data: &[u8],
+) -> std::io::Result<()> {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    writer.write_all(&crc.finalize().to_be_bytes())
+}
+
+/// IHDR contents for an 8 bit RGBA image.
+pub(crate) fn png_header(width: u32, height: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // Bit depth 8, colour type 6 (RGBA), default compression, filter and no interlace
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+    header
+}
+
+/// Deflated scanlines, the contents of IDAT (and APNG fdAT) chunks.
+pub(crate) fn png_image_data(image: &ImageView) -> Vec<u8> {
+    let row_len = image.width as usize * 4;
+    let mut scanlines = Vec::with_capacity((row_len + 1) * image.height as usize);
+    let mut row = Vec::with_capacity(row_len);
+    for y in 0..image.height {
+        image.row_rgba(y, &mut row);
+        // Filter type 0: none
+        scanlines.push(0);
+        scanlines.extend_from_slice(&row);
+    }
+    miniz_oxide::deflate::compress_to_vec_zlib(&scanlines, 6)
+}
+
+/// Encodes `image` as an 8 bit RGBA PNG.
+pub fn write_png<'a>(
+    writer: &mut impl Write,
+    image: impl Into<ImageView<'a>>,
+) -> std::io::Result<()> {
+    let image = image.into();
+    writer.write_all(&PNG_SIGNATURE)?;
+    write_chunk(writer, b"IHDR", &png_header(image.width, image.height))?;
+    write_chunk(writer, b"IDAT", &png_image_data(&image))?;
+    write_chunk(writer, b"IEND", &[])
+}
+
+const QOI_OP_INDEX: u8 = 0x00;
+const QOI_OP_DIFF: u8 = 0x40;
+const QOI_OP_LUMA: u8 = 0x80;
+const QOI_OP_RUN: u8 = 0xc0;
+const QOI_OP_RGB: u8 = 0xfe;
+const QOI_OP_RGBA: u8 = 0xff;
+const QOI_END: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 1];
+
+fn qoi_hash(p: [u8; 4]) -> usize {
+    (p[0] as usize * 3 + p[1] as usize * 5 + p[2] as usize * 7 + p[3] as usize * 11) % 64
+}
+
+/// Encodes `image` as a 4 channel sRGB QOI image.
+pub fn write_qoi<'a>(
+    writer: &mut impl Write,
+    image: impl Into<ImageView<'a>>,
+) -> std::io::Result<()> {
+    let image = image.into();
+    let mut out = Vec::with_capacity(14 + image.width as usize * image.height as usize * 2);
+    out.extend_from_slice(b"qoif");
+    out.extend_from_slice(&image.width.to_be_bytes());
+    out.extend_from_slice(&image.height.to_be_bytes());
+    // 4 channels, sRGB with linear alpha
+    out.extend_from_slice(&[4, 0]);
+
+    let mut index = [[0u8; 4]; 64];
+    let mut previous = [0, 0, 0, 255];
+    let mut run = 0u8;
+    let mut row = Vec::with_capacity(image.width as usize * 4);
+    for y in 0..image.height {
+        image.row_rgba(y, &mut row);
+        for pixel in row.chunks_exact(4) {
+            let pixel = [pixel[0], pixel[1], pixel[2], pixel[3]];
+            if pixel == previous {
+                run += 1;
+                if run == 62 {
+                    out.push(QOI_OP_RUN | (run - 1));
+                    run = 0;
+                }
+                continue;
+            }
+            if run > 0 {
+                out.push(QOI_OP_RUN | (run - 1));
+                run = 0;
+            }
+
+            let hash = qoi_hash(pixel);
+            if index[hash] == pixel {
+                out.push(QOI_OP_INDEX | hash as u8);
+            } else if pixel[3] == previous[3] {
+                let dr = pixel[0].wrapping_sub(previous[0]) as i8;
+                let dg = pixel[1].wrapping_sub(previous[1]) as i8;
+                let db = pixel[2].wrapping_sub(previous[2]) as i8;
+                let dr_dg = dr.wrapping_sub(dg);
+                let db_dg = db.wrapping_sub(dg);
+                if (-2..2).contains(&dr) && (-2..2).contains(&dg) && (-2..2).contains(&db) {
+                    out.push(
+                        QOI_OP_DIFF
+                            | ((dr + 2) as u8) << 4
+                            | ((dg + 2) as u8) << 2
+                            | (db + 2) as u8,
+                    );
+                } else if (-32..32).contains(&dg)
+                    && (-8..8).contains(&dr_dg)
+                    && (-8..8).contains(&db_dg)
+                {
+                    out.push(QOI_OP_LUMA | (dg + 32) as u8);
+                    out.push(((dr_dg + 8) as u8) << 4 | (db_dg + 8) as u8);
+                } else {
+                    out.extend_from_slice(&[QOI_OP_RGB, pixel[0], pixel[1], pixel[2]]);
+                }
+            } else {
+                out.push(QOI_OP_RGBA);
+                out.extend_from_slice(&pixel);
+            }
+            index[hash] = pixel;
+            previous = pixel;
+        }
+    }
+    if run > 0 {
+        out.push(QOI_OP_RUN | (run - 1));
+    }
+    out.extend_from_slice(&QOI_END);
+    writer.write_all(&out)
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Decodes a QOI image, e.g. a golden image in tests.
+pub fn read_qoi(reader: &mut impl Read) -> std::io::Result<Frame> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    if data.len() < 14 + QOI_END.len() || &data[..4] != b"qoif" {
+        return Err(invalid_data("Not a QOI image"));
+    }
+    let width = u32::from_be_bytes(data[4..8].try_into().unwrap());
+    let height = u32::from_be_bytes(data[8..12].try_into().unwrap());
+
+    let mut frame = Frame::new(width, height);
+    let mut index = [[0u8; 4]; 64];
+    let mut pixel = [0, 0, 0, 255];
+    let mut run = 0;
+    let mut pos = 14;
+    let end = data.len() - QOI_END.len();
+    let byte = |pos: usize| {
+        data.get(pos)
+            .copied()
+            .ok_or_else(|| invalid_data("Truncated QOI"))
+    };
+
+    for out in frame.make_mut_slice() {
+        if run > 0 {
+            run -= 1;
+        } else if pos < end {
+            let op = byte(pos)?;
+            pos += 1;
+            if op == QOI_OP_RGB {
+                pixel[..3].copy_from_slice(&data[pos..pos + 3]);
+                pos += 3;
+            } else if op == QOI_OP_RGBA {
+                pixel.copy_from_slice(&data[pos..pos + 4]);
+                pos += 4;
+            } else {
+                match op & 0xc0 {
+                    QOI_OP_INDEX => pixel = index[op as usize],
+                    QOI_OP_DIFF => {
+                        pixel[0] = pixel[0].wrapping_add((op >> 4 & 3).wrapping_sub(2));
+                        pixel[1] = pixel[1].wrapping_add((op >> 2 & 3).wrapping_sub(2));
+                        pixel[2] = pixel[2].wrapping_add((op & 3).wrapping_sub(2));
+                    }
+                    QOI_OP_LUMA => {
+                        let second = byte(pos)?;
+                        pos += 1;
+                        let dg = (op & 0x3f).wrapping_sub(32);
+                        pixel[0] =
+                            pixel[0].wrapping_add(dg.wrapping_add(second >> 4).wrapping_sub(8));
+                        pixel[1] = pixel[1].wrapping_add(dg);
+                        pixel[2] =
+                            pixel[2].wrapping_add(dg.wrapping_add(second & 0xf).wrapping_sub(8));
+                    }
+                    _ => run = op & 0x3f,
+                }
+            }
+            index[qoi_hash(pixel)] = pixel;
+        }
+        *out = slint::Rgba8Pixel::new(pixel[0], pixel[1], pixel[2], pixel[3]);
+    }
+    Ok(frame)
+}
+
+/// Binary RGB PPM, alpha is dropped.
+pub fn write_ppm<'a>(
+    writer: &mut impl Write,
+    image: impl Into<ImageView<'a>>,
+) -> std::io::Result<()> {
+    let image = image.into();
+    write!(writer, "P6\n{} {}\n255\n", image.width, image.height)?;
+    let mut row = Vec::with_capacity(image.width as usize * 4);
+    let mut rgb = Vec::with_capacity(image.width as usize * 3);
+    for y in 0..image.height {
+        image.row_rgba(y, &mut row);
+        rgb.clear();
+        rgb.extend(row.chunks_exact(4).flat_map(|p| [p[0], p[1], p[2]]));
+        writer.write_all(&rgb)?;
+    }
+    Ok(())
+}
+
+/// PAM with `RGB_ALPHA` tuples.
+pub fn write_pam<'a>(
+    writer: &mut impl Write,
+    image: impl Into<ImageView<'a>>,
+) -> std::io::Result<()> {
+    let image = image.into();
+    write!(
+        writer,
+        "P7\nWIDTH {}\nHEIGHT {}\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n",
+        image.width, image.height
+    )?;
+    let mut row = Vec::with_capacity(image.width as usize * 4);
+    for y in 0..image.height {
+        image.row_rgba(y, &mut row);
+        writer.write_all(&row)?;
+    }
+    Ok(())
+}
+
+/// Decodes what [`write_ppm`] and [`write_pam`] write: 8 bit `P6` or `P7`
+/// with 3 or 4 channels.
+pub fn read_pnm(reader: &mut impl BufRead) -> std::io::Result<Frame> {
+    let mut magic = String::new();
+    reader.read_line(&mut magic)?;
+    let (width, height, depth) = match magic.trim() {
+        "P6" => {
+            // Width, height and maxval, separated by whitespace or comments
+            let mut values = Vec::new();
+            while values.len() < 3 {
+                let mut line = String::new();
+                if reader.read_line(&mut line)? == 0 {
+                    return Err(invalid_data("Truncated PPM header"));
+                }
+                let line = line.split('#').next().unwrap_or_default();
+                for value in line.split_whitespace() {
+                    values.push(
+                        value
+                            .parse::<u32>()
+                            .map_err(|_| invalid_data("Bad PPM header"))?,
+                    );
+                }
+            }
+            if values[2] != 255 {
+                return Err(invalid_data("Only 8 bit PPM is supported"));
+            }
+            (values[0], values[1], 3)
+        }
+        "P7" => {
+            let (mut width, mut height, mut depth) = (0, 0, 0);
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line)? == 0 {
+                    return Err(invalid_data("Truncated PAM header"));
+                }
+                let mut fields = line.split_whitespace();
+                let value = |v: Option<&str>| v.and_then(|v| v.parse::<u32>().ok());
+                match fields.next() {
+                    Some("ENDHDR") => break,
+                    Some("WIDTH") => width = value(fields.next()).unwrap_or(0),
+                    Some("HEIGHT") => height = value(fields.next()).unwrap_or(0),
+                    Some("DEPTH") => depth = value(fields.next()).unwrap_or(0),
+                    Some("MAXVAL") if value(fields.next()) != Some(255) => {
+                        return Err(invalid_data("Only 8 bit PAM is supported"))
+                    }
+                    _ => {}
+                }
+            }
+            if depth != 3 && depth != 4 {
+                return Err(invalid_data("Only RGB and RGB_ALPHA PAM are supported"));
+            }
+            (width, height, depth as usize)
+        }
+        _ => return Err(invalid_data("Not a binary PPM or PAM image")),
+    };
+
+    let mut data = vec![0; width as usize * height as usize * depth];
+    reader.read_exact(&mut data)?;
+    let mut frame = Frame::new(width, height);
+    for (out, p) in frame
+        .make_mut_slice()
+        .iter_mut()
+        .zip(data.chunks_exact(depth))
+    {
+        let alpha = if depth == 4 { p[3] } else { 255 };
+        *out = slint::Rgba8Pixel::new(p[0], p[1], p[2], alpha);
+    }
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_pnm, read_qoi, write_png, ImageFormat, ImageView, PixelLayout};
+    use crate::pipewire_stream::Frame;
+
+    fn gradient(width: u32, height: u32) -> Frame {
+        let mut frame = Frame::new(width, height);
+        for (i, pixel) in frame.make_mut_slice().iter_mut().enumerate() {
+            let (x, y) = (i as u32 % width, i as u32 / width);
+            *pixel = slint::Rgba8Pixel::new(x as u8 * 3, y as u8 * 5, 128, 255 - (x / 4) as u8);
+        }
+        frame
+    }
+
+    #[test]
+    fn png_chunks() {
+        let mut frame = Frame::new(2, 2);
+        for (i, pixel) in frame.make_mut_slice().iter_mut().enumerate() {
+            *pixel = slint::Rgba8Pixel::new(i as u8, 10, 20, 255);
+        }
+        let mut png = Vec::new();
+        write_png(&mut png, &frame).unwrap();
+
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..29], &[0, 0, 0, 2, 0, 0, 0, 2, 8, 6, 0, 0, 0]);
+        // IHDR CRC
+        assert_eq!(&png[29..33], &[0x72, 0xb6, 0x0d, 0x24]);
+
+        let idat_len = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let scanlines =
+            miniz_oxide::inflate::decompress_to_vec_zlib(&png[41..41 + idat_len]).unwrap();
+        assert_eq!(
+            scanlines,
+            [0, 0, 10, 20, 255, 1, 10, 20, 255, 0, 2, 10, 20, 255, 3, 10, 20, 255]
+        );
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xae\x42\x60\x82");
+    }
+
+    #[test]
+    fn qoi_and_pnm_round_trip() {
+        let frame = gradient(37, 11);
+
+        let mut qoi = Vec::new();
+        ImageFormat::Qoi.write(&mut qoi, &frame).unwrap();
+        assert_eq!(&qoi[..14], b"qoif\0\0\0\x25\0\0\0\x0b\x04\x00");
+        assert!(qoi.len() < 37 * 11 * 4);
+        assert_eq!(
+            read_qoi(&mut qoi.as_slice()).unwrap().as_slice(),
+            frame.as_slice()
+        );
+
+        let mut pam = Vec::new();
+        ImageFormat::Pam.write(&mut pam, &frame).unwrap();
+        assert_eq!(
+            read_pnm(&mut pam.as_slice()).unwrap().as_slice(),
+            frame.as_slice()
+        );
+
+        let mut ppm = Vec::new();
+        ImageFormat::Ppm.write(&mut ppm, &frame).unwrap();
+        assert!(ppm.starts_with(b"P6\n37 11\n255\n"));
+        let opaque = read_pnm(&mut ppm.as_slice()).unwrap();
+        assert!(opaque
+            .as_slice()
+            .iter()
+            .zip(frame.as_slice())
+            .all(|(a, b)| (a.r, a.g, a.b, a.a) == (b.r, b.g, b.b, 255)));
+    }
+
+    #[test]
+    fn padded_bgrx_rows() {
+        // 2x2 BGRx with 4 bytes of padding per row
+        let data = [
+            1, 2, 3, 0, 4, 5, 6, 0, 0xAA, 0xAA, 0xAA, 0xAA, //
+            7, 8, 9, 0, 10, 11, 12, 0, 0xAA, 0xAA, 0xAA, 0xAA,
+        ];
+        let image = ImageView {
+            data: &data,
+            width: 2,
+            height: 2,
+            stride: 12,
+            layout: PixelLayout::Bgrx,
+        };
+        let mut pam = Vec::new();
+        ImageFormat::Pam.write(&mut pam, image).unwrap();
+        let frame = read_pnm(&mut pam.as_slice()).unwrap();
+        let pixels = frame
+            .as_slice()
+            .iter()
+            .map(|p| [p.r, p.g, p.b, p.a])
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pixels,
+            [
+                [3, 2, 1, 255],
+                [6, 5, 4, 255],
+                [9, 8, 7, 255],
+                [12, 11, 10, 255]
+            ]
+        );
+        assert_eq!(image.to_frame().as_slice(), frame.as_slice());
+    }
+}
@@ -0,0 +1,596 @@
+//! Encoding through a local `ffmpeg` binary, fed raw RGBA frames over a pipe
+//! and optionally PCM audio over a second one.
+
+use crate::audio::{self, AudioAligner, AudioChunk};
+use crate::frame_transform;
+use crate::pipewire_stream::{self, Frame, TimedFrame};
+use std::cell::RefCell;
+use std::io::Write;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Descriptor the audio pipe has in ffmpeg.
+const AUDIO_FD: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    /// H.264 with qp 0 and 4:4:4 chroma, large files.
+    X264Lossless,
+    X264Fast,
+    Vp9,
+}
+
+impl Preset {
+    /// VP9 for `.webm`, fast H.264 otherwise.
+    pub fn for_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("webm") => Preset::Vp9,
+            _ => Preset::X264Fast,
+        }
+    }
+
+    fn codec_args(self) -> &'static [&'static str] {
+        match self {
+            Preset::X264Lossless => &[
+                "-c:v",
+                "libx264",
+                "-preset",
+                "ultrafast",
+                "-qp",
+                "0",
+                "-pix_fmt",
+                "yuv444p",
+            ],
+            Preset::X264Fast => &[
+                "-c:v", "libx264", "-preset", "veryfast", "-crf", "23", "-pix_fmt", "yuv420p",
+            ],
+            Preset::Vp9 => &[
+                "-c:v",
+                "libvpx-vp9",
+                "-deadline",
+                "realtime",
+                "-cpu-used",
+                "8",
+                "-crf",
+                "32",
+                "-b:v",
+                "0",
+                "-pix_fmt",
+                "yuv420p",
+            ],
+        }
+    }
+
+    fn audio_codec_args(self) -> &'static [&'static str] {
+        match self {
+            Preset::Vp9 => &["-c:a", "libopus", "-b:a", "128k"],
+            _ => &["-c:a", "aac", "-b:a", "160k"],
+        }
+    }
+
+    fn subsampled(self) -> bool {
+        self != Preset::X264Lossless
+    }
+}
+
+impl std::str::FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "x264-lossless" => Ok(Preset::X264Lossless),
+            "x264-fast" => Ok(Preset::X264Fast),
+            "vp9" => Ok(Preset::Vp9),
+            _ => Err(format!(
+                "Unknown preset '{s}', expected x264-lossless, x264-fast or vp9"
+            )),
+        }
+    }
+}
+
+/// Whether `path` looks like a container ffmpeg should write.
+pub fn is_video_path(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("mp4" | "mkv" | "webm" | "mov")
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct FfmpegConfig {
+    pub output: PathBuf,
+    pub preset: Preset,
+    /// `ffmpeg` from `PATH` by default.
+    pub ffmpeg: PathBuf,
+    /// Output frame rate. [`FfmpegSink::write_timed`] duplicates or drops
+    /// frames by their pts to keep it.
+    pub frame_rate: u32,
+    /// Add an audio track, fed with [`FfmpegSink::write_audio`].
+    pub audio: bool,
+}
+
+impl FfmpegConfig {
+    pub fn new(output: PathBuf) -> Self {
+        Self {
+            preset: Preset::for_path(&output),
+            output,
+            ffmpeg: PathBuf::from("ffmpeg"),
+            frame_rate: 30,
+            audio: false,
+        }
+    }
+
+    /// Command line for `width` x `height` RGBA input on stdin.
+    pub fn args(&self, width: u32, height: u32) -> Vec<String> {
+        let mut args = vec!["-y".to_owned()];
+        args.extend(video_input_args(self.frame_rate, width, height));
+        if self.audio {
+            args.extend(["-f", "s16le", "-ar"].map(str::to_owned));
+            args.push(audio::SAMPLE_RATE.to_string());
+            args.push("-ac".to_owned());
+            args.push(audio::CHANNELS.to_string());
+            args.push("-i".to_owned());
+            args.push(format!("pipe:{AUDIO_FD}"));
+        }
+        if self.preset.subsampled() {
+            args.extend(even_size_args(width, height));
+        }
+        args.extend(self.preset.codec_args().iter().map(|a| a.to_string()));
+        if self.audio {
+            args.extend(self.preset.audio_codec_args().iter().map(|a| a.to_string()));
+        }
+        args.push(self.output.display().to_string());
+        args
+    }
+}
+
+/// Global options and the video input of `width` x `height` RGBA frames on
+/// stdin, timed by their count at `frame_rate` rather than by the wall clock.
+/// [`FfmpegSink::write_timed`] keeps the frames on that rate.
+pub(crate) fn video_input_args(frame_rate: u32, width: u32, height: u32) -> Vec<String> {
+    let mut args = ["-hide_banner", "-loglevel", "error", "-framerate"]
+        .map(str::to_owned)
+        .to_vec();
+    args.push(frame_rate.max(1).to_string());
+    args.extend(["-f", "rawvideo", "-pix_fmt", "rgba", "-video_size"].map(str::to_owned));
+    args.push(format!("{width}x{height}"));
+    args.extend(["-i", "pipe:0"].map(str::to_owned));
+    args
+}
+
+/// Filter cropping odd sizes to even ones, which 4:2:0 output needs.
+pub(crate) fn even_size_args(width: u32, height: u32) -> Vec<String> {
+    if width.is_multiple_of(2) && height.is_multiple_of(2) {
+        return Vec::new();
+    }
+    ["-vf", "crop=trunc(iw/2)*2:trunc(ih/2)*2"]
+        .map(str::to_owned)
+        .to_vec()
+}
+
+/// A running `ffmpeg` process. Dropping it or calling [`FfmpegSink::finish`]
+/// closes its input, so it finalises the file.
+pub struct FfmpegSink {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    audio: Option<AudioInput>,
+    width: u32,
+    height: u32,
+    pacer: FramePacer,
+    /// Last frame written by [`FfmpegSink::write_timed`], scaled to the
+    /// output size, to fill gaps with.
+    previous: Option<Frame>,
+}
+
+/// Places frames on the ticks of a constant rate output by their pts.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FramePacer {
+    rate: u32,
+    start: Duration,
+    /// First tick not written yet.
+    next: u64,
+}
+
+impl FramePacer {
+    pub(crate) fn new(rate: u32, start: Duration) -> Self {
+        Self {
+            rate: rate.max(1),
+            start,
+            next: 0,
+        }
+    }
+
+    /// How many ticks the previous frame has to be repeated for before the
+    /// frame shown at `pts`, `None` to drop it as its tick was written.
+    fn advance(&mut self, pts: Duration) -> Option<u64> {
+        let tick = (pts.saturating_sub(self.start).as_secs_f64() * self.rate as f64).round() as u64;
+        if tick < self.next {
+            return None;
+        }
+        let gap = tick - self.next;
+        self.next = tick + 1;
+        Some(gap)
+    }
+}
+
+/// Audio is written from its own thread, so ffmpeg waiting on one pipe
+/// never blocks writes to the other.
+struct AudioInput {
+    sender: async_channel::Sender<Vec<i16>>,
+    thread: JoinHandle<()>,
+    aligner: AudioAligner,
+}
+
+impl AudioInput {
+    fn start(pipe: OwnedFd, start: Duration) -> Self {
+        let (sender, receiver) = async_channel::bounded::<Vec<i16>>(64);
+        let thread = std::thread::spawn(move || {
+            let mut pipe = std::fs::File::from(pipe);
+            while let Ok(samples) = receiver.recv_blocking() {
+                let bytes = samples
+                    .iter()
+                    .flat_map(|s| s.to_le_bytes())
+                    .collect::<Vec<_>>();
+                if pipe.write_all(&bytes).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            sender,
+            thread,
+            aligner: AudioAligner::new(start),
+        }
+    }
+
+    /// Queues the aligned part of `chunk`. Samples that don't fit while the
+    /// writer is behind are dropped and left to the aligner to pad.
+    fn queue(&mut self, chunk: &AudioChunk) {
+        let mut samples = Vec::with_capacity(chunk.samples.len());
+        self.aligner.align(chunk, &mut samples);
+        if samples.is_empty() {
+            return;
+        }
+        if let Err(e) = self.sender.try_send(samples) {
+            let dropped = e.into_inner().len() / audio::CHANNELS as usize;
+            self.aligner.rewind(dropped as u64);
+        }
+    }
+
+    fn finish(self) {
+        drop(self.sender);
+        let _ = self.thread.join();
+    }
+}
+
+fn pipe() -> std::io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+impl FfmpegSink {
+    /// Starts encoding frames of `width` x `height`, later frames of other
+    /// sizes are scaled to it. Frames and audio are timed from `start`, the
+    /// session time of the first frame.
+    pub fn spawn(
+        config: &FfmpegConfig,
+        width: u32,
+        height: u32,
+        start: Duration,
+    ) -> std::io::Result<Self> {
+        Self::spawn_args(
+            &config.ffmpeg,
+            config.args(width, height),
+            FramePacer::new(config.frame_rate, start),
+            config.audio,
+            width,
+            height,
+        )
+    }
+
+    /// Runs `ffmpeg` with `args` as the whole command line, which reads
+    /// `width` x `height` RGBA frames at the pacer's rate from stdin, and PCM
+    /// from descriptor 3 if `audio`.
+    pub(crate) fn spawn_args(
+        ffmpeg: &Path,
+        args: Vec<String>,
+        pacer: FramePacer,
+        audio: bool,
+        width: u32,
+        height: u32,
+    ) -> std::io::Result<Self> {
+        let mut command = Command::new(ffmpeg);
+        command
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            // Keep Ctrl+C away from ffmpeg, it stops when its input closes
+            .process_group(0);
+        let audio_pipe = audio.then(pipe).transpose()?;
+        if let Some((read, _)) = &audio_pipe {
+            let fd = read.as_raw_fd();
+            unsafe {
+                command.pre_exec(move || {
+                    // dup2 onto itself would keep close-on-exec
+                    let result = if fd == AUDIO_FD {
+                        libc::fcntl(fd, libc::F_SETFD, 0)
+                    } else {
+                        libc::dup2(fd, AUDIO_FD)
+                    };
+                    if result < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+        let mut child = command.spawn()?;
+        let stdin = child.stdin.take();
+        let audio = audio_pipe.map(|(_read, write)| AudioInput::start(write, pacer.start));
+        Ok(Self {
+            child,
+            stdin,
+            audio,
+            width,
+            height,
+            pacer,
+            previous: None,
+        })
+    }
+
+    /// Writes the next frame of the output, for frames already at its rate.
+    pub fn write_frame(&mut self, frame: &Frame) -> std::io::Result<()> {
+        let stdin = self.stdin.as_mut().ok_or(std::io::ErrorKind::BrokenPipe)?;
+        if (frame.width(), frame.height()) == (self.width, self.height) {
+            stdin.write_all(frame.as_bytes())
+        } else {
+            let scaled = frame_transform::scale(frame, self.width, self.height);
+            stdin.write_all(scaled.as_bytes())
+        }
+    }
+
+    /// Writes `frame` on the output tick of `pts`, repeating the previous
+    /// frame up to it. Frames landing on a tick already written are dropped.
+    pub fn write_timed(&mut self, frame: &Frame, pts: Duration) -> std::io::Result<()> {
+        let Some(gap) = self.pacer.advance(pts) else {
+            return Ok(());
+        };
+        if let Some(previous) = self.previous.take() {
+            for _ in 0..gap {
+                self.write_frame(&previous)?;
+            }
+        }
+        let frame = if (frame.width(), frame.height()) == (self.width, self.height) {
+            frame.clone()
+        } else {
+            frame_transform::scale(frame, self.width, self.height)
+        };
+        self.write_frame(&frame)?;
+        self.previous = Some(frame);
+        Ok(())
+    }
+
+    /// Queues the part of `chunk` that falls after the start of the video,
+    /// padding gaps with silence. Ignored without an audio track.
+    pub fn write_audio(&mut self, chunk: &AudioChunk) {
+        if let Some(audio) = &mut self.audio {
+            audio.queue(chunk);
+        }
+    }
+
+    /// Closes the inputs and waits for ffmpeg to finish writing the file.
+    pub fn finish(mut self) -> std::io::Result<ExitStatus> {
+        self.close_inputs();
+        self.child.wait()
+    }
+
+    fn close_inputs(&mut self) {
+        if let Some(audio) = self.audio.take() {
+            audio.finish();
+        }
+        self.stdin = None;
+    }
+}
+
+impl Drop for FfmpegSink {
+    fn drop(&mut self) {
+        if self.stdin.is_some() {
+            self.close_inputs();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// Encodes frames, and `audio` if given, until the frame channel closes or
+/// `stop` returns true. ffmpeg is started with the size of the first frame;
+/// `None` if no frame arrived.
+pub fn record(
+    frames: &async_channel::Receiver<TimedFrame>,
+    audio: Option<&async_channel::Receiver<AudioChunk>>,
+    config: &FfmpegConfig,
+    stop: impl Fn() -> bool,
+) -> std::io::Result<Option<ExitStatus>> {
+    let config = FfmpegConfig {
+        audio: audio.is_some(),
+        ..config.clone()
+    };
+    let config = &config;
+    let sink = RefCell::new(None::<FfmpegSink>);
+    // Audio keeps coming while the screen is idle
+    let poll = || {
+        // Audio from before the first frame is dropped
+        while let Some(chunk) = audio.and_then(|audio| audio.try_recv().ok()) {
+            if let Some(sink) = &mut *sink.borrow_mut() {
+                sink.write_audio(&chunk);
+            }
+        }
+        stop()
+    };
+    pipewire_stream::for_each_frame(frames, poll, |TimedFrame { frame, pts, .. }| {
+        if frame.width() == 0 || frame.height() == 0 {
+            return Ok(());
+        }
+        let mut slot = sink.borrow_mut();
+        let sink = match &mut *slot {
+            Some(sink) => sink,
+            None => slot.insert(FfmpegSink::spawn(
+                config,
+                frame.width(),
+                frame.height(),
+                pts,
+            )?),
+        };
+        sink.write_timed(&frame, pts)
+    })?;
+    sink.into_inner().map(FfmpegSink::finish).transpose()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AudioInput, FfmpegConfig, FfmpegSink, FramePacer, Preset};
+    use crate::audio::{AudioChunk, CHANNELS, SAMPLE_RATE};
+    use crate::pipewire_stream::Frame;
+    use std::io::Read;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[test]
+    fn command_line() {
+        let config = FfmpegConfig::new(PathBuf::from("out.webm"));
+        assert_eq!(config.preset, Preset::Vp9);
+        let args = config.args(641, 480);
+        let position = |arg: &str| args.iter().position(|a| a == arg).unwrap();
+        assert_eq!(args[position("-video_size") + 1], "641x480");
+        assert_eq!(args[position("-pix_fmt") + 1], "rgba");
+        assert_eq!(args[position("-c:v") + 1], "libvpx-vp9");
+        assert_eq!(
+            args[position("-vf") + 1],
+            "crop=trunc(iw/2)*2:trunc(ih/2)*2"
+        );
+        assert_eq!(args.last().unwrap(), "out.webm");
+
+        let lossless = FfmpegConfig {
+            preset: "x264-lossless".parse().unwrap(),
+            ..FfmpegConfig::new(PathBuf::from("out.mkv"))
+        };
+        assert!(!lossless.args(641, 480).contains(&"-vf".to_owned()));
+
+        let constant = FfmpegConfig {
+            frame_rate: 25,
+            ..FfmpegConfig::new(PathBuf::from("out.mp4"))
+        };
+        let args = constant.args(640, 480);
+        assert!(args.windows(2).any(|w| w == ["-framerate", "25"]));
+        assert!(!args.contains(&"-use_wallclock_as_timestamps".to_owned()));
+        assert!(!args.contains(&"pipe:3".to_owned()));
+
+        let with_audio = FfmpegConfig {
+            audio: true,
+            ..FfmpegConfig::new(PathBuf::from("out.webm"))
+        };
+        let args = with_audio.args(640, 480);
+        assert!(args.windows(2).any(|w| w == ["-i", "pipe:3"]));
+        assert!(args.windows(2).any(|w| w == ["-c:a", "libopus"]));
+    }
+
+    #[test]
+    fn pacing() {
+        let start = Duration::from_secs(3);
+        let mut pacer = FramePacer::new(10, start);
+        let at = |ms| start + Duration::from_millis(ms);
+        assert_eq!(pacer.advance(at(0)), Some(0));
+        // Same tick as the first frame
+        assert_eq!(pacer.advance(at(40)), None);
+        assert_eq!(pacer.advance(at(90)), Some(0));
+        // Ticks 2 and 3 repeat the previous frame
+        assert_eq!(pacer.advance(at(420)), Some(2));
+        assert_eq!(pacer.advance(Duration::ZERO), None);
+    }
+
+    /// Frames decoded from the file sit on the ticks of their pts.
+    #[test]
+    fn output_follows_pts() {
+        let dir = std::env::temp_dir().join(format!("screencast-ffmpeg-{}", std::process::id()));
+        let config = FfmpegConfig {
+            preset: Preset::X264Lossless,
+            frame_rate: 10,
+            ..FfmpegConfig::new(dir.join("paced.mkv"))
+        };
+        if std::process::Command::new(&config.ffmpeg)
+            .arg("-version")
+            .output()
+            .is_err()
+        {
+            println!("ffmpeg not installed, skipping");
+            return;
+        }
+        std::fs::create_dir_all(&dir).unwrap();
+        let gray = |value| {
+            let mut frame = Frame::new(16, 16);
+            frame
+                .make_mut_slice()
+                .fill(slint::Rgba8Pixel::new(value, value, value, 255));
+            frame
+        };
+        let start = Duration::from_secs(1);
+        let mut sink = FfmpegSink::spawn(&config, 16, 16, start).unwrap();
+        for (value, ms) in [(40, 0), (120, 100), (200, 110), (250, 400)] {
+            sink.write_timed(&gray(value), start + Duration::from_millis(ms))
+                .unwrap();
+        }
+        assert!(sink.finish().unwrap().success());
+
+        let decoded = std::process::Command::new(&config.ffmpeg)
+            .args(["-loglevel", "error", "-i"])
+            .arg(&config.output)
+            .args(["-f", "rawvideo", "-pix_fmt", "gray", "pipe:1"])
+            .output()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let values = decoded
+            .stdout
+            .chunks(16 * 16)
+            .map(|frame| frame[0])
+            .collect::<Vec<_>>();
+        let expected = [40, 120, 120, 120, 250];
+        assert_eq!(values.len(), expected.len());
+        for (value, expected) in values.iter().zip(expected) {
+            assert!(value.abs_diff(expected) <= 4, "{values:?}");
+        }
+    }
+
+    /// Chunks dropped while the pipe is full come back as silence, so the
+    /// audio keeps its length.
+    #[test]
+    fn audio_overflow_keeps_length() {
+        let (read, write) = super::pipe().unwrap();
+        let start = Duration::from_secs(2);
+        let mut input = AudioInput::start(write, start);
+        // 100 ms each, far more than the pipe and the channel hold
+        let chunk = |i: u64| AudioChunk {
+            pts: start + Duration::from_millis(100 * i),
+            samples: vec![1; 4800 * CHANNELS as usize],
+        };
+        for i in 0..100 {
+            input.queue(&chunk(i));
+        }
+        let reader = std::thread::spawn(move || {
+            let mut bytes = Vec::new();
+            std::fs::File::from(read).read_to_end(&mut bytes).unwrap();
+            bytes.len()
+        });
+        std::thread::sleep(Duration::from_millis(200));
+        input.queue(&chunk(100));
+        input.finish();
+
+        let frames = reader.join().unwrap() / 2 / CHANNELS as usize;
+        assert_eq!(frames, 101 * SAMPLE_RATE as usize / 10);
+    }
+}
@@ -0,0 +1,383 @@
+use crate::pipewire_stream::Frame;
+
+/// Rectangle in frame pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl std::str::FromStr for Rect {
+    type Err = String;
+
+    /// `WIDTHxHEIGHT+X+Y`, like X11 geometry strings.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("Invalid rectangle '{s}', expected WIDTHxHEIGHT+X+Y");
+        let (size, position) = s.split_once('+').ok_or_else(error)?;
+        let (width, height) = size.split_once('x').ok_or_else(error)?;
+        let (x, y) = position.split_once('+').ok_or_else(error)?;
+        let parse = |v: &str| v.parse::<u32>().map_err(|_| error());
+        Ok(Rect {
+            x: parse(x)?,
+            y: parse(y)?,
+            width: parse(width)?,
+            height: parse(height)?,
+        })
+    }
+}
+
+impl Rect {
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// The overlap of both rectangles, `None` if they do not overlap.
+    pub fn intersect(&self, other: &Rect) -> Option<Rect> {
+        let x0 = self.x.max(other.x);
+        let y0 = self.y.max(other.y);
+        let x1 = (self.x + self.width).min(other.x + other.width);
+        let y1 = (self.y + self.height).min(other.y + other.height);
+        (x0 < x1 && y0 < y1).then(|| Rect {
+            x: x0,
+            y: y0,
+            width: x1 - x0,
+            height: y1 - y0,
+        })
+    }
+
+    /// The smallest rectangle containing both.
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x0 = self.x.min(other.x);
+        let y0 = self.y.min(other.y);
+        let x1 = (self.x + self.width).max(other.x + other.width);
+        let y1 = (self.y + self.height).max(other.y + other.height);
+        Rect {
+            x: x0,
+            y: y0,
+            width: x1 - x0,
+            height: y1 - y0,
+        }
+    }
+}
+
+/// Copies the part of `frame` inside `rect`, clipped to the frame.
+pub fn crop(frame: &Frame, rect: Rect) -> Frame {
+    let x0 = rect.x.min(frame.width());
+    let y0 = rect.y.min(frame.height());
+    let width = rect.width.min(frame.width() - x0);
+    let height = rect.height.min(frame.height() - y0);
+
+    let mut cropped = Frame::new(width, height);
+    let src = frame.as_slice();
+    let src_width = frame.width() as usize;
+    for (y, row) in cropped
+        .make_mut_slice()
+        .chunks_exact_mut(width.max(1) as usize)
+        .enumerate()
+    {
+        let start = (y0 as usize + y) * src_width + x0 as usize;
+        row.copy_from_slice(&src[start..start + width as usize]);
+    }
+    cropped
+}
+
+/// Nearest-neighbour scaling to `width` x `height`.
+pub fn scale(frame: &Frame, width: u32, height: u32) -> Frame {
+    let mut scaled = Frame::new(width, height);
+    if frame.width() == 0 || frame.height() == 0 {
+        return scaled;
+    }
+    let src = frame.as_slice();
+    let src_width = frame.width() as usize;
+    let pixels = scaled.make_mut_slice();
+    for y in 0..height as usize {
+        let src_y = y * frame.height() as usize / height as usize;
+        for x in 0..width as usize {
+            let src_x = x * src_width / width as usize;
+            pixels[y * width as usize + x] = src[src_y * src_width + src_x];
+        }
+    }
+    scaled
+}
+
+/// Crops, then scales, skipping the steps that are not needed.
+pub fn apply(frame: Frame, crop_rect: Option<Rect>, size: Option<(u32, u32)>) -> Frame {
+    let frame = match crop_rect {
+        Some(rect) => crop(&frame, rect),
+        None => frame,
+    };
+    match size {
+        Some((width, height)) if (width, height) != (frame.width(), frame.height()) => {
+            scale(&frame, width, height)
+        }
+        _ => frame,
+    }
+}
+
+/// An image blended over frames with its alpha, its top left corner at `x`,
+/// `y`.
+#[derive(Debug, Clone)]
+pub struct Overlay {
+    pub image: Frame,
+    pub x: u32,
+    pub y: u32,
+}
+
+/// Draws `overlay` over `frame`, clipped to the frame.
+pub fn draw_overlay(frame: &mut Frame, overlay: &Overlay) {
+    let (width, height) = (frame.width(), frame.height());
+    if overlay.x >= width || overlay.y >= height {
+        return;
+    }
+    let visible = (width - overlay.x).min(overlay.image.width()) as usize;
+    let src_width = overlay.image.width() as usize;
+    let pixels = frame.make_mut_slice();
+    for (y, src) in overlay
+        .image
+        .as_slice()
+        .chunks_exact(src_width.max(1))
+        .take((height - overlay.y) as usize)
+        .enumerate()
+    {
+        let start = (overlay.y as usize + y) * width as usize + overlay.x as usize;
+        for (dst, src) in pixels[start..start + visible].iter_mut().zip(src) {
+            let alpha = src.a as u32;
+            let blend =
+                |s: u8, d: u8| ((s as u32 * alpha + d as u32 * (255 - alpha) + 127) / 255) as u8;
+            *dst = slint::Rgba8Pixel::new(
+                blend(src.r, dst.r),
+                blend(src.g, dst.g),
+                blend(src.b, dst.b),
+                dst.a.max(src.a),
+            );
+        }
+    }
+}
+
+/// Box blurs the part of `frame` inside `rect` with `radius`, clipped to
+/// the frame. Pixels outside `rect` are left alone and do not bleed in.
+pub fn blur(frame: &mut Frame, rect: Rect, radius: u32) {
+    let bounds = Rect {
+        x: 0,
+        y: 0,
+        width: frame.width(),
+        height: frame.height(),
+    };
+    let Some(rect) = rect.intersect(&bounds) else {
+        return;
+    };
+    let mut region = crop(frame, rect);
+    let (width, height) = (rect.width as usize, rect.height as usize);
+    // Horizontal, then vertical; twice looks close to a gaussian
+    for _ in 0..2 {
+        box_blur(
+            region.make_mut_slice(),
+            width,
+            height,
+            1,
+            width,
+            radius as usize,
+        );
+        box_blur(
+            region.make_mut_slice(),
+            height,
+            width,
+            width,
+            1,
+            radius as usize,
+        );
+    }
+    let src = region.as_slice();
+    let frame_width = frame.width() as usize;
+    let pixels = frame.make_mut_slice();
+    for (y, row) in src.chunks_exact(width).enumerate() {
+        let start = (rect.y as usize + y) * frame_width + rect.x as usize;
+        pixels[start..start + width].copy_from_slice(row);
+    }
+}
+
+/// Averages every pixel with its neighbours up to `radius` away along
+/// `lines` lines of `len` pixels, `step` apart within a line and `next`
+/// apart between lines. Windows are clamped to the line.
+fn box_blur(
+    pixels: &mut [slint::Rgba8Pixel],
+    len: usize,
+    lines: usize,
+    step: usize,
+    next: usize,
+    radius: usize,
+) {
+    let mut line = Vec::with_capacity(len);
+    let mut sums = Vec::with_capacity(len + 1);
+    for l in 0..lines {
+        line.clear();
+        line.extend((0..len).map(|i| pixels[l * next + i * step]));
+        // Prefix sums of r, g, b and a
+        sums.clear();
+        sums.push([0u32; 4]);
+        for p in &line {
+            let last = sums[sums.len() - 1];
+            sums.push([
+                last[0] + p.r as u32,
+                last[1] + p.g as u32,
+                last[2] + p.b as u32,
+                last[3] + p.a as u32,
+            ]);
+        }
+        for i in 0..len {
+            let (start, end) = (i.saturating_sub(radius), (i + radius + 1).min(len));
+            let count = (end - start) as u32;
+            let channel = |c: usize| ((sums[end][c] - sums[start][c] + count / 2) / count) as u8;
+            pixels[l * next + i * step] =
+                slint::Rgba8Pixel::new(channel(0), channel(1), channel(2), channel(3));
+        }
+    }
+}
+
+/// Where `rect` of a `source_size` frame ends up after [`apply`] with the
+/// same crop and size, rounded outwards; `None` if it was cropped away.
+pub fn apply_to_rect(
+    rect: Rect,
+    source_size: (u32, u32),
+    crop_rect: Option<Rect>,
+    size: Option<(u32, u32)>,
+) -> Option<Rect> {
+    let bounds = Rect {
+        x: 0,
+        y: 0,
+        width: source_size.0,
+        height: source_size.1,
+    };
+    let crop_rect = crop_rect.unwrap_or(bounds).intersect(&bounds)?;
+    let rect = rect.intersect(&crop_rect)?;
+    let rect = Rect {
+        x: rect.x - crop_rect.x,
+        y: rect.y - crop_rect.y,
+        ..rect
+    };
+    let Some((width, height)) = size else {
+        return Some(rect);
+    };
+    let scale = |value: u32, to: u32, from: u32, round_up: bool| {
+        let scaled = value as u64 * to as u64;
+        let scaled = if round_up {
+            scaled.div_ceil(from as u64)
+        } else {
+            scaled / from as u64
+        };
+        scaled as u32
+    };
+    let x0 = scale(rect.x, width, crop_rect.width, false);
+    let y0 = scale(rect.y, height, crop_rect.height, false);
+    let x1 = scale(rect.x + rect.width, width, crop_rect.width, true);
+    let y1 = scale(rect.y + rect.height, height, crop_rect.height, true);
+    Some(Rect {
+        x: x0,
+        y: y0,
+        width: x1 - x0,
+        height: y1 - y0,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply, apply_to_rect, blur, draw_overlay, Overlay, Rect};
+    use crate::pipewire_stream::Frame;
+    use slint::Rgba8Pixel;
+
+    #[test]
+    fn crop_and_scale() {
+        let mut frame = Frame::new(4, 2);
+        for (i, pixel) in frame.make_mut_slice().iter_mut().enumerate() {
+            pixel.r = i as u8;
+        }
+
+        let rect = "2x2+1+0".parse::<Rect>().unwrap();
+        assert_eq!(
+            rect,
+            Rect {
+                x: 1,
+                y: 0,
+                width: 2,
+                height: 2
+            }
+        );
+        let cropped = apply(frame.clone(), Some(rect), None);
+        let reds = |f: &Frame| f.as_slice().iter().map(|p| p.r).collect::<Vec<_>>();
+        assert_eq!(reds(&cropped), [1, 2, 5, 6]);
+
+        let scaled = apply(frame, Some(rect), Some((4, 1)));
+        assert_eq!(reds(&scaled), [1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn rects_follow_crop_and_scale() {
+        let rect = |s: &str| s.parse::<Rect>().unwrap();
+        assert_eq!(
+            rect("4x4+2+2").intersect(&rect("4x4+4+0")),
+            Some(rect("2x2+4+2"))
+        );
+        assert_eq!(rect("1x1+0+0").intersect(&rect("1x1+1+0")), None);
+        assert_eq!(rect("1x1+0+0").union(&rect("2x1+3+2")), rect("5x3+0+0"));
+
+        let crop = Some(rect("100x50+10+10"));
+        assert_eq!(
+            apply_to_rect(rect("20x20+0+0"), (200, 100), crop, None),
+            Some(rect("10x10+0+0"))
+        );
+        assert_eq!(apply_to_rect(rect("5x5+0+0"), (200, 100), crop, None), None);
+        // Halved, with the odd edge rounded outwards
+        assert_eq!(
+            apply_to_rect(rect("3x3+11+11"), (200, 100), crop, Some((50, 25))),
+            Some(rect("2x2+0+0"))
+        );
+    }
+
+    #[test]
+    fn overlay_blends_and_clips() {
+        let mut frame = Frame::new(4, 3);
+        frame.make_mut_slice().fill(Rgba8Pixel::new(0, 0, 200, 255));
+        let mut image = Frame::new(2, 2);
+        image.make_mut_slice().copy_from_slice(&[
+            Rgba8Pixel::new(255, 0, 0, 255),
+            Rgba8Pixel::new(255, 0, 0, 0),
+            Rgba8Pixel::new(255, 0, 0, 128),
+            Rgba8Pixel::new(255, 0, 0, 255),
+        ]);
+        draw_overlay(&mut frame, &Overlay { image, x: 3, y: 1 });
+        let pixels = frame.as_slice();
+        assert_eq!(pixels[7], Rgba8Pixel::new(255, 0, 0, 255));
+        assert_eq!(pixels[11], Rgba8Pixel::new(128, 0, 100, 255));
+        // Everything else, including what would be off the right edge
+        let untouched = [0, 1, 2, 3, 4, 5, 6, 8, 9, 10];
+        assert!(untouched
+            .iter()
+            .all(|&i| pixels[i] == Rgba8Pixel::new(0, 0, 200, 255)));
+    }
+
+    #[test]
+    fn blur_stays_inside_rect() {
+        let mut frame = Frame::new(8, 8);
+        frame.make_mut_slice().fill(Rgba8Pixel::new(0, 0, 0, 255));
+        frame.make_mut_slice()[3 * 8 + 3] = Rgba8Pixel::new(255, 255, 255, 255);
+        let before = frame.clone();
+        blur(&mut frame, "4x4+2+2".parse().unwrap(), 1);
+
+        let pixels = frame.as_slice();
+        for (i, (pixel, before)) in pixels.iter().zip(before.as_slice()).enumerate() {
+            let (x, y) = (i % 8, i / 8);
+            if !(2..6).contains(&x) || !(2..6).contains(&y) {
+                assert_eq!(pixel, before);
+            }
+        }
+        // The bright pixel is spread over its neighbours
+        assert!(pixels[3 * 8 + 3].r < 255);
+        assert!(pixels[3 * 8 + 4].r > 0 && pixels[4 * 8 + 3].r > 0);
+        assert!(pixels.iter().all(|p| p.a == 255));
+
+        // Clipped to the frame
+        blur(&mut frame, "16x16+6+6".parse().unwrap(), 4);
+    }
+}
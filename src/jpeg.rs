@@ -0,0 +1,544 @@
+//! Baseline JPEG encoder: 4:2:0 chroma subsampling and the standard Huffman
+//! tables of Annex K, for previews where PNG is too slow and too big.
+
+use crate::export::ImageView;
+use std::io::Write;
+
+/// Luma quantization table of Annex K.1 for quality 50, in natural order.
+const LUMA_QUANTIZATION: [u8; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113,
+    92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+const CHROMA_QUANTIZATION: [u8; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+/// Natural index of each zigzag position.
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+const DC_LUMA_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const DC_CHROMA_BITS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+const DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+
+const AC_LUMA_BITS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d];
+const AC_LUMA_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7,
+    0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5,
+    0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2,
+    0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+
+const AC_CHROMA_BITS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+const AC_CHROMA_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0,
+    0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26,
+    0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5,
+    0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3,
+    0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda,
+    0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+
+/// Scales a quality 50 table like libjpeg does, which RFC 2435 receivers
+/// rely on to rebuild the tables from the quality alone.
+fn scaled_table(base: &[u8; 64], quality: u8) -> [u8; 64] {
+    let quality = quality.clamp(1, 100) as u32;
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - quality * 2
+    };
+    let mut table = [0; 64];
+    for (k, value) in table.iter_mut().enumerate() {
+        *value = ((base[ZIGZAG[k]] as u32 * scale + 50) / 100).clamp(1, 255) as u8;
+    }
+    table
+}
+
+/// Code and length of each symbol.
+struct Huffman {
+    codes: [(u16, u8); 256],
+}
+
+impl Huffman {
+    fn new(bits: &[u8; 16], values: &[u8]) -> Self {
+        let mut codes = [(0, 0); 256];
+        let mut code = 0u16;
+        let mut values = values.iter();
+        for (length, &count) in bits.iter().enumerate() {
+            for _ in 0..count {
+                codes[*values.next().unwrap() as usize] = (code, length as u8 + 1);
+                code += 1;
+            }
+            code <<= 1;
+        }
+        Self { codes }
+    }
+
+    fn put(&self, writer: &mut BitWriter, symbol: u8) {
+        let (code, length) = self.codes[symbol as usize];
+        writer.put(code as u32, length as u32);
+    }
+}
+
+/// Entropy-coded data with `0xff` bytes stuffed.
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn put(&mut self, value: u32, length: u32) {
+        self.bits = (self.bits << length) | (value & ((1 << length) - 1));
+        self.count += length;
+        while self.count >= 8 {
+            let byte = (self.bits >> (self.count - 8)) as u8;
+            self.out.push(byte);
+            if byte == 0xff {
+                self.out.push(0);
+            }
+            self.count -= 8;
+        }
+        self.bits &= (1 << self.count) - 1;
+    }
+
+    /// Pads the last byte with 1 bits.
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            let padding = 8 - self.count;
+            self.put((1 << padding) - 1, padding);
+        }
+        self.out
+    }
+}
+
+/// Size category and the bits of a coefficient, negative values in one's
+/// complement.
+fn magnitude(value: i32) -> (u8, u32) {
+    let size = 32 - value.unsigned_abs().leading_zeros();
+    let bits = if value < 0 {
+        (value + (1 << size) - 1) as u32
+    } else {
+        value as u32
+    };
+    (size as u8, bits)
+}
+
+/// Blocks of one component in a strip of 16 lines.
+struct Strip {
+    y: Vec<f32>,
+    cb: Vec<f32>,
+    cr: Vec<f32>,
+    /// Width of the luma rows, a multiple of 16.
+    width: usize,
+}
+
+pub struct JpegEncoder {
+    /// Luma and chroma tables in zigzag order, as written to the file.
+    tables: [[u8; 64]; 2],
+    /// `cos[u][x]`, the DCT basis with its normalization.
+    cos: [[f32; 8]; 8],
+    dc: [Huffman; 2],
+    ac: [Huffman; 2],
+}
+
+impl JpegEncoder {
+    /// `quality` from 1 to 100.
+    pub fn new(quality: u8) -> Self {
+        let mut cos = [[0.0; 8]; 8];
+        for (u, row) in cos.iter_mut().enumerate() {
+            let scale = if u == 0 { 0.5f32.sqrt() } else { 1.0 } / 2.0;
+            for (x, value) in row.iter_mut().enumerate() {
+                *value =
+                    scale * ((2 * x + 1) as f32 * u as f32 * std::f32::consts::PI / 16.0).cos();
+            }
+        }
+        Self {
+            tables: [
+                scaled_table(&LUMA_QUANTIZATION, quality),
+                scaled_table(&CHROMA_QUANTIZATION, quality),
+            ],
+            cos,
+            dc: [
+                Huffman::new(&DC_LUMA_BITS, &DC_VALUES),
+                Huffman::new(&DC_CHROMA_BITS, &DC_VALUES),
+            ],
+            ac: [
+                Huffman::new(&AC_LUMA_BITS, &AC_LUMA_VALUES),
+                Huffman::new(&AC_CHROMA_BITS, &AC_CHROMA_VALUES),
+            ],
+        }
+    }
+
+    /// Luma and chroma quantization tables in zigzag order.
+    pub fn quantization_tables(&self) -> &[[u8; 64]; 2] {
+        &self.tables
+    }
+
+    /// A complete JFIF file.
+    pub fn encode<'a>(&self, image: impl Into<ImageView<'a>>) -> Vec<u8> {
+        let mut out = Vec::new();
+        // Writing to a Vec only fails on sizes that do not fit the header
+        let _ = self.write(&mut out, image);
+        out
+    }
+
+    pub fn write<'a>(
+        &self,
+        writer: &mut impl Write,
+        image: impl Into<ImageView<'a>>,
+    ) -> std::io::Result<()> {
+        let image = image.into();
+        if image.width == 0 || image.height == 0 || image.width > 0xffff || image.height > 0xffff {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Cannot encode {}x{} as JPEG", image.width, image.height),
+            ));
+        }
+        let segment = |writer: &mut dyn Write, marker: u8, data: &[u8]| {
+            writer.write_all(&[0xff, marker])?;
+            writer.write_all(&(data.len() as u16 + 2).to_be_bytes())?;
+            writer.write_all(data)
+        };
+
+        writer.write_all(&[0xff, 0xd8])?;
+        segment(writer, 0xe0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0")?;
+        let mut tables = Vec::with_capacity(130);
+        for (id, table) in self.tables.iter().enumerate() {
+            tables.push(id as u8);
+            tables.extend_from_slice(table);
+        }
+        segment(writer, 0xdb, &tables)?;
+
+        let mut frame = vec![8];
+        frame.extend_from_slice(&(image.height as u16).to_be_bytes());
+        frame.extend_from_slice(&(image.width as u16).to_be_bytes());
+        // Component id, sampling factors and quantization table
+        frame.extend_from_slice(&[3, 1, 0x22, 0, 2, 0x11, 1, 3, 0x11, 1]);
+        segment(writer, 0xc0, &frame)?;
+
+        let mut huffman = Vec::new();
+        for (class, bits, values) in [
+            (0x00, &DC_LUMA_BITS, &DC_VALUES[..]),
+            (0x10, &AC_LUMA_BITS, &AC_LUMA_VALUES[..]),
+            (0x01, &DC_CHROMA_BITS, &DC_VALUES[..]),
+            (0x11, &AC_CHROMA_BITS, &AC_CHROMA_VALUES[..]),
+        ] {
+            huffman.push(class);
+            huffman.extend_from_slice(bits);
+            huffman.extend_from_slice(values);
+        }
+        segment(writer, 0xc4, &huffman)?;
+
+        segment(writer, 0xda, &[3, 1, 0x00, 2, 0x11, 3, 0x11, 0, 63, 0])?;
+        writer.write_all(&self.encode_scan(&image))?;
+        writer.write_all(&[0xff, 0xd9])
+    }
+
+    /// Entropy-coded data of the 4:2:0 scan, without any markers: MCUs of
+    /// four luma blocks followed by one block of each chroma component.
+    pub fn encode_scan(&self, image: &ImageView) -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        let mut strip = Strip {
+            width: (image.width as usize).div_ceil(16) * 16,
+            y: Vec::new(),
+            cb: Vec::new(),
+            cr: Vec::new(),
+        };
+        let mut predictions = [0; 3];
+        let mut block = [0.0; 64];
+        for top in (0..image.height).step_by(16) {
+            self.load_strip(image, top, &mut strip);
+            for mcu in 0..strip.width / 16 {
+                for (bx, by) in [(0, 0), (8, 0), (0, 8), (8, 8)] {
+                    copy_block(&strip.y, strip.width, mcu * 16 + bx, by, &mut block);
+                    self.encode_block(&block, 0, &mut predictions[0], &mut writer);
+                }
+                copy_block(&strip.cb, strip.width / 2, mcu * 8, 0, &mut block);
+                self.encode_block(&block, 1, &mut predictions[1], &mut writer);
+                copy_block(&strip.cr, strip.width / 2, mcu * 8, 0, &mut block);
+                self.encode_block(&block, 1, &mut predictions[2], &mut writer);
+            }
+        }
+        writer.finish()
+    }
+
+    /// Converts 16 lines from `top` to level shifted YCbCr, repeating the
+    /// last column and line past the edges of the image.
+    fn load_strip(&self, image: &ImageView, top: u32, strip: &mut Strip) {
+        let width = strip.width;
+        strip.y.clear();
+        strip.y.resize(width * 16, 0.0);
+        strip.cb.clear();
+        strip.cb.resize(width / 2 * 8, 0.0);
+        strip.cr.clear();
+        strip.cr.resize(width / 2 * 8, 0.0);
+
+        let mut row = Vec::with_capacity(image.width as usize * 4);
+        for line in 0..16 {
+            image.row_rgba((top + line as u32).min(image.height - 1), &mut row);
+            for x in 0..width {
+                let pixel = &row[x.min(image.width as usize - 1) * 4..][..3];
+                let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+                strip.y[line * width + x] = 0.299 * r + 0.587 * g + 0.114 * b - 128.0;
+                let chroma = (line / 2) * (width / 2) + x / 2;
+                strip.cb[chroma] += (-0.168736 * r - 0.331264 * g + 0.5 * b) / 4.0;
+                strip.cr[chroma] += (0.5 * r - 0.418688 * g - 0.081312 * b) / 4.0;
+            }
+        }
+    }
+
+    fn encode_block(
+        &self,
+        block: &[f32; 64],
+        table: usize,
+        prediction: &mut i32,
+        writer: &mut BitWriter,
+    ) {
+        let coefficients = self.fdct(block);
+        let mut quantized = [0i32; 64];
+        for (k, value) in quantized.iter_mut().enumerate() {
+            let q = self.tables[table][k] as f32;
+            *value = ((coefficients[ZIGZAG[k]] / q).round() as i32).clamp(-1023, 1023);
+        }
+
+        let (size, bits) = magnitude(quantized[0] - *prediction);
+        *prediction = quantized[0];
+        self.dc[table].put(writer, size);
+        writer.put(bits, size as u32);
+
+        let mut run = 0;
+        for &value in &quantized[1..] {
+            if value == 0 {
+                run += 1;
+                continue;
+            }
+            while run > 15 {
+                self.ac[table].put(writer, 0xf0);
+                run -= 16;
+            }
+            let (size, bits) = magnitude(value);
+            self.ac[table].put(writer, (run << 4) | size);
+            writer.put(bits, size as u32);
+            run = 0;
+        }
+        if run > 0 {
+            // End of block
+            self.ac[table].put(writer, 0x00);
+        }
+    }
+
+    /// Separable 2D DCT-II, natural order.
+    fn fdct(&self, block: &[f32; 64]) -> [f32; 64] {
+        let mut rows = [0.0; 64];
+        for y in 0..8 {
+            for u in 0..8 {
+                rows[y * 8 + u] = (0..8).map(|x| self.cos[u][x] * block[y * 8 + x]).sum();
+            }
+        }
+        let mut out = [0.0; 64];
+        for v in 0..8 {
+            for u in 0..8 {
+                out[v * 8 + u] = (0..8).map(|y| self.cos[v][y] * rows[y * 8 + u]).sum();
+            }
+        }
+        out
+    }
+}
+
+fn copy_block(plane: &[f32], width: usize, x: usize, y: usize, block: &mut [f32; 64]) {
+    for row in 0..8 {
+        let start = (y + row) * width + x;
+        block[row * 8..row * 8 + 8].copy_from_slice(&plane[start..start + 8]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        JpegEncoder, AC_CHROMA_BITS, AC_CHROMA_VALUES, AC_LUMA_BITS, AC_LUMA_VALUES,
+        DC_CHROMA_BITS, DC_LUMA_BITS, DC_VALUES, ZIGZAG,
+    };
+    use crate::export::ImageView;
+    use crate::pipewire_stream::Frame;
+    use std::collections::HashMap;
+
+    /// Huffman and coefficient decoding of a scan written by the encoder.
+    struct ScanReader<'a> {
+        data: &'a [u8],
+        position: usize,
+        bits: u32,
+        count: u32,
+    }
+
+    impl ScanReader<'_> {
+        fn bit(&mut self) -> u32 {
+            if self.count == 0 {
+                let byte = self.data[self.position];
+                self.position += if byte == 0xff { 2 } else { 1 };
+                self.bits = byte as u32;
+                self.count = 8;
+            }
+            self.count -= 1;
+            (self.bits >> self.count) & 1
+        }
+
+        fn receive(&mut self, size: u8) -> i32 {
+            let mut value = 0;
+            for _ in 0..size {
+                value = (value << 1) | self.bit() as i32;
+            }
+            if size > 0 && value < 1 << (size - 1) {
+                value - (1 << size) + 1
+            } else {
+                value
+            }
+        }
+
+        fn symbol(&mut self, table: &HashMap<(u8, u16), u8>) -> u8 {
+            let mut code = 0;
+            for length in 1..=16 {
+                code = (code << 1) | self.bit() as u16;
+                if let Some(&symbol) = table.get(&(length, code)) {
+                    return symbol;
+                }
+            }
+            panic!("invalid Huffman code");
+        }
+    }
+
+    fn table(bits: &[u8; 16], values: &[u8]) -> HashMap<(u8, u16), u8> {
+        let mut table = HashMap::new();
+        let (mut code, mut values) = (0u16, values.iter());
+        for (length, &count) in bits.iter().enumerate() {
+            for _ in 0..count {
+                table.insert((length as u8 + 1, code), *values.next().unwrap());
+                code += 1;
+            }
+            code <<= 1;
+        }
+        table
+    }
+
+    /// Decodes the luma plane, ignoring chroma beyond parsing it.
+    fn decode_luma(encoder: &JpegEncoder, scan: &[u8], width: usize, height: usize) -> Vec<f32> {
+        let dc = [
+            table(&DC_LUMA_BITS, &DC_VALUES),
+            table(&DC_CHROMA_BITS, &DC_VALUES),
+        ];
+        let ac = [
+            table(&AC_LUMA_BITS, &AC_LUMA_VALUES),
+            table(&AC_CHROMA_BITS, &AC_CHROMA_VALUES),
+        ];
+        let mut reader = ScanReader {
+            data: scan,
+            position: 0,
+            bits: 0,
+            count: 0,
+        };
+        let (mcus_x, mcus_y) = (width.div_ceil(16), height.div_ceil(16));
+        let mut luma = vec![0.0; mcus_x * 16 * mcus_y * 16];
+        let mut predictions = [0; 3];
+        for mcu in 0..mcus_x * mcus_y {
+            for block in 0..6 {
+                let (component, class) = match block {
+                    0..=3 => (0, 0),
+                    4 => (1, 1),
+                    _ => (2, 1),
+                };
+                let mut coefficients = [0.0f32; 64];
+                let size = reader.symbol(&dc[class]);
+                predictions[component] += reader.receive(size);
+                coefficients[0] = (predictions[component] * encoder.tables[class][0] as i32) as f32;
+                let mut k = 1;
+                while k < 64 {
+                    let symbol = reader.symbol(&ac[class]);
+                    if symbol == 0 {
+                        break;
+                    }
+                    k += (symbol >> 4) as usize;
+                    let value = reader.receive(symbol & 15);
+                    coefficients[ZIGZAG[k]] = (value * encoder.tables[class][k] as i32) as f32;
+                    k += 1;
+                }
+                if component != 0 {
+                    continue;
+                }
+                let left = (mcu % mcus_x) * 16 + (block % 2) * 8;
+                let top = (mcu / mcus_x) * 16 + (block / 2) * 8;
+                for y in 0..8 {
+                    for x in 0..8 {
+                        let mut sum = 0.0;
+                        for v in 0..8 {
+                            for u in 0..8 {
+                                sum +=
+                                    encoder.cos[u][x] * encoder.cos[v][y] * coefficients[v * 8 + u];
+                            }
+                        }
+                        luma[(top + y) * mcus_x * 16 + left + x] = sum + 128.0;
+                    }
+                }
+            }
+        }
+        luma
+    }
+
+    #[test]
+    fn luma_round_trip() {
+        let (width, height) = (30, 20);
+        let mut frame = Frame::new(width, height);
+        for (i, pixel) in frame.make_mut_slice().iter_mut().enumerate() {
+            let (x, y) = (i as u32 % width, i as u32 / width);
+            *pixel = slint::Rgba8Pixel::new((x * 8) as u8, (y * 12) as u8, 100, 255);
+        }
+        let encoder = JpegEncoder::new(90);
+        let scan = encoder.encode_scan(&ImageView::from(&frame));
+        let luma = decode_luma(&encoder, &scan, width as usize, height as usize);
+        let stride = (width as usize).div_ceil(16) * 16;
+        for (i, pixel) in frame.as_slice().iter().enumerate() {
+            let (x, y) = (i % width as usize, i / width as usize);
+            let expected = 0.299 * pixel.r as f32 + 0.587 * pixel.g as f32 + 0.114 * 100.0;
+            let decoded = luma[y * stride + x];
+            assert!(
+                (decoded - expected).abs() < 6.0,
+                "{x},{y}: {decoded} {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn file_structure() {
+        let frame = Frame::new(17, 9);
+        let encoder = JpegEncoder::new(75);
+        let jpeg = encoder.encode(&frame);
+        assert_eq!(&jpeg[..4], &[0xff, 0xd8, 0xff, 0xe0]);
+        assert_eq!(&jpeg[jpeg.len() - 2..], &[0xff, 0xd9]);
+        let sof = jpeg.windows(2).position(|w| w == [0xff, 0xc0]).unwrap();
+        assert_eq!(&jpeg[sof + 5..sof + 9], &[0, 9, 0, 17]);
+        // Quality 50 keeps the tables of the standard
+        let tables = JpegEncoder::new(50).quantization_tables()[0];
+        assert_eq!(&tables[..3], &[16, 11, 12]);
+        assert!(encoder.write(&mut Vec::new(), &Frame::new(0, 4)).is_err());
+    }
+}
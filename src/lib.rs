@@ -1,4 +1,27 @@
+pub mod audio;
+pub mod clip;
+pub mod clock;
+pub mod compositor;
+pub mod dma_buf_share;
 pub mod egl_dma_buf;
 mod egl_ext;
+pub mod export;
+pub mod ffmpeg;
+pub mod frame_transform;
 mod gl_ext;
+pub mod jpeg;
+pub mod mjpeg;
+pub mod pipewire_nodes;
 pub mod pipewire_stream;
+pub mod portal;
+pub mod recorder;
+pub mod remote_desktop;
+pub mod replay;
+pub mod restore_tokens;
+pub mod rfb;
+pub mod rtp;
+pub mod screenshot;
+pub mod shm_ring;
+pub mod test_pattern;
+pub mod test_source;
+pub mod yuv;
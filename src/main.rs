@@ -1,55 +1,1144 @@
 // Note https://github.com/Genymobile/scrcpy/issues/4507 (loop v4l2 not working)
 
-use portal_screencast as psc;
-use screencast::pipewire_stream::PipewireStream;
+use clap::{Parser, Subcommand};
+use screencast::audio::{AudioChunk, AudioSource};
+use screencast::clip;
+use screencast::compositor::{DesktopStitcher, Placement};
+use screencast::dma_buf_share::{self, DmaBufServer};
+use screencast::export;
+use screencast::ffmpeg::{self, FfmpegConfig, Preset};
+use screencast::frame_transform::{Overlay, Rect};
+use screencast::mjpeg::{self, MjpegConfig, MjpegServer};
+use screencast::pipewire_nodes;
+use screencast::pipewire_stream::{
+    self, Connector, Frame, PipewireStream, ReconnectPolicy, Remote, RepublishConfig, StreamEvent,
+    Target, TimedFrame,
+};
+use screencast::portal;
+use screencast::recorder::{self, RawFormat, RawRecorderConfig, Sidecar};
+use screencast::remote_desktop::{
+    Axis, DeviceOptions, DeviceType, RemoteDesktop, StreamSpace, BTN_LEFT, BTN_MIDDLE, BTN_RIGHT,
+};
+use screencast::replay::{ReplayBuffer, ReplayConfig};
+use screencast::restore_tokens::TokenStore;
+use screencast::rfb::{self, InputEvent, RfbConfig, RfbServer};
+use screencast::rtp::{self, RtpCodec, RtpConfig, RtpSink};
+use screencast::screenshot::{self, CaptureOptions};
+use screencast::shm_ring::{self, ShmRingConfig, ShmRingServer};
 use std::cell::RefCell;
-use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 slint::include_modules!();
 
+#[derive(Parser, Debug)]
+struct Args {
+    /// Capture this PipeWire node (id or node.name) directly, without the
+    /// ScreenCast portal
+    #[arg(long)]
+    node: Option<Target>,
+    /// Share several monitors through the portal and record them stitched
+    /// into one frame, laid out like the desktop
+    #[arg(long, conflicts_with = "node")]
+    multiple: bool,
+    /// Name to keep the portal's restore token under, so the sources picked
+    /// in its dialog are shared again without asking
+    #[arg(long, value_parser = parse_session)]
+    session: Option<String>,
+    /// In the window, part of the frame to show, record and take
+    /// screenshots of, as WIDTHxHEIGHT+X+Y
+    #[arg(long)]
+    crop: Option<Rect>,
+    /// In the window, scale to WIDTHxHEIGHT, after cropping
+    #[arg(long, value_parser = parse_size)]
+    size: Option<(u32, u32)>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List the video nodes that can be captured with --node
+    ListSources {
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+        /// Include all Video/* nodes, not only sources
+        #[arg(long)]
+        all: bool,
+    },
+    /// Save a single frame as PNG, QOI, PPM, PAM or JPEG
+    Screenshot {
+        /// Output file, the extension picks the format; screenshot-<time>.png by default
+        output: Option<PathBuf>,
+        /// Part of the frame to keep, as WIDTHxHEIGHT+X+Y
+        #[arg(long)]
+        crop: Option<Rect>,
+        /// Scale to WIDTHxHEIGHT, after cropping
+        #[arg(long, value_parser = parse_size)]
+        size: Option<(u32, u32)>,
+    },
+    /// Record until Ctrl+C, to MP4/MKV/WebM through ffmpeg or losslessly to
+    /// Y4M or raw RGBA
+    Record {
+        /// Output file; raw recordings start a new segment when the
+        /// resolution changes
+        #[arg(long)]
+        output: PathBuf,
+        /// y4m, y4m444 or rgba, picked from the extension by default
+        #[arg(long)]
+        format: Option<RawFormat>,
+        /// ffmpeg preset: x264-lossless, x264-fast or vp9 (the default for
+        /// .webm)
+        #[arg(long)]
+        preset: Option<Preset>,
+        /// Write per-frame timestamps next to each segment, json or csv
+        #[arg(long)]
+        sidecar: Option<Sidecar>,
+        /// Frame rate of the Y4M header or the ffmpeg output
+        #[arg(long, default_value_t = 30)]
+        fps: u32,
+        /// Stop after this many seconds
+        #[arg(long)]
+        duration: Option<u64>,
+        /// Also record audio: `monitor` for what the desktop plays, or a
+        /// PipeWire node id or name. Raw recordings get a .wav file next to
+        /// them
+        #[arg(long)]
+        audio: Option<AudioSource>,
+    },
+    /// Record a short looping GIF or APNG (.gif, .png or .apng)
+    Clip {
+        output: PathBuf,
+        /// Length of the clip in seconds
+        #[arg(long, default_value_t = 5)]
+        duration: u64,
+    },
+    /// Keep the last seconds of capture in memory and save them on SIGUSR1
+    /// (`pkill -USR1 screencast`) until Ctrl+C
+    Replay {
+        /// Seconds to keep
+        #[arg(long, default_value_t = 30)]
+        window: u64,
+        /// Memory for the compressed frames in MiB
+        #[arg(long, default_value_t = 256)]
+        budget: usize,
+        /// Downscale frames to fit WIDTHxHEIGHT to keep more in memory
+        #[arg(long, value_parser = parse_size)]
+        max_size: Option<(u32, u32)>,
+        /// Extension of the saved files, which picks the sink: mkv, mp4,
+        /// webm, gif, png, y4m or rgba
+        #[arg(long, default_value = "mkv")]
+        extension: String,
+    },
+    /// Serve an MJPEG preview over HTTP until Ctrl+C: /stream.mjpg,
+    /// /snapshot.jpg and /status
+    Serve {
+        /// Address to listen on, 0.0.0.0:8080 for the whole LAN
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        bind: std::net::SocketAddr,
+        /// JPEG quality from 1 to 100
+        #[arg(long, default_value_t = 80, value_parser = clap::value_parser!(u8).range(1..=100))]
+        quality: u8,
+        /// Frames per second sent to clients, 0 for no limit
+        #[arg(long, default_value_t = 15)]
+        max_fps: u32,
+    },
+    /// Serve the screen to VNC viewers until Ctrl+C, without a password
+    Vnc {
+        /// Address to listen on, tunnel it rather than opening it to the LAN
+        #[arg(long, default_value = "127.0.0.1:5900")]
+        bind: std::net::SocketAddr,
+        /// Desktop name shown by viewers
+        #[arg(long, default_value = "Screencast")]
+        name: String,
+        /// Let viewers use keyboard and pointer through the RemoteDesktop
+        /// portal, instead of only watching. Not with --node or --session
+        #[arg(long)]
+        control: bool,
+    },
+    /// Stream over RTP until Ctrl+C, play it on the receiver with
+    /// `ffplay -protocol_whitelist file,udp,rtp -i stream.sdp`
+    Rtp {
+        /// Address of the receiver
+        #[arg(long, default_value = "127.0.0.1:5004")]
+        destination: std::net::SocketAddr,
+        /// jpeg, or h264 encoded by ffmpeg
+        #[arg(long, default_value = "jpeg")]
+        codec: RtpCodec,
+        /// JPEG quality from 1 to 100
+        #[arg(long, default_value_t = 80, value_parser = clap::value_parser!(u8).range(1..=100))]
+        quality: u8,
+        /// H.264 bit rate in kbit/s
+        #[arg(long, default_value_t = 4000)]
+        bitrate: u32,
+        /// Largest UDP payload in bytes
+        #[arg(long, default_value_t = 1400)]
+        mtu: usize,
+        /// Frames per second sent, 0 for no limit
+        #[arg(long, default_value_t = 30)]
+        max_fps: u32,
+        /// Where to write the session description for the receiver
+        #[arg(long, default_value = "stream.sdp")]
+        sdp: PathBuf,
+    },
+    /// Share the captured DMA-BUFs with local processes until Ctrl+C
+    Share {
+        /// Socket to listen on, $XDG_RUNTIME_DIR/screencast-dma-buf.sock by
+        /// default
+        #[arg(long)]
+        socket: Option<PathBuf>,
+    },
+    /// Publish frames in shared memory for local processes until Ctrl+C
+    Shm {
+        /// Socket to listen on, $XDG_RUNTIME_DIR/screencast-shm.sock by
+        /// default
+        #[arg(long)]
+        socket: Option<PathBuf>,
+        /// Frames kept in the ring
+        #[arg(long, default_value_t = 3)]
+        slots: u32,
+        /// Downscale frames to fit WIDTHxHEIGHT
+        #[arg(long, value_parser = parse_size, default_value = "3840x2160")]
+        max_size: (u32, u32),
+    },
+    /// Re-publish the capture as a PipeWire camera until Ctrl+C
+    Republish {
+        /// Node name
+        #[arg(long, default_value = "screencast-output")]
+        name: String,
+        /// Name shown by browsers and OBS
+        #[arg(long, default_value = "Screencast")]
+        description: String,
+        /// Part of the frame to keep, as WIDTHxHEIGHT+X+Y
+        #[arg(long)]
+        crop: Option<Rect>,
+        /// Scale to WIDTHxHEIGHT, after cropping
+        #[arg(long, value_parser = parse_size)]
+        size: Option<(u32, u32)>,
+        /// Part of the output to blur, as WIDTHxHEIGHT+X+Y
+        #[arg(long)]
+        blur: Option<Rect>,
+        /// QOI, PPM or PAM image drawn over the output
+        #[arg(long)]
+        overlay: Option<PathBuf>,
+        /// Where the overlay goes in the output, as X+Y
+        #[arg(long, default_value = "0+0", value_parser = parse_position)]
+        overlay_at: (u32, u32),
+    },
+}
+
+fn parse_size(s: &str) -> Result<(u32, u32), String> {
+    s.split_once('x')
+        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+        .filter(|&(w, h)| w > 0 && h > 0)
+        .ok_or(format!("Invalid size '{s}', expected WIDTHxHEIGHT"))
+}
+
+fn parse_session(s: &str) -> Result<String, String> {
+    if TokenStore::is_valid_name(s) {
+        Ok(s.to_owned())
+    } else {
+        Err(format!(
+            "Invalid session name '{s}', it cannot contain '=' or line breaks"
+        ))
+    }
+}
+
+fn parse_position(s: &str) -> Result<(u32, u32), String> {
+    s.split_once('+')
+        .and_then(|(x, y)| Some((x.parse().ok()?, y.parse().ok()?)))
+        .ok_or(format!("Invalid position '{s}', expected X+Y"))
+}
+
+/// Creates an empty file named after the current time in milliseconds, with
+/// a counter added when that name is taken, so two saves never share a file.
+fn timestamped_path(prefix: &str, extension: &str) -> std::io::Result<PathBuf> {
+    let time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let mut path = PathBuf::from(format!("{prefix}-{time}.{extension}"));
+    for count in 1.. {
+        match std::fs::File::create_new(&path) {
+            Ok(_) => break,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                path = PathBuf::from(format!("{prefix}-{time}-{count}.{extension}"));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(path)
+}
+
+/// Saves to `output`, or to a new screenshot-<time>.png.
+fn save_screenshot(output: Option<PathBuf>, frame: &Frame) {
+    let path = match output.map_or_else(|| timestamped_path("screenshot", "png"), Ok) {
+        Ok(path) => path,
+        Err(e) => {
+            println!("Failed to create the screenshot file: {e}");
+            return;
+        }
+    };
+    match export::save(&path, frame) {
+        Ok(()) => println!(
+            "Saved {}x{} screenshot to {}",
+            frame.width(),
+            frame.height(),
+            path.display()
+        ),
+        Err(e) => println!("Failed to save {}: {e}", path.display()),
+    }
+}
+
+fn list_sources(json: bool, all: bool) {
+    let nodes = if all {
+        pipewire_nodes::list_video_nodes()
+    } else {
+        pipewire_nodes::list_video_sources()
+    };
+    let nodes = match nodes {
+        Ok(nodes) => nodes,
+        Err(e) => {
+            println!("Failed to list PipeWire nodes: {e}");
+            return;
+        }
+    };
+    if json {
+        println!("{}", pipewire_nodes::to_json(&nodes));
+        return;
+    }
+
+    let column = |value: &Option<String>| value.clone().unwrap_or("-".to_owned());
+    let rows = nodes
+        .iter()
+        .map(|node| {
+            [
+                node.id.to_string(),
+                column(&node.name),
+                if node.is_camera() {
+                    format!("{} (camera)", column(&node.media_class))
+                } else {
+                    column(&node.media_class)
+                },
+                column(&node.description),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let header = ["ID", "NAME", "CLASS", "DESCRIPTION"].map(str::to_owned);
+    let mut widths = header.each_ref().map(|h| h.len());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let print_row = |row: &[String; 4]| {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    };
+    print_row(&header);
+    for (row, node) in rows.iter().zip(&nodes) {
+        print_row(row);
+        for format in &node.formats {
+            println!("    {format}");
+        }
+    }
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+static SAVE_REPLAY: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+extern "C" fn on_save_replay(_signal: libc::c_int) {
+    SAVE_REPLAY.store(true, Ordering::Relaxed);
+}
+
+fn replay(frames: &async_channel::Receiver<TimedFrame>, config: ReplayConfig, extension: &str) {
+    unsafe { libc::signal(libc::SIGUSR1, on_save_replay as libc::sighandler_t) };
+    println!(
+        "Keeping the last {}s, send SIGUSR1 to process {} to save them",
+        config.window.as_secs(),
+        std::process::id()
+    );
+    let buffer = RefCell::new(ReplayBuffer::new(config));
+    let poll = || {
+        if SAVE_REPLAY.swap(false, Ordering::Relaxed) {
+            // Capture stalls while saving, the stream thread blocks once the
+            // frame channel is full
+            let buffer = buffer.borrow();
+            match timestamped_path("replay", extension) {
+                Ok(path) => match buffer.save(&path) {
+                    Ok(()) => println!(
+                        "Saved {:.1}s replay to {}",
+                        buffer.span().as_secs_f64(),
+                        path.display()
+                    ),
+                    Err(e) => println!("Failed to save {}: {e}", path.display()),
+                },
+                Err(e) => println!("Failed to create the replay file: {e}"),
+            }
+        }
+        INTERRUPTED.load(Ordering::Relaxed)
+    };
+    let _ = pipewire_stream::for_each_frame(frames, poll, |TimedFrame { frame, pts, .. }| {
+        if frame.width() > 0 && frame.height() > 0 {
+            buffer.borrow_mut().push(&frame, pts);
+        }
+        Ok(())
+    });
+}
+
+enum Recording {
+    Raw(RawRecorderConfig),
+    Ffmpeg(FfmpegConfig),
+    Clip(PathBuf, Duration),
+    Replay(ReplayConfig, String),
+    Serve(MjpegConfig),
+    Vnc(RfbConfig),
+    Rtp(RtpConfig),
+    Share(PathBuf),
+    Shm(PathBuf, ShmRingConfig),
+    Republish(RepublishConfig, Processing),
+}
+
+/// What is done to frames before they are re-published.
+struct Processing {
+    crop: Option<Rect>,
+    size: Option<(u32, u32)>,
+    blur: Option<Rect>,
+    overlay: Option<Overlay>,
+}
+
+fn record_ffmpeg(
+    frames: &async_channel::Receiver<TimedFrame>,
+    audio: Option<&async_channel::Receiver<AudioChunk>>,
+    config: &FfmpegConfig,
+    stop: impl Fn() -> bool,
+) {
+    match ffmpeg::record(frames, audio, config, stop) {
+        Ok(Some(status)) if status.success() => {
+            println!("Recorded {}", config.output.display())
+        }
+        Ok(Some(status)) => println!("ffmpeg failed with {status}"),
+        Ok(None) => println!("No frames recorded"),
+        Err(e) => println!("Recording failed: {e}"),
+    }
+}
+
+/// What to capture, from the options before the subcommand.
+struct Source {
+    node: Option<Target>,
+    multiple: bool,
+    session: Option<String>,
+}
+
+fn record(
+    source: Source,
+    recording: Recording,
+    duration: Option<u64>,
+    audio_source: Option<AudioSource>,
+) {
+    let Source {
+        node,
+        multiple,
+        session,
+    } = source;
+    let mut stream = PipewireStream::create();
+    // Viewers in control need a RemoteDesktop session carrying the screen cast
+    let control = matches!(&recording, Recording::Vnc(config) if !config.view_only);
+    if multiple && (control || matches!(&recording, Recording::Share(_) | Recording::Republish(..)))
+    {
+        println!("--multiple needs a recording of the frames, not of a single stream");
+        return;
+    }
+    let mut remote = None;
+    // The other monitors' streams and what joins them, with --multiple
+    let mut stitched = None;
+    let (frames, _session) = match node {
+        Some(target) => (stream.start_direct(target), None),
+        None if control => match start_remote_desktop() {
+            Ok((desktop, pw_fd, descriptor, devices)) => {
+                let frames = stream.start(pw_fd, descriptor.node_id);
+                remote = Some((desktop, descriptor, devices));
+                (frames, None)
+            }
+            Err(e) => {
+                println!("Failed to start remote desktop: {e}");
+                return;
+            }
+        },
+        None => match start_screen_cast(multiple, session.as_deref()) {
+            Ok((session, pw_fd, streams)) if multiple => {
+                match stitch(&mut stream, pw_fd, &streams) {
+                    Ok((frames, stitcher, others)) => {
+                        stitched = Some((stitcher, others));
+                        (frames, Some(session))
+                    }
+                    Err(e) => {
+                        println!("Failed to stitch the monitors: {e}");
+                        return;
+                    }
+                }
+            }
+            Ok((session, pw_fd, streams)) => {
+                (stream.start(pw_fd, streams[0].node_id), Some(session))
+            }
+            Err(e) => {
+                println!("Failed to start screen cast: {e}");
+                return;
+            }
+        },
+    };
+
+    let audio = audio_source.map(|source| stream.capture_audio(source));
+
+    // Stop cleanly on Ctrl+C so the last segment and its sidecar are written
+    unsafe { libc::signal(libc::SIGINT, on_interrupt as libc::sighandler_t) };
+    let deadline = duration.map(|secs| Instant::now() + Duration::from_secs(secs));
+    let stop =
+        || INTERRUPTED.load(Ordering::Relaxed) || deadline.is_some_and(|d| Instant::now() >= d);
+    match recording {
+        Recording::Raw(config) => match recorder::record(&frames, audio.as_ref(), config, stop) {
+            Ok(segments) => {
+                for segment in segments {
+                    println!("Recorded {}", segment.display());
+                }
+            }
+            Err(e) => println!("Recording failed: {e}"),
+        },
+        Recording::Ffmpeg(config) => record_ffmpeg(&frames, audio.as_ref(), &config, stop),
+        Recording::Clip(path, duration) => {
+            let clip = clip::capture(&frames, duration, stop);
+            match clip.save(&path) {
+                Ok(()) => println!(
+                    "Saved {} frame clip of {:.1}s to {}",
+                    clip.len(),
+                    clip.duration().as_secs_f64(),
+                    path.display()
+                ),
+                Err(e) => println!("Failed to save {}: {e}", path.display()),
+            }
+        }
+        Recording::Replay(config, extension) => replay(&frames, config, &extension),
+        Recording::Serve(config) => match MjpegServer::bind(config) {
+            Ok(mut server) => {
+                println!("Serving on http://{}/", server.local_addr());
+                mjpeg::serve(&frames, &mut server, stop);
+                server.stop();
+            }
+            Err(e) => println!("Failed to start the preview server: {e}"),
+        },
+        Recording::Vnc(config) => match RfbServer::bind(config) {
+            Ok(server) => {
+                println!("Serving VNC on {}", server.local_addr());
+                let mut input = remote.as_ref().map(|(desktop, descriptor, devices)| {
+                    RemoteInput::new(desktop, descriptor, *devices)
+                });
+                let forward = |event| {
+                    if let Some(input) = &mut input {
+                        if let Err(e) = input.forward(event) {
+                            println!("Failed to forward input: {e}");
+                        }
+                    }
+                };
+                rfb::serve(&frames, &server, forward, stop);
+                server.stop();
+            }
+            Err(e) => println!("Failed to start the VNC server: {e}"),
+        },
+        Recording::Rtp(config) => {
+            let destination = config.destination;
+            match RtpSink::new(config) {
+                Ok(mut sink) => {
+                    println!("Streaming to rtp://{destination}");
+                    if let Some(path) = sink.sdp_path() {
+                        println!("Session description in {}", path.display());
+                    }
+                    if let Err(e) =
+                        rtp::stream(&frames, &mut sink, stop).and_then(|()| sink.finish())
+                    {
+                        println!("Streaming failed: {e}");
+                    }
+                }
+                Err(e) => println!("Failed to start streaming: {e}"),
+            }
+        }
+        Recording::Share(path) => match DmaBufServer::bind(path) {
+            Ok(server) => {
+                println!("Sharing DMA-BUFs on {}", server.path().display());
+                let dma_bufs = stream.share_dma_bufs();
+                // The capture waits for its frames to be taken
+                let drain_and_stop = || {
+                    while frames.try_recv().is_ok() {}
+                    stop()
+                };
+                dma_buf_share::serve(&dma_bufs, &server, drain_and_stop);
+                server.stop();
+            }
+            Err(e) => println!("Failed to start sharing: {e}"),
+        },
+        Recording::Shm(path, config) => match ShmRingServer::bind(path, config) {
+            Ok(mut server) => {
+                println!("Publishing frames on {}", server.path().display());
+                shm_ring::serve(&frames, &mut server, stop);
+                server.stop();
+            }
+            Err(e) => println!("Failed to start publishing: {e}"),
+        },
+        Recording::Republish(config, processing) => {
+            // Replies need the frames to keep flowing, they come in later
+            let _ = stream.set_crop(processing.crop);
+            let _ = stream.set_output_size(processing.size);
+            let _ = stream.set_blur(processing.blur);
+            let _ = stream.set_overlay(processing.overlay);
+            let node = stream.republish(config);
+            std::thread::spawn(move || match node.wait() {
+                Ok(id) => println!("Re-published as node {id}"),
+                Err(e) => println!("Failed to re-publish: {e}"),
+            });
+            let _ = pipewire_stream::for_each_frame(&frames, stop, |_| Ok(()));
+        }
+    }
+    frames.close();
+    // Each stitched monitor runs on its own clock
+    let others = stitched
+        .as_ref()
+        .map_or(&[][..], |(_, others)| others.as_slice());
+    for (index, stream) in std::iter::once(&stream).chain(others).enumerate() {
+        if let Ok(stats) = stream.clock_stats().wait() {
+            let label = if others.is_empty() {
+                String::new()
+            } else {
+                format!("Monitor {index}: ")
+            };
+            println!(
+                "{label}Video clock drift {:.1} ppm, jitter up to {:?}",
+                stats.video.drift_ppm, stats.video.max_jitter
+            );
+        }
+    }
+    if let Some((mut stitcher, others)) = stitched {
+        stitcher.stop();
+        for mut other in others {
+            other.stop();
+        }
+    }
+    stream.stop();
+}
+
+/// Captures each monitor of a `--multiple` session, the first one with
+/// `first`, and joins their frames on one canvas.
+fn stitch(
+    first: &mut PipewireStream,
+    pw_fd: OwnedFd,
+    streams: &[portal::StreamDescriptor],
+) -> std::io::Result<(
+    async_channel::Receiver<TimedFrame>,
+    DesktopStitcher,
+    Vec<PipewireStream>,
+)> {
+    let placements = streams
+        .iter()
+        .map(|stream| {
+            Placement::from_descriptor(stream)
+                .ok_or_else(|| std::io::Error::other("only monitors have a place on the desktop"))
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let (stitcher, frames) = DesktopStitcher::start(slint::Rgba8Pixel::new(0, 0, 0, 255));
+    let mut others = Vec::new();
+    for (i, (stream, placement)) in streams.iter().zip(placements).enumerate() {
+        let fd = pw_fd.try_clone()?;
+        let monitor = if i == 0 {
+            first.start(fd, stream.node_id)
+        } else {
+            let mut other = PipewireStream::create();
+            let monitor = other.start(fd, stream.node_id);
+            others.push(other);
+            monitor
+        };
+        stitcher.add_monitor(stream.node_id, placement, monitor);
+    }
+    Ok((frames, stitcher, others))
+}
+
+/// Passes VNC input on through the RemoteDesktop portal.
+struct RemoteInput<'a> {
+    desktop: &'a RemoteDesktop,
+    stream: &'a portal::StreamDescriptor,
+    /// What the user allowed, events for other devices are dropped.
+    devices: DeviceType,
+    /// Button mask of the last pointer event.
+    buttons: u8,
+}
+
+impl<'a> RemoteInput<'a> {
+    fn new(
+        desktop: &'a RemoteDesktop,
+        stream: &'a portal::StreamDescriptor,
+        devices: DeviceType,
+    ) -> Self {
+        for (device, name) in [
+            (DeviceType::KEYBOARD, "keyboard"),
+            (DeviceType::POINTER, "pointer"),
+        ] {
+            if !devices.contains(device) {
+                println!("No {name} access was granted, viewers' {name} input is ignored");
+            }
+        }
+        Self {
+            desktop,
+            stream,
+            devices,
+            buttons: 0,
+        }
+    }
+
+    fn forward(&mut self, event: InputEvent) -> Result<(), portal::PortalError> {
+        let device = match event {
+            InputEvent::Key { .. } => DeviceType::KEYBOARD,
+            InputEvent::Pointer { .. } => DeviceType::POINTER,
+        };
+        if !self.devices.contains(device) {
+            return Ok(());
+        }
+        match event {
+            InputEvent::Key { keysym, down } => {
+                self.desktop.notify_keyboard_keysym(keysym as i32, down)
+            }
+            InputEvent::Pointer {
+                x,
+                y,
+                buttons,
+                frame_size,
+            } => {
+                // Frames are neither cropped nor scaled while serving
+                let space = StreamSpace::new(self.stream, frame_size);
+                self.desktop.move_pointer(&space, x as f64, y as f64)?;
+                let changed = buttons ^ self.buttons;
+                self.buttons = buttons;
+                for (bit, button) in [(1, BTN_LEFT), (2, BTN_MIDDLE), (4, BTN_RIGHT)] {
+                    if changed & bit != 0 {
+                        self.desktop
+                            .notify_pointer_button(button, buttons & bit != 0)?;
+                    }
+                }
+                // The wheel is pressing and releasing buttons 4 to 7
+                for (bit, axis, steps) in [
+                    (8, Axis::Vertical, -1),
+                    (16, Axis::Vertical, 1),
+                    (32, Axis::Horizontal, -1),
+                    (64, Axis::Horizontal, 1),
+                ] {
+                    if changed & buttons & bit != 0 {
+                        self.desktop.notify_pointer_axis_discrete(axis, steps)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Starts a session controlling the shared monitor, with the devices the
+/// user allowed.
+fn start_remote_desktop(
+) -> Result<(RemoteDesktop, OwnedFd, portal::StreamDescriptor, DeviceType), portal::PortalError> {
+    let portal = portal::Portal::new()?;
+    let desktop = RemoteDesktop::create(&portal)?;
+    desktop.select_devices(&DeviceOptions {
+        types: DeviceType::KEYBOARD | DeviceType::POINTER,
+        ..Default::default()
+    })?;
+    // Absolute pointer positions need a monitor, windows move around
+    desktop.session().select_sources(&portal::SourceOptions {
+        types: portal::SourceType::MONITOR,
+        ..Default::default()
+    })?;
+    let started = desktop.start("")?;
+    let stream = started
+        .streams
+        .into_iter()
+        .next()
+        .ok_or(portal::PortalError::Parse("streams"))?;
+    let pw_fd = desktop.session().open_pipewire_remote()?;
+    Ok((desktop, pw_fd, stream, started.devices))
+}
+
+/// Starts a session sharing one source, or with `multiple` any number of
+/// monitors. There is at least one stream.
+fn start_screen_cast(
+    multiple: bool,
+    session: Option<&str>,
+) -> Result<(portal::Session, OwnedFd, Vec<portal::StreamDescriptor>), portal::PortalError> {
+    let portal = portal::Portal::new()?;
+    // Only monitors have a place on the desktop to stitch them by
+    let options = portal::SourceOptions {
+        types: if multiple {
+            portal::SourceType::MONITOR
+        } else {
+            portal::SourceType::MONITOR | portal::SourceType::WINDOW
+        },
+        multiple,
+        ..Default::default()
+    };
+    // Kept apart, a restored session shares what was picked last time
+    let name = session.unwrap_or(if multiple { "multiple" } else { "default" });
+    // If you have a window handle you can tie the dialog to it
+    let (session, started) = match TokenStore::open_default() {
+        Some(store) => portal.start_persisted(name, &store, &options, "")?,
+        None => portal.start_session(&options, "")?,
+    };
+    if started.streams.is_empty() {
+        return Err(portal::PortalError::Parse("streams"));
+    }
+    let pw_fd = session.open_pipewire_remote()?;
+    Ok((session, pw_fd, started.streams))
+}
+
 fn main() {
+    let args = Args::parse();
+    let source = Source {
+        node: args.node.clone(),
+        multiple: args.multiple,
+        session: args.session.clone(),
+    };
+    match args.command {
+        Some(Command::ListSources { json, all }) => {
+            list_sources(json, all);
+            return;
+        }
+        Some(Command::Screenshot { output, crop, size }) => {
+            let mut options = CaptureOptions {
+                node: args.node,
+                crop,
+                size,
+                ..Default::default()
+            };
+            if args.session.is_some() {
+                options.session_name = args.session;
+            }
+            match screenshot::capture_one(&options) {
+                Ok(frame) => save_screenshot(output, &frame),
+                Err(e) => println!("Failed to take screenshot: {e}"),
+            }
+            return;
+        }
+        Some(Command::Record {
+            output,
+            format,
+            preset,
+            sidecar,
+            fps,
+            duration,
+            audio,
+        }) => {
+            let recording = match format.or_else(|| RawFormat::from_path(&output)) {
+                Some(format) => Recording::Raw(RawRecorderConfig {
+                    output,
+                    format,
+                    sidecar,
+                    fps,
+                }),
+                None if ffmpeg::is_video_path(&output) || preset.is_some() => {
+                    let mut config = FfmpegConfig::new(output);
+                    config.preset = preset.unwrap_or(config.preset);
+                    config.frame_rate = fps;
+                    Recording::Ffmpeg(config)
+                }
+                None => {
+                    println!("Unknown format of {}, pass --format", output.display());
+                    return;
+                }
+            };
+            record(source, recording, duration, audio);
+            return;
+        }
+        Some(Command::Clip { output, duration }) => {
+            if clip::ClipFormat::from_path(&output).is_none() {
+                println!(
+                    "Unknown clip format of {}, use .gif or .png",
+                    output.display()
+                );
+                return;
+            }
+            let recording = Recording::Clip(output, Duration::from_secs(duration));
+            record(source, recording, None, None);
+            return;
+        }
+        Some(Command::Replay {
+            window,
+            budget,
+            max_size,
+            extension,
+        }) => {
+            let config = ReplayConfig {
+                window: Duration::from_secs(window),
+                budget: budget << 20,
+                max_size,
+                ..Default::default()
+            };
+            record(source, Recording::Replay(config, extension), None, None);
+            return;
+        }
+        Some(Command::Serve {
+            bind,
+            quality,
+            max_fps,
+        }) => {
+            let config = MjpegConfig {
+                bind,
+                quality,
+                max_fps: (max_fps > 0).then_some(max_fps),
+            };
+            record(source, Recording::Serve(config), None, None);
+            return;
+        }
+        Some(Command::Vnc {
+            bind,
+            name,
+            control,
+        }) => {
+            if control && args.node.is_some() {
+                println!("--control needs the portal, viewers can only watch --node");
+                return;
+            }
+            // Remote desktop sessions are not persisted, each asks again
+            if control && args.session.is_some() {
+                println!("--control asks for the devices every time, it cannot restore --session");
+                return;
+            }
+            let config = RfbConfig {
+                bind,
+                name,
+                view_only: !control,
+            };
+            record(source, Recording::Vnc(config), None, None);
+            return;
+        }
+        Some(Command::Rtp {
+            destination,
+            codec,
+            quality,
+            bitrate,
+            mtu,
+            max_fps,
+            sdp,
+        }) => {
+            let config = RtpConfig {
+                destination,
+                codec,
+                quality,
+                bitrate,
+                mtu,
+                max_fps: (max_fps > 0).then_some(max_fps),
+                sdp: Some(sdp),
+                ..Default::default()
+            };
+            record(source, Recording::Rtp(config), None, None);
+            return;
+        }
+        Some(Command::Share { socket }) => {
+            let path = socket.unwrap_or_else(dma_buf_share::default_socket_path);
+            record(source, Recording::Share(path), None, None);
+            return;
+        }
+        Some(Command::Shm {
+            socket,
+            slots,
+            max_size,
+        }) => {
+            let path = socket.unwrap_or_else(shm_ring::default_socket_path);
+            let config = ShmRingConfig { slots, max_size };
+            record(source, Recording::Shm(path, config), None, None);
+            return;
+        }
+        Some(Command::Republish {
+            name,
+            description,
+            crop,
+            size,
+            blur,
+            overlay,
+            overlay_at: (x, y),
+        }) => {
+            let overlay = match overlay.map(export::load).transpose() {
+                Ok(image) => image.map(|image| Overlay { image, x, y }),
+                Err(e) => {
+                    println!("Failed to load the overlay: {e}");
+                    return;
+                }
+            };
+            let config = RepublishConfig { name, description };
+            let processing = Processing {
+                crop,
+                size,
+                blur,
+                overlay,
+            };
+            record(source, Recording::Republish(config, processing), None, None);
+            return;
+        }
+        None => {}
+    }
+
+    if args.multiple {
+        println!("--multiple is only for the recording commands, the preview shows one source");
+    }
     let ui = Ui::new().unwrap();
-    let active_screen_cast: Rc<RefCell<Option<psc::ActiveScreenCast>>> =
-        Rc::new(RefCell::new(None));
-    let mut pw_stream = PipewireStream::create();
+    let pw_stream = Rc::new(RefCell::new(PipewireStream::create()));
+    // Frames for the ffmpeg thread while recording
+    let recording = Rc::new(RefCell::new(None::<async_channel::Sender<TimedFrame>>));
     let weak_ui = ui.as_weak();
-    ui.on_start({
-        let active_screen_cast = Rc::clone(&active_screen_cast);
-        move |on| {
-            if on {
-                let mut screen_cast = psc::ScreenCast::new().unwrap();
-                // Set which source types to allow, and enable multiple items to be shared.
-                screen_cast.set_source_types(psc::SourceType::MONITOR | psc::SourceType::WINDOW);
-                // screen_cast.enable_multiple();
-                // If you have a window handle you can tie the dialog to it
-                if let Ok(screen_cast) = screen_cast.start(None) {
-                    let pw_fd =
-                        unsafe { std::os::fd::OwnedFd::from_raw_fd(screen_cast.pipewire_fd()) };
-                    let stream_id = screen_cast.streams().next().unwrap().pipewire_node();
-                    let frame_receiver = pw_stream.start(pw_fd, stream_id);
-                    slint::spawn_local({
-                        let weak_ui = weak_ui.clone();
-                        async move {
-                            while let Ok(frame) = frame_receiver.recv().await {
-                                weak_ui
-                                    .upgrade()
-                                    .unwrap()
-                                    .set_frame(slint::Image::from_rgba8(frame));
-                            }
-                            weak_ui
-                                .upgrade()
-                                .unwrap()
-                                .set_frame(slint::Image::default());
-                        }
-                    })
-                    .unwrap();
-                    *active_screen_cast.borrow_mut() = Some(screen_cast);
+    ui.on_pause({
+        let pw_stream = Rc::clone(&pw_stream);
+        move |pause| {
+            let reply = if pause {
+                pw_stream.borrow().pause()
+            } else {
+                pw_stream.borrow().resume()
+            };
+            slint::spawn_local(async move {
+                if let Err(e) = reply.recv().await {
+                    println!("Failed to pause/resume: {e}");
                 }
+            })
+            .unwrap();
+        }
+    });
+    ui.on_screenshot({
+        let pw_stream = Rc::clone(&pw_stream);
+        // Same as the preview, which the stream crops and scales
+        let mut options = CaptureOptions {
+            node: args.node.clone(),
+            crop: args.crop,
+            size: args.size,
+            ..Default::default()
+        };
+        if args.session.is_some() {
+            options.session_name = args.session.clone();
+        }
+        move |launched| {
+            if launched {
+                let reply = pw_stream.borrow().snapshot();
+                slint::spawn_local(async move {
+                    match reply.recv().await {
+                        Ok(frame) => save_screenshot(None, &frame),
+                        Err(e) => println!("Failed to take screenshot: {e}"),
+                    }
+                })
+                .unwrap();
             } else {
-                pw_stream.stop();
-                *active_screen_cast.borrow_mut() = None;
+                // The portal dialog blocks, keep it off the UI thread
+                let options = options.clone();
+                std::thread::spawn(move || match screenshot::capture_one(&options) {
+                    Ok(frame) => save_screenshot(None, &frame),
+                    Err(e) => println!("Failed to take screenshot: {e}"),
+                });
+            }
+        }
+    });
+    ui.on_record({
+        let recording = Rc::clone(&recording);
+        move |on| {
+            if !on {
+                // Closing the channel makes the thread finalise the file
+                recording.borrow_mut().take();
+                return;
             }
+            let path = match timestamped_path("recording", "mkv") {
+                Ok(path) => path,
+                Err(e) => {
+                    println!("Failed to create the recording file: {e}");
+                    return;
+                }
+            };
+            let (sender, receiver) = async_channel::bounded(8);
+            let config = FfmpegConfig::new(path);
+            std::thread::spawn(move || record_ffmpeg(&receiver, None, &config, || false));
+            *recording.borrow_mut() = Some(sender);
+        }
+    });
+    ui.on_start(move |on| {
+        if on {
+            let connector: Connector = match args.node.clone() {
+                Some(target) => Box::new(move || {
+                    Ok(Remote {
+                        fd: None,
+                        target: target.clone(),
+                        keep_alive: None,
+                    })
+                }),
+                // Runs again on reconnection, the restore token avoids a new dialog
+                None => Box::new({
+                    let session = args.session.clone();
+                    move || {
+                        let (screen_cast, pw_fd, streams) =
+                            start_screen_cast(false, session.as_deref())?;
+                        Ok(Remote {
+                            fd: Some(pw_fd),
+                            target: Target::Id(streams[0].node_id),
+                            keep_alive: Some(Box::new(screen_cast)),
+                        })
+                    }
+                }),
+            };
+            let (frame_receiver, event_receiver) = pw_stream
+                .borrow_mut()
+                .start_reconnecting(connector, ReconnectPolicy::default());
+            // Replies need the frames to keep flowing, they come in later
+            let _ = pw_stream.borrow().set_crop(args.crop);
+            let _ = pw_stream.borrow().set_output_size(args.size);
+            slint::spawn_local({
+                let weak_ui = weak_ui.clone();
+                let pw_stream = Rc::clone(&pw_stream);
+                async move {
+                    while let Ok(event) = event_receiver.recv().await {
+                        println!("Stream event: {event:?}");
+                        if let StreamEvent::GaveUp(_) = event {
+                            pw_stream.borrow_mut().stop();
+                            weak_ui.upgrade().unwrap().invoke_stopped();
+                        }
+                    }
+                }
+            })
+            .unwrap();
+            slint::spawn_local({
+                let weak_ui = weak_ui.clone();
+                let recording = Rc::clone(&recording);
+                async move {
+                    while let Ok(frame) = frame_receiver.recv().await {
+                        if let Some(sender) = recording.borrow().as_ref() {
+                            // Drop frames rather than stall the UI if ffmpeg falls behind
+                            let _ = sender.try_send(frame.clone());
+                        }
+                        weak_ui
+                            .upgrade()
+                            .unwrap()
+                            .set_frame(slint::Image::from_rgba8(frame.frame));
+                    }
+                    recording.borrow_mut().take();
+                    weak_ui
+                        .upgrade()
+                        .unwrap()
+                        .set_frame(slint::Image::default());
+                }
+            })
+            .unwrap();
+        } else {
+            pw_stream.borrow_mut().stop();
         }
     });
     ui.run().unwrap();
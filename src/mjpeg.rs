@@ -0,0 +1,439 @@
+//! Preview over HTTP for browsers on the LAN: an MJPEG stream
+//! (`multipart/x-mixed-replace`), single JPEG snapshots and a JSON status.
+//!
+//! | Path             | Response                                   |
+//! |------------------|--------------------------------------------|
+//! | `/`              | HTML page showing the stream               |
+//! | `/stream.mjpg`   | JPEG frames until the client disconnects   |
+//! | `/snapshot.jpg`  | The latest frame                           |
+//! | `/status`        | Size, frame count, clients and settings    |
+
+use crate::jpeg::JpegEncoder;
+use crate::pipewire_stream::{self, TimedFrame};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const BOUNDARY: &str = "frame";
+
+#[derive(Debug, Clone)]
+pub struct MjpegConfig {
+    pub bind: SocketAddr,
+    /// JPEG quality from 1 to 100.
+    pub quality: u8,
+    /// Frames arriving faster than this are not served, `None` for no limit.
+    pub max_fps: Option<u32>,
+}
+
+impl Default for MjpegConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            quality: 80,
+            max_fps: Some(15),
+        }
+    }
+}
+
+/// The latest frame, encoded by the first client that needs it.
+#[derive(Default)]
+struct Latest {
+    frame: Option<TimedFrame>,
+    /// Frames published so far, the number of the current one.
+    sequence: u64,
+    jpeg: Option<Arc<Vec<u8>>>,
+}
+
+struct Shared {
+    config: MjpegConfig,
+    encoder: JpegEncoder,
+    latest: Mutex<Latest>,
+    published: Condvar,
+    stopped: AtomicBool,
+    clients: AtomicUsize,
+    started: Instant,
+}
+
+impl Shared {
+    /// The first frame after `sequence`, waiting up to `timeout` for it.
+    fn jpeg_after(&self, sequence: u64, timeout: Duration) -> Option<(u64, Arc<Vec<u8>>)> {
+        let latest = self.latest.lock().unwrap();
+        let (mut latest, _) = self
+            .published
+            .wait_timeout_while(latest, timeout, |latest| {
+                latest.sequence <= sequence && !self.stopped.load(Ordering::Relaxed)
+            })
+            .unwrap();
+        if latest.sequence <= sequence {
+            return None;
+        }
+        if let Some(jpeg) = &latest.jpeg {
+            return Some((latest.sequence, Arc::clone(jpeg)));
+        }
+        let (current, frame) = (latest.sequence, latest.frame.clone()?);
+        // Encode without holding up the capture
+        drop(latest);
+        let jpeg = Arc::new(self.encoder.encode(&frame.frame));
+        latest = self.latest.lock().unwrap();
+        if latest.sequence == current {
+            latest.jpeg = Some(Arc::clone(&jpeg));
+        }
+        Some((current, jpeg))
+    }
+
+    fn status(&self) -> String {
+        let latest = self.latest.lock().unwrap();
+        let size = match &latest.frame {
+            Some(frame) => format!(
+                "{{\"width\":{},\"height\":{}}}",
+                frame.frame.width(),
+                frame.frame.height()
+            ),
+            None => "null".to_owned(),
+        };
+        let max_fps = match self.config.max_fps {
+            Some(fps) => fps.to_string(),
+            None => "null".to_owned(),
+        };
+        format!(
+            "{{\"size\":{size},\"frames\":{},\"clients\":{},\"quality\":{},\"max_fps\":{max_fps},\"uptime\":{:.1}}}",
+            latest.sequence,
+            self.clients.load(Ordering::Relaxed),
+            self.config.quality,
+            self.started.elapsed().as_secs_f64(),
+        )
+    }
+}
+
+pub struct MjpegServer {
+    shared: Arc<Shared>,
+    local_addr: SocketAddr,
+    thread: Option<JoinHandle<()>>,
+    /// Capture time of the last published frame, for the fps limit.
+    last_pts: Option<Duration>,
+}
+
+impl MjpegServer {
+    /// Listens on `config.bind`, clients get frames once they are
+    /// [published](Self::publish).
+    pub fn bind(config: MjpegConfig) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(config.bind)?;
+        let local_addr = listener.local_addr()?;
+        // Polled so that stop() does not have to wake a blocked accept()
+        listener.set_nonblocking(true)?;
+        let shared = Arc::new(Shared {
+            encoder: JpegEncoder::new(config.quality),
+            config,
+            latest: Mutex::new(Latest::default()),
+            published: Condvar::new(),
+            stopped: AtomicBool::new(false),
+            clients: AtomicUsize::new(0),
+            started: Instant::now(),
+        });
+        let thread = std::thread::spawn({
+            let shared = Arc::clone(&shared);
+            move || accept(listener, shared)
+        });
+        Ok(Self {
+            shared,
+            local_addr,
+            thread: Some(thread),
+            last_pts: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Clients currently receiving the stream.
+    pub fn clients(&self) -> usize {
+        self.shared.clients.load(Ordering::Relaxed)
+    }
+
+    /// Makes `frame` the latest one, unless it comes too soon after the
+    /// previous one for the fps limit. Returns whether it was taken.
+    pub fn publish(&mut self, frame: &TimedFrame) -> bool {
+        if frame.frame.width() == 0 || frame.frame.height() == 0 {
+            return false;
+        }
+        if let (Some(fps), Some(last)) = (self.shared.config.max_fps, self.last_pts) {
+            if frame.pts.saturating_sub(last) < Duration::from_secs(1) / fps.max(1) {
+                return false;
+            }
+        }
+        self.last_pts = Some(frame.pts);
+        let mut latest = self.shared.latest.lock().unwrap();
+        latest.frame = Some(frame.clone());
+        latest.sequence += 1;
+        latest.jpeg = None;
+        drop(latest);
+        self.shared.published.notify_all();
+        true
+    }
+
+    /// Stops accepting clients and ends the running streams.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+        self.shared.published.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for MjpegServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn accept(listener: TcpListener, shared: Arc<Shared>) {
+    while !shared.stopped.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let shared = Arc::clone(&shared);
+                std::thread::spawn(move || {
+                    if let Err(e) = handle_client(stream, &shared) {
+                        // Mostly clients going away mid-stream
+                        if e.kind() != std::io::ErrorKind::BrokenPipe
+                            && e.kind() != std::io::ErrorKind::ConnectionReset
+                        {
+                            println!("Preview client failed: {e}");
+                        }
+                    }
+                });
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(20))
+            }
+            Err(e) => {
+                println!("Preview server failed: {e}");
+                return;
+            }
+        }
+    }
+}
+
+fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.0 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(body)
+}
+
+fn handle_client(mut stream: TcpStream, shared: &Shared) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Headers are not needed, but have to be read before answering
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let path = target.split('?').next().unwrap_or("");
+    if method != "GET" {
+        return respond(
+            &mut stream,
+            "405 Method Not Allowed",
+            "text/plain",
+            b"Only GET is supported\n",
+        );
+    }
+    match path {
+        "/" => respond(
+            &mut stream,
+            "200 OK",
+            "text/html",
+            b"<!DOCTYPE html><title>Screencast</title><body style=\"margin:0;background:#000\"><img src=\"/stream.mjpg\" style=\"width:100%\"></body>\n",
+        ),
+        "/snapshot.jpg" => match shared.jpeg_after(0, Duration::from_secs(5)) {
+            Some((_, jpeg)) => respond(&mut stream, "200 OK", "image/jpeg", &jpeg),
+            None => respond(
+                &mut stream,
+                "503 Service Unavailable",
+                "text/plain",
+                b"No frame captured yet\n",
+            ),
+        },
+        "/status" => respond(
+            &mut stream,
+            "200 OK",
+            "application/json",
+            shared.status().as_bytes(),
+        ),
+        "/stream.mjpg" => {
+            shared.clients.fetch_add(1, Ordering::Relaxed);
+            let result = stream_frames(&mut stream, shared);
+            shared.clients.fetch_sub(1, Ordering::Relaxed);
+            result
+        }
+        _ => respond(&mut stream, "404 Not Found", "text/plain", b"Not found\n"),
+    }
+}
+
+fn stream_frames(stream: &mut TcpStream, shared: &Shared) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.0 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary={BOUNDARY}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"
+    )?;
+    let mut sequence = 0;
+    while !shared.stopped.load(Ordering::Relaxed) {
+        // Slow clients skip to the latest frame instead of queueing
+        let Some((current, jpeg)) = shared.jpeg_after(sequence, Duration::from_millis(500)) else {
+            continue;
+        };
+        sequence = current;
+        write!(
+            stream,
+            "--{BOUNDARY}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            jpeg.len()
+        )?;
+        stream.write_all(&jpeg)?;
+        stream.write_all(b"\r\n")?;
+    }
+    Ok(())
+}
+
+/// Publishes frames to `server` until the channel closes or `stop` returns
+/// true.
+pub fn serve(
+    frames: &async_channel::Receiver<TimedFrame>,
+    server: &mut MjpegServer,
+    stop: impl Fn() -> bool,
+) {
+    let _ = pipewire_stream::for_each_frame(frames, stop, |frame| {
+        server.publish(&frame);
+        Ok(())
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MjpegConfig, MjpegServer};
+    use crate::pipewire_stream::{Frame, TimedFrame};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::time::Duration;
+
+    fn get(addr: SocketAddr, path: &str) -> BufReader<TcpStream> {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        BufReader::new(stream)
+    }
+
+    /// Status line and headers, lowercased names.
+    fn headers(reader: &mut impl BufRead) -> (String, Vec<(String, String)>) {
+        let mut status = String::new();
+        reader.read_line(&mut status).unwrap();
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let Some((name, value)) = line.trim_end().split_once(": ") else {
+                break;
+            };
+            headers.push((name.to_ascii_lowercase(), value.to_owned()));
+        }
+        (status.trim_end().to_owned(), headers)
+    }
+
+    fn header<'a>(headers: &'a [(String, String)], name: &str) -> &'a str {
+        &headers.iter().find(|(n, _)| n == name).unwrap().1
+    }
+
+    fn timed(value: u8, pts: Duration) -> TimedFrame {
+        let mut frame = Frame::new(32, 16);
+        frame
+            .make_mut_slice()
+            .fill(slint::Rgba8Pixel::new(value, 0, 0, 255));
+        TimedFrame {
+            frame,
+            pts,
+            damage: None,
+        }
+    }
+
+    #[test]
+    fn serves_localhost() {
+        let mut server = MjpegServer::bind(MjpegConfig {
+            bind: SocketAddr::from(([127, 0, 0, 1], 0)),
+            max_fps: Some(10),
+            ..Default::default()
+        })
+        .unwrap();
+        let addr = server.local_addr();
+
+        let (status, _) = headers(&mut get(addr, "/missing"));
+        assert_eq!(status, "HTTP/1.0 404 Not Found");
+
+        assert!(server.publish(&timed(10, Duration::ZERO)));
+        // Within the fps limit of the previous frame
+        assert!(!server.publish(&timed(20, Duration::from_millis(50))));
+
+        let mut snapshot = get(addr, "/snapshot.jpg");
+        let (status, fields) = headers(&mut snapshot);
+        assert_eq!(status, "HTTP/1.0 200 OK");
+        assert_eq!(header(&fields, "content-type"), "image/jpeg");
+        let mut jpeg = Vec::new();
+        snapshot.read_to_end(&mut jpeg).unwrap();
+        assert_eq!(
+            jpeg.len(),
+            header(&fields, "content-length").parse().unwrap()
+        );
+        assert_eq!(&jpeg[..2], &[0xff, 0xd8]);
+
+        let mut stream = get(addr, "/stream.mjpg");
+        let (_, fields) = headers(&mut stream);
+        assert_eq!(
+            header(&fields, "content-type"),
+            "multipart/x-mixed-replace; boundary=frame"
+        );
+        let mut parts = 0;
+        for i in 1..=2u64 {
+            let mut boundary = String::new();
+            stream.read_line(&mut boundary).unwrap();
+            assert_eq!(boundary, "--frame\r\n");
+            let (_, fields) = headers(&mut stream);
+            let mut jpeg = vec![0; header(&fields, "content-length").parse().unwrap()];
+            stream.read_exact(&mut jpeg).unwrap();
+            stream.read_line(&mut String::new()).unwrap();
+            parts += 1;
+            server.publish(&timed(30, Duration::from_millis(200 * i)));
+        }
+        assert_eq!(parts, 2);
+
+        let mut status = get(addr, "/status");
+        let (_, fields) = headers(&mut status);
+        assert_eq!(header(&fields, "content-type"), "application/json");
+        let mut json = String::new();
+        status.read_to_string(&mut json).unwrap();
+        assert!(json.starts_with("{\"size\":{\"width\":32,\"height\":16},\"frames\":3,"));
+        assert!(json.contains("\"clients\":1,"), "{json}");
+
+        drop(stream);
+        server.stop();
+    }
+}
@@ -0,0 +1,464 @@
+use pipewire::spa;
+use pipewire::spa::param::format::{FormatProperties, MediaSubtype};
+use pipewire::spa::param::video::VideoFormat;
+use pipewire::spa::pod::{ChoiceValue, Value};
+use pipewire::spa::utils::ChoiceEnum;
+use pipewire::{self as pw, context::Context, main_loop::MainLoop};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::rc::Rc;
+
+/// Possible values of a format property.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Values<T> {
+    Fixed(T),
+    Range { min: T, max: T },
+    Enum(Vec<T>),
+}
+
+impl<T> Values<T> {
+    fn from_choice<S: spa::pod::CanonicalFixedSizedPod>(
+        choice: ChoiceEnum<S>,
+        map: impl Fn(S) -> T,
+    ) -> Self {
+        match choice {
+            ChoiceEnum::None(value) => Values::Fixed(map(value)),
+            ChoiceEnum::Range { min, max, .. } | ChoiceEnum::Step { min, max, .. } => {
+                Values::Range {
+                    min: map(min),
+                    max: map(max),
+                }
+            }
+            ChoiceEnum::Enum {
+                default,
+                alternatives,
+            } if alternatives.is_empty() => Values::Fixed(map(default)),
+            ChoiceEnum::Enum { alternatives, .. } => {
+                Values::Enum(alternatives.into_iter().map(map).collect())
+            }
+            ChoiceEnum::Flags { flags, .. } => Values::Enum(flags.into_iter().map(map).collect()),
+        }
+    }
+
+    fn describe(&self, item: impl Fn(&T) -> String) -> String {
+        match self {
+            Values::Fixed(value) => item(value),
+            Values::Range { min, max } => format!("{}-{}", item(min), item(max)),
+            Values::Enum(values) => values.iter().map(item).collect::<Vec<_>>().join(","),
+        }
+    }
+}
+
+/// One `EnumFormat` param of a node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnumFormat {
+    /// E.g. `raw`, `mjpg`, `h264`.
+    pub media_subtype: String,
+    /// Pixel formats of raw video, e.g. `BGRx`. Empty for compressed video.
+    pub video_formats: Vec<String>,
+    /// Width and height.
+    pub size: Option<Values<(u32, u32)>>,
+    /// Numerator and denominator.
+    pub framerate: Option<Values<(u32, u32)>>,
+}
+
+impl EnumFormat {
+    pub fn parse(pod: &spa::pod::Pod) -> Option<Self> {
+        let (_, Value::Object(object)) =
+            spa::pod::deserialize::PodDeserializer::deserialize_any_from(pod.as_bytes()).ok()?
+        else {
+            return None;
+        };
+
+        let mut format = EnumFormat {
+            media_subtype: String::new(),
+            video_formats: Vec::new(),
+            size: None,
+            framerate: None,
+        };
+        for property in object.properties {
+            let key = property.key;
+            if key == FormatProperties::MediaSubtype.as_raw() {
+                if let Value::Id(id) = property.value {
+                    format.media_subtype = media_subtype_name(MediaSubtype::from_raw(id.0));
+                }
+            } else if key == FormatProperties::VideoFormat.as_raw() {
+                let name = |id: spa::utils::Id| video_format_name(VideoFormat::from_raw(id.0));
+                format.video_formats = match property.value {
+                    Value::Id(id) => vec![name(id)],
+                    Value::Choice(ChoiceValue::Id(choice)) => {
+                        match Values::from_choice(choice.1, name) {
+                            Values::Fixed(value) => vec![value],
+                            Values::Range { min, max } => vec![min, max],
+                            Values::Enum(values) => values,
+                        }
+                    }
+                    _ => Vec::new(),
+                };
+                format.video_formats.dedup();
+            } else if key == FormatProperties::VideoSize.as_raw() {
+                let size = |r: spa::utils::Rectangle| (r.width, r.height);
+                format.size = match property.value {
+                    Value::Rectangle(r) => Some(Values::Fixed(size(r))),
+                    Value::Choice(ChoiceValue::Rectangle(choice)) => {
+                        Some(Values::from_choice(choice.1, size))
+                    }
+                    _ => None,
+                };
+            } else if key == FormatProperties::VideoFramerate.as_raw() {
+                let rate = |f: spa::utils::Fraction| (f.num, f.denom);
+                format.framerate = match property.value {
+                    Value::Fraction(f) => Some(Values::Fixed(rate(f))),
+                    Value::Choice(ChoiceValue::Fraction(choice)) => {
+                        Some(Values::from_choice(choice.1, rate))
+                    }
+                    _ => None,
+                };
+            }
+        }
+        Some(format)
+    }
+}
+
+impl std::fmt::Display for EnumFormat {
+    /// E.g. `raw BGRx,RGBx 1x1-4096x4096 @0/1-1000/1`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.media_subtype)?;
+        if !self.video_formats.is_empty() {
+            write!(f, " {}", self.video_formats.join(","))?;
+        }
+        if let Some(size) = &self.size {
+            write!(f, " {}", size.describe(|(w, h)| format!("{w}x{h}")))?;
+        }
+        if let Some(framerate) = &self.framerate {
+            write!(f, " @{}", framerate.describe(|(n, d)| format!("{n}/{d}")))?;
+        }
+        Ok(())
+    }
+}
+
+/// A node as announced by the PipeWire registry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub id: u32,
+    /// `node.name`, stable across restarts, usable as `target.object`.
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub media_class: Option<String>,
+    /// All global properties of the node.
+    pub properties: BTreeMap<String, String>,
+    /// Formats the node can produce or accept.
+    pub formats: Vec<EnumFormat>,
+}
+
+impl NodeInfo {
+    /// `device.api` is set on nodes backed by a device, e.g. `v4l2` or
+    /// `libcamera` for cameras.
+    pub fn is_camera(&self) -> bool {
+        self.properties
+            .get("device.api")
+            .is_some_and(|api| api == "v4l2" || api == "libcamera")
+    }
+}
+
+/// Lists the `Video/Source` nodes of the default PipeWire daemon: screen
+/// casts, cameras and other video producers.
+pub fn list_video_sources() -> Result<Vec<NodeInfo>, pw::Error> {
+    list_nodes(|media_class| media_class == "Video/Source")
+}
+
+/// Lists all `Video/*` nodes of the default PipeWire daemon.
+pub fn list_video_nodes() -> Result<Vec<NodeInfo>, pw::Error> {
+    list_nodes(|media_class| media_class.starts_with("Video/"))
+}
+
+/// Lists the nodes whose media class matches `filter`, with their formats.
+pub fn list_nodes(filter: impl Fn(&str) -> bool + 'static) -> Result<Vec<NodeInfo>, pw::Error> {
+    let mainloop = MainLoop::new(None)?;
+    let context = Context::new(&mainloop)?;
+    let core = context.connect(None)?;
+    let registry = core.get_registry()?;
+
+    let globals = Rc::new(RefCell::new(Vec::new()));
+    let _registry_listener = registry
+        .add_listener_local()
+        .global({
+            let globals = Rc::clone(&globals);
+            move |global| {
+                let media_class = global
+                    .props
+                    .and_then(|props| props.get(*pw::keys::MEDIA_CLASS));
+                if global.type_ == pw::types::ObjectType::Node && media_class.is_some_and(&filter) {
+                    globals.borrow_mut().push(global.to_owned());
+                }
+            }
+        })
+        .register();
+
+    let pending = Rc::new(Cell::new(core.sync(0)?));
+    let _core_listener = core
+        .add_listener_local()
+        .done({
+            let mainloop = mainloop.clone();
+            let pending = Rc::clone(&pending);
+            move |id, seq| {
+                if id == pw::core::PW_ID_CORE && seq == pending.get() {
+                    mainloop.quit();
+                }
+            }
+        })
+        .register();
+
+    // The registry announces every existing global before answering the sync
+    mainloop.run();
+
+    let mut nodes = Vec::new();
+    let mut proxies = Vec::new();
+    for global in globals.take() {
+        let properties = global
+            .props
+            .as_ref()
+            .map(|props| {
+                props
+                    .dict()
+                    .iter()
+                    .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                    .collect::<BTreeMap<_, _>>()
+            })
+            .unwrap_or_default();
+        let formats = Rc::new(RefCell::new(Vec::new()));
+
+        let node: pw::node::Node = registry.bind(&global)?;
+        let listener = node
+            .add_listener_local()
+            .param({
+                let formats = Rc::clone(&formats);
+                move |_, _, _, _, param| {
+                    if let Some(format) = param.and_then(EnumFormat::parse) {
+                        formats.borrow_mut().push(format);
+                    }
+                }
+            })
+            .register();
+        node.enum_params(0, Some(spa::param::ParamType::EnumFormat), 0, u32::MAX);
+
+        nodes.push((
+            NodeInfo {
+                id: global.id,
+                name: properties.get(*pw::keys::NODE_NAME).cloned(),
+                description: properties.get(*pw::keys::NODE_DESCRIPTION).cloned(),
+                media_class: properties.get(*pw::keys::MEDIA_CLASS).cloned(),
+                properties,
+                formats: Vec::new(),
+            },
+            formats,
+        ));
+        proxies.push((node, listener));
+    }
+
+    // Params are sent before the answer to the second sync
+    if !proxies.is_empty() {
+        pending.set(core.sync(0)?);
+        mainloop.run();
+    }
+
+    let mut nodes = nodes
+        .into_iter()
+        .map(|(mut node, formats)| {
+            node.formats = formats.take();
+            node
+        })
+        .collect::<Vec<_>>();
+    nodes.sort_by_key(|node| node.id);
+    Ok(nodes)
+}
+
+fn media_subtype_name(subtype: MediaSubtype) -> String {
+    short_name(
+        unsafe { spa::sys::spa_type_media_subtype },
+        subtype.as_raw(),
+    )
+}
+
+fn video_format_name(format: VideoFormat) -> String {
+    short_name(unsafe { spa::sys::spa_type_video_format }, format.as_raw())
+}
+
+fn short_name(info: *const spa::sys::spa_type_info, value: u32) -> String {
+    let name = unsafe { spa::sys::spa_debug_type_find_short_name(info, value) };
+    if name.is_null() {
+        return value.to_string();
+    }
+    unsafe { std::ffi::CStr::from_ptr(name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Nodes as a JSON array, e.g. for scripts.
+pub fn to_json(nodes: &[NodeInfo]) -> String {
+    fn string(out: &mut String, value: &str) {
+        out.push('"');
+        for c in value.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                c if (c as u32) < 0x20 => {
+                    let _ = write!(out, "\\u{:04x}", c as u32);
+                }
+                c => out.push(c),
+            }
+        }
+        out.push('"');
+    }
+    fn optional(out: &mut String, value: Option<&str>) {
+        match value {
+            Some(value) => string(out, value),
+            None => out.push_str("null"),
+        }
+    }
+    fn values(out: &mut String, values: Option<&Values<(u32, u32)>>, names: [&str; 2]) {
+        let pair = |(a, b): (u32, u32)| format!("{{\"{}\":{a},\"{}\":{b}}}", names[0], names[1]);
+        match values {
+            None => out.push_str("null"),
+            Some(Values::Fixed(value)) => out.push_str(&pair(*value)),
+            Some(Values::Range { min, max }) => {
+                let _ = write!(out, "{{\"min\":{},\"max\":{}}}", pair(*min), pair(*max));
+            }
+            Some(Values::Enum(values)) => {
+                let values = values.iter().map(|v| pair(*v)).collect::<Vec<_>>();
+                let _ = write!(out, "[{}]", values.join(","));
+            }
+        }
+    }
+
+    let mut out = String::from("[");
+    for (i, node) in nodes.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{{\"id\":{},\"name\":", node.id);
+        optional(&mut out, node.name.as_deref());
+        out.push_str(",\"media_class\":");
+        optional(&mut out, node.media_class.as_deref());
+        out.push_str(",\"description\":");
+        optional(&mut out, node.description.as_deref());
+        out.push_str(",\"formats\":[");
+        for (j, format) in node.formats.iter().enumerate() {
+            if j > 0 {
+                out.push(',');
+            }
+            out.push_str("{\"media_subtype\":");
+            string(&mut out, &format.media_subtype);
+            out.push_str(",\"video_formats\":[");
+            for (k, name) in format.video_formats.iter().enumerate() {
+                if k > 0 {
+                    out.push(',');
+                }
+                string(&mut out, name);
+            }
+            out.push_str("],\"size\":");
+            values(&mut out, format.size.as_ref(), ["width", "height"]);
+            out.push_str(",\"framerate\":");
+            values(&mut out, format.framerate.as_ref(), ["num", "denom"]);
+            out.push('}');
+        }
+        out.push_str("]}");
+    }
+    out.push(']');
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{to_json, EnumFormat, NodeInfo, Values};
+    use pipewire::spa;
+
+    #[test]
+    fn parse_enum_format() {
+        let object = spa::pod::object!(
+            spa::utils::SpaTypes::ObjectParamFormat,
+            spa::param::ParamType::EnumFormat,
+            spa::pod::property!(
+                spa::param::format::FormatProperties::MediaType,
+                Id,
+                spa::param::format::MediaType::Video
+            ),
+            spa::pod::property!(
+                spa::param::format::FormatProperties::MediaSubtype,
+                Id,
+                spa::param::format::MediaSubtype::Raw
+            ),
+            spa::pod::property!(
+                spa::param::format::FormatProperties::VideoFormat,
+                Choice,
+                Enum,
+                Id,
+                spa::param::video::VideoFormat::BGRx,
+                spa::param::video::VideoFormat::BGRx,
+                spa::param::video::VideoFormat::RGBA
+            ),
+            spa::pod::property!(
+                spa::param::format::FormatProperties::VideoSize,
+                Choice,
+                Range,
+                Rectangle,
+                spa::utils::Rectangle {
+                    width: 320,
+                    height: 240
+                },
+                spa::utils::Rectangle {
+                    width: 1,
+                    height: 1
+                },
+                spa::utils::Rectangle {
+                    width: 4096,
+                    height: 4096
+                }
+            ),
+            spa::pod::property!(
+                spa::param::format::FormatProperties::VideoFramerate,
+                Fraction,
+                spa::utils::Fraction { num: 30, denom: 1 }
+            ),
+        );
+        let bytes = spa::pod::serialize::PodSerializer::serialize(
+            std::io::Cursor::new(Vec::new()),
+            &spa::pod::Value::Object(object),
+        )
+        .unwrap()
+        .0
+        .into_inner();
+
+        let format = EnumFormat::parse(spa::pod::Pod::from_bytes(&bytes).unwrap()).unwrap();
+        assert_eq!(format.media_subtype, "raw");
+        assert_eq!(format.video_formats, ["BGRx", "RGBA"]);
+        assert_eq!(
+            format.size,
+            Some(Values::Range {
+                min: (1, 1),
+                max: (4096, 4096)
+            })
+        );
+        assert_eq!(format.framerate, Some(Values::Fixed((30, 1))));
+        assert_eq!(format.to_string(), "raw BGRx,RGBA 1x1-4096x4096 @30/1");
+
+        let node = NodeInfo {
+            id: 7,
+            name: Some("cam \"0\"".to_owned()),
+            description: None,
+            media_class: Some("Video/Source".to_owned()),
+            properties: Default::default(),
+            formats: vec![format],
+        };
+        assert_eq!(
+            to_json(&[node]),
+            concat!(
+                r#"[{"id":7,"name":"cam \"0\"","media_class":"Video/Source","description":null,"#,
+                r#""formats":[{"media_subtype":"raw","video_formats":["BGRx","RGBA"],"#,
+                r#""size":{"min":{"width":1,"height":1},"max":{"width":4096,"height":4096}},"#,
+                r#""framerate":{"num":30,"denom":1}}]}]"#
+            )
+        );
+    }
+}
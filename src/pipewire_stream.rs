@@ -1,9 +1,225 @@
+use crate::audio::{AudioChunk, AudioSource};
+use crate::clock::{ClockStats, SessionClock};
+use crate::dma_buf_share::DmaBufFrame;
+use crate::frame_transform::{Overlay, Rect};
 use std::os::fd::OwnedFd;
 use std::thread::JoinHandle;
+use std::time::Duration;
+
+pub type Frame = slint::SharedPixelBuffer<slint::Rgba8Pixel>;
+
+/// A frame with the time it was captured, on the stream's [`SessionClock`].
+#[derive(Clone)]
+pub struct TimedFrame {
+    pub frame: Frame,
+    pub pts: Duration,
+    /// What changed since the previous frame, from the producer's damage
+    /// metadata. `None` when unknown, i.e. anything may have changed.
+    pub damage: Option<Vec<Rect>>,
+}
+
+/// Hands each frame to `f` until the channel closes, `stop` returns true or
+/// `f` fails. `stop` is polled at least every few milliseconds, also while
+/// no frames arrive, so it may do work that cannot wait for the next frame.
+pub fn for_each_frame<T>(
+    frames: &async_channel::Receiver<T>,
+    mut stop: impl FnMut() -> bool,
+    mut f: impl FnMut(T) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    while !stop() {
+        match recv_timeout(frames, Duration::from_millis(5)) {
+            Ok(frame) => f(frame)?,
+            Err(async_channel::TryRecvError::Closed) => break,
+            Err(async_channel::TryRecvError::Empty) => {}
+        }
+    }
+    Ok(())
+}
+
+/// `recv_blocking` giving up after `timeout`, parking the thread in between.
+fn recv_timeout<T>(
+    receiver: &async_channel::Receiver<T>,
+    timeout: Duration,
+) -> Result<T, async_channel::TryRecvError> {
+    struct Unpark(std::thread::Thread);
+
+    impl std::task::Wake for Unpark {
+        fn wake(self: std::sync::Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = std::task::Waker::from(std::sync::Arc::new(Unpark(std::thread::current())));
+    let mut context = std::task::Context::from_waker(&waker);
+    let mut recv = std::pin::pin!(receiver.recv());
+    let deadline = std::time::Instant::now() + timeout;
+    loop {
+        match std::future::Future::poll(recv.as_mut(), &mut context) {
+            std::task::Poll::Ready(Ok(value)) => return Ok(value),
+            std::task::Poll::Ready(Err(_)) => return Err(async_channel::TryRecvError::Closed),
+            std::task::Poll::Pending => {
+                let left = deadline.saturating_duration_since(std::time::Instant::now());
+                if left.is_zero() {
+                    return Err(async_channel::TryRecvError::Empty);
+                }
+                std::thread::park_timeout(left);
+            }
+        }
+    }
+}
+
+/// Node to capture when connecting to PipeWire directly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Id(u32),
+    /// `node.name`, e.g. of a camera.
+    Name(String),
+}
+
+impl std::str::FromStr for Target {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(id) => Target::Id(id),
+            Err(_) => Target::Name(s.to_owned()),
+        })
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Id(id) => write!(f, "{id}"),
+            Target::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+/// PipeWire connection of a capture.
+pub struct Remote {
+    /// Remote opened by the portal, `None` for the default PipeWire daemon.
+    pub fd: Option<OwnedFd>,
+    pub target: Target,
+    /// Dropped when the connection is lost, e.g. the portal session.
+    pub keep_alive: Option<Box<dyn std::any::Any>>,
+}
+
+/// Opens a [`Remote`], on the PipeWire thread. Called again to reconnect.
+pub type Connector = Box<dyn FnMut() -> Result<Remote, Box<dyn std::error::Error>> + Send>;
+
+/// When and how often to reconnect after the stream failed or its source
+/// disappeared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// `None` to retry forever.
+    pub max_attempts: Option<u32>,
+    pub initial_delay: Duration,
+    /// The delay doubles after every failed attempt, up to this.
+    pub max_delay: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: Some(10),
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Delay before attempt number `attempt`, starting at 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+}
+
+/// The node frames are re-published on, see [`PipewireStream::republish`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepublishConfig {
+    /// Node name, e.g. to pick it with `target.object`.
+    pub name: String,
+    /// What browsers and OBS show in their camera lists.
+    pub description: String,
+}
+
+impl Default for RepublishConfig {
+    fn default() -> Self {
+        Self {
+            name: "screencast-output".to_owned(),
+            description: "Screencast".to_owned(),
+        }
+    }
+}
+
+/// Connection changes reported by [`PipewireStream::start_reconnecting`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamEvent {
+    Streaming,
+    Disconnected(String),
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+    },
+    Reconnected,
+    /// The frame channel is closed after this one.
+    GaveUp(String),
+}
+
+/// Why a stream command failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    /// The stream was not started or its thread has exited.
+    NotRunning,
+    /// Waiting for a reconnection.
+    NotConnected,
+    Paused,
+    InvalidArgument(&'static str),
+    PipeWire(String),
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::NotRunning => f.write_str("Stream is not running"),
+            CommandError::NotConnected => f.write_str("Stream is not connected"),
+            CommandError::Paused => f.write_str("Stream is paused"),
+            CommandError::InvalidArgument(what) => write!(f, "Invalid {what}"),
+            CommandError::PipeWire(e) => write!(f, "PipeWire error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// Answer of the stream thread to a command.
+pub struct Reply<T>(async_channel::Receiver<Result<T, CommandError>>);
+
+impl<T> Reply<T> {
+    pub async fn recv(self) -> Result<T, CommandError> {
+        self.0.recv().await.unwrap_or(Err(CommandError::NotRunning))
+    }
+
+    /// Blocking variant of `recv`, not for the UI thread.
+    pub fn wait(self) -> Result<T, CommandError> {
+        self.0
+            .recv_blocking()
+            .unwrap_or(Err(CommandError::NotRunning))
+    }
+}
 
 pub struct PipewireStream {
     thread_handle: Option<JoinHandle<()>>,
     cmd_sender: Option<pipewire::channel::Sender<inner::Command>>,
+    clock: SessionClock,
 }
 
 impl PipewireStream {
@@ -11,50 +227,510 @@ impl PipewireStream {
         Self {
             thread_handle: None,
             cmd_sender: None,
+            clock: SessionClock::new(),
         }
     }
 
+    /// Captures `stream_id` over the PipeWire remote opened by the portal.
     pub fn start(
         &mut self,
         pipewire_fd: OwnedFd,
         stream_id: u32,
-    ) -> async_channel::Receiver<slint::SharedPixelBuffer<slint::Rgba8Pixel>> {
+    ) -> async_channel::Receiver<TimedFrame> {
+        self.spawn(
+            Self::once(Some(pipewire_fd), Target::Id(stream_id)),
+            None,
+            None,
+        )
+    }
+
+    /// Captures `target` from the default PipeWire daemon, without the
+    /// portal. Only works outside of sandboxes.
+    pub fn start_direct(&mut self, target: Target) -> async_channel::Receiver<TimedFrame> {
+        self.spawn(Self::once(None, target), None, None)
+    }
+
+    /// Captures the remote opened by `connector` and opens a new one
+    /// following `policy` whenever the stream fails or goes unconnected.
+    pub fn start_reconnecting(
+        &mut self,
+        connector: Connector,
+        policy: ReconnectPolicy,
+    ) -> (
+        async_channel::Receiver<TimedFrame>,
+        async_channel::Receiver<StreamEvent>,
+    ) {
+        let (event_sender, event_receiver) = async_channel::unbounded();
+        let frames = self.spawn(connector, Some(policy), Some(event_sender));
+        (frames, event_receiver)
+    }
+
+    fn once(pipewire_fd: Option<OwnedFd>, target: Target) -> Connector {
+        let mut remote = Some(Remote {
+            fd: pipewire_fd,
+            target,
+            keep_alive: None,
+        });
+        Box::new(move || remote.take().ok_or("remote already used".into()))
+    }
+
+    fn spawn(
+        &mut self,
+        connector: Connector,
+        policy: Option<ReconnectPolicy>,
+        event_sender: Option<async_channel::Sender<StreamEvent>>,
+    ) -> async_channel::Receiver<TimedFrame> {
         let (frame_sender, frame_receiver) = async_channel::bounded(10);
         let (cmd_sender, cmd_receiver) = pipewire::channel::channel();
+        self.clock = SessionClock::new();
+        let clock = self.clock;
         self.thread_handle = Some(std::thread::spawn(move || {
-            inner::pipewire_thread(pipewire_fd, stream_id, frame_sender, cmd_receiver).unwrap()
+            inner::pipewire_thread(
+                connector,
+                policy,
+                clock,
+                frame_sender,
+                event_sender,
+                cmd_receiver,
+            )
+            .unwrap()
         }));
         self.cmd_sender = Some(cmd_sender);
         frame_receiver
     }
 
+    /// Stops delivering frames, the session stays open.
+    pub fn pause(&self) -> Reply<()> {
+        self.request(inner::Command::Pause)
+    }
+
+    pub fn resume(&self) -> Reply<()> {
+        self.request(inner::Command::Resume)
+    }
+
+    /// Drops frames arriving faster than `fps`, `None` for no limit.
+    pub fn set_max_fps(&self, fps: Option<u32>) -> Reply<()> {
+        self.request(|reply| inner::Command::SetMaxFps(fps, reply))
+    }
+
+    /// Delivers only `rect` of the frames, `None` for the whole frame.
+    pub fn set_crop(&self, rect: Option<Rect>) -> Reply<()> {
+        self.request(|reply| inner::Command::SetCrop(rect, reply))
+    }
+
+    /// Scales frames (after cropping) to `size`, `None` to keep their size.
+    pub fn set_output_size(&self, size: Option<(u32, u32)>) -> Reply<()> {
+        self.request(|reply| inner::Command::SetOutputSize(size, reply))
+    }
+
+    /// Blurs `rect` of the frames, in their coordinates after cropping and
+    /// scaling, e.g. to hide a password field. `None` to stop blurring.
+    pub fn set_blur(&self, rect: Option<Rect>) -> Reply<()> {
+        self.request(|reply| inner::Command::SetBlur(rect, reply))
+    }
+
+    /// Draws an image over the frames after cropping, scaling and blurring,
+    /// e.g. a logo. `None` to remove it.
+    pub fn set_overlay(&self, overlay: Option<Overlay>) -> Reply<()> {
+        self.request(|reply| inner::Command::SetOverlay(overlay, reply))
+    }
+
+    /// The next frame, cropped and scaled, whatever the fps limit. Raw video
+    /// has no keyframes, so this is also what a keyframe request amounts to.
+    pub fn snapshot(&self) -> Reply<Frame> {
+        self.request(inner::Command::Snapshot)
+    }
+
+    /// Captures audio from `source` through the stream's PipeWire thread,
+    /// restarted with every reconnection. The channel closes if the audio
+    /// stream cannot be created. While audio is captured, frames the consumer
+    /// is not ready for are dropped instead of waited for.
+    pub fn capture_audio(&self, source: AudioSource) -> async_channel::Receiver<AudioChunk> {
+        let (chunk_sender, chunk_receiver) = async_channel::bounded(64);
+        if let Some(cmd_sender) = &self.cmd_sender {
+            let _ = cmd_sender.send(inner::Command::CaptureAudio(source, chunk_sender));
+        }
+        chunk_receiver
+    }
+
+    /// The captured DMA-BUFs themselves, at the rate frames are delivered
+    /// but before cropping and scaling, for sharing with other processes.
+    /// The channel is closed at the first buffer the producer shares through
+    /// memory instead.
+    pub fn share_dma_bufs(&self) -> async_channel::Receiver<DmaBufFrame> {
+        let (frame_sender, frame_receiver) = async_channel::bounded(4);
+        if let Some(cmd_sender) = &self.cmd_sender {
+            let _ = cmd_sender.send(inner::Command::ShareDmaBufs(frame_sender));
+        }
+        frame_receiver
+    }
+
+    /// Publishes the frames sent, cropped and scaled, as a Video/Source node
+    /// on the default PipeWire daemon, e.g. as a virtual camera. Replies with
+    /// the node id once the first frame was offered; the node stays across
+    /// reconnections and replaces the one of an earlier call.
+    pub fn republish(&self, config: RepublishConfig) -> Reply<u32> {
+        self.request(|reply| inner::Command::Republish(config, reply))
+    }
+
+    /// The timeline of the frame and audio timestamps, restarted with every
+    /// `start`.
+    pub fn clock(&self) -> SessionClock {
+        self.clock
+    }
+
+    /// How the producers' timestamps drift against the PipeWire clock.
+    pub fn clock_stats(&self) -> Reply<ClockStats> {
+        self.request(inner::Command::ClockStats)
+    }
+
+    fn request<T>(
+        &self,
+        command: impl FnOnce(inner::ReplySender<T>) -> inner::Command,
+    ) -> Reply<T> {
+        let (reply_sender, reply_receiver) = async_channel::bounded(1);
+        let sent = self
+            .cmd_sender
+            .as_ref()
+            .is_some_and(|cmd_sender| cmd_sender.send(command(reply_sender.clone())).is_ok());
+        if !sent {
+            let _ = reply_sender.try_send(Err(CommandError::NotRunning));
+        }
+        Reply(reply_receiver)
+    }
+
+    /// Ends the capture and waits for the PipeWire thread. Does nothing if
+    /// the stream was not started or is already stopped.
     pub fn stop(&mut self) {
-        self.cmd_sender
-            .take()
-            .unwrap()
-            .send(inner::Command::Stop)
-            .unwrap();
-        self.thread_handle.take().unwrap().join().unwrap();
+        // The thread is already gone if it gave up reconnecting
+        if let Some(cmd_sender) = self.cmd_sender.take() {
+            let _ = cmd_sender.send(inner::Command::Stop);
+        }
+        if let Some(handle) = self.thread_handle.take() {
+            if let Err(panic) = handle.join() {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("unknown cause");
+                println!("PipeWire thread panicked: {message}");
+            }
+        }
     }
 }
 
 mod inner {
+    use crate::clock::{ClockStats, SessionClock, StreamClock};
     use crate::egl_dma_buf as dma;
     use pipewire::spa;
     use pipewire::{self as pw, context::Context, main_loop::MainLoop, properties::properties};
-    use std::cell::RefCell;
-    use std::os::fd::OwnedFd;
+    use std::cell::{Cell, RefCell};
     use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    pub type ReplySender<T> = async_channel::Sender<Result<T, super::CommandError>>;
 
     #[derive(Debug)]
     pub enum Command {
         Stop,
+        Pause(ReplySender<()>),
+        Resume(ReplySender<()>),
+        SetMaxFps(Option<u32>, ReplySender<()>),
+        SetCrop(Option<super::Rect>, ReplySender<()>),
+        SetOutputSize(Option<(u32, u32)>, ReplySender<()>),
+        SetBlur(Option<super::Rect>, ReplySender<()>),
+        SetOverlay(Option<super::Overlay>, ReplySender<()>),
+        Snapshot(ReplySender<super::Frame>),
+        CaptureAudio(super::AudioSource, async_channel::Sender<super::AudioChunk>),
+        ShareDmaBufs(async_channel::Sender<super::DmaBufFrame>),
+        Republish(super::RepublishConfig, ReplySender<u32>),
+        ClockStats(ReplySender<ClockStats>),
+    }
+
+    /// Capture parameters, kept across reconnections.
+    #[derive(Default)]
+    struct Settings {
+        paused: bool,
+        max_fps: Option<u32>,
+        crop: Option<super::Rect>,
+        output_size: Option<(u32, u32)>,
+        blur: Option<super::Rect>,
+        overlay: Option<super::Overlay>,
+        last_frame: Option<Instant>,
+    }
+
+    impl Settings {
+        /// Enough to make text unreadable.
+        const BLUR_RADIUS: u32 = 8;
+
+        /// Blurs and draws the overlay on a frame already cropped and scaled.
+        fn process(&self, frame: &mut super::Frame) {
+            if let Some(rect) = self.blur {
+                crate::frame_transform::blur(frame, rect, Self::BLUR_RADIUS);
+            }
+            if let Some(overlay) = &self.overlay {
+                crate::frame_transform::draw_overlay(frame, overlay);
+            }
+        }
+
+        /// Whether a frame arriving now fits under the fps limit.
+        fn frame_due(&self) -> bool {
+            match (self.max_fps, self.last_frame) {
+                (Some(fps), Some(last)) => last.elapsed() >= Duration::from_secs(1) / fps,
+                _ => true,
+            }
+        }
+    }
+
+    /// Shared by the loop driver and the stream callbacks.
+    struct Watch {
+        mainloop: Rc<MainLoop>,
+        stopped: Cell<bool>,
+        disconnected: RefCell<Option<String>>,
+        events: Option<async_channel::Sender<super::StreamEvent>>,
+        commands: RefCell<Vec<Command>>,
+        settings: RefCell<Settings>,
+        snapshots: RefCell<Vec<ReplySender<super::Frame>>>,
+        audio: RefCell<Option<(super::AudioSource, async_channel::Sender<super::AudioChunk>)>>,
+        dma_bufs: RefCell<Option<async_channel::Sender<super::DmaBufFrame>>>,
+        republished: RefCell<Option<Republished>>,
+        video_clock: RefCell<StreamClock>,
+        audio_clock: RefCell<StreamClock>,
+        /// Damage of the buffers since the last frame sent, `None` if unknown.
+        damage: RefCell<Option<Vec<super::Rect>>>,
+    }
+
+    impl Watch {
+        /// Most producers report a handful of rectangles, more are merged.
+        const MAX_DAMAGE_RECTS: usize = 32;
+
+        fn add_damage(&self, damage: Option<Vec<super::Rect>>) {
+            let mut pending = self.damage.borrow_mut();
+            let (Some(pending_rects), Some(rects)) = (pending.as_mut(), damage) else {
+                *pending = None;
+                return;
+            };
+            pending_rects.extend(rects);
+            if pending_rects.len() > Self::MAX_DAMAGE_RECTS {
+                let bounds = pending_rects
+                    .iter()
+                    .skip(1)
+                    .fold(pending_rects[0], |a, b| a.union(b));
+                *pending_rects = vec![bounds];
+            }
+        }
+
+        /// Offers `frame` on the re-published node, if there is one.
+        fn republish(&self, frame: &super::Frame, pts: Duration) {
+            let mut republished = self.republished.borrow_mut();
+            let Some(republished) = republished.as_mut() else {
+                return;
+            };
+            match &mut republished.output {
+                Some(output) => output.show(frame, pts),
+                None => {
+                    republished.pending = Some((frame.clone(), pts));
+                    // The driver loop creates the node, with the frame's size
+                    self.mainloop.quit();
+                }
+            }
+        }
+
+        /// Creates the re-published node once its first frame is known.
+        fn ensure_republished(&self, context: &Context) {
+            let mut republished = self.republished.borrow_mut();
+            let Some(pending) = republished.as_mut().filter(|r| r.output.is_none()) else {
+                return;
+            };
+            let Some((frame, pts)) = pending.pending.take() else {
+                return;
+            };
+            let reply = pending.reply.take();
+            let started = context.connect(None).and_then(|core| {
+                start_output_stream(core, &pending.config, reply.clone(), (&frame, pts))
+            });
+            match started {
+                Ok(output) => pending.output = Some(output),
+                Err(e) => {
+                    if let Some(reply) = reply {
+                        let _ = reply.try_send(Err(super::CommandError::PipeWire(e.to_string())));
+                    }
+                    *republished = None;
+                }
+            }
+        }
+
+        fn send(&self, event: super::StreamEvent) {
+            if let Some(events) = &self.events {
+                let _ = events.try_send(event);
+            }
+        }
+
+        fn disconnect(&self, reason: String) {
+            self.disconnected.borrow_mut().get_or_insert(reason);
+            self.mainloop.quit();
+        }
+
+        /// Runs the loop for `delay`, returns false when stopped meanwhile.
+        fn sleep(&self, delay: Duration) -> bool {
+            let deadline = Instant::now() + delay;
+            while !self.stopped.get() {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                self.mainloop.loop_().iterate(deadline - now);
+                self.handle_commands(None);
+            }
+            !self.stopped.get()
+        }
+
+        /// Applies the queued commands to the settings and the current stream.
+        fn handle_commands(&self, connection: Option<&Connection>) {
+            let stream = connection.map(|c| &c.stream_data.stream);
+            let set_active = |active: bool| match stream {
+                Some(stream) => stream
+                    .set_active(active)
+                    .map_err(|e| super::CommandError::PipeWire(e.to_string())),
+                None => Ok(()),
+            };
+
+            for command in self.commands.take() {
+                let mut settings = self.settings.borrow_mut();
+                match command {
+                    Command::Stop => self.stopped.set(true),
+                    Command::Pause(reply) => {
+                        settings.paused = true;
+                        let _ = reply.try_send(set_active(false));
+                    }
+                    Command::Resume(reply) => {
+                        settings.paused = false;
+                        self.damage.take();
+                        let _ = reply.try_send(set_active(true));
+                    }
+                    Command::SetMaxFps(Some(0), reply) => {
+                        let _ = reply.try_send(Err(super::CommandError::InvalidArgument("fps")));
+                    }
+                    Command::SetMaxFps(fps, reply) => {
+                        settings.max_fps = fps;
+                        let _ = reply.try_send(Ok(()));
+                    }
+                    Command::SetCrop(Some(rect), reply) if rect.width == 0 || rect.height == 0 => {
+                        let _ = reply.try_send(Err(super::CommandError::InvalidArgument("crop")));
+                    }
+                    Command::SetCrop(rect, reply) => {
+                        settings.crop = rect;
+                        self.damage.take();
+                        let _ = reply.try_send(Ok(()));
+                    }
+                    Command::SetOutputSize(Some((w, h)), reply) if w == 0 || h == 0 => {
+                        let _ = reply
+                            .try_send(Err(super::CommandError::InvalidArgument("output size")));
+                    }
+                    Command::SetOutputSize(size, reply) => {
+                        settings.output_size = size;
+                        self.damage.take();
+                        let _ = reply.try_send(Ok(()));
+                    }
+                    Command::SetBlur(Some(rect), reply) if rect.is_empty() => {
+                        let _ = reply.try_send(Err(super::CommandError::InvalidArgument("blur")));
+                    }
+                    Command::SetBlur(rect, reply) => {
+                        settings.blur = rect;
+                        self.damage.take();
+                        let _ = reply.try_send(Ok(()));
+                    }
+                    Command::SetOverlay(Some(overlay), reply)
+                        if overlay.image.width() == 0 || overlay.image.height() == 0 =>
+                    {
+                        let _ =
+                            reply.try_send(Err(super::CommandError::InvalidArgument("overlay")));
+                    }
+                    Command::SetOverlay(overlay, reply) => {
+                        settings.overlay = overlay;
+                        self.damage.take();
+                        let _ = reply.try_send(Ok(()));
+                    }
+                    Command::Snapshot(reply) => {
+                        if stream.is_none() {
+                            let _ = reply.try_send(Err(super::CommandError::NotConnected));
+                        } else if settings.paused {
+                            let _ = reply.try_send(Err(super::CommandError::Paused));
+                        } else {
+                            self.snapshots.borrow_mut().push(reply);
+                        }
+                    }
+                    Command::CaptureAudio(source, chunk_sender) => {
+                        *self.audio.borrow_mut() = Some((source, chunk_sender));
+                    }
+                    Command::ShareDmaBufs(frame_sender) => {
+                        *self.dma_bufs.borrow_mut() = Some(frame_sender);
+                    }
+                    Command::Republish(config, reply) => {
+                        *self.republished.borrow_mut() = Some(Republished {
+                            config,
+                            reply: Some(reply),
+                            pending: None,
+                            output: None,
+                        });
+                    }
+                    Command::ClockStats(reply) => {
+                        let audio = connection.is_some_and(|c| c.audio.is_some());
+                        let _ = reply.try_send(Ok(ClockStats {
+                            video: self.video_clock.borrow().stats(),
+                            audio: audio.then(|| self.audio_clock.borrow().stats()),
+                        }));
+                    }
+                }
+            }
+        }
+    }
+
+    struct Connection {
+        stream_data: StreamData,
+        audio: Option<AudioStream>,
+        core: pw::core::Core,
+        /// Connected to the default daemon rather than a portal remote.
+        direct: bool,
+        _core_listener: pw::core::Listener,
+        _keep_alive: Option<Box<dyn std::any::Any>>,
+    }
+
+    impl Connection {
+        /// Starts the requested audio stream if it is not running yet.
+        fn ensure_audio(&mut self, context: &Context, watch: &Rc<Watch>) {
+            if self.audio.is_some() {
+                return;
+            }
+            let Some((source, chunk_sender)) = watch.audio.borrow().clone() else {
+                return;
+            };
+            // The portal's remote only exposes the screen cast nodes
+            let core = if self.direct {
+                Ok(self.core.clone())
+            } else {
+                context.connect(None)
+            };
+            watch.audio_clock.borrow_mut().reset();
+            let started = core
+                .and_then(|core| start_audio_stream(core, source, chunk_sender, Rc::clone(watch)));
+            match started {
+                Ok(audio) => self.audio = Some(audio),
+                Err(e) => {
+                    println!("Failed to start audio capture: {e}");
+                    // Closes the chunk channel
+                    watch.audio.take();
+                }
+            }
+        }
     }
 
     pub fn pipewire_thread(
-        pipewire_fd: OwnedFd,
-        stream_id: u32,
-        frame_sender: async_channel::Sender<slint::SharedPixelBuffer<slint::Rgba8Pixel>>,
+        mut connector: super::Connector,
+        policy: Option<super::ReconnectPolicy>,
+        clock: SessionClock,
+        frame_sender: async_channel::Sender<super::TimedFrame>,
+        event_sender: Option<async_channel::Sender<super::StreamEvent>>,
         pw_receiver: pipewire::channel::Receiver<Command>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         unsafe {
@@ -68,62 +744,221 @@ mod inner {
 
         let mainloop = Rc::new(MainLoop::new(None)?);
         let context = Context::new(&*mainloop)?;
-        let core = context.connect_fd(pipewire_fd, None)?;
-
-        let stream_data = RefCell::new(Some(start_stream(core, frame_sender, stream_id)?));
+        let watch = Rc::new(Watch {
+            mainloop: Rc::clone(&mainloop),
+            stopped: Cell::new(false),
+            disconnected: RefCell::new(None),
+            events: event_sender,
+            commands: RefCell::new(Vec::new()),
+            settings: RefCell::new(Settings::default()),
+            snapshots: RefCell::new(Vec::new()),
+            damage: RefCell::new(None),
+            audio: RefCell::new(None),
+            dma_bufs: RefCell::new(None),
+            republished: RefCell::new(None),
+            video_clock: RefCell::new(StreamClock::new(clock)),
+            audio_clock: RefCell::new(StreamClock::new(clock)),
+        });
 
         let _receiver = pw_receiver.attach(mainloop.loop_(), {
-            let mainloop = Rc::clone(&mainloop);
-            move |cmd| match cmd {
-                Command::Stop => {
-                    (*stream_data.borrow_mut()) = None;
-                    mainloop.quit();
+            let watch = Rc::clone(&watch);
+            move |cmd| {
+                if let Command::Stop = cmd {
+                    watch.stopped.set(true);
                 }
+                // Handled by the driver loop below, which owns the stream
+                watch.commands.borrow_mut().push(cmd);
+                watch.mainloop.quit();
             }
         });
 
-        mainloop.run();
+        let reconnecting = policy.is_some();
+        let connected = connector().and_then(|remote| {
+            connect(&context, remote, &frame_sender, &watch, reconnecting).map_err(Into::into)
+        });
+        let mut connection = match connected {
+            Ok(connection) => Some(connection),
+            Err(e) => {
+                watch.send(super::StreamEvent::GaveUp(e.to_string()));
+                return Ok(());
+            }
+        };
+
+        loop {
+            if let Some(connection) = &mut connection {
+                connection.ensure_audio(&context, &watch);
+            }
+            watch.ensure_republished(&context);
+            if watch.disconnected.borrow().is_none() && watch.commands.borrow().is_empty() {
+                mainloop.run();
+            }
+            watch.handle_commands(connection.as_ref());
+            if watch.stopped.get() {
+                break;
+            }
+            let Some(reason) = watch.disconnected.take() else {
+                continue;
+            };
+
+            connection = None;
+            for reply in watch.snapshots.take() {
+                let _ = reply.try_send(Err(super::CommandError::NotConnected));
+            }
+            // Tearing down the old stream reports it unconnected once more
+            watch.disconnected.take();
+            watch.send(super::StreamEvent::Disconnected(reason.clone()));
+
+            let Some(policy) = &policy else {
+                watch.send(super::StreamEvent::GaveUp(reason));
+                break;
+            };
+            let mut last_error = reason;
+            for attempt in 1.. {
+                if policy.max_attempts.is_some_and(|max| attempt > max) {
+                    watch.send(super::StreamEvent::GaveUp(last_error));
+                    return Ok(());
+                }
+                let delay = policy.delay(attempt);
+                watch.send(super::StreamEvent::Reconnecting { attempt, delay });
+                if !watch.sleep(delay) {
+                    return Ok(());
+                }
+
+                let remote = match connector() {
+                    Ok(remote) => remote,
+                    Err(e) => {
+                        // Asking the user again and again is pointless
+                        if let Some(crate::portal::PortalError::Cancelled) = e.downcast_ref() {
+                            watch.send(super::StreamEvent::GaveUp(e.to_string()));
+                            return Ok(());
+                        }
+                        last_error = e.to_string();
+                        continue;
+                    }
+                };
+                match connect(&context, remote, &frame_sender, &watch, reconnecting) {
+                    Ok(new_connection) => {
+                        connection = Some(new_connection);
+                        watch.send(super::StreamEvent::Reconnected);
+                        break;
+                    }
+                    Err(e) => last_error = e.to_string(),
+                }
+            }
+        }
+
+        drop(connection);
         Ok(())
     }
 
+    fn connect(
+        context: &Context,
+        remote: super::Remote,
+        frame_sender: &async_channel::Sender<super::TimedFrame>,
+        watch: &Rc<Watch>,
+        reconnecting: bool,
+    ) -> Result<Connection, pw::Error> {
+        let direct = remote.fd.is_none();
+        let core = match remote.fd {
+            Some(fd) => context.connect_fd(fd, None)?,
+            None => context.connect(None)?,
+        };
+        // A compositor restart takes the portal's PipeWire remote down with it
+        let core_listener = core
+            .add_listener_local()
+            .error({
+                let watch = Rc::clone(watch);
+                move |id, _, res, message| {
+                    if id == pw::core::PW_ID_CORE {
+                        watch.disconnect(format!("core error {res}: {message}"));
+                    }
+                }
+            })
+            .register();
+
+        // The new producer may count time differently
+        watch.video_clock.borrow_mut().reset();
+        watch.damage.take();
+        let stream_data = start_stream(
+            core.clone(),
+            frame_sender.clone(),
+            remote.target,
+            Rc::clone(watch),
+            reconnecting,
+        )?;
+        if watch.settings.borrow().paused {
+            stream_data.stream.set_active(false)?;
+        }
+        Ok(Connection {
+            stream_data,
+            audio: None,
+            core,
+            direct,
+            _core_listener: core_listener,
+            _keep_alive: remote.keep_alive,
+        })
+    }
+
     struct UserData {
         format: spa::param::video::VideoInfoRaw,
-        dma_buf: dma::EglDmaBuf,
+        dma_buf: Option<dma::EglDmaBuf>,
     }
 
     struct StreamData {
-        _stream: pw::stream::Stream,
+        stream: pw::stream::Stream,
         _stream_listener: pw::stream::StreamListener<Rc<RefCell<UserData>>>,
     }
 
     fn start_stream(
         core: pipewire::core::Core,
-        frame_sender: async_channel::Sender<slint::SharedPixelBuffer<slint::Rgba8Pixel>>,
-        target: u32,
+        frame_sender: async_channel::Sender<super::TimedFrame>,
+        target: super::Target,
+        watch: Rc<Watch>,
+        reconnecting: bool,
     ) -> Result<StreamData, pw::Error> {
         let data = Rc::new(RefCell::new(UserData {
             format: Default::default(),
-            dma_buf: dma::EglDmaBuf::new().unwrap(),
+            // Without a GPU (e.g. headless tests) only MemFd buffers are negotiated
+            dma_buf: dma::EglDmaBuf::new()
+                .map_err(|e| println!("DMA-BUF import disabled: {e}"))
+                .ok(),
         }));
 
-        let stream = pipewire::stream::Stream::new(
-            &core,
-            "video-test",
-            /*properties! {
-                // *pw::keys::TARGET_OBJECT => target.to_string(),
-                *pw::keys::MEDIA_TYPE => "Video",
-                *pw::keys::MEDIA_CATEGORY => "Capture",
-                *pw::keys::MEDIA_ROLE => "Camera",
-            },*/
-            properties! {"pipewire.client.reuse" => "1"},
-        )?;
+        let mut props = properties! {
+            "pipewire.client.reuse" => "1",
+            *pw::keys::MEDIA_TYPE => "Video",
+            *pw::keys::MEDIA_CATEGORY => "Capture",
+        };
+        // Ids are passed to connect(), names are resolved by the session manager
+        let target_id = match &target {
+            super::Target::Id(id) => Some(*id),
+            super::Target::Name(name) => {
+                props.insert(*pw::keys::TARGET_OBJECT, name.as_str());
+                None
+            }
+        };
+
+        let stream = pipewire::stream::Stream::new(&core, "video-test", props)?;
 
         let stream_listener = stream
             .add_local_listener_with_user_data(data.clone())
-            .state_changed(|_, _, old, new| {
-                println!("State changed: {:?} -> {:?}", old, new);
+            .state_changed({
+                let watch = Rc::clone(&watch);
+                move |_, _, old, new| {
+                    println!("State changed: {:?} -> {:?}", old, new);
+                    match new {
+                        pw::stream::StreamState::Streaming => {
+                            watch.send(super::StreamEvent::Streaming)
+                        }
+                        pw::stream::StreamState::Error(e) => watch.disconnect(e),
+                        pw::stream::StreamState::Unconnected => {
+                            watch.disconnect("stream unconnected".to_owned())
+                        }
+                        _ => {}
+                    }
+                }
             })
-            .param_changed(|_stream, user_data, id, param| {
+            .param_changed(|stream, user_data, id, param| {
                 let Some(param) = param else {
                     return;
                 };
@@ -137,9 +972,11 @@ mod inner {
                         Err(_) => return,
                     };
 
-                if media_type != pw::spa::param::format::MediaType::Video
-                    || media_subtype != pw::spa::param::format::MediaSubtype::Raw
-                {
+                if media_type != pw::spa::param::format::MediaType::Video {
+                    return;
+                }
+                if media_subtype != pw::spa::param::format::MediaSubtype::Raw {
+                    println!("Unsupported video format {media_subtype:?}, only raw video");
                     return;
                 }
 
@@ -172,6 +1009,35 @@ mod inner {
                 unsafe {
                     spa::sys::spa_debug_format(2, std::ptr::null(), param.as_raw_ptr());
                 }
+
+                // Ask for the producer's timestamps and damage
+                let region = std::mem::size_of::<spa::sys::spa_meta_region>() as i32;
+                let metas = [
+                    meta_param(
+                        spa::sys::SPA_META_Header,
+                        spa::pod::Value::Int(
+                            std::mem::size_of::<spa::sys::spa_meta_header>() as i32
+                        ),
+                    ),
+                    meta_param(
+                        spa::sys::SPA_META_VideoDamage,
+                        spa::pod::Value::Choice(spa::pod::ChoiceValue::Int(spa::utils::Choice(
+                            spa::utils::ChoiceFlags::empty(),
+                            spa::utils::ChoiceEnum::Range {
+                                default: region * 16,
+                                min: region,
+                                max: region * 16,
+                            },
+                        ))),
+                    ),
+                ];
+                let mut params = metas
+                    .iter()
+                    .map(|v| spa::pod::Pod::from_bytes(v).unwrap())
+                    .collect::<Vec<_>>();
+                if let Err(e) = stream.update_params(&mut params) {
+                    println!("Failed to request buffer metadata: {e}");
+                }
                 /*
                 let stride = user_data.format.size().width * 4 / 4;
                 let size = user_data.format.size().height * stride;
@@ -234,22 +1100,45 @@ mod inner {
                 stream.update_params(&mut [pod]).unwrap();*/
             })
             .process(move |stream, user_data| {
-                let mut last_buffer: Option<pw::buffer::Buffer> = None;
-                while let Some(next_buffer) = stream.dequeue_buffer() {
-                    last_buffer = Some(next_buffer);
+                let mut last_buffer = None;
+                while let Some(next_buffer) = RawBuffer::dequeue(stream) {
+                    watch.add_damage(next_buffer.damage());
+                    // Every buffer feeds the drift statistics, even dropped ones
+                    let pts = watch
+                        .video_clock
+                        .borrow_mut()
+                        .map(next_buffer.pts(), stream_time(stream).now);
+                    last_buffer = Some((next_buffer, pts));
                 }
 
                 match last_buffer {
                     None => println!("out of buffers"),
-                    Some(mut buffer) => {
+                    Some((mut buffer, pts)) => {
                         let datas = buffer.datas_mut();
                         if datas.is_empty() {
                             return;
                         }
+                        if !watch.settings.borrow().frame_due()
+                            && watch.snapshots.borrow().is_empty()
+                        {
+                            return;
+                        }
 
                         let user_data = user_data.borrow();
 
                         let buffer = if datas[0].type_() == spa::buffer::DataType::DmaBuf {
+                            if let Some(frame_sender) = &*watch.dma_bufs.borrow() {
+                                // Dropped while the consumer is busy
+                                match share_dma_buf(datas, &user_data.format, pts) {
+                                    Ok(frame) => {
+                                        let _ = frame_sender.try_send(frame);
+                                    }
+                                    Err(e) => println!("Failed to share DMA-BUF: {e}"),
+                                }
+                            }
+                            let Some(dma_buf) = &user_data.dma_buf else {
+                                return;
+                            };
                             let mut fds = Vec::with_capacity(datas.len());
                             let mut offsets = Vec::with_capacity(datas.len());
                             let mut strides = Vec::with_capacity(datas.len());
@@ -266,35 +1155,99 @@ mod inner {
                             );
                             let format = user_data.format.format();
                             let modifier = user_data.format.modifier();
-                            let mut image = user_data
-                                .dma_buf
-                                .image_from_dma_buf(
-                                    desktop_size,
-                                    format,
-                                    &fds,
-                                    &strides,
-                                    &offsets,
-                                    modifier,
-                                )
-                                .unwrap();
-                            convert_bgr_to_rgb(&mut image);
-                            slint::SharedPixelBuffer::clone_from_slice(
-                                &image,
-                                user_data.format.size().width,
-                                user_data.format.size().height,
-                            )
+                            let image = match dma_buf.image_from_dma_buf(
+                                desktop_size,
+                                format,
+                                &fds,
+                                &strides,
+                                &offsets,
+                                modifier,
+                            ) {
+                                Ok(image) => image,
+                                Err(e) => {
+                                    println!("Failed to import DMA-BUF: {e}");
+                                    return;
+                                }
+                            };
+                            // Read back tightly packed, in the buffer's byte order
+                            let Some(frame) = to_frame(
+                                &user_data.format,
+                                &[(&image, desktop_size.0 as usize * 4)],
+                            ) else {
+                                return;
+                            };
+                            frame
                         } else {
-                            // copy frame data to screen
-                            let data = &mut datas[0];
-                            convert_bgr_to_rgb(data.data().unwrap());
-                            slint::SharedPixelBuffer::clone_from_slice(
-                                data.data().unwrap(),
-                                user_data.format.size().width,
-                                user_data.format.size().height,
-                            )
+                            // Say so once rather than serve nothing
+                            if watch.dma_bufs.borrow_mut().take().is_some() {
+                                println!("Buffers are shared through memory, no DMA-BUFs to share");
+                            }
+                            // One data per plane, for NV12 possibly one for both
+                            let mut planes = Vec::with_capacity(datas.len());
+                            for data in datas.iter_mut() {
+                                let (offset, stride) =
+                                    (data.chunk().offset() as usize, data.chunk().stride());
+                                let Some(plane) =
+                                    data.data().and_then(|mapped| mapped.get(offset..))
+                                else {
+                                    return;
+                                };
+                                planes.push((&*plane, stride.max(0) as usize));
+                            }
+                            let Some(frame) = to_frame(&user_data.format, &planes) else {
+                                return;
+                            };
+                            frame
                         };
 
-                        frame_sender.send_blocking(buffer).unwrap();
+                        let mut settings = watch.settings.borrow_mut();
+                        let source_size = (buffer.width(), buffer.height());
+                        let mut frame = crate::frame_transform::apply(
+                            buffer,
+                            settings.crop,
+                            settings.output_size,
+                        );
+                        settings.process(&mut frame);
+                        for reply in watch.snapshots.take() {
+                            let _ = reply.try_send(Ok(frame.clone()));
+                        }
+                        if settings.frame_due() {
+                            settings.last_frame = Some(std::time::Instant::now());
+                            let damage = watch.damage.replace(Some(Vec::new())).map(|rects| {
+                                let mut rects = rects
+                                    .into_iter()
+                                    .filter_map(|rect| {
+                                        crate::frame_transform::apply_to_rect(
+                                            rect,
+                                            source_size,
+                                            settings.crop,
+                                            settings.output_size,
+                                        )
+                                    })
+                                    .collect::<Vec<_>>();
+                                // Blurring spreads changes over the blurred area
+                                if let Some(blur) = settings.blur.filter(|blur| {
+                                    rects.iter().any(|rect| rect.intersect(blur).is_some())
+                                }) {
+                                    rects.push(blur);
+                                }
+                                rects
+                            });
+                            drop(settings);
+                            watch.republish(&frame, pts);
+                            let frame = super::TimedFrame { frame, pts, damage };
+                            if watch.audio.borrow().is_some() {
+                                // Audio runs on this loop too, a slow consumer
+                                // loses frames rather than stall the capture
+                                if frame_sender.try_send(frame).is_err() {
+                                    // The next frame covers what this one changed
+                                    watch.damage.replace(None);
+                                }
+                            } else {
+                                // Fails once the consumer closed the channel
+                                let _ = frame_sender.send_blocking(frame);
+                            }
+                        }
                     }
                 }
             })
@@ -302,25 +1255,21 @@ mod inner {
 
         println!("Created stream {:#?}", stream);
 
+        // RGB first, YUV as cameras produce it after. Cameras only offering
+        // MJPG are not supported, there is no JPEG decoder.
         let formats = [
             spa::param::video::VideoFormat::BGRA,
             spa::param::video::VideoFormat::RGBA,
             spa::param::video::VideoFormat::RGBx,
             spa::param::video::VideoFormat::BGRx,
+            spa::param::video::VideoFormat::YUY2,
+            spa::param::video::VideoFormat::NV12,
         ];
 
         let mut params = Vec::with_capacity(formats.len() * 2);
 
         for format in formats {
-            let modifiers = data
-                .borrow()
-                .dma_buf
-                .query_dma_buf_modifiers(format)
-                .unwrap_or(vec![drm::buffer::DrmModifier::Invalid.into()]);
-            let modifiers = modifiers.into_iter().map(|m| m as i64).collect::<Vec<_>>();
-            let default_modifier = modifiers[0];
-
-            let obj = pw::spa::pod::object!(
+            let mut obj = pw::spa::pod::object!(
                 pw::spa::utils::SpaTypes::ObjectParamFormat,
                 pw::spa::param::ParamType::EnumFormat,
                 pw::spa::pod::property!(
@@ -338,20 +1287,6 @@ mod inner {
                     Id,
                     format
                 ),
-                pw::spa::pod::Property {
-                    key: spa::param::format::FormatProperties::VideoModifier.as_raw(),
-                    flags: spa::pod::PropertyFlags::MANDATORY
-                        | spa::pod::PropertyFlags::DONT_FIXATE,
-                    value: spa::pod::Value::Choice(spa::pod::ChoiceValue::Long(
-                        spa::utils::Choice(
-                            spa::utils::ChoiceFlags::empty(),
-                            spa::utils::ChoiceEnum::Enum {
-                                default: default_modifier,
-                                alternatives: modifiers
-                            }
-                        )
-                    ))
-                },
                 pw::spa::pod::property!(
                     pw::spa::param::format::FormatProperties::VideoSize,
                     Choice,
@@ -383,6 +1318,34 @@ mod inner {
                     }
                 ),
             );
+            let yuv = matches!(
+                format,
+                spa::param::video::VideoFormat::YUY2 | spa::param::video::VideoFormat::NV12
+            );
+            // DMA-BUFs are only imported as RGB textures
+            if let Some(dma_buf) = data.borrow().dma_buf.as_ref().filter(|_| !yuv) {
+                let modifiers = dma_buf
+                    .query_dma_buf_modifiers(format)
+                    .unwrap_or(vec![drm::buffer::DrmModifier::Invalid.into()]);
+                let modifiers = modifiers.into_iter().map(|m| m as i64).collect::<Vec<_>>();
+                let default_modifier = modifiers[0];
+
+                obj.properties.push(spa::pod::Property {
+                    key: spa::param::format::FormatProperties::VideoModifier.as_raw(),
+                    flags: spa::pod::PropertyFlags::MANDATORY
+                        | spa::pod::PropertyFlags::DONT_FIXATE,
+                    value: spa::pod::Value::Choice(spa::pod::ChoiceValue::Long(
+                        spa::utils::Choice(
+                            spa::utils::ChoiceFlags::empty(),
+                            spa::utils::ChoiceEnum::Enum {
+                                default: default_modifier,
+                                alternatives: modifiers,
+                            },
+                        ),
+                    )),
+                });
+            }
+
             let values: Vec<u8> = pw::spa::pod::serialize::PodSerializer::serialize(
                 std::io::Cursor::new(Vec::new()),
                 &pw::spa::pod::Value::Object(obj),
@@ -406,22 +1369,628 @@ mod inner {
         //     }
         // }
 
+        let mut flags = pw::stream::StreamFlags::AUTOCONNECT | pw::stream::StreamFlags::MAP_BUFFERS;
+        if reconnecting {
+            // Go unconnected when the source disappears, we reconnect ourselves
+            flags |= pw::stream::StreamFlags::DONT_RECONNECT;
+        }
+        stream.connect(spa::utils::Direction::Input, target_id, flags, &mut params)?;
+
+        println!("Connected stream, target: {target}");
+
+        Ok(StreamData {
+            stream,
+            _stream_listener: stream_listener,
+        })
+    }
+
+    struct AudioStream {
+        _stream: pw::stream::Stream,
+        _stream_listener: pw::stream::StreamListener<()>,
+    }
+
+    fn start_audio_stream(
+        core: pipewire::core::Core,
+        source: super::AudioSource,
+        chunk_sender: async_channel::Sender<super::AudioChunk>,
+        watch: Rc<Watch>,
+    ) -> Result<AudioStream, pw::Error> {
+        let mut props = properties! {
+            *pw::keys::MEDIA_TYPE => "Audio",
+            *pw::keys::MEDIA_CATEGORY => "Capture",
+            *pw::keys::MEDIA_ROLE => "Screen",
+        };
+        let target_id = match &source {
+            super::AudioSource::DesktopMonitor => {
+                props.insert("stream.capture.sink", "true");
+                None
+            }
+            super::AudioSource::Node(super::Target::Id(id)) => Some(*id),
+            super::AudioSource::Node(super::Target::Name(name)) => {
+                props.insert(*pw::keys::TARGET_OBJECT, name.as_str());
+                None
+            }
+        };
+
+        let stream = pw::stream::Stream::new(&core, "screencast-audio", props)?;
+        let stream_listener = stream
+            .add_local_listener_with_user_data(())
+            .process(move |stream, _| {
+                let Some(mut buffer) = stream.dequeue_buffer() else {
+                    return;
+                };
+                let time = stream_time(stream);
+                let Some(data) = buffer.datas_mut().first_mut() else {
+                    return;
+                };
+                let offset = data.chunk().offset() as usize;
+                let size = data.chunk().size() as usize;
+                let Some(bytes) = data.data() else {
+                    return;
+                };
+                let bytes = &bytes[offset.min(bytes.len())..(offset + size).min(bytes.len())];
+                let samples = bytes
+                    .chunks_exact(2)
+                    .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                    .collect();
+
+                // The first sample was captured `delay` graph ticks before `now`
+                let delay = match time.rate.denom {
+                    0 => 0,
+                    denom => time.delay * 1_000_000_000 * time.rate.num as i64 / denom as i64,
+                };
+                let pts = watch
+                    .audio_clock
+                    .borrow_mut()
+                    .map(Some(time.now - delay), time.now);
+                // Drops audio rather than stall the graph when the consumer is slow
+                let _ = chunk_sender.try_send(super::AudioChunk { pts, samples });
+            })
+            .register()?;
+
+        let mut audio_info = spa::param::audio::AudioInfoRaw::new();
+        audio_info.set_format(spa::param::audio::AudioFormat::S16LE);
+        audio_info.set_rate(crate::audio::SAMPLE_RATE);
+        audio_info.set_channels(crate::audio::CHANNELS);
+        let values: Vec<u8> = pw::spa::pod::serialize::PodSerializer::serialize(
+            std::io::Cursor::new(Vec::new()),
+            &pw::spa::pod::Value::Object(pw::spa::pod::Object {
+                type_: pw::spa::utils::SpaTypes::ObjectParamFormat.as_raw(),
+                id: pw::spa::param::ParamType::EnumFormat.as_raw(),
+                properties: audio_info.into(),
+            }),
+        )
+        .unwrap()
+        .0
+        .into_inner();
+        let mut params = [spa::pod::Pod::from_bytes(&values).unwrap()];
+
         stream.connect(
             spa::utils::Direction::Input,
-            // None,
-            Some(target),
+            target_id,
             pw::stream::StreamFlags::AUTOCONNECT | pw::stream::StreamFlags::MAP_BUFFERS,
             &mut params,
         )?;
+        println!("Connected audio stream, source: {source:?}");
 
-        println!("Connected stream, target: {target}");
-
-        Ok(StreamData {
+        Ok(AudioStream {
             _stream: stream,
             _stream_listener: stream_listener,
         })
     }
 
+    /// A requested re-published node, created with the first frame.
+    struct Republished {
+        config: super::RepublishConfig,
+        reply: Option<ReplySender<u32>>,
+        pending: Option<(super::Frame, Duration)>,
+        output: Option<OutputStream>,
+    }
+
+    struct OutputData {
+        format: spa::param::video::VideoInfoRaw,
+        latest: Rc<RefCell<Option<(super::Frame, Duration)>>>,
+        sequence: u64,
+        /// Answered once the node has an id.
+        reply: Option<ReplySender<u32>>,
+    }
+
+    /// Output stream driving its own graph with the latest frame, on its own
+    /// core so that it outlives the capture's remote.
+    struct OutputStream {
+        stream: pw::stream::Stream,
+        _stream_listener: pw::stream::StreamListener<OutputData>,
+        _core: pw::core::Core,
+        /// Size of the offered format.
+        size: (u32, u32),
+        latest: Rc<RefCell<Option<(super::Frame, Duration)>>>,
+    }
+
+    impl OutputStream {
+        /// Queues `frame`, offering a new format first if its size changed.
+        fn show(&mut self, frame: &super::Frame, pts: Duration) {
+            *self.latest.borrow_mut() = Some((frame.clone(), pts));
+            let size = (frame.width(), frame.height());
+            if size != self.size {
+                self.size = size;
+                let format = output_format(size);
+                let mut params = [spa::pod::Pod::from_bytes(&format).unwrap()];
+                if let Err(e) = self.stream.update_params(&mut params) {
+                    println!("Failed to renegotiate the re-published format: {e}");
+                }
+                return;
+            }
+            if self.stream.state() == pw::stream::StreamState::Streaming {
+                let _ = self.stream.trigger_process();
+            }
+        }
+    }
+
+    fn start_output_stream(
+        core: pipewire::core::Core,
+        config: &super::RepublishConfig,
+        reply: Option<ReplySender<u32>>,
+        (frame, pts): (&super::Frame, Duration),
+    ) -> Result<OutputStream, pw::Error> {
+        let stream = pw::stream::Stream::new(
+            &core,
+            &config.name,
+            properties! {
+                *pw::keys::MEDIA_CLASS => "Video/Source",
+                *pw::keys::MEDIA_TYPE => "Video",
+                *pw::keys::MEDIA_CATEGORY => "Playback",
+                *pw::keys::MEDIA_ROLE => "Camera",
+                *pw::keys::NODE_DESCRIPTION => config.description.as_str(),
+            },
+        )?;
+        let latest = Rc::new(RefCell::new(Some((frame.clone(), pts))));
+        let data = OutputData {
+            format: Default::default(),
+            latest: Rc::clone(&latest),
+            sequence: 0,
+            reply,
+        };
+
+        let stream_listener = stream
+            .add_local_listener_with_user_data(data)
+            .state_changed(|stream, data, old, new| {
+                println!("Re-published state changed: {:?} -> {:?}", old, new);
+                let Some(reply) = data.reply.take() else {
+                    return;
+                };
+                match new {
+                    pw::stream::StreamState::Paused | pw::stream::StreamState::Streaming => {
+                        let _ = reply.try_send(Ok(stream.node_id()));
+                    }
+                    pw::stream::StreamState::Error(e) => {
+                        let _ = reply.try_send(Err(super::CommandError::PipeWire(e)));
+                    }
+                    _ => data.reply = Some(reply),
+                }
+            })
+            .param_changed(|stream, data, id, param| {
+                let Some(param) = param else {
+                    return;
+                };
+                if id != pw::spa::param::ParamType::Format.as_raw()
+                    || data.format.parse(param).is_err()
+                {
+                    return;
+                }
+                let size = data.format.size();
+                let stride = size.width * 4;
+                println!("Re-published format: {}x{}", size.width, size.height);
+
+                // PipeWire allocates the memfds, mapped for process()
+                let buffers = spa::pod::Object {
+                    type_: spa::sys::SPA_TYPE_OBJECT_ParamBuffers,
+                    id: spa::sys::SPA_PARAM_Buffers,
+                    properties: vec![
+                        spa::pod::Property::new(
+                            spa::sys::SPA_PARAM_BUFFERS_buffers,
+                            spa::pod::Value::Choice(spa::pod::ChoiceValue::Int(
+                                spa::utils::Choice(
+                                    spa::utils::ChoiceFlags::empty(),
+                                    spa::utils::ChoiceEnum::Range {
+                                        default: 4,
+                                        min: 2,
+                                        max: 16,
+                                    },
+                                ),
+                            )),
+                        ),
+                        spa::pod::Property::new(
+                            spa::sys::SPA_PARAM_BUFFERS_blocks,
+                            spa::pod::Value::Int(1),
+                        ),
+                        spa::pod::Property::new(
+                            spa::sys::SPA_PARAM_BUFFERS_size,
+                            spa::pod::Value::Int((stride * size.height) as i32),
+                        ),
+                        spa::pod::Property::new(
+                            spa::sys::SPA_PARAM_BUFFERS_stride,
+                            spa::pod::Value::Int(stride as i32),
+                        ),
+                        spa::pod::Property::new(
+                            spa::sys::SPA_PARAM_BUFFERS_dataType,
+                            spa::pod::Value::Choice(spa::pod::ChoiceValue::Int(
+                                spa::utils::Choice(
+                                    spa::utils::ChoiceFlags::empty(),
+                                    spa::utils::ChoiceEnum::Flags {
+                                        default: 1 << spa::sys::SPA_DATA_MemFd,
+                                        flags: vec![1 << spa::sys::SPA_DATA_MemFd],
+                                    },
+                                ),
+                            )),
+                        ),
+                    ],
+                };
+                let buffers = pw::spa::pod::serialize::PodSerializer::serialize(
+                    std::io::Cursor::new(Vec::new()),
+                    &pw::spa::pod::Value::Object(buffers),
+                )
+                .unwrap()
+                .0
+                .into_inner();
+                let header = meta_param(
+                    spa::sys::SPA_META_Header,
+                    spa::pod::Value::Int(std::mem::size_of::<spa::sys::spa_meta_header>() as i32),
+                );
+                let values = [buffers, header];
+                let mut params = values
+                    .iter()
+                    .map(|v| spa::pod::Pod::from_bytes(v).unwrap())
+                    .collect::<Vec<_>>();
+                if let Err(e) = stream.update_params(&mut params) {
+                    println!("Failed to set the re-published buffers: {e}");
+                }
+            })
+            .process(|stream, data| {
+                let size = data.format.size();
+                let Some((frame, pts)) = data.latest.borrow().clone() else {
+                    return;
+                };
+                // Waiting for the consumer to take the new size
+                if (frame.width(), frame.height()) != (size.width, size.height) {
+                    return;
+                }
+                let Some(mut buffer) = RawBuffer::dequeue(stream) else {
+                    return;
+                };
+                let Some(out) = buffer.datas_mut().first_mut() else {
+                    return;
+                };
+                let pixels = frame.as_bytes();
+                let Some(target) = out.data().and_then(|d| d.get_mut(..pixels.len())) else {
+                    return;
+                };
+                target.copy_from_slice(pixels);
+                convert_bgr_to_rgb(target);
+                let chunk = out.chunk_mut();
+                *chunk.offset_mut() = 0;
+                *chunk.size_mut() = pixels.len() as u32;
+                *chunk.stride_mut() = (frame.width() * 4) as i32;
+                buffer.set_header(pts.as_nanos() as i64, data.sequence);
+                data.sequence += 1;
+            })
+            .register()?;
+
+        let format = output_format((frame.width(), frame.height()));
+        let mut params = [spa::pod::Pod::from_bytes(&format).unwrap()];
+        stream.connect(
+            spa::utils::Direction::Output,
+            None,
+            pw::stream::StreamFlags::DRIVER | pw::stream::StreamFlags::MAP_BUFFERS,
+            &mut params,
+        )?;
+
+        Ok(OutputStream {
+            stream,
+            _stream_listener: stream_listener,
+            _core: core,
+            size: (frame.width(), frame.height()),
+            latest,
+        })
+    }
+
+    /// The only format offered for re-published frames: BGRA, like most
+    /// compositors' screen casts, of their size and at the variable rate they
+    /// arrive.
+    fn output_format((width, height): (u32, u32)) -> Vec<u8> {
+        let format = pw::spa::pod::object!(
+            pw::spa::utils::SpaTypes::ObjectParamFormat,
+            pw::spa::param::ParamType::EnumFormat,
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::MediaType,
+                Id,
+                pw::spa::param::format::MediaType::Video
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::MediaSubtype,
+                Id,
+                pw::spa::param::format::MediaSubtype::Raw
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::VideoFormat,
+                Id,
+                spa::param::video::VideoFormat::BGRA
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::VideoSize,
+                Rectangle,
+                pw::spa::utils::Rectangle { width, height }
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::VideoFramerate,
+                Fraction,
+                pw::spa::utils::Fraction { num: 0, denom: 1 }
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::VideoMaxFramerate,
+                Choice,
+                Range,
+                Fraction,
+                pw::spa::utils::Fraction { num: 60, denom: 1 },
+                pw::spa::utils::Fraction { num: 1, denom: 1 },
+                pw::spa::utils::Fraction {
+                    num: 1000,
+                    denom: 1
+                }
+            ),
+        );
+        pw::spa::pod::serialize::PodSerializer::serialize(
+            std::io::Cursor::new(Vec::new()),
+            &pw::spa::pod::Value::Object(format),
+        )
+        .unwrap()
+        .0
+        .into_inner()
+    }
+
+    /// `SPA_PARAM_Meta` asking for metadata of `type_` with `size`.
+    fn meta_param(type_: u32, size: spa::pod::Value) -> Vec<u8> {
+        pw::spa::pod::serialize::PodSerializer::serialize(
+            std::io::Cursor::new(Vec::new()),
+            &pw::spa::pod::Value::Object(spa::pod::Object {
+                type_: spa::sys::SPA_TYPE_OBJECT_ParamMeta,
+                id: spa::sys::SPA_PARAM_Meta,
+                properties: vec![
+                    spa::pod::Property::new(
+                        spa::sys::SPA_PARAM_META_type,
+                        spa::pod::Value::Id(spa::utils::Id(type_)),
+                    ),
+                    spa::pod::Property::new(spa::sys::SPA_PARAM_META_size, size),
+                ],
+            }),
+        )
+        .unwrap()
+        .0
+        .into_inner()
+    }
+
+    /// Timing of the graph cycle the stream is processing.
+    fn stream_time(stream: &pw::stream::StreamRef) -> pw::sys::pw_time {
+        let mut time: pw::sys::pw_time = unsafe { std::mem::zeroed() };
+        unsafe {
+            pw::sys::pw_stream_get_time_n(
+                stream.as_raw_ptr(),
+                &mut time,
+                std::mem::size_of::<pw::sys::pw_time>(),
+            );
+        }
+        time
+    }
+
+    /// Duplicates the descriptors of a DMA-BUF buffer, which goes back to
+    /// the producer once processed.
+    fn share_dma_buf(
+        datas: &[spa::buffer::Data],
+        format: &spa::param::video::VideoInfoRaw,
+        pts: Duration,
+    ) -> std::io::Result<super::DmaBufFrame> {
+        let fourcc = dma::spa_pixel_format_to_drm_format(format.format()).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("No DRM format for {:?}", format.format()),
+            )
+        })?;
+        let planes = datas
+            .iter()
+            .map(|data| {
+                let fd = unsafe { std::os::fd::BorrowedFd::borrow_raw(data.as_raw().fd as i32) };
+                Ok(crate::dma_buf_share::DmaBufPlane {
+                    fd: fd.try_clone_to_owned()?,
+                    offset: data.chunk().offset(),
+                    stride: data.chunk().stride() as u32,
+                })
+            })
+            .collect::<std::io::Result<_>>()?;
+        Ok(super::DmaBufFrame {
+            width: format.size().width,
+            height: format.size().height,
+            fourcc: fourcc as u32,
+            modifier: format.modifier(),
+            planes,
+            sequence: 0,
+            pts,
+        })
+    }
+
+    /// A dequeued buffer, queued back when dropped. Unlike
+    /// `pw::buffer::Buffer` it gives access to the buffer's metadata.
+    struct RawBuffer<'s> {
+        stream: &'s pw::stream::StreamRef,
+        buffer: std::ptr::NonNull<pw::sys::pw_buffer>,
+    }
+
+    impl<'s> RawBuffer<'s> {
+        fn dequeue(stream: &'s pw::stream::StreamRef) -> Option<Self> {
+            let buffer = unsafe { stream.dequeue_raw_buffer() };
+            std::ptr::NonNull::new(buffer).map(|buffer| Self { stream, buffer })
+        }
+
+        fn spa_buffer(&self) -> *mut spa::sys::spa_buffer {
+            unsafe { self.buffer.as_ref().buffer }
+        }
+
+        fn datas_mut(&mut self) -> &mut [spa::buffer::Data] {
+            let buffer = self.spa_buffer();
+            unsafe {
+                if buffer.is_null() || (*buffer).n_datas == 0 || (*buffer).datas.is_null() {
+                    return &mut [];
+                }
+                // Data is a transparent wrapper of spa_data
+                std::slice::from_raw_parts_mut(
+                    (*buffer).datas as *mut spa::buffer::Data,
+                    (*buffer).n_datas as usize,
+                )
+            }
+        }
+
+        /// Changed rectangles, if the producer attached damage metadata.
+        fn damage(&self) -> Option<Vec<super::Rect>> {
+            let buffer = self.spa_buffer();
+            if buffer.is_null() {
+                return None;
+            }
+            let meta = unsafe {
+                spa::sys::spa_buffer_find_meta(buffer, spa::sys::SPA_META_VideoDamage).as_ref()?
+            };
+            let count = meta.size as usize / std::mem::size_of::<spa::sys::spa_meta_region>();
+            let regions = unsafe {
+                std::slice::from_raw_parts(meta.data as *const spa::sys::spa_meta_region, count)
+            };
+            let rects = regions
+                .iter()
+                // An empty region ends the list
+                .take_while(|r| r.region.size.width > 0 && r.region.size.height > 0)
+                .map(|r| super::Rect {
+                    x: r.region.position.x.max(0) as u32,
+                    y: r.region.position.y.max(0) as u32,
+                    width: r.region.size.width,
+                    height: r.region.size.height,
+                })
+                .collect::<Vec<_>>();
+            // Without any region there is nothing to go by
+            (!rects.is_empty()).then_some(rects)
+        }
+
+        /// `spa_meta_header.pts` in nanoseconds, if the producer attached one.
+        fn pts(&self) -> Option<i64> {
+            let buffer = self.spa_buffer();
+            if buffer.is_null() {
+                return None;
+            }
+            let header = unsafe {
+                spa::sys::spa_buffer_find_meta_data(
+                    buffer,
+                    spa::sys::SPA_META_Header,
+                    std::mem::size_of::<spa::sys::spa_meta_header>(),
+                ) as *const spa::sys::spa_meta_header
+            };
+            unsafe { header.as_ref() }.map(|header| header.pts)
+        }
+
+        /// Stamps an outgoing buffer, if the consumer accepted headers.
+        fn set_header(&mut self, pts: i64, seq: u64) {
+            let buffer = self.spa_buffer();
+            if buffer.is_null() {
+                return;
+            }
+            let header = unsafe {
+                spa::sys::spa_buffer_find_meta_data(
+                    buffer,
+                    spa::sys::SPA_META_Header,
+                    std::mem::size_of::<spa::sys::spa_meta_header>(),
+                ) as *mut spa::sys::spa_meta_header
+            };
+            if let Some(header) = unsafe { header.as_mut() } {
+                header.flags = 0;
+                header.offset = 0;
+                header.pts = pts;
+                header.dts_offset = 0;
+                header.seq = seq;
+            }
+        }
+    }
+
+    impl Drop for RawBuffer<'_> {
+        fn drop(&mut self) {
+            unsafe { self.stream.queue_raw_buffer(self.buffer.as_ptr()) };
+        }
+    }
+
+    /// Converts the mapped planes of a buffer of `format`, each with the
+    /// distance between its rows, into a new frame, leaving the producer's
+    /// memory untouched. A stride of 0 means tightly packed. `None` for
+    /// formats without a conversion and buffers too short for the size.
+    fn to_frame(
+        format: &spa::param::video::VideoInfoRaw,
+        planes: &[(&[u8], usize)],
+    ) -> Option<super::Frame> {
+        use crate::export::{ImageView, PixelLayout};
+        use spa::param::video::VideoFormat;
+
+        let (width, height) = (format.size().width, format.size().height);
+        if width == 0 || height == 0 {
+            return None;
+        }
+        let stride = |plane: usize, packed: usize| match planes.get(plane) {
+            Some(&(_, stride)) if stride > 0 => stride,
+            _ => packed,
+        };
+        let frame = match format.format() {
+            VideoFormat::YUY2 => {
+                let row = width.div_ceil(2) as usize * 4;
+                crate::yuv::yuy2_to_frame(planes[0].0, stride(0, row), width, height)
+            }
+            VideoFormat::NV12 => {
+                let y_stride = stride(0, width as usize);
+                // Without a second data the UV plane follows the Y plane
+                let (uv_plane, uv_stride) = match planes.get(1) {
+                    Some(&(plane, _)) => (plane, stride(1, y_stride)),
+                    None => (
+                        planes[0].0.get(y_stride * height as usize..).unwrap_or(&[]),
+                        y_stride,
+                    ),
+                };
+                crate::yuv::nv12_to_frame(planes[0].0, y_stride, uv_plane, uv_stride, width, height)
+            }
+            format => {
+                let layout = match format {
+                    VideoFormat::RGBA => PixelLayout::Rgba,
+                    VideoFormat::RGBx => PixelLayout::Rgbx,
+                    VideoFormat::BGRA => PixelLayout::Bgra,
+                    VideoFormat::BGRx => PixelLayout::Bgrx,
+                    other => {
+                        println!("No conversion from {other:?}");
+                        return None;
+                    }
+                };
+                let (data, stride) = (planes[0].0, stride(0, width as usize * 4));
+                let needed = stride * (height as usize).saturating_sub(1) + width as usize * 4;
+                (stride >= width as usize * 4 && data.len() >= needed).then(|| {
+                    ImageView {
+                        data,
+                        width,
+                        height,
+                        stride,
+                        layout,
+                    }
+                    .to_frame()
+                })
+            }
+        };
+        if frame.is_none() {
+            println!(
+                "Buffer of {} bytes too short for {:?} {width}x{height}",
+                planes[0].0.len(),
+                format.format()
+            );
+        }
+        frame
+    }
+
     fn convert_bgr_to_rgb(frame: &mut [u8]) {
         for i in (0..frame.len()).step_by(4) {
             let temp_red = frame[i];
@@ -430,3 +1999,77 @@ mod inner {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{for_each_frame, ReconnectPolicy};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn for_each_frame_until_closed() {
+        let (sender, receiver) = async_channel::unbounded();
+        let producer = std::thread::spawn(move || {
+            for i in 0..3 {
+                std::thread::sleep(Duration::from_millis(20));
+                sender.send_blocking(i).unwrap();
+            }
+        });
+        let mut received = Vec::new();
+        for_each_frame(
+            &receiver,
+            || false,
+            |i| {
+                received.push(i);
+                Ok(())
+            },
+        )
+        .unwrap();
+        producer.join().unwrap();
+        assert_eq!(received, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn for_each_frame_polls_stop_while_idle() {
+        let (_sender, receiver) = async_channel::unbounded::<u32>();
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let start = Instant::now();
+        for_each_frame(&receiver, || Instant::now() >= deadline, |_| Ok(())).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn for_each_frame_stops_on_error() {
+        let (sender, receiver) = async_channel::unbounded();
+        for i in 0..3 {
+            sender.send_blocking(i).unwrap();
+        }
+        let mut received = Vec::new();
+        let result = for_each_frame(
+            &receiver,
+            || false,
+            |i| {
+                received.push(i);
+                match i {
+                    1 => Err(std::io::Error::other("full")),
+                    _ => Ok(()),
+                }
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(received, vec![0, 1]);
+    }
+
+    #[test]
+    fn reconnect_delay_backs_off() {
+        let policy = ReconnectPolicy {
+            max_attempts: None,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(4), Duration::from_millis(800));
+        assert_eq!(policy.delay(5), Duration::from_secs(1));
+        assert_eq!(policy.delay(100), Duration::from_secs(1));
+    }
+}
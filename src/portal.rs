@@ -0,0 +1,583 @@
+//! Client for the `org.freedesktop.portal.ScreenCast` interface (version 5).
+//!
+//! ```no_run
+//! # use screencast::portal::{Portal, SourceOptions, SourceType};
+//! # fn test() -> Result<(), screencast::portal::PortalError> {
+//! let portal = Portal::new()?;
+//! let session = portal.create_session()?;
+//! session.select_sources(&SourceOptions {
+//!     types: SourceType::MONITOR | SourceType::WINDOW,
+//!     ..Default::default()
+//! })?;
+//! let started = session.start("")?;
+//! let pipewire_fd = session.open_pipewire_remote()?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::restore_tokens::TokenStore;
+use dbus::arg::{ArgType, PropMap, RefArg, Variant};
+use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+use dbus::blocking::{Connection, Proxy};
+use dbus::message::MatchRule;
+use std::cell::Cell;
+use std::os::fd::{FromRawFd, IntoRawFd, OwnedFd};
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::time::Duration;
+
+pub const DESTINATION: &str = "org.freedesktop.portal.Desktop";
+pub const PATH: &str = "/org/freedesktop/portal/desktop";
+pub const SCREEN_CAST_INTERFACE: &str = "org.freedesktop.portal.ScreenCast";
+pub const REQUEST_INTERFACE: &str = "org.freedesktop.portal.Request";
+pub const SESSION_INTERFACE: &str = "org.freedesktop.portal.Session";
+
+const CALL_TIMEOUT: Duration = Duration::from_secs(20);
+
+#[derive(Debug)]
+pub enum PortalError {
+    /// Error from the underlying `dbus` library.
+    DBus(dbus::Error),
+    /// The user dismissed the dialog.
+    Cancelled,
+    /// The request ended with a non-zero response code.
+    Failed(u32),
+    /// The response is missing a field or has an unexpected type.
+    Parse(&'static str),
+}
+
+impl From<dbus::Error> for PortalError {
+    fn from(error: dbus::Error) -> Self {
+        PortalError::DBus(error)
+    }
+}
+
+impl std::fmt::Display for PortalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PortalError::DBus(e) => write!(f, "D-Bus error: {e}"),
+            PortalError::Cancelled => write!(f, "Portal request cancelled by the user"),
+            PortalError::Failed(code) => write!(f, "Portal request failed, response: {code}"),
+            PortalError::Parse(field) => write!(f, "Failed to parse portal response: {field}"),
+        }
+    }
+}
+
+impl std::error::Error for PortalError {}
+
+/// Bit set of `types` accepted by `SelectSources`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SourceType(pub u32);
+
+impl SourceType {
+    pub const MONITOR: Self = Self(1);
+    pub const WINDOW: Self = Self(2);
+    pub const VIRTUAL: Self = Self(4);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for SourceType {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Bit set of cursor modes, since interface version 2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CursorMode(pub u32);
+
+impl CursorMode {
+    pub const HIDDEN: Self = Self(1);
+    pub const EMBEDDED: Self = Self(2);
+    pub const METADATA: Self = Self(4);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for CursorMode {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// How the permission for the selected sources is stored, since version 4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PersistMode {
+    #[default]
+    DoNot = 0,
+    /// Permission lasts while the application is running.
+    Application = 1,
+    /// Permission lasts until explicitly revoked.
+    Persistent = 2,
+}
+
+/// Options of `SelectSources`. Fields left as `None` are not sent, so the
+/// portal picks its defaults.
+#[derive(Debug, Clone, Default)]
+pub struct SourceOptions {
+    pub types: SourceType,
+    pub multiple: bool,
+    pub cursor_mode: Option<CursorMode>,
+    pub restore_token: Option<String>,
+    pub persist_mode: PersistMode,
+}
+
+/// Stream returned by `Start`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamDescriptor {
+    /// PipeWire node id to connect the stream to.
+    pub node_id: u32,
+    /// Opaque identifier, stable across restored sessions (version 4).
+    pub id: Option<String>,
+    /// Position in the compositor coordinate space (monitors only).
+    pub position: Option<(i32, i32)>,
+    /// Logical size of the source.
+    pub size: Option<(i32, i32)>,
+    pub source_type: Option<SourceType>,
+    /// Identifier used to map the stream to other portals, e.g.
+    /// RemoteDesktop's `ConnectToEIS` regions (version 5).
+    pub mapping_id: Option<String>,
+}
+
+/// Result of `Start`.
+#[derive(Debug, Clone, Default)]
+pub struct Started {
+    pub streams: Vec<StreamDescriptor>,
+    /// Token to restore the session, present when a persist mode was set.
+    pub restore_token: Option<String>,
+}
+
+struct Inner {
+    connection: Connection,
+    sender_token: String,
+    next_token: Cell<u32>,
+}
+
+/// Connection to the desktop portal. Cheap to clone, but bound to the
+/// thread that created it.
+#[derive(Clone)]
+pub struct Portal {
+    inner: Rc<Inner>,
+}
+
+impl Portal {
+    pub fn new() -> Result<Self, PortalError> {
+        Self::with_connection(Connection::new_session()?)
+    }
+
+    pub fn with_connection(connection: Connection) -> Result<Self, PortalError> {
+        // Request objects are created under a path derived from our unique name
+        let sender_token = connection.unique_name()[1..].replace('.', "_");
+        Ok(Self {
+            inner: Rc::new(Inner {
+                connection,
+                sender_token,
+                next_token: Cell::new(0),
+            }),
+        })
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.inner.connection
+    }
+
+    pub(crate) fn proxy(&self) -> Proxy<'_, &Connection> {
+        self.inner
+            .connection
+            .with_proxy(DESTINATION, PATH, CALL_TIMEOUT)
+    }
+
+    pub fn version(&self) -> Result<u32, PortalError> {
+        Ok(self.proxy().get(SCREEN_CAST_INTERFACE, "version")?)
+    }
+
+    pub fn available_source_types(&self) -> Result<SourceType, PortalError> {
+        Ok(SourceType(
+            self.proxy()
+                .get(SCREEN_CAST_INTERFACE, "AvailableSourceTypes")?,
+        ))
+    }
+
+    pub fn available_cursor_modes(&self) -> Result<CursorMode, PortalError> {
+        Ok(CursorMode(
+            self.proxy()
+                .get(SCREEN_CAST_INTERFACE, "AvailableCursorModes")?,
+        ))
+    }
+
+    pub fn create_session(&self) -> Result<Session, PortalError> {
+        self.create_session_on(SCREEN_CAST_INTERFACE)
+    }
+
+    /// Creates a session, selects the sources and starts it.
+    pub fn start_session(
+        &self,
+        options: &SourceOptions,
+        parent_window: &str,
+    ) -> Result<(Session, Started), PortalError> {
+        let session = self.create_session()?;
+        session.select_sources(options)?;
+        let started = session.start(parent_window)?;
+        if started.streams.is_empty() {
+            return Err(PortalError::Parse("streams"));
+        }
+        Ok((session, started))
+    }
+
+    /// Same as `start_session`, but restores the sources picked last time
+    /// under `name` and stores the new restore token in `store`.
+    ///
+    /// When the compositor rejects the stored token the token is forgotten
+    /// and the sources are selected again in the dialog.
+    pub fn start_persisted(
+        &self,
+        name: &str,
+        store: &TokenStore,
+        options: &SourceOptions,
+        parent_window: &str,
+    ) -> Result<(Session, Started), PortalError> {
+        let mut options = options.clone();
+
+        // Restore tokens were added in version 4
+        if self.version()? < 4 {
+            options.persist_mode = PersistMode::DoNot;
+            options.restore_token = None;
+            return self.start_session(&options, parent_window);
+        }
+
+        if options.persist_mode == PersistMode::DoNot {
+            options.persist_mode = PersistMode::Persistent;
+        }
+        options.restore_token = store.load(name);
+
+        let (session, started) = match self.start_session(&options, parent_window) {
+            Err(e) if options.restore_token.is_some() && !matches!(e, PortalError::Cancelled) => {
+                println!("Restore token for '{name}' rejected ({e}), selecting sources again");
+                let _ = store.remove(name);
+                options.restore_token = None;
+                self.start_session(&options, parent_window)?
+            }
+            result => result?,
+        };
+
+        // Tokens are single use, the new one replaces the stored one
+        if let Some(token) = &started.restore_token {
+            if let Err(e) = store.save(name, token) {
+                println!("Failed to save restore token for '{name}': {e}");
+            }
+        }
+
+        Ok((session, started))
+    }
+
+    /// Calls `CreateSession` on `interface`, which must follow the
+    /// ScreenCast signature (e.g. RemoteDesktop).
+    pub(crate) fn create_session_on(&self, interface: &str) -> Result<Session, PortalError> {
+        let session_token = self.handle_token();
+        let results = self.request(|handle_token, proxy| {
+            let mut options = PropMap::new();
+            insert(&mut options, "handle_token", handle_token.to_owned());
+            insert(&mut options, "session_handle_token", session_token.clone());
+            proxy.method_call::<(dbus::Path,), _, _, _>(interface, "CreateSession", (options,))?;
+            Ok(())
+        })?;
+
+        // Documented as a string, but some implementations send an object path
+        let handle = results
+            .get("session_handle")
+            .and_then(|v| v.0.as_str())
+            .ok_or(PortalError::Parse("session_handle"))?;
+        let handle =
+            dbus::Path::new(handle.to_owned()).map_err(|_| PortalError::Parse("session_handle"))?;
+
+        Ok(Session {
+            portal: self.clone(),
+            handle,
+        })
+    }
+
+    pub(crate) fn handle_token(&self) -> String {
+        let token = self.inner.next_token.get();
+        self.inner.next_token.set(token + 1);
+        format!("screencast_{}_{token}", std::process::id())
+    }
+
+    /// Performs a portal call returning a `Request` object and waits for its
+    /// `Response` signal. `call` gets the `handle_token` to put in the options.
+    pub(crate) fn request<F>(&self, call: F) -> Result<PropMap, PortalError>
+    where
+        F: FnOnce(&str, &Proxy<'_, &Connection>) -> Result<(), dbus::Error>,
+    {
+        let handle_token = self.handle_token();
+        let request_path = dbus::Path::new(format!(
+            "{PATH}/request/{}/{handle_token}",
+            self.inner.sender_token
+        ))
+        .map_err(|_| PortalError::Parse("request path"))?;
+
+        // Subscribe before calling, the response can arrive before the reply
+        let (sender, receiver) = mpsc::channel();
+        let rule = MatchRule::new_signal(REQUEST_INTERFACE, "Response").with_path(request_path);
+        let match_token = self.inner.connection.add_match(
+            rule,
+            move |(): (), _: &Connection, message: &dbus::Message| {
+                if let Ok(message) = message.duplicate() {
+                    let _ = sender.send(message);
+                }
+                true
+            },
+        )?;
+
+        let result = call(&handle_token, &self.proxy())
+            .map_err(PortalError::from)
+            .and_then(|_| loop {
+                if let Ok(message) = receiver.try_recv() {
+                    break message
+                        .read2::<u32, PropMap>()
+                        .map_err(|_| PortalError::Parse("Response"));
+                }
+                self.inner.connection.process(Duration::from_millis(100))?;
+            });
+
+        let _ = self.inner.connection.remove_match(match_token);
+
+        match result? {
+            (0, results) => Ok(results),
+            (1, _) => Err(PortalError::Cancelled),
+            (code, _) => Err(PortalError::Failed(code)),
+        }
+    }
+}
+
+/// Portal session. The session is closed when dropped.
+pub struct Session {
+    portal: Portal,
+    handle: dbus::Path<'static>,
+}
+
+impl Session {
+    pub fn handle(&self) -> &dbus::Path<'static> {
+        &self.handle
+    }
+
+    pub fn portal(&self) -> &Portal {
+        &self.portal
+    }
+
+    pub fn select_sources(&self, options: &SourceOptions) -> Result<(), PortalError> {
+        self.portal.request(|handle_token, proxy| {
+            let mut args = PropMap::new();
+            insert(&mut args, "handle_token", handle_token.to_owned());
+            insert(&mut args, "types", options.types.0);
+            insert(&mut args, "multiple", options.multiple);
+            if let Some(cursor_mode) = options.cursor_mode {
+                insert(&mut args, "cursor_mode", cursor_mode.0);
+            }
+            if let Some(restore_token) = &options.restore_token {
+                insert(&mut args, "restore_token", restore_token.clone());
+            }
+            if options.persist_mode != PersistMode::DoNot {
+                insert(&mut args, "persist_mode", options.persist_mode as u32);
+            }
+            proxy.method_call::<(dbus::Path,), _, _, _>(
+                SCREEN_CAST_INTERFACE,
+                "SelectSources",
+                (&self.handle, args),
+            )?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    pub fn start(&self, parent_window: &str) -> Result<Started, PortalError> {
+        parse_started(&self.start_on(SCREEN_CAST_INTERFACE, parent_window)?)
+    }
+
+    /// Calls `Start` on `interface`, returning the raw results.
+    pub(crate) fn start_on(
+        &self,
+        interface: &str,
+        parent_window: &str,
+    ) -> Result<PropMap, PortalError> {
+        self.portal.request(|handle_token, proxy| {
+            let mut options = PropMap::new();
+            insert(&mut options, "handle_token", handle_token.to_owned());
+            proxy.method_call::<(dbus::Path,), _, _, _>(
+                interface,
+                "Start",
+                (&self.handle, parent_window, options),
+            )?;
+            Ok(())
+        })
+    }
+
+    pub fn open_pipewire_remote(&self) -> Result<OwnedFd, PortalError> {
+        let (fd,): (dbus::arg::OwnedFd,) = self.portal.proxy().method_call(
+            SCREEN_CAST_INTERFACE,
+            "OpenPipeWireRemote",
+            (&self.handle, PropMap::new()),
+        )?;
+        Ok(unsafe { OwnedFd::from_raw_fd(fd.into_raw_fd()) })
+    }
+
+    pub fn close(&self) -> Result<(), PortalError> {
+        self.portal
+            .connection()
+            .with_proxy(DESTINATION, &self.handle, CALL_TIMEOUT)
+            .method_call::<(), _, _, _>(SESSION_INTERFACE, "Close", ())?;
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+pub(crate) fn insert<T: RefArg + 'static>(map: &mut PropMap, key: &str, value: T) {
+    map.insert(key.to_owned(), Variant(Box::new(value)));
+}
+
+/// Looks through variants wrapping `arg`.
+fn unwrap_variant(arg: &dyn RefArg) -> &dyn RefArg {
+    if arg.arg_type() == ArgType::Variant {
+        if let Some(inner) = arg.as_iter().and_then(|mut i| i.next()) {
+            return unwrap_variant(inner);
+        }
+    }
+    arg
+}
+
+fn parse_pair(arg: &dyn RefArg) -> Option<(i32, i32)> {
+    let mut iter = unwrap_variant(arg).as_iter()?;
+    let first = iter.next()?.as_i64()?;
+    let second = iter.next()?.as_i64()?;
+    Some((first as i32, second as i32))
+}
+
+fn parse_stream(arg: &dyn RefArg) -> Result<StreamDescriptor, PortalError> {
+    let mut parts = arg.as_iter().ok_or(PortalError::Parse("streams"))?;
+    let node_id = parts
+        .next()
+        .and_then(|v| v.as_u64())
+        .ok_or(PortalError::Parse("stream node id"))? as u32;
+    let properties = parts
+        .next()
+        .and_then(|v| v.as_iter())
+        .ok_or(PortalError::Parse("stream properties"))?;
+
+    let mut stream = StreamDescriptor {
+        node_id,
+        id: None,
+        position: None,
+        size: None,
+        source_type: None,
+        mapping_id: None,
+    };
+
+    // Dictionaries are iterated as key, value, key, value...
+    let properties = properties.collect::<Vec<_>>();
+    for pair in properties.chunks(2) {
+        let [key, value] = pair else {
+            return Err(PortalError::Parse("stream properties"));
+        };
+        let value = unwrap_variant(*value);
+        match key.as_str() {
+            Some("id") => stream.id = value.as_str().map(str::to_owned),
+            Some("position") => stream.position = parse_pair(value),
+            Some("size") => stream.size = parse_pair(value),
+            Some("source_type") => {
+                stream.source_type = value.as_u64().map(|v| SourceType(v as u32))
+            }
+            Some("mapping_id") => stream.mapping_id = value.as_str().map(str::to_owned),
+            _ => {}
+        }
+    }
+
+    Ok(stream)
+}
+
+pub(crate) fn parse_started(results: &PropMap) -> Result<Started, PortalError> {
+    let streams = results
+        .get("streams")
+        .map(|v| unwrap_variant(&v.0))
+        .ok_or(PortalError::Parse("streams"))?
+        .as_iter()
+        .ok_or(PortalError::Parse("streams"))?
+        .map(parse_stream)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let restore_token = results
+        .get("restore_token")
+        .and_then(|v| v.0.as_str())
+        .map(str::to_owned);
+
+    Ok(Started {
+        streams,
+        restore_token,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{insert, parse_started, SourceType, StreamDescriptor};
+    use dbus::arg::PropMap;
+
+    #[test]
+    fn parse_start_response() {
+        let mut monitor = PropMap::new();
+        insert(&mut monitor, "id", "0".to_owned());
+        insert(&mut monitor, "position", (1920i32, 0i32));
+        insert(&mut monitor, "size", (1280i32, 1024i32));
+        insert(&mut monitor, "source_type", 1u32);
+        insert(&mut monitor, "mapping_id", "DP-1".to_owned());
+
+        let mut results = PropMap::new();
+        insert(
+            &mut results,
+            "streams",
+            vec![(42u32, monitor), (43u32, PropMap::new())],
+        );
+        insert(&mut results, "restore_token", "token".to_owned());
+
+        // Round-trip through a message, as received from the bus
+        let message = dbus::Message::new_signal("/", "org.example", "Test")
+            .unwrap()
+            .append1(results);
+        let results: PropMap = message.read1().unwrap();
+
+        let started = parse_started(&results).unwrap();
+        assert_eq!(started.restore_token.as_deref(), Some("token"));
+        assert_eq!(
+            started.streams,
+            vec![
+                StreamDescriptor {
+                    node_id: 42,
+                    id: Some("0".to_owned()),
+                    position: Some((1920, 0)),
+                    size: Some((1280, 1024)),
+                    source_type: Some(SourceType::MONITOR),
+                    mapping_id: Some("DP-1".to_owned()),
+                },
+                StreamDescriptor {
+                    node_id: 43,
+                    id: None,
+                    position: None,
+                    size: None,
+                    source_type: None,
+                    mapping_id: None,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,402 @@
+//! Lossless recording to YUV4MPEG2 or raw RGBA, with a sidecar file holding
+//! the presentation time of every frame.
+
+use crate::audio::{AudioAligner, AudioChunk, WavWriter};
+use crate::pipewire_stream::{self, Frame, TimedFrame};
+use crate::yuv::{chroma, luma};
+use std::cell::RefCell;
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RawFormat {
+    /// YUV4MPEG2 with 4:2:0 chroma, BT.601 limited range.
+    Y4m420,
+    /// YUV4MPEG2 without chroma subsampling.
+    Y4m444,
+    /// Tightly packed RGBA frames, the size is only in the sidecar.
+    Rgba,
+}
+
+impl RawFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "y4m" => Some(RawFormat::Y4m420),
+            "rgba" | "raw" => Some(RawFormat::Rgba),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            RawFormat::Y4m420 => "y4m",
+            RawFormat::Y4m444 => "y4m444",
+            RawFormat::Rgba => "rgba",
+        }
+    }
+}
+
+impl std::str::FromStr for RawFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "y4m" | "y4m420" => Ok(RawFormat::Y4m420),
+            "y4m444" => Ok(RawFormat::Y4m444),
+            "rgba" => Ok(RawFormat::Rgba),
+            _ => Err(format!(
+                "Unknown format '{s}', expected y4m, y4m444 or rgba"
+            )),
+        }
+    }
+}
+
+/// Format of the per-frame timestamp file written next to each segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sidecar {
+    Json,
+    Csv,
+}
+
+impl std::str::FromStr for Sidecar {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Sidecar::Json),
+            "csv" => Ok(Sidecar::Csv),
+            _ => Err(format!(
+                "Unknown sidecar format '{s}', expected json or csv"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RawRecorderConfig {
+    /// Path of the first segment, later ones get `-1`, `-2`, ... appended to
+    /// the file stem.
+    pub output: PathBuf,
+    pub format: RawFormat,
+    pub sidecar: Option<Sidecar>,
+    /// Nominal frame rate for the Y4M header, the sidecar has the real
+    /// timestamps.
+    pub fps: u32,
+}
+
+struct Segment {
+    path: PathBuf,
+    writer: std::io::BufWriter<std::fs::File>,
+    width: u32,
+    height: u32,
+    pts: Vec<Duration>,
+}
+
+/// Writes frames to the current segment, starting a new one whenever the
+/// frame size changes.
+pub struct RawRecorder {
+    config: RawRecorderConfig,
+    segment: Option<Segment>,
+    segments: Vec<PathBuf>,
+    yuv: Vec<u8>,
+}
+
+impl RawRecorder {
+    pub fn new(config: RawRecorderConfig) -> Self {
+        Self {
+            config,
+            segment: None,
+            segments: Vec::new(),
+            yuv: Vec::new(),
+        }
+    }
+
+    /// `pts` is the presentation time relative to the start of the
+    /// recording.
+    pub fn write_frame(&mut self, frame: &Frame, pts: Duration) -> std::io::Result<()> {
+        let size = (frame.width(), frame.height());
+        if self.segment.as_ref().map(|s| (s.width, s.height)) != Some(size) {
+            self.close_segment()?;
+            self.open_segment(size.0, size.1)?;
+        }
+        let segment = self.segment.as_mut().unwrap();
+        match self.config.format {
+            RawFormat::Y4m420 => {
+                rgba_to_i420(frame, &mut self.yuv);
+                segment.writer.write_all(b"FRAME\n")?;
+                segment.writer.write_all(&self.yuv)?;
+            }
+            RawFormat::Y4m444 => {
+                rgba_to_i444(frame, &mut self.yuv);
+                segment.writer.write_all(b"FRAME\n")?;
+                segment.writer.write_all(&self.yuv)?;
+            }
+            RawFormat::Rgba => segment.writer.write_all(frame.as_bytes())?,
+        }
+        segment.pts.push(pts);
+        Ok(())
+    }
+
+    /// Flushes the last segment and returns the paths of all segments.
+    pub fn finish(mut self) -> std::io::Result<Vec<PathBuf>> {
+        self.close_segment()?;
+        Ok(self.segments)
+    }
+
+    fn open_segment(&mut self, width: u32, height: u32) -> std::io::Result<()> {
+        let path = segment_path(&self.config.output, self.segments.len());
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        match self.config.format {
+            RawFormat::Y4m420 => writeln!(
+                writer,
+                "YUV4MPEG2 W{width} H{height} F{}:1 Ip A1:1 C420jpeg XCOLORRANGE=LIMITED",
+                self.config.fps
+            )?,
+            RawFormat::Y4m444 => writeln!(
+                writer,
+                "YUV4MPEG2 W{width} H{height} F{}:1 Ip A1:1 C444 XCOLORRANGE=LIMITED",
+                self.config.fps
+            )?,
+            RawFormat::Rgba => {}
+        }
+        self.segments.push(path.clone());
+        self.segment = Some(Segment {
+            path,
+            writer,
+            width,
+            height,
+            pts: Vec::new(),
+        });
+        Ok(())
+    }
+
+    fn close_segment(&mut self) -> std::io::Result<()> {
+        let Some(mut segment) = self.segment.take() else {
+            return Ok(());
+        };
+        segment.writer.flush()?;
+        let Some(sidecar) = self.config.sidecar else {
+            return Ok(());
+        };
+        let micros = |pts: &Duration| pts.as_micros();
+        let content = match sidecar {
+            Sidecar::Json => {
+                let frames = segment
+                    .pts
+                    .iter()
+                    .enumerate()
+                    .map(|(i, pts)| format!("{{\"frame\":{i},\"pts_us\":{}}}", micros(pts)))
+                    .collect::<Vec<_>>()
+                    .join(",\n    ");
+                format!(
+                    "{{\n  \"format\": \"{}\",\n  \"width\": {},\n  \"height\": {},\n  \"frames\": [\n    {frames}\n  ]\n}}\n",
+                    self.config.format.name(),
+                    segment.width,
+                    segment.height
+                )
+            }
+            Sidecar::Csv => {
+                let mut csv = String::from("frame,pts_us\n");
+                for (i, pts) in segment.pts.iter().enumerate() {
+                    csv.push_str(&format!("{i},{}\n", micros(pts)));
+                }
+                csv
+            }
+        };
+        std::fs::write(sidecar_path(&segment.path, sidecar), content)
+    }
+}
+
+/// `capture.y4m`, `capture-1.y4m`, `capture-2.y4m`, ...
+fn segment_path(output: &Path, index: usize) -> PathBuf {
+    if index == 0 {
+        return output.to_owned();
+    }
+    let mut name = output.file_stem().unwrap_or_default().to_owned();
+    name.push(format!("-{index}"));
+    if let Some(extension) = output.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    output.with_file_name(name)
+}
+
+/// `capture.y4m` -> `capture.y4m.pts.json`
+pub fn sidecar_path(segment: &Path, sidecar: Sidecar) -> PathBuf {
+    let mut path = OsString::from(segment);
+    path.push(match sidecar {
+        Sidecar::Json => ".pts.json",
+        Sidecar::Csv => ".pts.csv",
+    });
+    PathBuf::from(path)
+}
+
+/// Records frames until the channel closes or `stop` returns true. The
+/// sidecar times are the capture timestamps relative to the first frame.
+///
+/// With `audio`, the samples matching the video timeline go to a WAV file
+/// next to the first segment (`capture.y4m.wav`), which is returned last.
+pub fn record(
+    frames: &async_channel::Receiver<TimedFrame>,
+    audio: Option<&async_channel::Receiver<AudioChunk>>,
+    config: RawRecorderConfig,
+    stop: impl Fn() -> bool,
+) -> std::io::Result<Vec<PathBuf>> {
+    let mut wav_path = OsString::from(&config.output);
+    wav_path.push(".wav");
+    let wav_path = PathBuf::from(wav_path);
+    let wav = RefCell::new(None::<(WavWriter, AudioAligner)>);
+    let mut samples = Vec::new();
+    let mut audio_error = None;
+
+    let mut recorder = RawRecorder::new(config);
+    let mut start = None;
+    // Audio keeps coming while the screen is idle
+    let poll = || {
+        // Audio from before the first frame is dropped
+        while let Some(chunk) = audio.and_then(|audio| audio.try_recv().ok()) {
+            if let Some((wav, aligner)) = &mut *wav.borrow_mut() {
+                samples.clear();
+                aligner.align(&chunk, &mut samples);
+                if let Err(e) = wav.write(&samples) {
+                    audio_error = Some(e);
+                    return true;
+                }
+            }
+        }
+        stop()
+    };
+    pipewire_stream::for_each_frame(frames, poll, |TimedFrame { frame, pts, .. }| {
+        if frame.width() == 0 || frame.height() == 0 {
+            return Ok(());
+        }
+        let start = *start.get_or_insert(pts);
+        let mut wav = wav.borrow_mut();
+        if audio.is_some() && wav.is_none() {
+            *wav = Some((WavWriter::create(&wav_path)?, AudioAligner::new(start)));
+        }
+        recorder.write_frame(&frame, pts - start)
+    })?;
+    if let Some(e) = audio_error {
+        return Err(e);
+    }
+    let mut paths = recorder.finish()?;
+    if let Some((wav, _)) = wav.into_inner() {
+        wav.finish()?;
+        paths.push(wav_path);
+    }
+    Ok(paths)
+}
+
+/// Planar BT.601 limited range Y, U and V, chroma averaged over 2x2 blocks.
+pub fn rgba_to_i420(frame: &Frame, out: &mut Vec<u8>) {
+    let (width, height) = (frame.width() as usize, frame.height() as usize);
+    let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+    let pixels = frame.as_slice();
+    out.clear();
+    out.extend(
+        pixels
+            .iter()
+            .map(|p| luma(p.r as i32, p.g as i32, p.b as i32)),
+    );
+
+    let mut u_plane = Vec::with_capacity(chroma_width * chroma_height);
+    let mut v_plane = Vec::with_capacity(chroma_width * chroma_height);
+    for cy in 0..chroma_height {
+        for cx in 0..chroma_width {
+            let (mut r, mut g, mut b, mut count) = (0, 0, 0, 0);
+            for y in cy * 2..(cy * 2 + 2).min(height) {
+                for x in cx * 2..(cx * 2 + 2).min(width) {
+                    let p = pixels[y * width + x];
+                    r += p.r as i32;
+                    g += p.g as i32;
+                    b += p.b as i32;
+                    count += 1;
+                }
+            }
+            let (u, v) = chroma(r / count, g / count, b / count);
+            u_plane.push(u);
+            v_plane.push(v);
+        }
+    }
+    out.extend_from_slice(&u_plane);
+    out.extend_from_slice(&v_plane);
+}
+
+/// Planar BT.601 limited range Y, U and V at full resolution.
+pub fn rgba_to_i444(frame: &Frame, out: &mut Vec<u8>) {
+    let pixels = frame.as_slice();
+    out.clear();
+    out.extend(
+        pixels
+            .iter()
+            .map(|p| luma(p.r as i32, p.g as i32, p.b as i32)),
+    );
+    let chroma = pixels
+        .iter()
+        .map(|p| chroma(p.r as i32, p.g as i32, p.b as i32))
+        .collect::<Vec<_>>();
+    out.extend(chroma.iter().map(|c| c.0));
+    out.extend(chroma.iter().map(|c| c.1));
+}
+
+#[cfg(test)]
+mod test {
+    use super::{rgba_to_i420, sidecar_path, RawFormat, RawRecorder, RawRecorderConfig, Sidecar};
+    use crate::pipewire_stream::Frame;
+    use std::time::Duration;
+
+    fn solid(width: u32, height: u32, value: u8) -> Frame {
+        let mut frame = Frame::new(width, height);
+        for pixel in frame.make_mut_slice() {
+            *pixel = slint::Rgba8Pixel::new(value, value, value, 255);
+        }
+        frame
+    }
+
+    #[test]
+    fn i420_conversion() {
+        let mut frame = solid(3, 2, 255);
+        frame.make_mut_slice()[0] = slint::Rgba8Pixel::new(0, 0, 0, 255);
+        let mut yuv = Vec::new();
+        rgba_to_i420(&frame, &mut yuv);
+        // 6 luma samples, then 2x1 U and V
+        assert_eq!(yuv, [16, 235, 235, 235, 235, 235, 128, 128, 128, 128]);
+    }
+
+    #[test]
+    fn segments_on_resolution_change() {
+        let dir = std::env::temp_dir().join(format!("screencast-recorder-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("capture.y4m");
+        let mut recorder = RawRecorder::new(RawRecorderConfig {
+            output: output.clone(),
+            format: RawFormat::Y4m420,
+            sidecar: Some(Sidecar::Csv),
+            fps: 30,
+        });
+        for i in 0..2 {
+            recorder
+                .write_frame(&solid(4, 2, 0), Duration::from_millis(i * 40))
+                .unwrap();
+        }
+        recorder
+            .write_frame(&solid(2, 2, 255), Duration::from_millis(80))
+            .unwrap();
+        let segments = recorder.finish().unwrap();
+        assert_eq!(segments, [output.clone(), dir.join("capture-1.y4m")]);
+
+        let first = std::fs::read(&output).unwrap();
+        let header = b"YUV4MPEG2 W4 H2 F30:1 Ip A1:1 C420jpeg XCOLORRANGE=LIMITED\n";
+        assert!(first.starts_with(header));
+        assert_eq!(first.len(), header.len() + 2 * (6 + 8 + 2 + 2));
+        let csv = std::fs::read_to_string(sidecar_path(&output, Sidecar::Csv)).unwrap();
+        assert_eq!(csv, "frame,pts_us\n0,0\n1,40000\n");
+        let csv = std::fs::read_to_string(sidecar_path(&segments[1], Sidecar::Csv)).unwrap();
+        assert_eq!(csv, "frame,pts_us\n0,80000\n");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,353 @@
+//! Input injection through `org.freedesktop.portal.RemoteDesktop`
+//! (version 2), together with a screen cast of the same session.
+//!
+//! ```no_run
+//! # use screencast::portal::{Portal, SourceOptions, SourceType};
+//! # use screencast::remote_desktop::{DeviceOptions, DeviceType, RemoteDesktop, StreamSpace, BTN_LEFT};
+//! # fn test() -> Result<(), screencast::portal::PortalError> {
+//! let portal = Portal::new()?;
+//! let remote = RemoteDesktop::create(&portal)?;
+//! remote.select_devices(&DeviceOptions {
+//!     types: DeviceType::KEYBOARD | DeviceType::POINTER,
+//!     ..Default::default()
+//! })?;
+//! remote.session().select_sources(&SourceOptions {
+//!     types: SourceType::MONITOR,
+//!     ..Default::default()
+//! })?;
+//! let started = remote.start("")?;
+//! let space = StreamSpace::new(&started.streams[0], (1920, 1080));
+//! remote.move_pointer(&space, 100.0, 200.0)?;
+//! remote.notify_pointer_button(BTN_LEFT, true)?;
+//! remote.notify_pointer_button(BTN_LEFT, false)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::frame_transform::Rect;
+use crate::portal::{
+    insert, parse_started, PersistMode, Portal, PortalError, Session, StreamDescriptor,
+};
+use dbus::arg::{AppendAll, PropMap};
+use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+
+pub const REMOTE_DESKTOP_INTERFACE: &str = "org.freedesktop.portal.RemoteDesktop";
+
+/// Linux evdev codes for `NotifyPointerButton`.
+pub const BTN_LEFT: i32 = 0x110;
+pub const BTN_RIGHT: i32 = 0x111;
+pub const BTN_MIDDLE: i32 = 0x112;
+
+/// Bit set of input devices accepted by `SelectDevices`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DeviceType(pub u32);
+
+impl DeviceType {
+    pub const KEYBOARD: Self = Self(1);
+    pub const POINTER: Self = Self(2);
+    pub const TOUCHSCREEN: Self = Self(4);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for DeviceType {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Scroll axis of `NotifyPointerAxisDiscrete`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    Vertical = 0,
+    Horizontal = 1,
+}
+
+/// Options of `SelectDevices`. An empty `types` is not sent, so the portal
+/// offers all devices.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceOptions {
+    pub types: DeviceType,
+    /// Restores devices and sources of an earlier session (version 2).
+    pub restore_token: Option<String>,
+    pub persist_mode: PersistMode,
+}
+
+/// Result of `RemoteDesktop.Start`.
+#[derive(Debug, Clone, Default)]
+pub struct RemoteStarted {
+    /// The devices the user allowed.
+    pub devices: DeviceType,
+    /// Streams of the sources selected through ScreenCast, if any.
+    pub streams: Vec<StreamDescriptor>,
+    pub restore_token: Option<String>,
+}
+
+impl Portal {
+    pub fn remote_desktop_version(&self) -> Result<u32, PortalError> {
+        Ok(self.proxy().get(REMOTE_DESKTOP_INTERFACE, "version")?)
+    }
+
+    pub fn available_device_types(&self) -> Result<DeviceType, PortalError> {
+        Ok(DeviceType(
+            self.proxy()
+                .get(REMOTE_DESKTOP_INTERFACE, "AvailableDeviceTypes")?,
+        ))
+    }
+}
+
+/// Where a position in a captured frame is on the stream.
+///
+/// `NotifyPointerMotionAbsolute` takes logical coordinates of the stream,
+/// which differ from frame pixels with fractional scaling, and
+/// `PipewireStream` may have cropped and scaled the frames on top.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamSpace {
+    /// PipeWire node id of the stream, which names it in the notify calls.
+    pub stream: u32,
+    /// Logical size from `Start`, the buffer size if the portal sent none.
+    pub logical_size: (u32, u32),
+    /// Size of the buffers from PipeWire, before cropping and scaling.
+    pub buffer_size: (u32, u32),
+    /// Crop set with `PipewireStream::set_crop`, in buffer pixels.
+    pub crop: Option<Rect>,
+    /// Size of the frames, as set with `PipewireStream::set_output_size`.
+    pub frame_size: (u32, u32),
+}
+
+impl StreamSpace {
+    /// Untransformed frames of `stream`, whose buffers are `buffer_size`.
+    pub fn new(stream: &StreamDescriptor, buffer_size: (u32, u32)) -> Self {
+        let logical_size = stream
+            .size
+            .filter(|&(w, h)| w > 0 && h > 0)
+            .map_or(buffer_size, |(w, h)| (w as u32, h as u32));
+        Self {
+            stream: stream.node_id,
+            logical_size,
+            buffer_size,
+            crop: None,
+            frame_size: buffer_size,
+        }
+    }
+
+    /// Logical stream coordinates of frame position `x`, `y`, kept inside
+    /// the stream.
+    pub fn to_stream(&self, x: f64, y: f64) -> (f64, f64) {
+        let crop = self.crop.unwrap_or(Rect {
+            x: 0,
+            y: 0,
+            width: self.buffer_size.0,
+            height: self.buffer_size.1,
+        });
+        (
+            to_logical(
+                x,
+                self.frame_size.0,
+                crop.x,
+                crop.width,
+                self.buffer_size.0,
+                self.logical_size.0,
+            ),
+            to_logical(
+                y,
+                self.frame_size.1,
+                crop.y,
+                crop.height,
+                self.buffer_size.1,
+                self.logical_size.1,
+            ),
+        )
+    }
+}
+
+/// One axis of [`StreamSpace::to_stream`]: frame to buffer to logical.
+fn to_logical(value: f64, frame: u32, offset: u32, cropped: u32, buffer: u32, logical: u32) -> f64 {
+    let buffer_position = offset as f64 + value * cropped as f64 / frame.max(1) as f64;
+    let position = buffer_position * logical as f64 / buffer.max(1) as f64;
+    position.clamp(0.0, logical.saturating_sub(1) as f64)
+}
+
+/// RemoteDesktop session, which can also carry a screen cast: select the
+/// sources on [`session`](Self::session) before [`start`](Self::start).
+pub struct RemoteDesktop {
+    session: Session,
+}
+
+impl RemoteDesktop {
+    pub fn create(portal: &Portal) -> Result<Self, PortalError> {
+        Ok(Self {
+            session: portal.create_session_on(REMOTE_DESKTOP_INTERFACE)?,
+        })
+    }
+
+    /// The underlying session, for `select_sources` and
+    /// `open_pipewire_remote`.
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    pub fn select_devices(&self, options: &DeviceOptions) -> Result<(), PortalError> {
+        self.session.portal().request(|handle_token, proxy| {
+            let mut args = PropMap::new();
+            insert(&mut args, "handle_token", handle_token.to_owned());
+            if options.types.0 != 0 {
+                insert(&mut args, "types", options.types.0);
+            }
+            if let Some(restore_token) = &options.restore_token {
+                insert(&mut args, "restore_token", restore_token.clone());
+            }
+            if options.persist_mode != PersistMode::DoNot {
+                insert(&mut args, "persist_mode", options.persist_mode as u32);
+            }
+            proxy.method_call::<(dbus::Path,), _, _, _>(
+                REMOTE_DESKTOP_INTERFACE,
+                "SelectDevices",
+                (self.session.handle(), args),
+            )?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Starts the session. This replaces `Session::start`, which would start
+    /// a screen cast only.
+    pub fn start(&self, parent_window: &str) -> Result<RemoteStarted, PortalError> {
+        let mut results = self
+            .session
+            .start_on(REMOTE_DESKTOP_INTERFACE, parent_window)?;
+        let devices = results
+            .get("devices")
+            .and_then(|v| v.0.as_u64())
+            .ok_or(PortalError::Parse("devices"))?;
+        // Sessions without sources have no streams
+        if !results.contains_key("streams") {
+            insert(&mut results, "streams", Vec::<(u32, PropMap)>::new());
+        }
+        let started = parse_started(&results)?;
+        Ok(RemoteStarted {
+            devices: DeviceType(devices as u32),
+            streams: started.streams,
+            restore_token: started.restore_token,
+        })
+    }
+
+    fn call<A: AppendAll>(&self, method: &str, args: A) -> Result<(), PortalError> {
+        self.session.portal().proxy().method_call::<(), _, _, _>(
+            REMOTE_DESKTOP_INTERFACE,
+            method,
+            args,
+        )?;
+        Ok(())
+    }
+
+    fn handle(&self) -> &dbus::Path<'static> {
+        self.session.handle()
+    }
+
+    /// Moves the pointer by `dx`, `dy` logical pixels.
+    pub fn notify_pointer_motion(&self, dx: f64, dy: f64) -> Result<(), PortalError> {
+        self.call(
+            "NotifyPointerMotion",
+            (self.handle(), PropMap::new(), dx, dy),
+        )
+    }
+
+    /// Moves the pointer to `x`, `y` in logical coordinates of `stream`.
+    pub fn notify_pointer_motion_absolute(
+        &self,
+        stream: u32,
+        x: f64,
+        y: f64,
+    ) -> Result<(), PortalError> {
+        self.call(
+            "NotifyPointerMotionAbsolute",
+            (self.handle(), PropMap::new(), stream, x, y),
+        )
+    }
+
+    /// Moves the pointer to position `x`, `y` of a frame in `space`.
+    pub fn move_pointer(&self, space: &StreamSpace, x: f64, y: f64) -> Result<(), PortalError> {
+        let (x, y) = space.to_stream(x, y);
+        self.notify_pointer_motion_absolute(space.stream, x, y)
+    }
+
+    /// Presses or releases `button`, an evdev code like [`BTN_LEFT`].
+    pub fn notify_pointer_button(&self, button: i32, pressed: bool) -> Result<(), PortalError> {
+        self.call(
+            "NotifyPointerButton",
+            (self.handle(), PropMap::new(), button, pressed as u32),
+        )
+    }
+
+    /// Smooth scrolling by `dx`, `dy`. `finish` ends the scroll sequence,
+    /// for kinetic scrolling.
+    pub fn notify_pointer_axis(&self, dx: f64, dy: f64, finish: bool) -> Result<(), PortalError> {
+        let mut options = PropMap::new();
+        insert(&mut options, "finish", finish);
+        self.call("NotifyPointerAxis", (self.handle(), options, dx, dy))
+    }
+
+    /// Scrolls by `steps` wheel clicks, positive down or right.
+    pub fn notify_pointer_axis_discrete(&self, axis: Axis, steps: i32) -> Result<(), PortalError> {
+        self.call(
+            "NotifyPointerAxisDiscrete",
+            (self.handle(), PropMap::new(), axis as u32, steps),
+        )
+    }
+
+    /// Presses or releases the key with evdev `keycode`.
+    pub fn notify_keyboard_keycode(&self, keycode: i32, pressed: bool) -> Result<(), PortalError> {
+        self.call(
+            "NotifyKeyboardKeycode",
+            (self.handle(), PropMap::new(), keycode, pressed as u32),
+        )
+    }
+
+    /// Presses or releases the key producing X11 `keysym`, as sent by VNC
+    /// clients.
+    pub fn notify_keyboard_keysym(&self, keysym: i32, pressed: bool) -> Result<(), PortalError> {
+        self.call(
+            "NotifyKeyboardKeysym",
+            (self.handle(), PropMap::new(), keysym, pressed as u32),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::StreamSpace;
+    use crate::frame_transform::Rect;
+    use crate::portal::StreamDescriptor;
+
+    #[test]
+    fn frame_positions_map_to_stream() {
+        let stream = StreamDescriptor {
+            node_id: 42,
+            id: None,
+            position: Some((1920, 0)),
+            size: Some((1280, 720)),
+            source_type: None,
+            mapping_id: None,
+        };
+        // Scaled by 1.5, so the buffers are larger than the logical size
+        let mut space = StreamSpace::new(&stream, (1920, 1080));
+        assert_eq!(space.to_stream(960.0, 540.0), (640.0, 360.0));
+        assert_eq!(space.to_stream(-5.0, 5000.0), (0.0, 719.0));
+
+        // Right half, scaled down to 480x540
+        space.crop = Some(Rect {
+            x: 960,
+            y: 0,
+            width: 960,
+            height: 1080,
+        });
+        space.frame_size = (480, 540);
+        assert_eq!(space.to_stream(0.0, 0.0), (640.0, 0.0));
+        assert_eq!(space.to_stream(240.0, 270.0), (960.0, 360.0));
+    }
+}
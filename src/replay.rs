@@ -0,0 +1,245 @@
+//! "Instant replay": the last seconds of capture kept in memory as QOI
+//! compressed frames, ready to be written out through any of the sinks.
+
+use crate::clip::{Clip, ClipFormat};
+use crate::export;
+use crate::ffmpeg::{self, FfmpegConfig, FfmpegSink};
+use crate::frame_transform;
+use crate::pipewire_stream::Frame;
+use crate::recorder::{RawFormat, RawRecorder, RawRecorderConfig};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct ReplayConfig {
+    /// How much capture to keep.
+    pub window: Duration,
+    /// Upper bound for the compressed frames, the oldest frames are dropped
+    /// when it is reached.
+    pub budget: usize,
+    /// Downscale frames to fit inside this size before storing them.
+    pub max_size: Option<(u32, u32)>,
+    /// Frame rate of video files written with ffmpeg.
+    pub frame_rate: u32,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(30),
+            budget: 256 << 20,
+            max_size: None,
+            frame_rate: 30,
+        }
+    }
+}
+
+struct Entry {
+    pts: Duration,
+    qoi: Vec<u8>,
+}
+
+pub struct ReplayBuffer {
+    config: ReplayConfig,
+    entries: VecDeque<Entry>,
+    bytes: usize,
+}
+
+impl ReplayBuffer {
+    pub fn new(config: ReplayConfig) -> Self {
+        Self {
+            config,
+            entries: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Stores a frame captured at `pts`, dropping frames that fell out of the
+    /// window or the memory budget.
+    pub fn push(&mut self, frame: &Frame, pts: Duration) {
+        let scaled = self.config.max_size.and_then(|(max_width, max_height)| {
+            let factor = f64::min(
+                max_width as f64 / frame.width() as f64,
+                max_height as f64 / frame.height() as f64,
+            );
+            (factor < 1.0).then(|| {
+                let width = ((frame.width() as f64 * factor) as u32).max(1);
+                let height = ((frame.height() as f64 * factor) as u32).max(1);
+                frame_transform::scale(frame, width, height)
+            })
+        });
+        let mut qoi = Vec::new();
+        // Writing to a Vec does not fail
+        export::write_qoi(&mut qoi, scaled.as_ref().unwrap_or(frame)).unwrap();
+
+        self.bytes += qoi.len();
+        self.entries.push_back(Entry { pts, qoi });
+        while let Some(front) = self.entries.front() {
+            let expired = pts.saturating_sub(front.pts) > self.config.window;
+            if !expired && (self.bytes <= self.config.budget || self.entries.len() == 1) {
+                break;
+            }
+            self.bytes -= front.qoi.len();
+            self.entries.pop_front();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Memory used by the compressed frames.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Time between the oldest and the newest frame.
+    pub fn span(&self) -> Duration {
+        match (self.entries.front(), self.entries.back()) {
+            (Some(front), Some(back)) => back.pts - front.pts,
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Decoded frames with timestamps relative to the oldest one.
+    pub fn frames(&self) -> impl Iterator<Item = (Frame, Duration)> + '_ {
+        let start = self.entries.front().map(|e| e.pts).unwrap_or_default();
+        self.entries.iter().map(move |entry| {
+            // Only holds what write_qoi produced
+            let frame = export::read_qoi(&mut entry.qoi.as_slice()).unwrap();
+            (frame, entry.pts - start)
+        })
+    }
+
+    /// Writes the buffered frames to `path`: a GIF/APNG clip, raw Y4M/RGBA,
+    /// or a video file through ffmpeg, picked by extension.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let unsupported =
+            |message: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+        if self.is_empty() {
+            return Err(unsupported("Replay buffer is empty".to_owned()));
+        }
+
+        if ClipFormat::from_path(path).is_some() {
+            let mut clip = Clip::new();
+            for (frame, pts) in self.frames() {
+                clip.push(frame, pts);
+            }
+            return clip.save(path);
+        }
+        if let Some(format) = RawFormat::from_path(path) {
+            let mut recorder = RawRecorder::new(RawRecorderConfig {
+                output: path.to_owned(),
+                format,
+                sidecar: None,
+                fps: self.config.frame_rate,
+            });
+            for (frame, pts) in self.frames() {
+                recorder.write_frame(&frame, pts)?;
+            }
+            return recorder.finish().map(|_| ());
+        }
+        if ffmpeg::is_video_path(path) {
+            return self.save_video(path);
+        }
+        Err(unsupported(format!(
+            "Unknown replay format of {}",
+            path.display()
+        )))
+    }
+
+    /// ffmpeg gets frames much faster than real time, so they are repeated
+    /// or skipped to a constant rate.
+    fn save_video(&self, path: &Path) -> std::io::Result<()> {
+        let rate = self.config.frame_rate.max(1);
+        let config = FfmpegConfig {
+            frame_rate: rate,
+            ..FfmpegConfig::new(path.to_owned())
+        };
+        let mut frames = self.frames().peekable();
+        let (mut current, _) = frames.next().unwrap();
+        let mut sink =
+            FfmpegSink::spawn(&config, current.width(), current.height(), Duration::ZERO)?;
+        let ticks = (self.span().as_secs_f64() * rate as f64).round() as u32;
+        for tick in 0..=ticks {
+            let time = Duration::from_secs_f64(tick as f64 / rate as f64);
+            while let Some((frame, _)) = frames.next_if(|(_, pts)| *pts <= time) {
+                current = frame;
+            }
+            sink.write_frame(&current)?;
+        }
+        let status = sink.finish()?;
+        if !status.success() {
+            return Err(std::io::Error::other(format!(
+                "ffmpeg failed with {status}"
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ReplayBuffer, ReplayConfig};
+    use crate::pipewire_stream::Frame;
+    use std::time::Duration;
+
+    fn frame(value: u8) -> Frame {
+        let mut frame = Frame::new(64, 32);
+        for (i, pixel) in frame.make_mut_slice().iter_mut().enumerate() {
+            *pixel = slint::Rgba8Pixel::new(value, (i * 7) as u8, (i / 64) as u8, 255);
+        }
+        frame
+    }
+
+    #[test]
+    fn window_and_budget() {
+        let mut replay = ReplayBuffer::new(ReplayConfig {
+            window: Duration::from_secs(1),
+            max_size: Some((32, 32)),
+            ..Default::default()
+        });
+        for i in 0..30 {
+            replay.push(&frame(i), Duration::from_millis(i as u64 * 100));
+        }
+        // 1.9 s to 2.9 s
+        assert_eq!(replay.len(), 11);
+        assert_eq!(replay.span(), Duration::from_secs(1));
+        let (first, pts) = replay.frames().next().unwrap();
+        assert_eq!(
+            (first.width(), first.height(), pts),
+            (32, 16, Duration::ZERO)
+        );
+        assert_eq!(first.as_slice()[0].r, 19);
+
+        let frame_bytes = replay.bytes() / replay.len();
+        let mut replay = ReplayBuffer::new(ReplayConfig {
+            budget: frame_bytes * 5,
+            max_size: Some((32, 32)),
+            ..Default::default()
+        });
+        for i in 0..30 {
+            replay.push(&frame(i), Duration::from_millis(i as u64 * 100));
+        }
+        assert!(replay.len() <= 5 && replay.len() >= 4);
+        assert!(replay.bytes() <= frame_bytes * 5);
+    }
+
+    #[test]
+    fn save_raw() {
+        let mut replay = ReplayBuffer::new(ReplayConfig::default());
+        for i in 0..3 {
+            replay.push(&frame(i), Duration::from_millis(i as u64 * 40));
+        }
+        let path =
+            std::env::temp_dir().join(format!("screencast-replay-{}.rgba", std::process::id()));
+        replay.save(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 3 * 64 * 32 * 4);
+        std::fs::remove_file(path).unwrap();
+    }
+}
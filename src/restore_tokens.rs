@@ -0,0 +1,131 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Portal restore tokens stored per user, keyed by a session name.
+///
+/// The file holds one `name=token` pair per line, so names with `=` or line
+/// breaks are rejected rather than stored.
+#[derive(Debug, Clone)]
+pub struct TokenStore {
+    path: PathBuf,
+}
+
+impl TokenStore {
+    /// Store at `$XDG_CONFIG_HOME/screencast/restore_tokens`, falling back to
+    /// `~/.config`.
+    pub fn open_default() -> Option<Self> {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(Self::open(
+            config_dir.join("screencast").join("restore_tokens"),
+        ))
+    }
+
+    pub fn open(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether `name` can be stored: not empty, no `=`, no line breaks and
+    /// no surrounding whitespace, which reading trims off.
+    pub fn is_valid_name(name: &str) -> bool {
+        !name.is_empty() && name.trim() == name && !name.contains(['=', '\n', '\r'])
+    }
+
+    pub fn load(&self, name: &str) -> Option<String> {
+        self.read().remove(name)
+    }
+
+    pub fn save(&self, name: &str, token: &str) -> std::io::Result<()> {
+        if !Self::is_valid_name(name) || token.contains(['\n', '\r']) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("cannot store a restore token for {name:?}"),
+            ));
+        }
+        let mut tokens = self.read();
+        tokens.insert(name.to_owned(), token.to_owned());
+        self.write(&tokens)
+    }
+
+    pub fn remove(&self, name: &str) -> std::io::Result<()> {
+        let mut tokens = self.read();
+        if tokens.remove(name).is_some() {
+            self.write(&tokens)?;
+        }
+        Ok(())
+    }
+
+    fn read(&self) -> BTreeMap<String, String> {
+        let Ok(content) = std::fs::read_to_string(&self.path) else {
+            return BTreeMap::new();
+        };
+        content
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(name, token)| (name.trim().to_owned(), token.trim().to_owned()))
+            .collect()
+    }
+
+    fn write(&self, tokens: &BTreeMap<String, String>) -> std::io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let content = tokens
+            .iter()
+            .map(|(name, token)| format!("{name}={token}\n"))
+            .collect::<String>();
+        // Replace atomically so a crash does not lose the other sessions
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, content)?;
+        std::fs::rename(tmp_path, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TokenStore;
+
+    #[test]
+    fn save_load_remove() {
+        let dir = std::env::temp_dir().join(format!("screencast-tokens-{}", std::process::id()));
+        let store = TokenStore::open(dir.join("restore_tokens"));
+
+        assert_eq!(store.load("main"), None);
+        store.save("main", "abc").unwrap();
+        store.save("second", "def").unwrap();
+        store.save("main", "ghi").unwrap();
+        assert_eq!(store.load("main").as_deref(), Some("ghi"));
+        assert_eq!(store.load("second").as_deref(), Some("def"));
+
+        store.remove("main").unwrap();
+        assert_eq!(store.load("main"), None);
+        assert_eq!(store.load("second").as_deref(), Some("def"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_names_breaking_lines() {
+        let dir = std::env::temp_dir().join(format!("screencast-names-{}", std::process::id()));
+        let store = TokenStore::open(dir.join("restore_tokens"));
+        store.save("main", "abc").unwrap();
+
+        for name in ["a=b", "other\nmain=evil", "", " main"] {
+            assert!(!TokenStore::is_valid_name(name));
+            let error = store.save(name, "def").unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        }
+        assert!(store.save("second", "def\nmain=evil").is_err());
+        assert_eq!(store.load("main").as_deref(), Some("abc"));
+        assert_eq!(store.load("a"), None);
+        assert_eq!(store.load("other"), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,1013 @@
+//! VNC server for the captured stream: RFB 3.8 over TCP, also accepting
+//! 3.3 and 3.7 clients. The desktop is view-only unless
+//! [`RfbConfig::view_only`] is off, in which case key and pointer events are
+//! handed to the server's owner as [`InputEvent`]s, e.g. to forward them
+//! through the RemoteDesktop portal.
+//!
+//! Updates use ZRLE, Hextile or Raw, whichever the client lists first, and
+//! only cover what changed: the producer's damage where it reports some,
+//! otherwise the tiles that differ from the previous frame.
+//!
+//! There is no authentication (security type None), so bind to localhost
+//! and tunnel the port to reach it from elsewhere.
+
+use crate::frame_transform::Rect;
+use crate::pipewire_stream::{self, Frame, TimedFrame};
+use miniz_oxide::deflate::core::CompressorOxide;
+use miniz_oxide::MZFlush;
+use std::collections::VecDeque;
+use std::io::{BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+pub const ENCODING_RAW: i32 = 0;
+pub const ENCODING_HEXTILE: i32 = 5;
+pub const ENCODING_ZRLE: i32 = 16;
+/// Pseudo-encoding of clients that follow changes of the desktop size.
+pub const ENCODING_DESKTOP_SIZE: i32 = -223;
+
+const SECURITY_NONE: u8 = 1;
+
+const HEXTILE_RAW: u8 = 1;
+const HEXTILE_BACKGROUND: u8 = 2;
+const HEXTILE_FOREGROUND: u8 = 4;
+const HEXTILE_ANY_SUBRECTS: u8 = 8;
+const HEXTILE_SUBRECTS_COLOURED: u8 = 16;
+
+/// Frames whose damage is kept for clients that fall behind.
+const DAMAGE_HISTORY: usize = 64;
+/// Pending rectangles of a client beyond this are merged into one.
+const MAX_DIRTY_RECTS: usize = 64;
+/// Tile size for finding changes in frames without damage.
+const DIFF_TILE: u32 = 64;
+
+#[derive(Debug, Clone)]
+pub struct RfbConfig {
+    pub bind: SocketAddr,
+    /// Desktop name shown by clients.
+    pub name: String,
+    /// Drop key and pointer events instead of passing them on.
+    pub view_only: bool,
+}
+
+impl Default for RfbConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([127, 0, 0, 1], 5900)),
+            name: "Screencast".to_owned(),
+            view_only: true,
+        }
+    }
+}
+
+fn invalid(message: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.into())
+}
+
+/// How a client wants its pixels, always true colour.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PixelFormat {
+    bits_per_pixel: u8,
+    depth: u8,
+    big_endian: bool,
+    /// Red, green and blue.
+    max: [u16; 3],
+    shift: [u8; 3],
+}
+
+impl PixelFormat {
+    /// What the server announces: 32 bit little-endian xRGB.
+    const DEFAULT: Self = Self {
+        bits_per_pixel: 32,
+        depth: 24,
+        big_endian: false,
+        max: [255; 3],
+        shift: [16, 8, 0],
+    };
+
+    fn read(bytes: &[u8; 16]) -> std::io::Result<Self> {
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        if bytes[3] == 0 {
+            return Err(invalid("Colour map pixel formats are not supported"));
+        }
+        if ![8, 16, 32].contains(&bytes[0]) {
+            return Err(invalid(format!("Unsupported {} bits per pixel", bytes[0])));
+        }
+        Ok(Self {
+            bits_per_pixel: bytes[0],
+            depth: bytes[1],
+            big_endian: bytes[2] != 0,
+            max: [u16_at(4), u16_at(6), u16_at(8)],
+            shift: [bytes[10], bytes[11], bytes[12]],
+        })
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend([self.bits_per_pixel, self.depth, self.big_endian as u8, 1]);
+        for max in self.max {
+            out.extend(max.to_be_bytes());
+        }
+        out.extend(self.shift);
+        out.extend([0; 3]);
+    }
+
+    fn bytes_per_pixel(&self) -> usize {
+        self.bits_per_pixel as usize / 8
+    }
+
+    fn value(&self, pixel: slint::Rgba8Pixel) -> u32 {
+        [pixel.r, pixel.g, pixel.b]
+            .into_iter()
+            .zip(self.max)
+            .zip(self.shift)
+            .fold(0, |value, ((channel, max), shift)| {
+                let channel = (channel as u32 * max as u32 + 127) / 255;
+                value | channel.checked_shl(shift as u32).unwrap_or(0)
+            })
+    }
+
+    /// `value` as a PIXEL in the client's byte order.
+    fn put(&self, out: &mut Vec<u8>, value: u32) {
+        let n = self.bytes_per_pixel();
+        if self.big_endian {
+            out.extend(&value.to_be_bytes()[4 - n..]);
+        } else {
+            out.extend(&value.to_le_bytes()[..n]);
+        }
+    }
+
+    /// The bytes of a PIXEL that make up a ZRLE CPIXEL: three for 32 bit
+    /// pixels whose colour fits into the upper or lower three bytes.
+    fn compressed_bytes(&self) -> std::ops::Range<usize> {
+        let n = self.bytes_per_pixel();
+        if n != 4 || self.depth > 24 {
+            return 0..n;
+        }
+        let mask = (0..3).fold(0u32, |mask, i| {
+            mask | (self.max[i] as u32)
+                .checked_shl(self.shift[i] as u32)
+                .unwrap_or(0)
+        });
+        let low = mask <= 0xff_ffff;
+        let high = mask & 0xff == 0;
+        match (low, high, self.big_endian) {
+            (true, _, false) | (false, true, true) => 0..3,
+            (true, _, true) | (false, true, false) => 1..4,
+            _ => 0..4,
+        }
+    }
+
+    fn put_compressed(&self, out: &mut Vec<u8>, value: u32) {
+        let bytes = if self.big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        };
+        match self.bytes_per_pixel() {
+            4 => out.extend(&bytes[self.compressed_bytes()]),
+            _ => self.put(out, value),
+        }
+    }
+}
+
+/// Pixel values of `rect`, black where it reaches outside of `frame`.
+fn pixels(frame: &Frame, rect: Rect, format: &PixelFormat) -> Vec<u32> {
+    let data = frame.as_slice();
+    let mut pixels = Vec::with_capacity(rect.width as usize * rect.height as usize);
+    for y in rect.y..rect.y + rect.height {
+        for x in rect.x..rect.x + rect.width {
+            pixels.push(match x < frame.width() && y < frame.height() {
+                true => format.value(data[(y * frame.width() + x) as usize]),
+                false => 0,
+            });
+        }
+    }
+    pixels
+}
+
+/// The pixels of `tile` out of those of a `width` pixels wide rectangle.
+fn tile(pixels: &[u32], width: u32, tile: Rect) -> Vec<u32> {
+    (tile.y..tile.y + tile.height)
+        .flat_map(|y| {
+            let start = (y * width + tile.x) as usize;
+            pixels[start..start + tile.width as usize].iter().copied()
+        })
+        .collect()
+}
+
+/// Rectangles of at most `size` pixels covering `width` x `height`, row by
+/// row.
+fn tiles(width: u32, height: u32, size: u32) -> impl Iterator<Item = Rect> {
+    (0..height).step_by(size as usize).flat_map(move |y| {
+        (0..width).step_by(size as usize).map(move |x| Rect {
+            x,
+            y,
+            width: size.min(width - x),
+            height: size.min(height - y),
+        })
+    })
+}
+
+fn most_common(pixels: &[u32]) -> u32 {
+    let mut sorted = pixels.to_vec();
+    sorted.sort_unstable();
+    sorted
+        .chunk_by(|a, b| a == b)
+        .max_by_key(|run| run.len())
+        .map_or(0, |run| run[0])
+}
+
+/// Covers the pixels of `tile` that are not `background` with solid
+/// rectangles, grown greedily to the right and then down.
+fn subrects(tile: &[u32], width: u32, height: u32, background: u32) -> Vec<(u32, Rect)> {
+    let at = |x: u32, y: u32| (y * width + x) as usize;
+    let mut covered = vec![false; tile.len()];
+    let mut rects = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            let colour = tile[at(x, y)];
+            if colour == background || covered[at(x, y)] {
+                continue;
+            }
+            let free =
+                |x: u32, y: u32, covered: &[bool]| tile[at(x, y)] == colour && !covered[at(x, y)];
+            let mut w = 1;
+            while x + w < width && free(x + w, y, &covered) {
+                w += 1;
+            }
+            let mut h = 1;
+            while y + h < height && (x..x + w).all(|x| free(x, y + h, &covered)) {
+                h += 1;
+            }
+            for cy in y..y + h {
+                for cx in x..x + w {
+                    covered[at(cx, cy)] = true;
+                }
+            }
+            rects.push((
+                colour,
+                Rect {
+                    x,
+                    y,
+                    width: w,
+                    height: h,
+                },
+            ));
+        }
+    }
+    rects
+}
+
+/// One Hextile tile, raw when the subrectangles would not be smaller. The
+/// background is always sent, so tiles do not depend on each other.
+fn hextile_tile(out: &mut Vec<u8>, format: &PixelFormat, tile: &[u32], width: u32, height: u32) {
+    let bpp = format.bytes_per_pixel();
+    let background = most_common(tile);
+    let subrects = subrects(tile, width, height, background);
+    let two_colours = subrects.iter().all(|(colour, _)| *colour == subrects[0].0);
+    let size = 1
+        + bpp
+        + match (subrects.len(), two_colours) {
+            (0, _) => 0,
+            (n, true) => bpp + 1 + 2 * n,
+            (n, false) => 1 + (bpp + 2) * n,
+        };
+    if subrects.len() > 255 || size > 1 + tile.len() * bpp {
+        out.push(HEXTILE_RAW);
+        for &pixel in tile {
+            format.put(out, pixel);
+        }
+        return;
+    }
+
+    let mut flags = HEXTILE_BACKGROUND;
+    if !subrects.is_empty() {
+        flags |= HEXTILE_ANY_SUBRECTS;
+        flags |= match two_colours {
+            true => HEXTILE_FOREGROUND,
+            false => HEXTILE_SUBRECTS_COLOURED,
+        };
+    }
+    out.push(flags);
+    format.put(out, background);
+    if subrects.is_empty() {
+        return;
+    }
+    if two_colours {
+        format.put(out, subrects[0].0);
+    }
+    out.push(subrects.len() as u8);
+    for (colour, rect) in subrects {
+        if !two_colours {
+            format.put(out, colour);
+        }
+        out.push((rect.x << 4 | rect.y) as u8);
+        out.push(((rect.width - 1) << 4 | (rect.height - 1)) as u8);
+    }
+}
+
+/// One ZRLE tile: solid, packed palette for up to 16 colours, otherwise the
+/// smaller of raw and run-length encoded pixels.
+fn zrle_tile(out: &mut Vec<u8>, format: &PixelFormat, tile: &[u32], width: u32) {
+    let mut palette = Vec::with_capacity(17);
+    for &pixel in tile {
+        if !palette.contains(&pixel) {
+            palette.push(pixel);
+            if palette.len() > 16 {
+                break;
+            }
+        }
+    }
+
+    if palette.len() == 1 {
+        out.push(1);
+        format.put_compressed(out, palette[0]);
+    } else if palette.len() <= 16 {
+        out.push(palette.len() as u8);
+        for &colour in &palette {
+            format.put_compressed(out, colour);
+        }
+        let bits = match palette.len() {
+            2 => 1,
+            3 | 4 => 2,
+            _ => 4,
+        };
+        // Rows start on a byte boundary
+        for row in tile.chunks(width as usize) {
+            let (mut byte, mut used) = (0u8, 0);
+            for pixel in row {
+                let index = palette.iter().position(|c| c == pixel).unwrap() as u8;
+                byte |= index << (8 - bits - used);
+                used += bits;
+                if used == 8 {
+                    out.push(byte);
+                    (byte, used) = (0, 0);
+                }
+            }
+            if used > 0 {
+                out.push(byte);
+            }
+        }
+    } else {
+        let cpixel = format.compressed_bytes().len();
+        let runs = tile.chunk_by(|a, b| a == b).collect::<Vec<_>>();
+        let rle_size: usize = runs
+            .iter()
+            .map(|run| cpixel + (run.len() - 1) / 255 + 1)
+            .sum();
+        if rle_size < tile.len() * cpixel {
+            out.push(128);
+            for run in runs {
+                format.put_compressed(out, run[0]);
+                let mut rest = run.len() - 1;
+                while rest >= 255 {
+                    out.push(255);
+                    rest -= 255;
+                }
+                out.push(rest as u8);
+            }
+        } else {
+            out.push(0);
+            for &pixel in tile {
+                format.put_compressed(out, pixel);
+            }
+        }
+    }
+}
+
+/// Encodes rectangles for one connection, whose ZRLE zlib stream lasts as
+/// long as the connection does.
+struct Encoder {
+    format: PixelFormat,
+    encoding: i32,
+    zlib: Box<CompressorOxide>,
+}
+
+impl Encoder {
+    fn new() -> Self {
+        Self {
+            format: PixelFormat::DEFAULT,
+            encoding: ENCODING_RAW,
+            zlib: Box::default(),
+        }
+    }
+
+    fn rect(&mut self, out: &mut Vec<u8>, frame: &Frame, rect: Rect) {
+        for value in [rect.x, rect.y, rect.width, rect.height] {
+            out.extend((value as u16).to_be_bytes());
+        }
+        out.extend(self.encoding.to_be_bytes());
+        let pixels = pixels(frame, rect, &self.format);
+        match self.encoding {
+            ENCODING_ZRLE => {
+                let mut data = Vec::new();
+                for tile_rect in tiles(rect.width, rect.height, 64) {
+                    let tile = tile(&pixels, rect.width, tile_rect);
+                    zrle_tile(&mut data, &self.format, &tile, tile_rect.width);
+                }
+                let compressed = self.deflate(&data);
+                out.extend((compressed.len() as u32).to_be_bytes());
+                out.extend(compressed);
+            }
+            ENCODING_HEXTILE => {
+                for tile_rect in tiles(rect.width, rect.height, 16) {
+                    let tile = tile(&pixels, rect.width, tile_rect);
+                    hextile_tile(out, &self.format, &tile, tile_rect.width, tile_rect.height);
+                }
+            }
+            _ => {
+                for pixel in pixels {
+                    self.format.put(out, pixel);
+                }
+            }
+        }
+    }
+
+    /// Compresses into the connection's zlib stream, flushed so the client
+    /// can decode the rectangle right away.
+    fn deflate(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::with_capacity(data.len() / 4 + 64);
+        let mut chunk = vec![0; 64 * 1024];
+        let mut input = data;
+        loop {
+            let result = miniz_oxide::deflate::stream::deflate(
+                &mut self.zlib,
+                input,
+                &mut chunk,
+                MZFlush::Sync,
+            );
+            compressed.extend_from_slice(&chunk[..result.bytes_written]);
+            input = &input[result.bytes_consumed..];
+            // The flush is complete once it no longer fills the output
+            if result.status.is_err() || (input.is_empty() && result.bytes_written < chunk.len()) {
+                return compressed;
+            }
+        }
+    }
+}
+
+/// Tiles of `new` that differ from `old`, merged along rows.
+fn changed_tiles(old: &Frame, new: &Frame) -> Vec<Rect> {
+    let width = new.width();
+    let (old, new_pixels) = (old.as_slice(), new.as_slice());
+    let mut rects: Vec<Rect> = Vec::new();
+    for tile in tiles(width, new.height(), DIFF_TILE) {
+        let changed = (tile.y..tile.y + tile.height).any(|y| {
+            let start = (y * width + tile.x) as usize;
+            let end = start + tile.width as usize;
+            old[start..end] != new_pixels[start..end]
+        });
+        if !changed {
+            continue;
+        }
+        match rects.last_mut() {
+            Some(last) if last.y == tile.y && last.x + last.width == tile.x => {
+                last.width += tile.width
+            }
+            _ => rects.push(tile),
+        }
+    }
+    rects
+}
+
+/// The latest frame and the damage of the ones before it.
+#[derive(Default)]
+struct Latest {
+    frame: Option<Frame>,
+    /// Frames published so far, the number of the current one.
+    sequence: u64,
+    /// What each recent frame changed against the one before.
+    damage: VecDeque<(u64, Option<Vec<Rect>>)>,
+}
+
+impl Latest {
+    /// What changed after frame `sequence`, `None` if that is unknown.
+    fn damage_since(&self, sequence: u64) -> Option<Vec<Rect>> {
+        if self.damage.front()?.0 > sequence + 1 {
+            return None;
+        }
+        let mut rects = Vec::new();
+        for (_, damage) in self.damage.iter().filter(|(s, _)| *s > sequence) {
+            rects.extend(damage.as_ref()?);
+        }
+        Some(rects)
+    }
+}
+
+/// Input from a client that is not view-only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    /// X11 keysym pressed or released.
+    Key { keysym: u32, down: bool },
+    /// Pointer at `x`, `y` of a frame of `frame_size`, with the mask of
+    /// pressed buttons: bits 0 to 2 are left, middle and right, 3 to 6 are
+    /// wheel clicks up, down, left and right.
+    Pointer {
+        x: u32,
+        y: u32,
+        buttons: u8,
+        frame_size: (u32, u32),
+    },
+}
+
+struct Shared {
+    config: RfbConfig,
+    latest: Mutex<Latest>,
+    published: Condvar,
+    stopped: AtomicBool,
+    clients: AtomicUsize,
+    input: mpsc::Sender<InputEvent>,
+}
+
+pub struct RfbServer {
+    shared: Arc<Shared>,
+    local_addr: SocketAddr,
+    thread: Option<JoinHandle<()>>,
+    input: mpsc::Receiver<InputEvent>,
+}
+
+impl RfbServer {
+    /// Listens on `config.bind`. Clients are greeted once the first frame
+    /// is [published](Self::publish), which gives the desktop size.
+    pub fn bind(config: RfbConfig) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(config.bind)?;
+        let local_addr = listener.local_addr()?;
+        // Polled so that stop() does not have to wake a blocked accept()
+        listener.set_nonblocking(true)?;
+        let (input_sender, input) = mpsc::channel();
+        let shared = Arc::new(Shared {
+            config,
+            latest: Mutex::new(Latest::default()),
+            published: Condvar::new(),
+            stopped: AtomicBool::new(false),
+            clients: AtomicUsize::new(0),
+            input: input_sender,
+        });
+        let thread = std::thread::spawn({
+            let shared = Arc::clone(&shared);
+            move || accept(listener, shared)
+        });
+        Ok(Self {
+            shared,
+            local_addr,
+            thread: Some(thread),
+            input,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The next input event of any client, never any when view-only.
+    pub fn try_input(&self) -> Option<InputEvent> {
+        self.input.try_recv().ok()
+    }
+
+    /// Connected clients.
+    pub fn clients(&self) -> usize {
+        self.shared.clients.load(Ordering::Relaxed)
+    }
+
+    /// Makes `frame` the latest one, sent to clients as they ask for it.
+    pub fn publish(&self, frame: &TimedFrame) {
+        if frame.frame.width() == 0 || frame.frame.height() == 0 {
+            return;
+        }
+        let mut latest = self.shared.latest.lock().unwrap();
+        let resized = latest.frame.as_ref().is_some_and(|previous| {
+            (previous.width(), previous.height()) != (frame.frame.width(), frame.frame.height())
+        });
+        latest.frame = Some(frame.frame.clone());
+        latest.sequence += 1;
+        let sequence = latest.sequence;
+        let damage = frame.damage.clone().filter(|_| !resized);
+        latest.damage.push_back((sequence, damage));
+        if latest.damage.len() > DAMAGE_HISTORY {
+            latest.damage.pop_front();
+        }
+        drop(latest);
+        self.shared.published.notify_all();
+    }
+
+    /// Stops accepting clients and disconnects the connected ones.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+        self.shared.published.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for RfbServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn accept(listener: TcpListener, shared: Arc<Shared>) {
+    while !shared.stopped.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let shared = Arc::clone(&shared);
+                std::thread::spawn(move || {
+                    shared.clients.fetch_add(1, Ordering::Relaxed);
+                    let result = handle_client(&stream, &shared);
+                    let _ = stream.shutdown(Shutdown::Both);
+                    shared.clients.fetch_sub(1, Ordering::Relaxed);
+                    if let Err(e) = result {
+                        // Mostly viewers being closed
+                        if !matches!(
+                            e.kind(),
+                            std::io::ErrorKind::BrokenPipe
+                                | std::io::ErrorKind::ConnectionReset
+                                | std::io::ErrorKind::UnexpectedEof
+                        ) {
+                            println!("VNC client failed: {e}");
+                        }
+                    }
+                });
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(20))
+            }
+            Err(e) => {
+                println!("VNC server failed: {e}");
+                return;
+            }
+        }
+    }
+}
+
+/// Protocol version, security and ClientInit.
+fn handshake(mut stream: &TcpStream) -> std::io::Result<()> {
+    stream.write_all(b"RFB 003.008\n")?;
+    let mut version = [0; 12];
+    stream.read_exact(&mut version)?;
+    let minor = std::str::from_utf8(&version)
+        .ok()
+        .and_then(|version| version.strip_prefix("RFB 003."))
+        .and_then(|minor| minor.trim_end().parse::<u32>().ok())
+        .ok_or_else(|| invalid("Not an RFB 3.x client"))?;
+    if minor < 7 {
+        // 3.3 has the server pick the security type
+        stream.write_all(&(SECURITY_NONE as u32).to_be_bytes())?;
+    } else {
+        stream.write_all(&[1, SECURITY_NONE])?;
+        let mut chosen = [0];
+        stream.read_exact(&mut chosen)?;
+        if chosen[0] != SECURITY_NONE {
+            if minor >= 8 {
+                let reason = b"Only security type None is supported";
+                stream.write_all(&1u32.to_be_bytes())?;
+                stream.write_all(&(reason.len() as u32).to_be_bytes())?;
+                stream.write_all(reason)?;
+            }
+            return Err(invalid(format!("Unsupported security type {}", chosen[0])));
+        }
+        // 3.7 only sends a result for types with authentication
+        if minor >= 8 {
+            stream.write_all(&0u32.to_be_bytes())?;
+        }
+    }
+    // The shared flag, clients never get exclusive access
+    let mut client_init = [0];
+    stream.read_exact(&mut client_init)
+}
+
+enum ClientMessage {
+    SetPixelFormat(PixelFormat),
+    SetEncodings(Vec<i32>),
+    UpdateRequest { incremental: bool, rect: Rect },
+    Key { keysym: u32, down: bool },
+    Pointer { x: u32, y: u32, buttons: u8 },
+}
+
+/// Parses client messages until the connection ends.
+fn read_messages(
+    mut reader: impl Read,
+    messages: mpsc::Sender<ClientMessage>,
+) -> std::io::Result<()> {
+    loop {
+        let mut kind = [0];
+        reader.read_exact(&mut kind)?;
+        let message = match kind[0] {
+            0 => {
+                let mut bytes = [0; 19];
+                reader.read_exact(&mut bytes)?;
+                ClientMessage::SetPixelFormat(PixelFormat::read(bytes[3..].try_into().unwrap())?)
+            }
+            2 => {
+                let mut header = [0; 3];
+                reader.read_exact(&mut header)?;
+                let mut encodings =
+                    vec![0; u16::from_be_bytes([header[1], header[2]]) as usize * 4];
+                reader.read_exact(&mut encodings)?;
+                ClientMessage::SetEncodings(
+                    encodings
+                        .chunks(4)
+                        .map(|e| i32::from_be_bytes(e.try_into().unwrap()))
+                        .collect(),
+                )
+            }
+            3 => {
+                let mut bytes = [0; 9];
+                reader.read_exact(&mut bytes)?;
+                let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]) as u32;
+                ClientMessage::UpdateRequest {
+                    incremental: bytes[0] != 0,
+                    rect: Rect {
+                        x: u16_at(1),
+                        y: u16_at(3),
+                        width: u16_at(5),
+                        height: u16_at(7),
+                    },
+                }
+            }
+            4 => {
+                let mut bytes = [0; 7];
+                reader.read_exact(&mut bytes)?;
+                ClientMessage::Key {
+                    down: bytes[0] != 0,
+                    keysym: u32::from_be_bytes(bytes[3..].try_into().unwrap()),
+                }
+            }
+            5 => {
+                let mut bytes = [0; 5];
+                reader.read_exact(&mut bytes)?;
+                ClientMessage::Pointer {
+                    buttons: bytes[0],
+                    x: u16::from_be_bytes([bytes[1], bytes[2]]) as u32,
+                    y: u16::from_be_bytes([bytes[3], bytes[4]]) as u32,
+                }
+            }
+            // Clipboard text
+            6 => {
+                let mut header = [0; 7];
+                reader.read_exact(&mut header)?;
+                let length = u32::from_be_bytes(header[3..].try_into().unwrap());
+                std::io::copy(&mut (&mut reader).take(length as u64), &mut std::io::sink())?;
+                continue;
+            }
+            other => return Err(invalid(format!("Unknown client message {other}"))),
+        };
+        if messages.send(message).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// What one client has seen and asked for.
+struct Client {
+    encoder: Encoder,
+    desktop_size: bool,
+    /// The framebuffer size the client knows.
+    size: (u32, u32),
+    /// Whether the client has to be told about a new size.
+    resized: bool,
+    frame: Frame,
+    sequence: u64,
+    /// Areas changed since they were last sent.
+    dirty: Vec<Rect>,
+    request: Option<(bool, Rect)>,
+    /// Where input goes, `None` when view-only.
+    input: Option<mpsc::Sender<InputEvent>>,
+}
+
+impl Client {
+    fn handle(&mut self, message: ClientMessage) {
+        match message {
+            ClientMessage::SetPixelFormat(format) => self.encoder.format = format,
+            ClientMessage::SetEncodings(encodings) => {
+                self.encoder.encoding = encodings
+                    .iter()
+                    .copied()
+                    .find(|e| [ENCODING_ZRLE, ENCODING_HEXTILE, ENCODING_RAW].contains(e))
+                    .unwrap_or(ENCODING_RAW);
+                self.desktop_size = encodings.contains(&ENCODING_DESKTOP_SIZE);
+            }
+            ClientMessage::UpdateRequest { incremental, rect } => {
+                self.request = Some(match self.request {
+                    Some((pending, pending_rect)) => {
+                        (pending && incremental, pending_rect.union(&rect))
+                    }
+                    None => (incremental, rect),
+                });
+            }
+            ClientMessage::Key { keysym, down } => self.input(InputEvent::Key { keysym, down }),
+            ClientMessage::Pointer { x, y, buttons } => self.input(InputEvent::Pointer {
+                x,
+                y,
+                buttons,
+                frame_size: (self.frame.width(), self.frame.height()),
+            }),
+        }
+    }
+
+    fn input(&self, event: InputEvent) {
+        if let Some(input) = &self.input {
+            let _ = input.send(event);
+        }
+    }
+
+    /// Takes the latest frame and marks what changed as dirty.
+    fn refresh(&mut self, shared: &Shared) {
+        let latest = shared.latest.lock().unwrap();
+        if latest.sequence == self.sequence {
+            return;
+        }
+        let Some(frame) = latest.frame.clone() else {
+            return;
+        };
+        let damage = latest.damage_since(self.sequence);
+        self.sequence = latest.sequence;
+        drop(latest);
+
+        let size = (frame.width(), frame.height());
+        let dirty = if size != (self.frame.width(), self.frame.height()) {
+            // Clients without DesktopSize keep their size, clipped or padded
+            if self.desktop_size {
+                self.size = (size.0.min(u16::MAX as u32), size.1.min(u16::MAX as u32));
+                self.resized = true;
+            }
+            vec![self.bounds()]
+        } else {
+            damage.unwrap_or_else(|| changed_tiles(&self.frame, &frame))
+        };
+        self.frame = frame;
+        self.dirty.extend(dirty);
+        if self.dirty.len() > MAX_DIRTY_RECTS {
+            let bounds = self.dirty.iter().fold(self.dirty[0], |a, b| a.union(b));
+            self.dirty = vec![bounds];
+        }
+    }
+
+    fn bounds(&self) -> Rect {
+        Rect {
+            x: 0,
+            y: 0,
+            width: self.size.0,
+            height: self.size.1,
+        }
+    }
+
+    /// The rectangles to send for the pending request, `None` while an
+    /// incremental request has nothing new.
+    fn update(&mut self) -> Option<Vec<Rect>> {
+        let (incremental, request) = self.request?;
+        let bounds = self.bounds();
+        let request = request.intersect(&bounds);
+        let rects = if self.resized {
+            vec![bounds]
+        } else if !incremental {
+            request.into_iter().collect()
+        } else {
+            let request = request?;
+            self.dirty
+                .iter()
+                .filter_map(|rect| rect.intersect(&request))
+                .collect()
+        };
+        if rects.is_empty() && incremental {
+            return None;
+        }
+        self.request = None;
+        self.dirty
+            .retain(|rect| !rects.iter().any(|sent| sent.intersect(rect) == Some(*rect)));
+        Some(rects)
+    }
+
+    fn send_update(&mut self, mut stream: &TcpStream, rects: &[Rect]) -> std::io::Result<()> {
+        let mut out = vec![0, 0];
+        out.extend(((rects.len() + self.resized as usize) as u16).to_be_bytes());
+        if std::mem::take(&mut self.resized) {
+            for value in [0, 0, self.size.0, self.size.1] {
+                out.extend((value as u16).to_be_bytes());
+            }
+            out.extend(ENCODING_DESKTOP_SIZE.to_be_bytes());
+        }
+        for rect in rects {
+            self.encoder.rect(&mut out, &self.frame, *rect);
+        }
+        stream.write_all(&out)
+    }
+}
+
+fn handle_client(mut stream: &TcpStream, shared: &Shared) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    handshake(stream)?;
+
+    let latest = shared.latest.lock().unwrap();
+    let latest = shared
+        .published
+        .wait_while(latest, |latest| {
+            latest.frame.is_none() && !shared.stopped.load(Ordering::Relaxed)
+        })
+        .unwrap();
+    let Some(frame) = latest.frame.clone() else {
+        return Ok(());
+    };
+    let sequence = latest.sequence;
+    drop(latest);
+
+    let size = (
+        frame.width().min(u16::MAX as u32),
+        frame.height().min(u16::MAX as u32),
+    );
+    let name = shared.config.name.as_bytes();
+    let mut init = Vec::new();
+    init.extend((size.0 as u16).to_be_bytes());
+    init.extend((size.1 as u16).to_be_bytes());
+    PixelFormat::DEFAULT.write(&mut init);
+    init.extend((name.len() as u32).to_be_bytes());
+    init.extend(name);
+    stream.write_all(&init)?;
+
+    // Viewers may stay idle for as long as they like
+    stream.set_read_timeout(None)?;
+    let (message_sender, messages) = mpsc::channel();
+    let reader = std::thread::spawn({
+        let reader = BufReader::new(stream.try_clone()?);
+        move || read_messages(reader, message_sender)
+    });
+
+    let mut client = Client {
+        encoder: Encoder::new(),
+        desktop_size: false,
+        size,
+        resized: false,
+        frame,
+        sequence,
+        dirty: Vec::new(),
+        request: None,
+        input: (!shared.config.view_only).then(|| shared.input.clone()),
+    };
+    // Also for clients that start with an incremental request
+    client.dirty.push(client.bounds());
+    while !shared.stopped.load(Ordering::Relaxed) {
+        loop {
+            match messages.try_recv() {
+                Ok(message) => client.handle(message),
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    return reader.join().unwrap_or(Ok(()));
+                }
+            }
+        }
+        client.refresh(shared);
+        match client.update() {
+            Some(rects) => client.send_update(stream, &rects)?,
+            None if client.request.is_some() => {
+                // Wait for a frame with changes
+                let latest = shared.latest.lock().unwrap();
+                let _ = shared
+                    .published
+                    .wait_timeout_while(latest, Duration::from_millis(20), |latest| {
+                        latest.sequence == client.sequence
+                            && !shared.stopped.load(Ordering::Relaxed)
+                    })
+                    .unwrap();
+            }
+            None => match messages.recv_timeout(Duration::from_millis(20)) {
+                Ok(message) => client.handle(message),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return reader.join().unwrap_or(Ok(()));
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Publishes frames to `server` and hands client input to `input` until
+/// the channel closes or `stop` returns true.
+pub fn serve(
+    frames: &async_channel::Receiver<TimedFrame>,
+    server: &RfbServer,
+    mut input: impl FnMut(InputEvent),
+    stop: impl Fn() -> bool,
+) {
+    // Input is handed on between frames and while the screen is idle
+    let poll = || {
+        while let Some(event) = server.try_input() {
+            input(event);
+        }
+        stop()
+    };
+    let _ = pipewire_stream::for_each_frame(frames, poll, |frame| {
+        server.publish(&frame);
+        Ok(())
+    });
+}
@@ -0,0 +1,482 @@
+//! Streaming over RTP to a UDP destination, e.g. for
+//! `ffplay -protocol_whitelist file,udp,rtp -i stream.sdp` on another box.
+//!
+//! JPEG frames are packetised here as RFC 2435 describes. H.264 is encoded
+//! and packetised (RFC 6184, FU-A for large NAL units) by an `ffmpeg`
+//! process, which also writes the SDP since only it knows the parameter
+//! sets. No RTCP is sent.
+
+use crate::export::ImageView;
+use crate::ffmpeg::{self, FfmpegSink, FramePacer};
+use crate::frame_transform;
+use crate::jpeg::JpegEncoder;
+use crate::pipewire_stream::{self, Frame, TimedFrame};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Static payload type of RFC 2435 JPEG.
+pub const JPEG_PAYLOAD_TYPE: u8 = 26;
+/// First dynamic payload type, used for H.264.
+pub const H264_PAYLOAD_TYPE: u8 = 96;
+/// Clock rate of both video payloads.
+pub const CLOCK_RATE: u32 = 90_000;
+
+const RTP_HEADER_SIZE: usize = 12;
+const JPEG_HEADER_SIZE: usize = 8;
+/// Quantization table header and both 8 bit tables.
+const TABLES_SIZE: usize = 4 + 2 * 64;
+/// RFC 2435 type of a 4:2:0 image, which is what [`JpegEncoder`] writes.
+const JPEG_TYPE_420: u8 = 1;
+/// Width and height go in units of 8 pixels in a byte.
+const MAX_JPEG_SIZE: u32 = 255 * 8;
+/// Room for the headers, the tables and some scan data.
+const MIN_MTU: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtpCodec {
+    Jpeg,
+    H264,
+}
+
+impl std::str::FromStr for RtpCodec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jpeg" => Ok(RtpCodec::Jpeg),
+            "h264" => Ok(RtpCodec::H264),
+            _ => Err(format!("Unknown codec '{s}', expected jpeg or h264")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RtpConfig {
+    pub destination: SocketAddr,
+    pub codec: RtpCodec,
+    /// JPEG quality from 1 to 100. Receivers rebuild the tables of 1 to 99
+    /// from the quality alone, 100 sends them with every frame.
+    pub quality: u8,
+    /// H.264 bit rate in kbit/s.
+    pub bitrate: u32,
+    /// Largest UDP payload, RTP header included.
+    pub mtu: usize,
+    /// Frames arriving faster than this are not sent, `None` for no limit.
+    /// H.264 goes out at this constant rate, 30 without a limit.
+    pub max_fps: Option<u32>,
+    /// Where to write the session description for receivers.
+    pub sdp: Option<PathBuf>,
+    /// `ffmpeg` from `PATH` by default, for H.264.
+    pub ffmpeg: PathBuf,
+}
+
+impl Default for RtpConfig {
+    fn default() -> Self {
+        Self {
+            destination: SocketAddr::from(([127, 0, 0, 1], 5004)),
+            codec: RtpCodec::Jpeg,
+            quality: 80,
+            bitrate: 4000,
+            mtu: 1400,
+            max_fps: Some(30),
+            sdp: None,
+            ffmpeg: PathBuf::from("ffmpeg"),
+        }
+    }
+}
+
+impl RtpConfig {
+    /// Rate of the H.264 stream, frames are repeated or dropped to keep it.
+    fn frame_rate(&self) -> u32 {
+        self.max_fps.unwrap_or(30)
+    }
+
+    /// ffmpeg command line sending H.264 for `width` x `height` RGBA input
+    /// on stdin.
+    pub fn h264_args(&self, width: u32, height: u32, ssrc: u32) -> Vec<String> {
+        let mut args = ffmpeg::video_input_args(self.frame_rate(), width, height);
+        args.extend(ffmpeg::even_size_args(width, height));
+        args.extend(
+            [
+                "-c:v",
+                "libx264",
+                "-preset",
+                "ultrafast",
+                "-tune",
+                "zerolatency",
+                "-pix_fmt",
+                "yuv420p",
+                // Parameter sets and an IDR now and then for receivers
+                // that join late or lose packets
+                "-g",
+                "60",
+                "-b:v",
+            ]
+            .map(str::to_owned),
+        );
+        args.push(format!("{}k", self.bitrate));
+        args.extend(["-f", "rtp", "-payload_type"].map(str::to_owned));
+        args.push(H264_PAYLOAD_TYPE.to_string());
+        args.push("-ssrc".to_owned());
+        args.push(ssrc.to_string());
+        if let Some(sdp) = &self.sdp {
+            args.push("-sdp_file".to_owned());
+            args.push(sdp.display().to_string());
+        }
+        args.push(format!("rtp://{}?pkt_size={}", self.destination, self.mtu));
+        args
+    }
+}
+
+/// Session description of a JPEG stream sent to `destination`.
+pub fn jpeg_sdp(destination: SocketAddr) -> String {
+    let (family, local) = match destination.ip() {
+        IpAddr::V4(_) => ("IP4", "127.0.0.1"),
+        IpAddr::V6(_) => ("IP6", "::1"),
+    };
+    format!(
+        "v=0\r\n\
+         o=- 0 0 IN {family} {local}\r\n\
+         s=Screencast\r\n\
+         c=IN {family} {}\r\n\
+         t=0 0\r\n\
+         m=video {} RTP/AVP {JPEG_PAYLOAD_TYPE}\r\n\
+         a=rtpmap:{JPEG_PAYLOAD_TYPE} JPEG/{CLOCK_RATE}\r\n",
+        destination.ip(),
+        destination.port(),
+    )
+}
+
+/// Fixed part of an RTP header (RFC 3550), without CSRCs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtpHeader {
+    /// Last packet of a frame.
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+}
+
+impl RtpHeader {
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.push(2 << 6);
+        out.push((self.marker as u8) << 7 | self.payload_type & 0x7f);
+        out.extend_from_slice(&self.sequence.to_be_bytes());
+        out.extend_from_slice(&self.timestamp.to_be_bytes());
+        out.extend_from_slice(&self.ssrc.to_be_bytes());
+    }
+
+    /// Header and payload of `packet`, skipping CSRCs, the extension and
+    /// padding. `None` if it is not an RTP version 2 packet.
+    pub fn parse(packet: &[u8]) -> Option<(Self, &[u8])> {
+        if packet.len() < RTP_HEADER_SIZE || packet[0] >> 6 != 2 {
+            return None;
+        }
+        let header = Self {
+            marker: packet[1] & 0x80 != 0,
+            payload_type: packet[1] & 0x7f,
+            sequence: u16::from_be_bytes([packet[2], packet[3]]),
+            timestamp: u32::from_be_bytes(packet[4..8].try_into().unwrap()),
+            ssrc: u32::from_be_bytes(packet[8..12].try_into().unwrap()),
+        };
+        let mut start = RTP_HEADER_SIZE + (packet[0] & 0x0f) as usize * 4;
+        if packet[0] & 0x10 != 0 {
+            let length = packet.get(start + 2..start + 4)?;
+            start += 4 + u16::from_be_bytes([length[0], length[1]]) as usize * 4;
+        }
+        let mut end = packet.len();
+        if packet[0] & 0x20 != 0 {
+            end = end.checked_sub(*packet.last()? as usize)?;
+        }
+        Some((header, packet.get(start..end)?))
+    }
+}
+
+/// Splits JPEG frames into RFC 2435 packets of one stream.
+pub struct JpegPacketizer {
+    encoder: JpegEncoder,
+    /// Q field of the JPEG header: the quality, or 255 for tables in band.
+    q: u8,
+    mtu: usize,
+    ssrc: u32,
+    sequence: u16,
+}
+
+impl JpegPacketizer {
+    pub fn new(quality: u8, mtu: usize, ssrc: u32, sequence: u16) -> Self {
+        let quality = quality.clamp(1, 100);
+        Self {
+            encoder: JpegEncoder::new(quality),
+            q: if quality < 100 { quality } else { 255 },
+            mtu: mtu.max(MIN_MTU),
+            ssrc,
+            sequence,
+        }
+    }
+
+    /// Packets of `frame` with `timestamp` on the 90 kHz clock, the last
+    /// one marked. Frames larger than 2040 pixels are scaled down and the
+    /// edges past a multiple of 8 pixels are cut off, as the header cannot
+    /// describe other sizes.
+    pub fn packetize(&mut self, frame: &Frame, timestamp: u32) -> Vec<Vec<u8>> {
+        let (width, height) = (frame.width(), frame.height());
+        let largest = width.max(height);
+        let scaled = (largest > MAX_JPEG_SIZE).then(|| {
+            let fit = |size: u32| (size as u64 * MAX_JPEG_SIZE as u64 / largest as u64) as u32;
+            frame_transform::scale(frame, fit(width), fit(height))
+        });
+        let frame = scaled.as_ref().unwrap_or(frame);
+        let image = ImageView {
+            width: frame.width() / 8 * 8,
+            height: frame.height() / 8 * 8,
+            ..ImageView::from(frame)
+        };
+        if image.width == 0 || image.height == 0 {
+            return Vec::new();
+        }
+
+        let scan = self.encoder.encode_scan(&image);
+        let mut packets = Vec::new();
+        let mut offset = 0;
+        while offset < scan.len() {
+            let tables = offset == 0 && self.q >= 128;
+            let headers = RTP_HEADER_SIZE + JPEG_HEADER_SIZE + if tables { TABLES_SIZE } else { 0 };
+            let end = scan.len().min(offset + self.mtu - headers);
+            let mut packet = Vec::with_capacity(headers + end - offset);
+            RtpHeader {
+                marker: end == scan.len(),
+                payload_type: JPEG_PAYLOAD_TYPE,
+                sequence: self.sequence,
+                timestamp,
+                ssrc: self.ssrc,
+            }
+            .write(&mut packet);
+            self.sequence = self.sequence.wrapping_add(1);
+            // Type-specific field, then the 24 bit fragment offset
+            packet.extend_from_slice(&(offset as u32 & 0xff_ffff).to_be_bytes());
+            packet.extend_from_slice(&[
+                JPEG_TYPE_420,
+                self.q,
+                (image.width / 8) as u8,
+                (image.height / 8) as u8,
+            ]);
+            if tables {
+                packet.extend_from_slice(&[0, 0]);
+                packet.extend_from_slice(&(2 * 64u16).to_be_bytes());
+                for table in self.encoder.quantization_tables() {
+                    packet.extend_from_slice(table);
+                }
+            }
+            packet.extend_from_slice(&scan[offset..end]);
+            packets.push(packet);
+            offset = end;
+        }
+        packets
+    }
+}
+
+enum Output {
+    Jpeg {
+        socket: UdpSocket,
+        packetizer: Box<JpegPacketizer>,
+    },
+    /// Started with the size of the first frame.
+    H264(Option<FfmpegSink>),
+}
+
+/// Sends frames to [`RtpConfig::destination`].
+pub struct RtpSink {
+    config: RtpConfig,
+    output: Output,
+    ssrc: u32,
+    /// RTP timestamp of pts zero, random like RFC 3550 asks.
+    timestamp_offset: u32,
+    /// Capture time of the last frame sent, for the fps limit.
+    last_pts: Option<Duration>,
+}
+
+impl RtpSink {
+    /// Opens a socket for JPEG and writes its SDP. H.264 starts ffmpeg
+    /// with the first frame.
+    pub fn new(config: RtpConfig) -> std::io::Result<Self> {
+        if config.mtu < MIN_MTU {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("An MTU of {} leaves no room for data", config.mtu),
+            ));
+        }
+        let ssrc = random();
+        let output = match config.codec {
+            RtpCodec::Jpeg => {
+                let unspecified = match config.destination {
+                    SocketAddr::V4(_) => SocketAddr::from(([0; 4], 0)),
+                    SocketAddr::V6(_) => SocketAddr::from(([0; 16], 0)),
+                };
+                let socket = UdpSocket::bind(unspecified)?;
+                if let Some(path) = &config.sdp {
+                    std::fs::write(path, jpeg_sdp(config.destination))?;
+                }
+                Output::Jpeg {
+                    socket,
+                    packetizer: Box::new(JpegPacketizer::new(
+                        config.quality,
+                        config.mtu,
+                        ssrc,
+                        random() as u16,
+                    )),
+                }
+            }
+            RtpCodec::H264 => Output::H264(None),
+        };
+        Ok(Self {
+            config,
+            output,
+            ssrc,
+            timestamp_offset: random(),
+            last_pts: None,
+        })
+    }
+
+    pub fn ssrc(&self) -> u32 {
+        self.ssrc
+    }
+
+    /// Where the session description goes, written by ffmpeg for H.264 once
+    /// the first frame is sent.
+    pub fn sdp_path(&self) -> Option<&Path> {
+        self.config.sdp.as_deref()
+    }
+
+    /// Sends `frame`, unless it comes too soon after the previous one for
+    /// the fps limit. Returns whether it was sent.
+    pub fn send(&mut self, frame: &TimedFrame) -> std::io::Result<bool> {
+        if frame.frame.width() == 0 || frame.frame.height() == 0 {
+            return Ok(false);
+        }
+        if let (Some(fps), Some(last)) = (self.config.max_fps, self.last_pts) {
+            if frame.pts.saturating_sub(last) < Duration::from_secs(1) / fps.max(1) {
+                return Ok(false);
+            }
+        }
+        self.last_pts = Some(frame.pts);
+        match &mut self.output {
+            Output::Jpeg { socket, packetizer } => {
+                let ticks = frame.pts.as_nanos() * CLOCK_RATE as u128 / 1_000_000_000;
+                let timestamp = self.timestamp_offset.wrapping_add(ticks as u32);
+                for packet in packetizer.packetize(&frame.frame, timestamp) {
+                    socket.send_to(&packet, self.config.destination)?;
+                }
+            }
+            Output::H264(sink) => {
+                let sink = match sink {
+                    Some(sink) => sink,
+                    None => {
+                        let (width, height) = (frame.frame.width(), frame.frame.height());
+                        sink.insert(FfmpegSink::spawn_args(
+                            &self.config.ffmpeg,
+                            self.config.h264_args(width, height, self.ssrc),
+                            FramePacer::new(self.config.frame_rate(), frame.pts),
+                            false,
+                            width,
+                            height,
+                        )?)
+                    }
+                };
+                sink.write_timed(&frame.frame, frame.pts)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Stops ffmpeg, if it was started, and reports how it exited.
+    pub fn finish(self) -> std::io::Result<()> {
+        match self.output {
+            Output::H264(Some(sink)) => {
+                let status = sink.finish()?;
+                if !status.success() {
+                    return Err(std::io::Error::other(format!(
+                        "ffmpeg failed with {status}"
+                    )));
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Sends frames until the channel closes or `stop` returns true.
+pub fn stream(
+    frames: &async_channel::Receiver<TimedFrame>,
+    sink: &mut RtpSink,
+    stop: impl Fn() -> bool,
+) -> std::io::Result<()> {
+    pipewire_stream::for_each_frame(frames, stop, |frame| {
+        sink.send(&frame)?;
+        Ok(())
+    })
+}
+
+/// Seeded per process by the standard library, good enough for SSRCs.
+fn random() -> u32 {
+    use std::hash::{BuildHasher, Hasher};
+    std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish() as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RtpCodec, RtpConfig, RtpHeader};
+    use std::path::PathBuf;
+
+    #[test]
+    fn header_round_trip() {
+        let header = RtpHeader {
+            marker: true,
+            payload_type: 96,
+            sequence: 0xfffe,
+            timestamp: 0x1234_5678,
+            ssrc: 42,
+        };
+        let mut packet = Vec::new();
+        header.write(&mut packet);
+        packet.extend_from_slice(b"data");
+        assert_eq!(RtpHeader::parse(&packet), Some((header, &b"data"[..])));
+
+        // One CSRC, a one word extension and two bytes of padding
+        let mut extended = packet[..12].to_vec();
+        extended[0] |= 0x20 | 0x10 | 1;
+        extended.extend_from_slice(&[0, 0, 0, 7]);
+        extended.extend_from_slice(&[0xbe, 0xde, 0, 1, 1, 2, 3, 4]);
+        extended.extend_from_slice(b"data\0\x02");
+        assert_eq!(RtpHeader::parse(&extended), Some((header, &b"data"[..])));
+        assert_eq!(RtpHeader::parse(&extended[..10]), None);
+    }
+
+    #[test]
+    fn h264_command_line() {
+        let config = RtpConfig {
+            destination: "[::1]:6000".parse().unwrap(),
+            codec: RtpCodec::H264,
+            mtu: 1200,
+            sdp: Some(PathBuf::from("stream.sdp")),
+            ..Default::default()
+        };
+        let args = config.h264_args(641, 480, 7);
+        let position = |arg: &str| args.iter().position(|a| a == arg).unwrap();
+        assert_eq!(args[position("-video_size") + 1], "641x480");
+        assert_eq!(args[position("-c:v") + 1], "libx264");
+        assert_eq!(args[position("-b:v") + 1], "4000k");
+        assert_eq!(args[position("-f") + 1], "rawvideo");
+        assert_eq!(args[position("-payload_type") + 1], "96");
+        assert_eq!(args[position("-ssrc") + 1], "7");
+        assert_eq!(args[position("-sdp_file") + 1], "stream.sdp");
+        assert!(args.contains(&"-vf".to_owned()));
+        assert!(args.windows(2).any(|w| w == ["-framerate", "30"]));
+        assert!(!args.contains(&"-use_wallclock_as_timestamps".to_owned()));
+        assert_eq!(args.last().unwrap(), "rtp://[::1]:6000?pkt_size=1200");
+    }
+}
@@ -0,0 +1,111 @@
+use crate::frame_transform::{self, Rect};
+use crate::pipewire_stream::{Frame, PipewireStream, Target, TimedFrame};
+use crate::portal::{self, Portal, PortalError};
+use crate::restore_tokens::TokenStore;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct CaptureOptions {
+    /// Capture this node directly instead of asking the portal.
+    pub node: Option<Target>,
+    /// Name of the restore token used to skip the portal dialog, `None` to
+    /// always ask.
+    pub session_name: Option<String>,
+    pub crop: Option<Rect>,
+    /// Scale to this size, after cropping.
+    pub size: Option<(u32, u32)>,
+    pub timeout: Duration,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        Self {
+            node: None,
+            session_name: Some("screenshot".to_owned()),
+            crop: None,
+            size: None,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CaptureError {
+    Portal(PortalError),
+    /// No frame arrived within the timeout.
+    Timeout,
+    /// The stream ended before delivering a frame.
+    StreamEnded,
+}
+
+impl std::fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CaptureError::Portal(e) => write!(f, "{e}"),
+            CaptureError::Timeout => f.write_str("No frame received in time"),
+            CaptureError::StreamEnded => f.write_str("Stream ended without a frame"),
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {}
+
+impl From<PortalError> for CaptureError {
+    fn from(e: PortalError) -> Self {
+        CaptureError::Portal(e)
+    }
+}
+
+/// Grabs a single frame: starts a capture, waits for the first non-empty
+/// frame, then stops the capture again.
+pub fn capture_one(options: &CaptureOptions) -> Result<Frame, CaptureError> {
+    let mut stream = PipewireStream::create();
+    let (frames, _session) = match &options.node {
+        Some(target) => (stream.start_direct(target.clone()), None),
+        None => {
+            let (session, node_id) = start_session(options.session_name.as_deref())?;
+            let pipewire_fd = session.open_pipewire_remote()?;
+            (stream.start(pipewire_fd, node_id), Some(session))
+        }
+    };
+
+    let deadline = Instant::now() + options.timeout;
+    let frame = loop {
+        match frames.try_recv() {
+            Ok(TimedFrame { frame, .. }) if frame.width() > 0 && frame.height() > 0 => {
+                break Ok(frame)
+            }
+            Ok(_) => {}
+            Err(async_channel::TryRecvError::Closed) => break Err(CaptureError::StreamEnded),
+            Err(async_channel::TryRecvError::Empty) => {
+                if Instant::now() >= deadline {
+                    break Err(CaptureError::Timeout);
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+    };
+
+    // Unblock the stream thread if it is waiting to send more frames
+    frames.close();
+    stream.stop();
+    Ok(frame_transform::apply(frame?, options.crop, options.size))
+}
+
+fn start_session(session_name: Option<&str>) -> Result<(portal::Session, u32), PortalError> {
+    let portal = Portal::new()?;
+    let options = portal::SourceOptions {
+        types: portal::SourceType::MONITOR | portal::SourceType::WINDOW,
+        ..Default::default()
+    };
+    let (session, started) = match (session_name, TokenStore::open_default()) {
+        (Some(name), Some(store)) => portal.start_persisted(name, &store, &options, "")?,
+        _ => portal.start_session(&options, "")?,
+    };
+    let node_id = started
+        .streams
+        .first()
+        .ok_or(PortalError::Parse("streams"))?
+        .node_id;
+    Ok((session, node_id))
+}
@@ -0,0 +1,801 @@
+//! Frames in shared memory for local consumers that cannot import DMA-BUFs,
+//! e.g. Python or C tools mapping a memfd instead of reading a pipe.
+//!
+//! A client connects to the Unix socket and receives a 16 byte hello (magic
+//! `SCRG`, version u32, memfd size u64, little-endian) in one `recvmsg`,
+//! with the memfd attached to it as the only `SCM_RIGHTS` descriptor. After
+//! that the server writes the number of every frame it publishes as a
+//! little-endian u64, so clients can block on the socket rather than poll;
+//! slow clients miss some numbers, never frames that are still in the ring,
+//! and always read whole numbers.
+//!
+//! The memfd is sealed against resizing and new writable mappings, so
+//! clients map it with `PROT_READ` and `MAP_SHARED`.
+//!
+//! The memfd starts with a 64 byte ring header, followed by `slots` slots of
+//! `slot stride` bytes: a 64 byte slot header, then the pixels. All fields
+//! are in native byte order.
+//!
+//! | Ring header | Field                                   |
+//! |-------------|-----------------------------------------|
+//! | 0           | Magic `SCRG`                            |
+//! | 4           | Version, u32                            |
+//! | 8           | Slot count, u32                         |
+//! | 16          | Pixel bytes per slot, u64               |
+//! | 24          | Slot stride, u64                        |
+//! | 32          | Frames published, u64                   |
+//!
+//! | Slot header | Field                                   |
+//! |-------------|-----------------------------------------|
+//! | 0           | Seqlock sequence, u64, odd while written |
+//! | 8           | Frame number, u64, from 1               |
+//! | 16          | Capture time in ns, u64                 |
+//! | 24          | Width, height, stride, DRM fourcc, u32s |
+//! | 40          | Length of the pixels, u64               |
+//!
+//! Frame `n` lives in slot `(n - 1) % slots`. To read the latest one, load
+//! the frame count, then the slot's sequence; retry while it is odd, copy
+//! the header and pixels, and retry if the sequence changed meanwhile.
+
+use crate::dma_buf_share::{recv_with_fds, send_with_fds};
+use crate::frame_transform;
+use crate::pipewire_stream::{self, Frame, TimedFrame};
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
+use std::sync::atomic::{fence, AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+pub const MAGIC: [u8; 4] = *b"SCRG";
+pub const VERSION: u32 = 1;
+/// DRM fourcc of the pixels, `AB24`: R, G, B and A bytes.
+pub const FOURCC_RGBA: u32 = u32::from_le_bytes(*b"AB24");
+
+const HEADER_SIZE: usize = 64;
+const HELLO_SIZE: usize = 16;
+/// Tries before a reader gives up on a slot the writer keeps changing.
+const READ_ATTEMPTS: usize = 16;
+
+/// `$XDG_RUNTIME_DIR/screencast-shm.sock`, in the temporary directory
+/// without a runtime directory.
+pub fn default_socket_path() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("screencast-shm.sock")
+}
+
+#[derive(Debug, Clone)]
+pub struct ShmRingConfig {
+    /// Frames kept, so readers have time to copy one before it is reused.
+    pub slots: u32,
+    /// Larger frames are scaled down to fit, keeping their aspect ratio.
+    pub max_size: (u32, u32),
+}
+
+impl Default for ShmRingConfig {
+    fn default() -> Self {
+        Self {
+            slots: 3,
+            max_size: (3840, 2160),
+        }
+    }
+}
+
+/// A shared mapping of the memfd, unmapped when dropped.
+struct Mapping {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// Only touched through atomics and the seqlock protocol
+unsafe impl Send for Mapping {}
+
+impl Mapping {
+    fn new(fd: &OwnedFd, len: usize, writable: bool) -> std::io::Result<Self> {
+        let protection = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                protection,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Self {
+            ptr: NonNull::new(ptr.cast()).unwrap(),
+            len,
+        })
+    }
+
+    fn u32_at(&self, offset: usize) -> u32 {
+        assert!(offset + 4 <= self.len);
+        unsafe { self.ptr.as_ptr().add(offset).cast::<u32>().read_volatile() }
+    }
+
+    fn u64_at(&self, offset: usize) -> u64 {
+        assert!(offset + 8 <= self.len);
+        unsafe { self.ptr.as_ptr().add(offset).cast::<u64>().read_volatile() }
+    }
+
+    fn set_u32(&mut self, offset: usize, value: u32) {
+        assert!(offset + 4 <= self.len);
+        unsafe {
+            self.ptr
+                .as_ptr()
+                .add(offset)
+                .cast::<u32>()
+                .write_volatile(value)
+        }
+    }
+
+    fn set_u64(&mut self, offset: usize, value: u64) {
+        assert!(offset + 8 <= self.len);
+        unsafe {
+            self.ptr
+                .as_ptr()
+                .add(offset)
+                .cast::<u64>()
+                .write_volatile(value)
+        }
+    }
+
+    /// The 8 byte aligned counter at `offset`.
+    fn atomic(&self, offset: usize) -> &AtomicU64 {
+        assert!(offset + 8 <= self.len && offset.is_multiple_of(8));
+        unsafe { AtomicU64::from_ptr(self.ptr.as_ptr().add(offset).cast()) }
+    }
+
+    fn slice(&self, offset: usize, len: usize) -> &[u8] {
+        assert!(offset + len <= self.len);
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr().add(offset), len) }
+    }
+
+    fn slice_mut(&mut self, offset: usize, len: usize) -> &mut [u8] {
+        assert!(offset + len <= self.len);
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr().add(offset), len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr.as_ptr().cast(), self.len) };
+    }
+}
+
+/// Slot geometry, from the ring header.
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    slots: u32,
+    slot_size: usize,
+    slot_stride: usize,
+}
+
+impl Layout {
+    fn new(config: &ShmRingConfig) -> Self {
+        let slot_size = config.max_size.0 as usize * config.max_size.1 as usize * 4;
+        Self {
+            slots: config.slots.max(1),
+            slot_size,
+            slot_stride: HEADER_SIZE + slot_size.next_multiple_of(HEADER_SIZE),
+        }
+    }
+
+    fn read(map: &Mapping) -> std::io::Result<Self> {
+        if map.len < HEADER_SIZE || map.slice(0, 4) != MAGIC || map.u32_at(4) != VERSION {
+            return Err(invalid_data("Not a frame ring of a known version"));
+        }
+        let layout = Self {
+            slots: map.u32_at(8),
+            slot_size: map.u64_at(16) as usize,
+            slot_stride: map.u64_at(24) as usize,
+        };
+        if layout.slots == 0
+            || layout.slot_stride < HEADER_SIZE + layout.slot_size
+            || layout.len() > map.len
+        {
+            return Err(invalid_data("Frame ring header does not fit the memory"));
+        }
+        Ok(layout)
+    }
+
+    fn write(&self, map: &mut Mapping) {
+        map.slice_mut(0, 4).copy_from_slice(&MAGIC);
+        map.set_u32(4, VERSION);
+        map.set_u32(8, self.slots);
+        map.set_u64(16, self.slot_size as u64);
+        map.set_u64(24, self.slot_stride as u64);
+    }
+
+    fn len(&self) -> usize {
+        HEADER_SIZE + self.slots as usize * self.slot_stride
+    }
+
+    /// Offset of the header of the slot holding frame `number`.
+    fn slot(&self, number: u64) -> usize {
+        HEADER_SIZE + ((number - 1) % self.slots as u64) as usize * self.slot_stride
+    }
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+/// A frame copied out of the ring.
+#[derive(Debug, Clone)]
+pub struct ShmFrame {
+    pub number: u64,
+    pub pts: Duration,
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32,
+    pub fourcc: u32,
+    pub data: Vec<u8>,
+}
+
+impl ShmFrame {
+    pub fn to_frame(&self) -> Frame {
+        let mut frame = Frame::new(self.width, self.height);
+        let row = self.width as usize * 4;
+        for (y, out) in frame.make_mut_bytes().chunks_exact_mut(row).enumerate() {
+            out.copy_from_slice(&self.data[y * self.stride as usize..][..row]);
+        }
+        frame
+    }
+}
+
+struct Shared {
+    memfd: OwnedFd,
+    len: usize,
+    clients: Mutex<Vec<Client>>,
+    stopped: AtomicBool,
+}
+
+/// A connected client and the part of a frame number its socket did not
+/// take yet.
+struct Client {
+    stream: UnixStream,
+    pending: Vec<u8>,
+}
+
+impl Client {
+    /// Writes `number` unless the rest of the previous one is still stuck.
+    /// `false` once the client is gone.
+    fn notify(&mut self, number: u64) -> bool {
+        if !self.flush() {
+            return false;
+        }
+        if self.pending.is_empty() {
+            self.pending.extend_from_slice(&number.to_le_bytes());
+        }
+        self.flush()
+    }
+
+    /// Writes what it can of `pending`, `false` once the client is gone.
+    fn flush(&mut self) -> bool {
+        while !self.pending.is_empty() {
+            match self.stream.write(&self.pending) {
+                Ok(0) => return false,
+                Ok(written) => {
+                    self.pending.drain(..written);
+                }
+                // Catches up with a later number
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return true,
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Publishes frames into a memfd ring shared with the clients of a Unix
+/// socket.
+pub struct ShmRingServer {
+    shared: Arc<Shared>,
+    map: Mapping,
+    layout: Layout,
+    config: ShmRingConfig,
+    path: PathBuf,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ShmRingServer {
+    /// Creates the ring and listens on `path`, replacing a socket left
+    /// behind by a server that is gone but not one that still answers.
+    pub fn bind(path: impl Into<PathBuf>, config: ShmRingConfig) -> std::io::Result<Self> {
+        let path = path.into();
+        // Frames are scaled to fit, a slot has room for at least one pixel
+        if config.max_size.0 == 0 || config.max_size.1 == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "the largest frame size must not be zero",
+            ));
+        }
+        let layout = Layout::new(&config);
+        let memfd = unsafe {
+            libc::memfd_create(
+                c"screencast-frames".as_ptr(),
+                libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+            )
+        };
+        if memfd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let memfd = unsafe { OwnedFd::from_raw_fd(memfd) };
+        // Pages are only allocated once written
+        if unsafe { libc::ftruncate(memfd.as_raw_fd(), layout.len() as libc::off_t) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut map = Mapping::new(&memfd, layout.len(), true)?;
+        layout.write(&mut map);
+        // Clients must not be able to pull the memory from under the server
+        // or write to it, the server's own mapping stays writable
+        let seals =
+            libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_FUTURE_WRITE | libc::F_SEAL_SEAL;
+        if unsafe { libc::fcntl(memfd.as_raw_fd(), libc::F_ADD_SEALS, seals) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        if UnixStream::connect(&path).is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                format!("{} is served by another process", path.display()),
+            ));
+        }
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        // Polled so that stop() does not have to wake a blocked accept()
+        listener.set_nonblocking(true)?;
+        let shared = Arc::new(Shared {
+            memfd,
+            len: layout.len(),
+            clients: Mutex::new(Vec::new()),
+            stopped: AtomicBool::new(false),
+        });
+        let thread = std::thread::spawn({
+            let shared = Arc::clone(&shared);
+            move || accept(listener, shared)
+        });
+        Ok(Self {
+            shared,
+            map,
+            layout,
+            config,
+            path,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn clients(&self) -> usize {
+        self.shared.clients.lock().unwrap().len()
+    }
+
+    /// Writes `frame` into the next slot and tells the clients. Returns its
+    /// number.
+    pub fn publish(&mut self, frame: &TimedFrame) -> u64 {
+        let (max_width, max_height) = self.config.max_size;
+        let (width, height) = (frame.frame.width(), frame.frame.height());
+        let scaled = (width > max_width || height > max_height).then(|| {
+            let scale = f64::min(
+                max_width as f64 / width as f64,
+                max_height as f64 / height as f64,
+            );
+            let fit = |size: u32| ((size as f64 * scale) as u32).max(1);
+            frame_transform::scale(&frame.frame, fit(width), fit(height))
+        });
+        let image = scaled.as_ref().unwrap_or(&frame.frame);
+        let pixels = image.as_bytes();
+
+        let number = self.published().load(Ordering::Relaxed) + 1;
+        let slot = self.layout.slot(number);
+        let sequence = self.map.atomic(slot).load(Ordering::Relaxed);
+        // Odd while written, readers that saw the old value retry
+        self.map.atomic(slot).store(sequence + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.map.set_u64(slot + 8, number);
+        self.map.set_u64(slot + 16, frame.pts.as_nanos() as u64);
+        self.map.set_u32(slot + 24, image.width());
+        self.map.set_u32(slot + 28, image.height());
+        self.map.set_u32(slot + 32, image.width() * 4);
+        self.map.set_u32(slot + 36, FOURCC_RGBA);
+        self.map.set_u64(slot + 40, pixels.len() as u64);
+        self.map
+            .slice_mut(slot + HEADER_SIZE, pixels.len())
+            .copy_from_slice(pixels);
+        self.map.atomic(slot).store(sequence + 2, Ordering::Release);
+        self.published().store(number, Ordering::Release);
+
+        let mut clients = self.shared.clients.lock().unwrap();
+        clients.retain_mut(|client| client.notify(number));
+        number
+    }
+
+    fn published(&self) -> &AtomicU64 {
+        self.map.atomic(32)
+    }
+
+    /// Disconnects the clients and removes the socket. Their mappings stay
+    /// valid.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+            let _ = std::fs::remove_file(&self.path);
+        }
+        self.shared.clients.lock().unwrap().clear();
+    }
+}
+
+impl Drop for ShmRingServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn accept(listener: UnixListener, shared: Arc<Shared>) {
+    while !shared.stopped.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = greet(&stream, &shared) {
+                    println!("Frame ring client failed: {e}");
+                    continue;
+                }
+                shared.clients.lock().unwrap().push(Client {
+                    stream,
+                    pending: Vec::new(),
+                });
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(20))
+            }
+            Err(e) => {
+                println!("Frame ring server failed: {e}");
+                return;
+            }
+        }
+    }
+}
+
+/// Sends the hello with the memfd attached, then leaves the socket
+/// non-blocking so that slow clients do not hold up publishing.
+fn greet(stream: &UnixStream, shared: &Shared) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    let mut hello = [0; HELLO_SIZE];
+    hello[0..4].copy_from_slice(&MAGIC);
+    hello[4..8].copy_from_slice(&VERSION.to_le_bytes());
+    hello[8..16].copy_from_slice(&(shared.len as u64).to_le_bytes());
+    send_with_fds(stream, &hello, &[shared.memfd.as_raw_fd()])?;
+    stream.set_nonblocking(true)
+}
+
+/// Reads the frames of a [`ShmRingServer`].
+pub struct ShmRingClient {
+    stream: UnixStream,
+    map: Mapping,
+    layout: Layout,
+    /// Number of the last frame returned.
+    last: u64,
+}
+
+impl ShmRingClient {
+    /// Connects and maps the ring.
+    pub fn connect(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        let mut hello = [0; HELLO_SIZE];
+        let mut fds = recv_with_fds(&stream, &mut hello)?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Frame ring server closed the connection",
+            )
+        })?;
+        if hello[0..4] != MAGIC || hello[4..8] != VERSION.to_le_bytes() {
+            return Err(invalid_data("Not a frame ring server of a known version"));
+        }
+        if fds.len() != 1 {
+            return Err(invalid_data("Expected the frame ring memfd with the hello"));
+        }
+        let memfd = fds.remove(0);
+        let len = u64::from_le_bytes(hello[8..16].try_into().unwrap()) as usize;
+        let map = Mapping::new(&memfd, len, false)?;
+        let layout = Layout::read(&map)?;
+        Ok(Self {
+            stream,
+            map,
+            layout,
+            last: 0,
+        })
+    }
+
+    /// How long [`wait`](Self::wait) blocks, `None` for ever.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    /// The latest frame if it is newer than the last one returned.
+    pub fn latest(&mut self) -> std::io::Result<Option<ShmFrame>> {
+        let published = self.map.atomic(32).load(Ordering::Acquire);
+        if published <= self.last {
+            return Ok(None);
+        }
+        let frame = self.read(published)?;
+        self.last = frame.number;
+        Ok(Some(frame))
+    }
+
+    /// Waits for a frame newer than the last one returned, `None` once the
+    /// server stopped.
+    pub fn wait(&mut self) -> std::io::Result<Option<ShmFrame>> {
+        loop {
+            if let Some(frame) = self.latest()? {
+                return Ok(Some(frame));
+            }
+            let mut number = [0; 8];
+            match self.stream.read_exact(&mut number) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Copies frame `number`, or whatever replaced it in its slot.
+    fn read(&self, number: u64) -> std::io::Result<ShmFrame> {
+        let slot = self.layout.slot(number);
+        for _ in 0..READ_ATTEMPTS {
+            let sequence = self.map.atomic(slot).load(Ordering::Acquire);
+            if sequence % 2 == 1 {
+                std::thread::yield_now();
+                continue;
+            }
+            let len = (self.map.u64_at(slot + 40) as usize).min(self.layout.slot_size);
+            let frame = ShmFrame {
+                number: self.map.u64_at(slot + 8),
+                pts: Duration::from_nanos(self.map.u64_at(slot + 16)),
+                width: self.map.u32_at(slot + 24),
+                height: self.map.u32_at(slot + 28),
+                stride: self.map.u32_at(slot + 32),
+                fourcc: self.map.u32_at(slot + 36),
+                data: self.map.slice(slot + HEADER_SIZE, len).to_vec(),
+            };
+            fence(Ordering::Acquire);
+            if self.map.atomic(slot).load(Ordering::Relaxed) != sequence {
+                continue;
+            }
+            if frame.stride < frame.width * 4
+                || frame.stride as usize * frame.height as usize > frame.data.len()
+            {
+                return Err(invalid_data("Frame does not fit its slot"));
+            }
+            return Ok(frame);
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "Frame kept changing while read",
+        ))
+    }
+}
+
+/// Publishes frames to `server` until the channel closes or `stop` returns
+/// true.
+pub fn serve(
+    frames: &async_channel::Receiver<TimedFrame>,
+    server: &mut ShmRingServer,
+    stop: impl Fn() -> bool,
+) {
+    let _ = pipewire_stream::for_each_frame(frames, stop, |frame| {
+        if frame.frame.width() > 0 && frame.frame.height() > 0 {
+            server.publish(&frame);
+        }
+        Ok(())
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Mapping, ShmRingClient, ShmRingConfig, ShmRingServer, FOURCC_RGBA};
+    use crate::dma_buf_share::recv_with_fds;
+    use crate::pipewire_stream::{Frame, TimedFrame};
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("screencast-{}-{name}.sock", std::process::id()))
+    }
+
+    fn solid(width: u32, height: u32, value: u8, pts: Duration) -> TimedFrame {
+        let mut frame = Frame::new(width, height);
+        frame
+            .make_mut_slice()
+            .fill(slint::Rgba8Pixel::new(value, 255 - value, value / 2, 255));
+        TimedFrame {
+            frame,
+            pts,
+            damage: None,
+        }
+    }
+
+    #[test]
+    fn zero_max_size_is_rejected() {
+        for max_size in [(0, 0), (0, 10), (10, 0)] {
+            let config = ShmRingConfig {
+                max_size,
+                ..Default::default()
+            };
+            let result = ShmRingServer::bind(socket_path("zero"), config);
+            assert_eq!(
+                result.err().map(|e| e.kind()),
+                Some(std::io::ErrorKind::InvalidInput)
+            );
+        }
+    }
+
+    fn wait_for_client(server: &ShmRingServer) {
+        for _ in 0..250 {
+            if server.clients() > 0 {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        panic!("Client did not connect");
+    }
+
+    fn connect(server: &ShmRingServer) -> ShmRingClient {
+        let client = ShmRingClient::connect(server.path()).unwrap();
+        client.set_timeout(Some(Duration::from_secs(5))).unwrap();
+        wait_for_client(server);
+        client
+    }
+
+    /// The socket and memory layout from the module documentation, read
+    /// byte by byte as a client in another language would.
+    #[test]
+    fn protocol_bytes() {
+        let config = ShmRingConfig {
+            slots: 2,
+            max_size: (8, 4),
+        };
+        let mut server = ShmRingServer::bind(socket_path("bytes"), config).unwrap();
+        let stream = UnixStream::connect(server.path()).unwrap();
+        let mut hello = [0; 16];
+        let mut fds = recv_with_fds(&stream, &mut hello).unwrap().unwrap();
+        assert_eq!(fds.len(), 1);
+        assert_eq!(&hello[0..8], b"SCRG\x01\0\0\0");
+        let len = u64::from_le_bytes(hello[8..16].try_into().unwrap()) as usize;
+        // Slot stride 64 + 8 * 4 * 4
+        assert_eq!(len, 64 + 2 * 192);
+        let memfd = fds.remove(0);
+        assert!(Mapping::new(&memfd, len, true).is_err());
+        let map = Mapping::new(&memfd, len, false).unwrap();
+        wait_for_client(&server);
+
+        let sent = solid(4, 2, 7, Duration::from_millis(5));
+        server.publish(&sent);
+        let mut number = [0; 8];
+        (&stream).read_exact(&mut number).unwrap();
+        assert_eq!(u64::from_le_bytes(number), 1);
+
+        let bytes = map.slice(0, len);
+        let u32_at = |offset: usize| u32::from_ne_bytes(bytes[offset..][..4].try_into().unwrap());
+        let u64_at = |offset: usize| u64::from_ne_bytes(bytes[offset..][..8].try_into().unwrap());
+        assert_eq!(&bytes[0..4], b"SCRG");
+        assert_eq!((u32_at(4), u32_at(8)), (1, 2));
+        assert_eq!((u64_at(16), u64_at(24), u64_at(32)), (128, 192, 1));
+        // Frame 1 in slot 0
+        assert_eq!((u64_at(64), u64_at(72), u64_at(80)), (2, 1, 5_000_000));
+        assert_eq!(
+            [u32_at(88), u32_at(92), u32_at(96), u32_at(100)],
+            [4, 2, 16, u32::from_le_bytes(*b"AB24")]
+        );
+        assert_eq!(u64_at(104), 32);
+        assert_eq!(&bytes[128..160], sent.frame.as_bytes());
+
+        // A client that stops reading misses numbers, but never gets part
+        // of one
+        let tiny = solid(1, 1, 0, Duration::ZERO);
+        for _ in 0..50_000 {
+            server.publish(&tiny);
+        }
+        stream.set_nonblocking(true).unwrap();
+        let mut received = Vec::new();
+        let _ = (&stream).read_to_end(&mut received);
+        let numbers = received
+            .chunks(8)
+            .map(|n| u64::from_le_bytes(n.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        assert!(numbers.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(numbers.iter().all(|&n| (2..=50_001).contains(&n)));
+    }
+
+    #[test]
+    fn clients_read_the_latest_frame() {
+        let config = ShmRingConfig {
+            slots: 2,
+            max_size: (64, 64),
+        };
+        let mut server = ShmRingServer::bind(socket_path("ring"), config).unwrap();
+        let mut client = connect(&server);
+        assert!(client.latest().unwrap().is_none());
+
+        let sent = solid(40, 30, 10, Duration::from_millis(20));
+        assert_eq!(server.publish(&sent), 1);
+        let received = client.wait().unwrap().unwrap();
+        assert_eq!(received.number, 1);
+        assert_eq!(received.pts, Duration::from_millis(20));
+        assert_eq!((received.width, received.height), (40, 30));
+        assert_eq!((received.stride, received.fourcc), (160, FOURCC_RGBA));
+        assert_eq!(received.to_frame().as_bytes(), sent.frame.as_bytes());
+        assert!(client.latest().unwrap().is_none());
+
+        // Slots are reused, readers skip to the newest frame
+        for value in 2..=5 {
+            server.publish(&solid(40, 30, value, Duration::ZERO));
+        }
+        let latest = client.wait().unwrap().unwrap();
+        assert_eq!(latest.number, 5);
+        assert_eq!(latest.data[0], 5);
+
+        // Too large for the slots
+        server.publish(&solid(200, 100, 6, Duration::ZERO));
+        let scaled = client.wait().unwrap().unwrap();
+        assert_eq!((scaled.width, scaled.height), (64, 32));
+
+        let path = server.path().to_owned();
+        server.stop();
+        assert!(client.wait().unwrap().is_none());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn reads_are_never_torn() {
+        let config = ShmRingConfig {
+            slots: 2,
+            max_size: (128, 128),
+        };
+        let mut server = ShmRingServer::bind(socket_path("torn"), config).unwrap();
+        let mut client = connect(&server);
+        let writer = std::thread::spawn(move || {
+            for i in 0..500u32 {
+                server.publish(&solid(128, 128, i as u8, Duration::ZERO));
+            }
+            server
+        });
+        let mut frames = 0;
+        while !writer.is_finished() {
+            match client.latest() {
+                Ok(Some(frame)) => {
+                    let first = &frame.data[..4];
+                    assert!(frame.data.chunks_exact(4).all(|pixel| pixel == first));
+                    assert_eq!(first[0], (frame.number - 1) as u8);
+                    frames += 1;
+                }
+                // The writer lapped the reader too often
+                Ok(None) | Err(_) => {}
+            }
+        }
+        writer.join().unwrap().stop();
+        assert!(frames > 0);
+    }
+}
@@ -0,0 +1,194 @@
+/// Frame contents drawn by the synthetic test source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    /// SMPTE-like vertical colour bars.
+    Bars,
+    /// White box bouncing over a grey background.
+    MovingBox,
+    /// Frame number printed over colour bars, handy to spot dropped frames.
+    Counter,
+}
+
+impl std::str::FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bars" => Ok(Pattern::Bars),
+            "box" | "moving-box" => Ok(Pattern::MovingBox),
+            "counter" => Ok(Pattern::Counter),
+            _ => Err(format!(
+                "Unknown pattern '{s}', expected bars, box or counter"
+            )),
+        }
+    }
+}
+
+/// Byte order of a 32 bits per pixel format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelOrder {
+    /// RGBA / RGBx
+    Rgba,
+    /// BGRA / BGRx
+    Bgra,
+}
+
+const BARS: [[u8; 3]; 8] = [
+    [255, 255, 255],
+    [255, 255, 0],
+    [0, 255, 255],
+    [0, 255, 0],
+    [255, 0, 255],
+    [255, 0, 0],
+    [0, 0, 255],
+    [0, 0, 0],
+];
+
+// 3x5 bitmaps of the digits, one row per 3 low bits
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+/// Frame of `width` x `height` pixels, 4 bytes each, rows `stride` bytes
+/// apart. Padding bytes are left untouched.
+pub struct Canvas<'a> {
+    pub data: &'a mut [u8],
+    pub width: u32,
+    pub height: u32,
+    pub stride: usize,
+    pub order: ChannelOrder,
+}
+
+impl Canvas<'_> {
+    fn put(&mut self, x: u32, y: u32, rgb: [u8; 3]) {
+        let idx = y as usize * self.stride + x as usize * 4;
+        let pixel = &mut self.data[idx..idx + 4];
+        match self.order {
+            ChannelOrder::Rgba => pixel.copy_from_slice(&[rgb[0], rgb[1], rgb[2], 255]),
+            ChannelOrder::Bgra => pixel.copy_from_slice(&[rgb[2], rgb[1], rgb[0], 255]),
+        }
+    }
+
+    fn fill_rect(&mut self, x: u32, y: u32, width: u32, height: u32, rgb: [u8; 3]) {
+        for py in y..(y + height).min(self.height) {
+            for px in x..(x + width).min(self.width) {
+                self.put(px, py, rgb);
+            }
+        }
+    }
+
+    fn bars(&mut self) {
+        for x in 0..self.width {
+            let rgb = BARS[(x as usize * BARS.len()) / self.width as usize];
+            for y in 0..self.height {
+                self.put(x, y, rgb);
+            }
+        }
+    }
+
+    fn number(&mut self, value: u64, scale: u32) {
+        let text = value.to_string();
+        // Black plate behind the digits so they stay readable on any bar
+        let plate_width = (text.len() as u32 * 4 + 1) * scale;
+        self.fill_rect(0, 0, plate_width, 7 * scale, [0, 0, 0]);
+
+        for (i, digit) in text.bytes().enumerate() {
+            let glyph = DIGITS[(digit - b'0') as usize];
+            let origin_x = (i as u32 * 4 + 1) * scale;
+            for (row, bits) in glyph.iter().enumerate() {
+                for col in 0..3 {
+                    if bits & (0b100 >> col) != 0 {
+                        self.fill_rect(
+                            origin_x + col * scale,
+                            (row as u32 + 1) * scale,
+                            scale,
+                            scale,
+                            [255, 255, 255],
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Draws frame number `frame` of `pattern`.
+    pub fn draw(&mut self, pattern: Pattern, frame: u64) {
+        match pattern {
+            Pattern::Bars => self.bars(),
+            Pattern::MovingBox => {
+                self.fill_rect(0, 0, self.width, self.height, [64, 64, 64]);
+                let size = (self.width.min(self.height) / 4).max(1);
+                let range_x = (self.width - size).max(1) as u64;
+                let range_y = (self.height - size).max(1) as u64;
+                // Bounce back and forth, moving 4 pixels per frame
+                let bounce = |range: u64, pos: u64| {
+                    let pos = pos % (2 * range);
+                    if pos < range {
+                        pos
+                    } else {
+                        2 * range - pos
+                    }
+                };
+                let x = bounce(range_x, frame * 4) as u32;
+                let y = bounce(range_y, frame * 3) as u32;
+                self.fill_rect(x, y, size, size, [255, 255, 255]);
+            }
+            Pattern::Counter => {
+                self.bars();
+                let scale = (self.height / 16).max(1);
+                self.number(frame, scale);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Canvas, ChannelOrder, Pattern};
+
+    #[test]
+    fn bars_respect_stride_and_order() {
+        let (width, height, stride) = (8, 2, 40);
+        let mut data = vec![0xAA; stride * height as usize];
+        Canvas {
+            data: &mut data,
+            width,
+            height,
+            stride,
+            order: ChannelOrder::Bgra,
+        }
+        .draw(Pattern::Bars, 0);
+
+        // Second bar is yellow, stored as BGRA
+        assert_eq!(&data[4..8], &[0, 255, 255, 255]);
+        // Padding after each row is untouched
+        assert!(data[32..40].iter().all(|&b| b == 0xAA));
+        assert_eq!(&data[40..44], &[255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn moving_box_moves() {
+        let draw = |frame| {
+            let mut data = vec![0; 32 * 32 * 4];
+            Canvas {
+                data: &mut data,
+                width: 32,
+                height: 32,
+                stride: 32 * 4,
+                order: ChannelOrder::Rgba,
+            }
+            .draw(Pattern::MovingBox, frame);
+            data
+        };
+        assert_ne!(draw(0), draw(1));
+    }
+}
@@ -0,0 +1,751 @@
+use crate::test_pattern::{Canvas, ChannelOrder, Pattern};
+use pipewire::spa::param::video::VideoFormat;
+use std::path::PathBuf;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct TestSourceConfig {
+    /// Node name, e.g. to pick it with `target.object`.
+    pub name: String,
+    pub pattern: Pattern,
+    /// One of `BGRx`, `BGRA`, `RGBx`, `RGBA`, or `YUY2` like a camera, which
+    /// is only offered in MemFd buffers.
+    pub format: VideoFormat,
+    pub width: u32,
+    pub height: u32,
+    /// Extra bytes at the end of every row of MemFd buffers.
+    pub stride_padding: u32,
+    pub fps: u32,
+    /// DRM device to allocate DMA-BUF buffers from with GBM. MemFd buffers
+    /// are offered as well, the consumer picks.
+    pub dma_buf_device: Option<PathBuf>,
+}
+
+impl Default for TestSourceConfig {
+    fn default() -> Self {
+        Self {
+            name: "screencast-test-source".to_owned(),
+            pattern: Pattern::Counter,
+            format: VideoFormat::BGRx,
+            width: 320,
+            height: 240,
+            stride_padding: 0,
+            fps: 30,
+            dma_buf_device: None,
+        }
+    }
+}
+
+/// Output PipeWire stream producing test patterns, connected to the default
+/// PipeWire daemon.
+pub struct TestSource {
+    thread: SourceThread,
+    node_id: u32,
+}
+
+impl TestSource {
+    /// Starts the source and waits until PipeWire assigned it a node id.
+    pub fn start(config: TestSourceConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let (thread, node_id) = SourceThread::spawn(move |node_sender, cmd_receiver| {
+            inner::source_thread(config, node_sender, cmd_receiver)
+        })?;
+        Ok(Self { thread, node_id })
+    }
+
+    pub fn node_id(&self) -> u32 {
+        self.node_id
+    }
+
+    /// Blocks until the source thread exits.
+    pub fn wait(mut self) {
+        if let Some(handle) = self.thread.thread_handle.take() {
+            handle.join().unwrap();
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.thread.stop();
+    }
+}
+
+/// Output PipeWire stream playing a tone in the format audio is captured
+/// in, connected to the default PipeWire daemon.
+pub struct TestAudioSource {
+    thread: SourceThread,
+    node_id: u32,
+}
+
+impl TestAudioSource {
+    /// Starts the source named `name` and waits until PipeWire assigned it a
+    /// node id.
+    pub fn start(name: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let name = name.to_owned();
+        let (thread, node_id) = SourceThread::spawn(move |node_sender, cmd_receiver| {
+            inner::audio_source_thread(name, node_sender, cmd_receiver)
+        })?;
+        Ok(Self { thread, node_id })
+    }
+
+    pub fn node_id(&self) -> u32 {
+        self.node_id
+    }
+
+    pub fn stop(&mut self) {
+        self.thread.stop();
+    }
+}
+
+/// The thread running a source's main loop, stopped when dropped.
+struct SourceThread {
+    thread_handle: Option<JoinHandle<()>>,
+    cmd_sender: Option<pipewire::channel::Sender<inner::Command>>,
+}
+
+impl SourceThread {
+    /// Runs `source` on a new thread and waits for the node id it sends.
+    fn spawn<F>(source: F) -> Result<(Self, u32), Box<dyn std::error::Error>>
+    where
+        F: FnOnce(
+                std::sync::mpsc::Sender<u32>,
+                pipewire::channel::Receiver<inner::Command>,
+            ) -> Result<(), Box<dyn std::error::Error>>
+            + Send
+            + 'static,
+    {
+        let (cmd_sender, cmd_receiver) = pipewire::channel::channel();
+        let (node_sender, node_receiver) = std::sync::mpsc::channel();
+        let thread_handle = std::thread::spawn(move || {
+            if let Err(e) = source(node_sender, cmd_receiver) {
+                println!("Test source failed: {e}");
+            }
+        });
+
+        let thread = Self {
+            thread_handle: Some(thread_handle),
+            cmd_sender: Some(cmd_sender),
+        };
+        // Dropping the thread on a timeout stops it rather than leave it running
+        let node_id = node_receiver.recv_timeout(Duration::from_secs(5))?;
+        Ok((thread, node_id))
+    }
+
+    fn stop(&mut self) {
+        if let Some(cmd_sender) = self.cmd_sender.take() {
+            let _ = cmd_sender.send(inner::Command::Stop);
+        }
+        if let Some(handle) = self.thread_handle.take() {
+            handle.join().unwrap();
+        }
+    }
+}
+
+impl Drop for SourceThread {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn channel_order(format: VideoFormat) -> ChannelOrder {
+    match format {
+        VideoFormat::RGBA | VideoFormat::RGBx => ChannelOrder::Rgba,
+        _ => ChannelOrder::Bgra,
+    }
+}
+
+/// Bytes of pixels in a row of `width`, before padding.
+fn row_bytes(format: VideoFormat, width: u32) -> u32 {
+    match format {
+        VideoFormat::YUY2 => width.div_ceil(2) * 4,
+        _ => width * 4,
+    }
+}
+
+mod inner {
+    use super::{channel_order, row_bytes, Canvas, ChannelOrder, TestSourceConfig};
+    use crate::pipewire_stream::Frame;
+    use pipewire::spa;
+    use pipewire::{self as pw, context::Context, main_loop::MainLoop, properties::properties};
+    use std::collections::HashMap;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    #[derive(Debug)]
+    pub enum Command {
+        Stop,
+    }
+
+    enum Allocation {
+        MemFd {
+            _fd: OwnedFd,
+            ptr: *mut u8,
+            size: usize,
+        },
+        DmaBuf {
+            _fd: OwnedFd,
+            bo: gbm::BufferObject<()>,
+        },
+    }
+
+    impl Drop for Allocation {
+        fn drop(&mut self) {
+            if let Allocation::MemFd { ptr, size, .. } = self {
+                unsafe { libc::munmap(*ptr as *mut _, *size) };
+            }
+        }
+    }
+
+    struct UserData {
+        config: TestSourceConfig,
+        format: spa::param::video::VideoInfoRaw,
+        stride: u32,
+        frame: u64,
+        gbm: Option<gbm::Device<std::fs::File>>,
+        buffers: HashMap<usize, Allocation>,
+    }
+
+    pub fn source_thread(
+        config: TestSourceConfig,
+        node_sender: std::sync::mpsc::Sender<u32>,
+        pw_receiver: pipewire::channel::Receiver<Command>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mainloop = Rc::new(MainLoop::new(None)?);
+        let context = Context::new(&*mainloop)?;
+        let core = context.connect(None)?;
+
+        let gbm = match &config.dma_buf_device {
+            Some(path) => {
+                let drm_fd = std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(path)?;
+                Some(gbm::Device::new(drm_fd)?)
+            }
+            None => None,
+        };
+
+        let stream = Rc::new(pw::stream::Stream::new(
+            &core,
+            &config.name,
+            properties! {
+                *pw::keys::MEDIA_CLASS => "Video/Source",
+                *pw::keys::MEDIA_TYPE => "Video",
+                *pw::keys::MEDIA_CATEGORY => "Playback",
+                *pw::keys::NODE_DESCRIPTION => "Screencast test pattern",
+            },
+        )?);
+
+        let interval = Duration::from_secs(1) / config.fps.max(1);
+        let mut node_sender = Some(node_sender);
+
+        let data = UserData {
+            config: config.clone(),
+            format: Default::default(),
+            stride: 0,
+            frame: 0,
+            gbm,
+            buffers: HashMap::new(),
+        };
+
+        let _listener = stream
+            .add_local_listener_with_user_data(data)
+            .state_changed(move |stream, _, old, new| {
+                println!("Test source state changed: {:?} -> {:?}", old, new);
+                if let pw::stream::StreamState::Paused | pw::stream::StreamState::Streaming = new {
+                    if let Some(sender) = node_sender.take() {
+                        let _ = sender.send(stream.node_id());
+                    }
+                }
+            })
+            .param_changed(|stream, user_data, id, param| {
+                let Some(param) = param else {
+                    return;
+                };
+                if id != pw::spa::param::ParamType::Format.as_raw() {
+                    return;
+                }
+                if user_data.format.parse(param).is_err() {
+                    return;
+                }
+
+                let size = user_data.format.size();
+                user_data.stride = row_bytes(user_data.format.format(), size.width)
+                    + user_data.config.stride_padding;
+
+                let dma_buf = user_data.gbm.is_some()
+                    && user_data
+                        .format
+                        .flags()
+                        .contains(spa::param::video::VideoFlags::MODIFIER);
+                let data_type = if dma_buf {
+                    spa::buffer::DataType::DmaBuf
+                } else {
+                    spa::buffer::DataType::MemFd
+                };
+                println!(
+                    "Test source format: {:?} {}x{}, {:?}",
+                    user_data.format.format(),
+                    size.width,
+                    size.height,
+                    data_type
+                );
+
+                let buffers = spa::pod::Object {
+                    type_: spa::sys::SPA_TYPE_OBJECT_ParamBuffers,
+                    id: spa::sys::SPA_PARAM_Buffers,
+                    properties: vec![
+                        spa::pod::Property::new(
+                            spa::sys::SPA_PARAM_BUFFERS_buffers,
+                            spa::pod::Value::Choice(spa::pod::ChoiceValue::Int(
+                                spa::utils::Choice(
+                                    spa::utils::ChoiceFlags::empty(),
+                                    spa::utils::ChoiceEnum::Range {
+                                        default: 8,
+                                        min: 2,
+                                        max: 16,
+                                    },
+                                ),
+                            )),
+                        ),
+                        spa::pod::Property::new(
+                            spa::sys::SPA_PARAM_BUFFERS_blocks,
+                            spa::pod::Value::Int(1),
+                        ),
+                        spa::pod::Property::new(
+                            spa::sys::SPA_PARAM_BUFFERS_size,
+                            spa::pod::Value::Int((user_data.stride * size.height) as i32),
+                        ),
+                        spa::pod::Property::new(
+                            spa::sys::SPA_PARAM_BUFFERS_stride,
+                            spa::pod::Value::Int(user_data.stride as i32),
+                        ),
+                        spa::pod::Property::new(
+                            spa::sys::SPA_PARAM_BUFFERS_dataType,
+                            spa::pod::Value::Choice(spa::pod::ChoiceValue::Int(
+                                spa::utils::Choice(
+                                    spa::utils::ChoiceFlags::empty(),
+                                    spa::utils::ChoiceEnum::Flags {
+                                        default: 1 << data_type.as_raw(),
+                                        flags: vec![1 << data_type.as_raw()],
+                                    },
+                                ),
+                            )),
+                        ),
+                    ],
+                };
+                let header = spa::pod::Object {
+                    type_: spa::sys::SPA_TYPE_OBJECT_ParamMeta,
+                    id: spa::sys::SPA_PARAM_Meta,
+                    properties: vec![
+                        spa::pod::Property::new(
+                            spa::sys::SPA_PARAM_META_type,
+                            spa::pod::Value::Id(spa::utils::Id(spa::sys::SPA_META_Header)),
+                        ),
+                        spa::pod::Property::new(
+                            spa::sys::SPA_PARAM_META_size,
+                            spa::pod::Value::Int(
+                                std::mem::size_of::<spa::sys::spa_meta_header>() as i32
+                            ),
+                        ),
+                    ],
+                };
+
+                let values = [serialize(buffers), serialize(header)];
+                let mut params = values
+                    .iter()
+                    .map(|v| spa::pod::Pod::from_bytes(v).unwrap())
+                    .collect::<Vec<_>>();
+                if let Err(e) = stream.update_params(&mut params) {
+                    println!("Failed to update test source params: {e}");
+                }
+            })
+            .add_buffer(|_, user_data, buffer| unsafe {
+                let spa_buffer = (*buffer).buffer;
+                if spa_buffer.is_null() || (*spa_buffer).n_datas < 1 {
+                    return;
+                }
+                let data = &mut *(*spa_buffer).datas;
+                let size = user_data.format.size();
+
+                let allocation = if data.type_ & (1 << spa::sys::SPA_DATA_DmaBuf) != 0 {
+                    allocate_dma_buf(user_data, data, size.width, size.height)
+                } else if data.type_ & (1 << spa::sys::SPA_DATA_MemFd) != 0 {
+                    allocate_memfd(data, (user_data.stride * size.height) as usize)
+                } else {
+                    None
+                };
+
+                match allocation {
+                    Some(allocation) => {
+                        user_data.buffers.insert(buffer as usize, allocation);
+                    }
+                    None => println!("Test source failed to allocate a buffer"),
+                }
+            })
+            .remove_buffer(|_, user_data, buffer| {
+                user_data.buffers.remove(&(buffer as usize));
+            })
+            .process(|stream, user_data| {
+                let raw_buffer = unsafe { stream.dequeue_raw_buffer() };
+                if raw_buffer.is_null() {
+                    return;
+                }
+
+                let size = user_data.format.size();
+                let order = channel_order(user_data.format.format());
+                let pattern = user_data.config.pattern;
+                let frame = user_data.frame;
+                user_data.frame += 1;
+
+                let stride = match user_data.buffers.get_mut(&(raw_buffer as usize)) {
+                    Some(Allocation::MemFd { ptr, size: len, .. }) => {
+                        let data = unsafe { std::slice::from_raw_parts_mut(*ptr, *len) };
+                        let stride = user_data.stride as usize;
+                        if user_data.format.format() == spa::param::video::VideoFormat::YUY2 {
+                            let mut rgba = Frame::new(size.width, size.height);
+                            Canvas {
+                                data: rgba.make_mut_bytes(),
+                                width: size.width,
+                                height: size.height,
+                                stride: size.width as usize * 4,
+                                order: ChannelOrder::Rgba,
+                            }
+                            .draw(pattern, frame);
+                            crate::yuv::frame_to_yuy2(&rgba, data, stride);
+                        } else {
+                            Canvas {
+                                data,
+                                width: size.width,
+                                height: size.height,
+                                stride,
+                                order,
+                            }
+                            .draw(pattern, frame);
+                        }
+                        user_data.stride
+                    }
+                    Some(Allocation::DmaBuf { bo, .. }) => {
+                        let gbm = user_data.gbm.as_ref().unwrap();
+                        let stride = bo.stride().unwrap_or(size.width * 4);
+                        let _ = bo.map_mut(gbm, 0, 0, size.width, size.height, |mbo| {
+                            let stride = mbo.stride() as usize;
+                            Canvas {
+                                data: mbo.buffer_mut(),
+                                width: size.width,
+                                height: size.height,
+                                stride,
+                                order,
+                            }
+                            .draw(pattern, frame);
+                        });
+                        stride
+                    }
+                    None => 0,
+                };
+
+                unsafe {
+                    let spa_buffer = (*raw_buffer).buffer;
+                    let header = spa::sys::spa_buffer_find_meta_data(
+                        spa_buffer,
+                        spa::sys::SPA_META_Header,
+                        std::mem::size_of::<spa::sys::spa_meta_header>(),
+                    ) as *mut spa::sys::spa_meta_header;
+                    if let Some(header) = header.as_mut() {
+                        header.pts =
+                            frame as i64 * 1_000_000_000 / user_data.config.fps.max(1) as i64;
+                        header.flags = 0;
+                        header.seq = frame;
+                        header.dts_offset = 0;
+                    }
+
+                    let chunk = &mut *(*(*spa_buffer).datas).chunk;
+                    chunk.offset = 0;
+                    chunk.stride = stride as i32;
+                    chunk.size = stride * size.height;
+
+                    stream.queue_raw_buffer(raw_buffer);
+                }
+            })
+            .register()?;
+
+        let mut params = enum_formats(&config)
+            .iter()
+            .map(|v| spa::pod::Pod::from_bytes(v).unwrap())
+            .collect::<Vec<_>>();
+
+        stream.connect(
+            spa::utils::Direction::Output,
+            None,
+            pw::stream::StreamFlags::DRIVER | pw::stream::StreamFlags::ALLOC_BUFFERS,
+            &mut params,
+        )?;
+
+        // Drive the graph at the configured rate
+        let timer = mainloop.loop_().add_timer({
+            let stream = Rc::clone(&stream);
+            move |_| {
+                if stream.state() == pw::stream::StreamState::Streaming {
+                    let _ = stream.trigger_process();
+                }
+            }
+        });
+        let _ = timer.update_timer(Some(interval), Some(interval));
+
+        let _receiver = pw_receiver.attach(mainloop.loop_(), {
+            let mainloop = Rc::clone(&mainloop);
+            move |cmd| match cmd {
+                Command::Stop => mainloop.quit(),
+            }
+        });
+
+        mainloop.run();
+        Ok(())
+    }
+
+    /// Plays a 440 Hz tone in 10 ms periods, driving the graph itself.
+    pub fn audio_source_thread(
+        name: String,
+        node_sender: std::sync::mpsc::Sender<u32>,
+        pw_receiver: pipewire::channel::Receiver<Command>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use crate::audio::{CHANNELS, SAMPLE_RATE};
+
+        let mainloop = Rc::new(MainLoop::new(None)?);
+        let context = Context::new(&*mainloop)?;
+        let core = context.connect(None)?;
+
+        let stream = Rc::new(pw::stream::Stream::new(
+            &core,
+            &name,
+            properties! {
+                *pw::keys::MEDIA_CLASS => "Audio/Source",
+                *pw::keys::MEDIA_TYPE => "Audio",
+                *pw::keys::MEDIA_CATEGORY => "Playback",
+                *pw::keys::NODE_DESCRIPTION => "Screencast test tone",
+            },
+        )?);
+
+        let period = Duration::from_millis(10);
+        let period_frames = (SAMPLE_RATE / 100) as usize;
+        let frame_bytes = CHANNELS as usize * 2;
+        let mut node_sender = Some(node_sender);
+        let mut position = 0u64;
+
+        let _listener = stream
+            .add_local_listener_with_user_data(())
+            .state_changed(move |stream, _, _, new| {
+                if let pw::stream::StreamState::Paused | pw::stream::StreamState::Streaming = new {
+                    if let Some(sender) = node_sender.take() {
+                        let _ = sender.send(stream.node_id());
+                    }
+                }
+            })
+            .process(move |stream, _| {
+                let Some(mut buffer) = stream.dequeue_buffer() else {
+                    return;
+                };
+                let Some(data) = buffer.datas_mut().first_mut() else {
+                    return;
+                };
+                let Some(bytes) = data.data() else {
+                    return;
+                };
+                let frames = period_frames.min(bytes.len() / frame_bytes);
+                for frame in bytes.chunks_exact_mut(frame_bytes).take(frames) {
+                    let time = position as f64 / SAMPLE_RATE as f64;
+                    let sample = ((time * 440.0 * std::f64::consts::TAU).sin() * 8000.0) as i16;
+                    for channel in frame.chunks_exact_mut(2) {
+                        channel.copy_from_slice(&sample.to_le_bytes());
+                    }
+                    position += 1;
+                }
+                let chunk = data.chunk_mut();
+                *chunk.offset_mut() = 0;
+                *chunk.stride_mut() = frame_bytes as i32;
+                *chunk.size_mut() = (frames * frame_bytes) as u32;
+            })
+            .register()?;
+
+        let mut audio_info = spa::param::audio::AudioInfoRaw::new();
+        audio_info.set_format(spa::param::audio::AudioFormat::S16LE);
+        audio_info.set_rate(SAMPLE_RATE);
+        audio_info.set_channels(CHANNELS);
+        let values = serialize(spa::pod::Object {
+            type_: pw::spa::utils::SpaTypes::ObjectParamFormat.as_raw(),
+            id: pw::spa::param::ParamType::EnumFormat.as_raw(),
+            properties: audio_info.into(),
+        });
+        let mut params = [spa::pod::Pod::from_bytes(&values).unwrap()];
+
+        stream.connect(
+            spa::utils::Direction::Output,
+            None,
+            pw::stream::StreamFlags::DRIVER | pw::stream::StreamFlags::MAP_BUFFERS,
+            &mut params,
+        )?;
+
+        let timer = mainloop.loop_().add_timer({
+            let stream = Rc::clone(&stream);
+            move |_| {
+                if stream.state() == pw::stream::StreamState::Streaming {
+                    let _ = stream.trigger_process();
+                }
+            }
+        });
+        let _ = timer.update_timer(Some(period), Some(period));
+
+        let _receiver = pw_receiver.attach(mainloop.loop_(), {
+            let mainloop = Rc::clone(&mainloop);
+            move |cmd| match cmd {
+                Command::Stop => mainloop.quit(),
+            }
+        });
+
+        mainloop.run();
+        Ok(())
+    }
+
+    /// EnumFormat params: with modifiers first when DMA-BUF is possible, then
+    /// the plain MemFd variant.
+    fn enum_formats(config: &TestSourceConfig) -> Vec<Vec<u8>> {
+        use spa::param::video::VideoFormat;
+
+        let format = pw::spa::pod::object!(
+            pw::spa::utils::SpaTypes::ObjectParamFormat,
+            pw::spa::param::ParamType::EnumFormat,
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::MediaType,
+                Id,
+                pw::spa::param::format::MediaType::Video
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::MediaSubtype,
+                Id,
+                pw::spa::param::format::MediaSubtype::Raw
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::VideoFormat,
+                Id,
+                config.format
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::VideoSize,
+                Rectangle,
+                pw::spa::utils::Rectangle {
+                    width: config.width,
+                    height: config.height
+                }
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::VideoFramerate,
+                Fraction,
+                pw::spa::utils::Fraction {
+                    num: config.fps,
+                    denom: 1
+                }
+            ),
+        );
+
+        let mut formats = Vec::with_capacity(2);
+        if config.dma_buf_device.is_some() && config.format != VideoFormat::YUY2 {
+            let mut with_modifier = format.clone();
+            let linear = u64::from(drm::buffer::DrmModifier::Linear) as i64;
+            with_modifier.properties.push(spa::pod::Property {
+                key: spa::param::format::FormatProperties::VideoModifier.as_raw(),
+                flags: spa::pod::PropertyFlags::MANDATORY,
+                value: spa::pod::Value::Choice(spa::pod::ChoiceValue::Long(spa::utils::Choice(
+                    spa::utils::ChoiceFlags::empty(),
+                    spa::utils::ChoiceEnum::Enum {
+                        default: linear,
+                        alternatives: vec![linear],
+                    },
+                ))),
+            });
+            formats.push(serialize(with_modifier));
+        }
+        formats.push(serialize(format));
+        formats
+    }
+
+    fn serialize(object: spa::pod::Object) -> Vec<u8> {
+        pw::spa::pod::serialize::PodSerializer::serialize(
+            std::io::Cursor::new(Vec::new()),
+            &pw::spa::pod::Value::Object(object),
+        )
+        .unwrap()
+        .0
+        .into_inner()
+    }
+
+    unsafe fn allocate_memfd(data: &mut spa::sys::spa_data, size: usize) -> Option<Allocation> {
+        let fd = libc::memfd_create(c"screencast-test-source".as_ptr(), libc::MFD_CLOEXEC);
+        if fd < 0 {
+            return None;
+        }
+        let fd = OwnedFd::from_raw_fd(fd);
+        if libc::ftruncate(fd.as_raw_fd(), size as libc::off_t) < 0 {
+            return None;
+        }
+        let ptr = libc::mmap(
+            std::ptr::null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd.as_raw_fd(),
+            0,
+        );
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+
+        data.type_ = spa::sys::SPA_DATA_MemFd;
+        data.flags = spa::buffer::DataFlags::READWRITE.bits();
+        data.fd = fd.as_raw_fd() as i64;
+        data.mapoffset = 0;
+        data.maxsize = size as u32;
+        data.data = ptr;
+
+        Some(Allocation::MemFd {
+            _fd: fd,
+            ptr: ptr as *mut u8,
+            size,
+        })
+    }
+
+    unsafe fn allocate_dma_buf(
+        user_data: &UserData,
+        data: &mut spa::sys::spa_data,
+        width: u32,
+        height: u32,
+    ) -> Option<Allocation> {
+        let gbm = user_data.gbm.as_ref()?;
+        let format = match user_data.format.format() {
+            spa::param::video::VideoFormat::RGBA => gbm::Format::Abgr8888,
+            spa::param::video::VideoFormat::RGBx => gbm::Format::Xbgr8888,
+            spa::param::video::VideoFormat::BGRA => gbm::Format::Argb8888,
+            _ => gbm::Format::Xrgb8888,
+        };
+        let bo = gbm
+            .create_buffer_object_with_modifiers2::<()>(
+                width,
+                height,
+                format,
+                [gbm::Modifier::Linear].into_iter(),
+                gbm::BufferObjectFlags::RENDERING,
+            )
+            .map_err(|e| println!("Failed to allocate GBM buffer: {e}"))
+            .ok()?;
+        let fd = bo.fd().ok()?;
+        let stride = bo.stride().ok()?;
+
+        data.type_ = spa::sys::SPA_DATA_DmaBuf;
+        data.flags = spa::buffer::DataFlags::READWRITE.bits();
+        data.fd = fd.as_raw_fd() as i64;
+        data.mapoffset = 0;
+        data.maxsize = stride * height;
+        data.data = std::ptr::null_mut();
+
+        Some(Allocation::DmaBuf { _fd: fd, bo })
+    }
+}
@@ -0,0 +1,157 @@
+//! BT.601 limited range YUV, as cameras produce it: conversion of YUY2 and
+//! NV12 buffers to frames, and of frames to YUY2 for test sources.
+
+use crate::pipewire_stream::Frame;
+use slint::Rgba8Pixel;
+
+pub(crate) fn luma(r: i32, g: i32, b: i32) -> u8 {
+    (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8
+}
+
+pub(crate) fn chroma(r: i32, g: i32, b: i32) -> (u8, u8) {
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+    (u as u8, v as u8)
+}
+
+fn rgb(y: u8, u: u8, v: u8) -> Rgba8Pixel {
+    let c = 298 * (y as i32 - 16);
+    let (d, e) = (u as i32 - 128, v as i32 - 128);
+    let clamp = |value: i32| ((value + 128) >> 8).clamp(0, 255) as u8;
+    Rgba8Pixel::new(
+        clamp(c + 409 * e),
+        clamp(c - 100 * d - 208 * e),
+        clamp(c + 516 * d),
+        255,
+    )
+}
+
+/// Packed 4:2:2 `Y0 U Y1 V` rows `stride` bytes apart. `None` if `data` is
+/// too short.
+pub fn yuy2_to_frame(data: &[u8], stride: usize, width: u32, height: u32) -> Option<Frame> {
+    let row = width.div_ceil(2) as usize * 4;
+    if stride < row || data.len() < stride * (height as usize).saturating_sub(1) + row {
+        return None;
+    }
+    let mut frame = Frame::new(width, height);
+    let pixels = frame.make_mut_slice();
+    for (line, out) in data.chunks(stride).zip(pixels.chunks_mut(width as usize)) {
+        for (x, pixel) in out.iter_mut().enumerate() {
+            let pair = &line[x / 2 * 4..x / 2 * 4 + 4];
+            *pixel = rgb(pair[(x % 2) * 2], pair[1], pair[3]);
+        }
+    }
+    Some(frame)
+}
+
+/// Y plane and an interleaved `U V` plane at half the resolution, rows
+/// `y_stride` and `uv_stride` bytes apart. `None` if a plane is too short.
+pub fn nv12_to_frame(
+    y_plane: &[u8],
+    y_stride: usize,
+    uv_plane: &[u8],
+    uv_stride: usize,
+    width: u32,
+    height: u32,
+) -> Option<Frame> {
+    let (width, height) = (width as usize, height as usize);
+    let uv_row = width.div_ceil(2) * 2;
+    let uv_height = height.div_ceil(2);
+    if y_stride < width
+        || uv_stride < uv_row
+        || y_plane.len() < y_stride * height.saturating_sub(1) + width
+        || uv_plane.len() < uv_stride * uv_height.saturating_sub(1) + uv_row
+    {
+        return None;
+    }
+    let mut frame = Frame::new(width as u32, height as u32);
+    let pixels = frame.make_mut_slice();
+    for (y, out) in pixels.chunks_mut(width).enumerate() {
+        let luma = &y_plane[y * y_stride..];
+        let chroma = &uv_plane[y / 2 * uv_stride..];
+        for (x, pixel) in out.iter_mut().enumerate() {
+            *pixel = rgb(luma[x], chroma[x / 2 * 2], chroma[x / 2 * 2 + 1]);
+        }
+    }
+    Some(frame)
+}
+
+/// Writes `frame` as YUY2 rows `stride` bytes apart into `out`, chroma
+/// averaged over pixel pairs.
+pub fn frame_to_yuy2(frame: &Frame, out: &mut [u8], stride: usize) {
+    let width = frame.width() as usize;
+    for (pixels, line) in frame.as_slice().chunks(width).zip(out.chunks_mut(stride)) {
+        for (pair, out) in pixels.chunks(2).zip(line.chunks_exact_mut(4)) {
+            let second = pair.last().unwrap();
+            let (r, g, b) = (
+                (pair[0].r as i32 + second.r as i32) / 2,
+                (pair[0].g as i32 + second.g as i32) / 2,
+                (pair[0].b as i32 + second.b as i32) / 2,
+            );
+            let (u, v) = chroma(r, g, b);
+            out[0] = luma(pair[0].r as i32, pair[0].g as i32, pair[0].b as i32);
+            out[1] = u;
+            out[2] = luma(second.r as i32, second.g as i32, second.b as i32);
+            out[3] = v;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{frame_to_yuy2, nv12_to_frame, yuy2_to_frame};
+    use crate::pipewire_stream::Frame;
+    use crate::recorder::rgba_to_i420;
+    use slint::Rgba8Pixel;
+
+    const COLORS: [Rgba8Pixel; 4] = [
+        Rgba8Pixel::new(255, 0, 0, 255),
+        Rgba8Pixel::new(0, 255, 0, 255),
+        Rgba8Pixel::new(0, 0, 255, 255),
+        Rgba8Pixel::new(200, 200, 200, 255),
+    ];
+
+    /// 2x2 blocks of one colour each, so subsampled chroma stays exact.
+    fn blocks() -> Frame {
+        let mut frame = Frame::new(8, 2);
+        for (i, pixel) in frame.make_mut_slice().iter_mut().enumerate() {
+            *pixel = COLORS[i % 8 / 2];
+        }
+        frame
+    }
+
+    fn assert_close(a: &Frame, b: &Frame) {
+        for (a, b) in a.as_slice().iter().zip(b.as_slice()) {
+            let channels = [(a.r, b.r), (a.g, b.g), (a.b, b.b), (a.a, b.a)];
+            assert!(
+                channels.iter().all(|(a, b)| a.abs_diff(*b) <= 3),
+                "{a:?} != {b:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn yuy2_round_trip() {
+        let frame = blocks();
+        let stride = 8 * 2 + 6;
+        let mut yuy2 = vec![0; stride * 2];
+        frame_to_yuy2(&frame, &mut yuy2, stride);
+        assert_close(&yuy2_to_frame(&yuy2, stride, 8, 2).unwrap(), &frame);
+        assert!(yuy2_to_frame(&yuy2[..stride], stride, 8, 2).is_none());
+    }
+
+    #[test]
+    fn nv12_from_i420() {
+        let frame = blocks();
+        let mut i420 = Vec::new();
+        rgba_to_i420(&frame, &mut i420);
+        let (y_plane, chroma) = i420.split_at(16);
+        let (u, v) = chroma.split_at(4);
+        let uv = u
+            .iter()
+            .zip(v)
+            .flat_map(|(u, v)| [*u, *v])
+            .collect::<Vec<_>>();
+        assert_close(&nv12_to_frame(y_plane, 8, &uv, 8, 8, 2).unwrap(), &frame);
+    }
+}
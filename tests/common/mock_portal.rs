@@ -0,0 +1,358 @@
+//! Minimal `org.freedesktop.portal.Desktop` implementation for tests.
+//!
+//! Implements the ScreenCast and RemoteDesktop interfaces on a private bus:
+//! every request is answered immediately (no dialog) with the configured
+//! streams, and every method call is recorded so tests can check what the
+//! client sent.
+
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::blocking::Connection;
+use dbus::channel::{MatchingReceiver, Sender};
+use dbus::message::MatchRule;
+use dbus::Message;
+use std::collections::HashSet;
+use std::os::fd::{FromRawFd, IntoRawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+const PATH: &str = "/org/freedesktop/portal/desktop";
+const SCREEN_CAST: &str = "org.freedesktop.portal.ScreenCast";
+const REMOTE_DESKTOP: &str = "org.freedesktop.portal.RemoteDesktop";
+
+#[derive(Debug, Clone)]
+pub struct MockStream {
+    pub node_id: u32,
+    pub position: (i32, i32),
+    pub size: (i32, i32),
+    pub source_type: u32,
+    pub mapping_id: String,
+}
+
+impl MockStream {
+    pub fn monitor(node_id: u32, position: (i32, i32), size: (i32, i32)) -> Self {
+        Self {
+            node_id,
+            position,
+            size,
+            source_type: 1,
+            mapping_id: format!("monitor-{node_id}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MockConfig {
+    pub version: u32,
+    pub streams: Vec<MockStream>,
+    /// Response code of `Start`: 0 success, 1 cancelled, 2 failed.
+    pub start_response: u32,
+    /// Fail `Start` when `SelectSources` got a token this mock did not issue.
+    pub reject_unknown_tokens: bool,
+    /// Socket of the PipeWire daemon handed out by `OpenPipeWireRemote`.
+    /// When unset, one end of an unconnected socket pair is returned.
+    pub pipewire_socket: Option<PathBuf>,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        Self {
+            version: 5,
+            streams: vec![MockStream::monitor(42, (0, 0), (1920, 1080))],
+            start_response: 0,
+            reject_unknown_tokens: true,
+            pipewire_socket: None,
+        }
+    }
+}
+
+impl MockConfig {
+    /// Socket of the PipeWire daemon of the current user, if running.
+    pub fn default_pipewire_socket() -> Option<PathBuf> {
+        let runtime_dir = std::env::var_os("PIPEWIRE_RUNTIME_DIR")
+            .or_else(|| std::env::var_os("XDG_RUNTIME_DIR"))?;
+        let remote = std::env::var("PIPEWIRE_REMOTE").unwrap_or("pipewire-0".to_owned());
+        let socket = PathBuf::from(runtime_dir).join(remote);
+        socket.exists().then_some(socket)
+    }
+}
+
+/// A recorded method call: interface, member and the message itself.
+pub struct Call {
+    pub interface: String,
+    pub member: String,
+    pub message: Message,
+}
+
+impl Call {
+    /// The `a{sv}` options, i.e. the last argument of portal methods.
+    pub fn options(&self) -> PropMap {
+        let mut iter = self.message.iter_init();
+        let mut options = PropMap::new();
+        loop {
+            if let Some(map) = iter.get::<PropMap>() {
+                options = map;
+            }
+            if !iter.next() {
+                return options;
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    calls: Vec<Call>,
+    issued_tokens: HashSet<String>,
+    restore_token: Option<String>,
+    persist_mode: u32,
+    next_token: u32,
+    /// Devices of the last `SelectDevices`.
+    devices: u32,
+    /// RemoteDesktop sessions that were started, which may send input.
+    remote_sessions: HashSet<String>,
+}
+
+pub struct MockPortal {
+    state: Arc<Mutex<State>>,
+    running: Arc<AtomicBool>,
+    thread_handle: Option<JoinHandle<()>>,
+}
+
+impl MockPortal {
+    pub fn start(bus_address: &str, config: MockConfig) -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+        let running = Arc::new(AtomicBool::new(true));
+        let (ready_sender, ready_receiver) = std::sync::mpsc::channel();
+
+        let thread_handle = std::thread::spawn({
+            let bus_address = bus_address.to_owned();
+            let state = Arc::clone(&state);
+            let running = Arc::clone(&running);
+            move || {
+                let connection = Connection::new_address(&bus_address).unwrap();
+                connection
+                    .request_name("org.freedesktop.portal.Desktop", false, true, false)
+                    .unwrap();
+
+                connection.start_receive(
+                    MatchRule::new_method_call(),
+                    Box::new(move |message, connection| {
+                        handle(&config, &state, message, connection);
+                        true
+                    }),
+                );
+                ready_sender.send(()).unwrap();
+
+                while running.load(Ordering::Relaxed) {
+                    connection.process(Duration::from_millis(50)).unwrap();
+                }
+            }
+        });
+        ready_receiver.recv().unwrap();
+
+        Self {
+            state,
+            running,
+            thread_handle: Some(thread_handle),
+        }
+    }
+
+    /// Names of the methods called so far, e.g. `ScreenCast.Start`.
+    pub fn call_names(&self) -> Vec<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .calls
+            .iter()
+            .map(|c| {
+                let interface = c.interface.rsplit('.').next().unwrap_or_default();
+                format!("{interface}.{}", c.member)
+            })
+            .collect()
+    }
+
+    /// Runs `f` on the recorded calls.
+    pub fn with_calls<R>(&self, f: impl FnOnce(&[Call]) -> R) -> R {
+        f(&self.state.lock().unwrap().calls)
+    }
+}
+
+impl Drop for MockPortal {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(handle) = self.thread_handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn variant<T: RefArg + 'static>(value: T) -> Variant<Box<dyn RefArg>> {
+    Variant(Box::new(value))
+}
+
+fn request_path(message: &Message, options: &PropMap) -> dbus::Path<'static> {
+    let sender = message.sender().unwrap()[1..].replace('.', "_");
+    let token = options
+        .get("handle_token")
+        .and_then(|v| v.0.as_str())
+        .unwrap_or("t");
+    dbus::Path::new(format!("{PATH}/request/{sender}/{token}")).unwrap()
+}
+
+/// Replies with the request handle and emits its `Response` signal.
+fn respond(
+    connection: &Connection,
+    message: &Message,
+    options: &PropMap,
+    code: u32,
+    results: PropMap,
+) {
+    let path = request_path(message, options);
+    let _ = connection.send(message.method_return().append1(&path));
+    let mut signal = Message::signal(
+        &path,
+        &"org.freedesktop.portal.Request".into(),
+        &"Response".into(),
+    )
+    .append2(code, results);
+    signal.set_destination(message.sender());
+    let _ = connection.send(signal);
+}
+
+fn pipewire_fd(config: &MockConfig) -> dbus::arg::OwnedFd {
+    let stream = match &config.pipewire_socket {
+        Some(path) => UnixStream::connect(path).unwrap(),
+        None => UnixStream::pair().unwrap().0,
+    };
+    unsafe { dbus::arg::OwnedFd::from_raw_fd(stream.into_raw_fd()) }
+}
+
+fn handle(config: &MockConfig, state: &Mutex<State>, message: Message, connection: &Connection) {
+    let interface = message
+        .interface()
+        .map(|i| i.to_string())
+        .unwrap_or_default();
+    let member = message.member().map(|m| m.to_string()).unwrap_or_default();
+    let mut state = state.lock().unwrap();
+
+    match (interface.as_str(), member.as_str()) {
+        ("org.freedesktop.DBus.Properties", "Get") => {
+            let (_, property): (&str, &str) = message.read2().unwrap();
+            let value = match property {
+                "version" => config.version,
+                "AvailableSourceTypes" => 1 | 2 | 4,
+                "AvailableCursorModes" => 1 | 2 | 4,
+                "AvailableDeviceTypes" => 1 | 2 | 4,
+                _ => 0,
+            };
+            let _ = connection.send(message.method_return().append1(Variant(value)));
+        }
+        (_, "CreateSession") => {
+            let options: PropMap = message.read1().unwrap();
+            let sender = message.sender().unwrap()[1..].replace('.', "_");
+            let token = options
+                .get("session_handle_token")
+                .and_then(|v| v.0.as_str())
+                .unwrap_or("s");
+            let mut results = PropMap::new();
+            results.insert(
+                "session_handle".to_owned(),
+                variant(format!("{PATH}/session/{sender}/{token}")),
+            );
+            respond(connection, &message, &options, 0, results);
+        }
+        (SCREEN_CAST, "SelectSources") => {
+            let (_, options): (dbus::Path, PropMap) = message.read2().unwrap();
+            state.restore_token = options
+                .get("restore_token")
+                .and_then(|v| v.0.as_str())
+                .map(str::to_owned);
+            state.persist_mode = options
+                .get("persist_mode")
+                .and_then(|v| v.0.as_u64())
+                .unwrap_or(0) as u32;
+            respond(connection, &message, &options, 0, PropMap::new());
+        }
+        (REMOTE_DESKTOP, "SelectDevices") => {
+            let (_, options): (dbus::Path, PropMap) = message.read2().unwrap();
+            // All devices unless asked for fewer
+            state.devices = options
+                .get("types")
+                .and_then(|v| v.0.as_u64())
+                .unwrap_or(1 | 2 | 4) as u32;
+            respond(connection, &message, &options, 0, PropMap::new());
+        }
+        (_, "Start") => {
+            let (session, _, options): (dbus::Path, &str, PropMap) = message.read3().unwrap();
+
+            let rejected = config.reject_unknown_tokens
+                && state
+                    .restore_token
+                    .as_ref()
+                    .is_some_and(|t| !state.issued_tokens.contains(t));
+            let code = if rejected { 2 } else { config.start_response };
+
+            let streams = config
+                .streams
+                .iter()
+                .map(|s| {
+                    let mut properties = PropMap::new();
+                    properties.insert("id".to_owned(), variant(s.node_id.to_string()));
+                    properties.insert("position".to_owned(), variant(s.position));
+                    properties.insert("size".to_owned(), variant(s.size));
+                    properties.insert("source_type".to_owned(), variant(s.source_type));
+                    properties.insert("mapping_id".to_owned(), variant(s.mapping_id.clone()));
+                    (s.node_id, properties)
+                })
+                .collect::<Vec<_>>();
+
+            let mut results = PropMap::new();
+            results.insert("streams".to_owned(), variant(streams));
+            if interface == REMOTE_DESKTOP && code == 0 {
+                results.insert("devices".to_owned(), variant(state.devices));
+                state.remote_sessions.insert(session.to_string());
+            }
+            if code == 0 && state.persist_mode != 0 {
+                state.next_token += 1;
+                let token = format!("mock-token-{}", state.next_token);
+                state.issued_tokens.insert(token.clone());
+                results.insert("restore_token".to_owned(), variant(token));
+            }
+            respond(connection, &message, &options, code, results);
+        }
+        (SCREEN_CAST, "OpenPipeWireRemote") => {
+            let _ = connection.send(message.method_return().append1(pipewire_fd(config)));
+        }
+        (REMOTE_DESKTOP, notify) if notify.starts_with("Notify") => {
+            let session: dbus::Path = message.read1().unwrap();
+            if !state.remote_sessions.contains(&*session) {
+                let _ = connection.send(message.error(
+                    &"org.freedesktop.DBus.Error.AccessDenied".into(),
+                    &std::ffi::CString::new("Session not started").unwrap(),
+                ));
+                return;
+            }
+            let _ = connection.send(message.method_return());
+        }
+        ("org.freedesktop.portal.Session", "Close") => {
+            let _ = connection.send(message.method_return());
+        }
+        _ => {
+            let _ = connection.send(message.error(
+                &"org.freedesktop.DBus.Error.UnknownMethod".into(),
+                &std::ffi::CString::new(format!("Unknown method {interface}.{member}")).unwrap(),
+            ));
+            return;
+        }
+    }
+
+    state.calls.push(Call {
+        interface,
+        member,
+        message,
+    });
+}
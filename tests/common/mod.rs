@@ -0,0 +1,79 @@
+// Each test binary uses a different subset of the helpers
+#![allow(dead_code)]
+
+pub mod mock_portal;
+
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+
+/// Private session bus, killed when dropped.
+pub struct DbusDaemon {
+    child: Child,
+    address: String,
+}
+
+impl DbusDaemon {
+    /// Starts `dbus-daemon --session`. Returns `None` when the daemon is not
+    /// installed, so tests can be skipped on machines without it.
+    pub fn start() -> Option<Self> {
+        let mut child = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address=1"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .ok()?;
+
+        let mut address = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+
+        Some(Self {
+            child,
+            address: address.trim().to_owned(),
+        })
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn connect(&self) -> dbus::blocking::Connection {
+        dbus::blocking::Connection::new_address(&self.address).unwrap()
+    }
+}
+
+impl Drop for DbusDaemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Returns early from a test when no `dbus-daemon` is available.
+#[macro_export]
+macro_rules! require_dbus {
+    () => {
+        match $crate::common::DbusDaemon::start() {
+            Some(daemon) => daemon,
+            None => {
+                println!("dbus-daemon not found, skipping");
+                return;
+            }
+        }
+    };
+}
+
+/// Returns early from a test when no PipeWire daemon is running, else
+/// evaluates to the path of its socket.
+#[macro_export]
+macro_rules! require_pipewire {
+    () => {
+        match $crate::common::mock_portal::MockConfig::default_pipewire_socket() {
+            Some(socket) => socket,
+            None => {
+                println!("PipeWire daemon not running, skipping");
+                return;
+            }
+        }
+    };
+}
@@ -0,0 +1,97 @@
+//! Golden images: the encoders' output for a fixed test pattern is compared
+//! byte for byte with the files in tests/golden. Run with
+//! `SCREENCAST_BLESS=1` to rewrite them after an intended change.
+
+use screencast::export::{self, ImageFormat, ImageView, PixelLayout};
+use screencast::pipewire_stream::Frame;
+use screencast::test_pattern::{Canvas, ChannelOrder, Pattern};
+use std::path::PathBuf;
+
+const WIDTH: u32 = 64;
+const HEIGHT: u32 = 48;
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(name)
+}
+
+/// Colour bars drawn into rows `stride` bytes apart.
+fn bars(order: ChannelOrder, stride: usize) -> Vec<u8> {
+    let mut data = vec![0; stride * HEIGHT as usize];
+    Canvas {
+        data: &mut data,
+        width: WIDTH,
+        height: HEIGHT,
+        stride,
+        order,
+    }
+    .draw(Pattern::Bars, 0);
+    data
+}
+
+fn bars_frame() -> Frame {
+    let data = bars(ChannelOrder::Rgba, WIDTH as usize * 4);
+    Frame::clone_from_slice(&data, WIDTH, HEIGHT)
+}
+
+fn encode<'a>(format: ImageFormat, image: impl Into<ImageView<'a>>) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    format.write(&mut encoded, image).unwrap();
+    encoded
+}
+
+fn check_golden(name: &str, encoded: &[u8]) {
+    let path = golden_path(name);
+    if std::env::var_os("SCREENCAST_BLESS").is_some() {
+        std::fs::write(&path, encoded).unwrap();
+        return;
+    }
+    let golden = std::fs::read(&path).unwrap();
+    assert!(
+        golden == encoded,
+        "{name} differs from {}, rerun with SCREENCAST_BLESS=1 if intended",
+        path.display()
+    );
+}
+
+#[test]
+fn encoders_match_golden_images() {
+    let frame = bars_frame();
+    for (name, format) in [
+        ("bars.png", ImageFormat::Png),
+        ("bars.qoi", ImageFormat::Qoi),
+        ("bars.ppm", ImageFormat::Ppm),
+        ("bars.pam", ImageFormat::Pam),
+    ] {
+        check_golden(name, &encode(format, &frame));
+    }
+}
+
+/// A padded BGRx buffer, as PipeWire producers map them, encodes like the
+/// packed RGBA frame.
+#[test]
+fn padded_buffers_match_golden_images() {
+    let stride = WIDTH as usize * 4 + 64;
+    let data = bars(ChannelOrder::Bgra, stride);
+    let image = ImageView {
+        data: &data,
+        width: WIDTH,
+        height: HEIGHT,
+        stride,
+        layout: PixelLayout::Bgrx,
+    };
+    let golden = std::fs::read(golden_path("bars.qoi")).unwrap();
+    assert!(encode(ImageFormat::Qoi, image) == golden);
+    assert_eq!(image.to_frame().as_bytes(), bars_frame().as_bytes());
+}
+
+#[test]
+fn golden_images_decode() {
+    let frame = bars_frame();
+    let qoi = export::read_qoi(&mut std::fs::File::open(golden_path("bars.qoi")).unwrap()).unwrap();
+    assert_eq!(qoi.as_bytes(), frame.as_bytes());
+    let pam = std::fs::read(golden_path("bars.pam")).unwrap();
+    let pam = export::read_pnm(&mut pam.as_slice()).unwrap();
+    assert_eq!(pam.as_bytes(), frame.as_bytes());
+}
@@ -0,0 +1,448 @@
+mod common;
+
+use common::mock_portal::{MockConfig, MockPortal, MockStream};
+use pipewire::spa::param::video::VideoFormat;
+use screencast::audio::AudioSource;
+use screencast::export::read_qoi;
+use screencast::frame_transform::{Overlay, Rect};
+use screencast::pipewire_nodes::list_video_sources;
+use screencast::pipewire_stream::{
+    CommandError, Frame, PipewireStream, ReconnectPolicy, Remote, RepublishConfig, StreamEvent,
+    Target, TimedFrame,
+};
+use screencast::portal::{Portal, SourceOptions};
+use screencast::screenshot::{capture_one, CaptureOptions};
+use screencast::test_pattern::Pattern;
+use screencast::test_source::{TestAudioSource, TestSource, TestSourceConfig};
+use std::time::{Duration, Instant};
+
+fn receive_timed_frame(frames: &async_channel::Receiver<TimedFrame>) -> TimedFrame {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        if let Ok(frame) = frames.try_recv() {
+            return frame;
+        }
+        assert!(Instant::now() < deadline, "no frame received");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn receive_frame(frames: &async_channel::Receiver<TimedFrame>) -> Frame {
+    receive_timed_frame(frames).frame
+}
+
+/// Portal -> `PipewireStream` -> frame, against the local PipeWire daemon.
+///
+/// Needs a running PipeWire daemon, the frames come from a `TestSource`.
+#[test]
+fn frames_through_mock_portal() {
+    let pipewire_socket = require_pipewire!();
+    let source = TestSource::start(TestSourceConfig::default()).unwrap();
+    let node_id = source.node_id();
+    let daemon = require_dbus!();
+    let _mock = MockPortal::start(
+        daemon.address(),
+        MockConfig {
+            streams: vec![MockStream::monitor(node_id, (0, 0), (320, 240))],
+            pipewire_socket: Some(pipewire_socket),
+            ..Default::default()
+        },
+    );
+
+    let portal = Portal::with_connection(daemon.connect()).unwrap();
+    let (session, started) = portal.start_session(&SourceOptions::default(), "").unwrap();
+    let pipewire_fd = session.open_pipewire_remote().unwrap();
+
+    let mut stream = PipewireStream::create();
+    let frames = stream.start(pipewire_fd, started.streams[0].node_id);
+
+    let frame = receive_frame(&frames);
+    assert_eq!((frame.width(), frame.height()), (320, 240));
+
+    stream.stop();
+}
+
+/// `TestSource` -> registry listing -> direct capture by node name.
+#[test]
+fn direct_capture_by_name() {
+    require_pipewire!();
+    let source = TestSource::start(TestSourceConfig {
+        name: "screencast-direct-test".to_owned(),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let sources = list_video_sources().unwrap();
+    let node = sources
+        .iter()
+        .find(|node| node.id == source.node_id())
+        .expect("test source not listed");
+    assert_eq!(node.name.as_deref(), Some("screencast-direct-test"));
+    assert_eq!(node.media_class.as_deref(), Some("Video/Source"));
+    assert!(node
+        .formats
+        .iter()
+        .any(|f| f.media_subtype == "raw" && f.video_formats == ["BGRx"]));
+
+    let mut stream = PipewireStream::create();
+    let frames = stream.start_direct(Target::Name("screencast-direct-test".to_owned()));
+    let frame = receive_frame(&frames);
+    assert_eq!((frame.width(), frame.height()), (320, 240));
+
+    stream.stop();
+}
+
+/// The stream reconnects once its source comes back.
+#[test]
+fn reconnects_after_source_restart() {
+    require_pipewire!();
+    let config = TestSourceConfig {
+        name: "screencast-reconnect-test".to_owned(),
+        ..Default::default()
+    };
+    let source = TestSource::start(config.clone()).unwrap();
+
+    let mut stream = PipewireStream::create();
+    let (frames, events) = stream.start_reconnecting(
+        Box::new(|| {
+            Ok(Remote {
+                fd: None,
+                target: Target::Name("screencast-reconnect-test".to_owned()),
+                keep_alive: None,
+            })
+        }),
+        ReconnectPolicy {
+            max_attempts: Some(50),
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(200),
+        },
+    );
+    receive_frame(&frames);
+
+    drop(source);
+    let wait_for = |expected: fn(&StreamEvent) -> bool| {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            // Keep the frame channel from filling up and blocking the stream
+            while frames.try_recv().is_ok() {}
+            match events.try_recv() {
+                Ok(event) if expected(&event) => return,
+                Ok(StreamEvent::GaveUp(reason)) => panic!("gave up: {reason}"),
+                Ok(_) => {}
+                Err(_) => std::thread::sleep(Duration::from_millis(10)),
+            }
+            assert!(Instant::now() < deadline, "event not received");
+        }
+    };
+    wait_for(|e| matches!(e, StreamEvent::Disconnected(_)));
+
+    let _source = TestSource::start(config).unwrap();
+    wait_for(|e| matches!(e, StreamEvent::Reconnected));
+    receive_frame(&frames);
+
+    stream.stop();
+}
+
+/// The thread is gone once the stream gave up, stopping it is still fine
+/// and so is stopping twice.
+#[test]
+fn stop_after_giving_up() {
+    require_pipewire!();
+    let mut stream = PipewireStream::create();
+    let (frames, events) = stream.start_reconnecting(
+        Box::new(|| Err("no remote".into())),
+        ReconnectPolicy::default(),
+    );
+    match events.recv_blocking() {
+        Ok(StreamEvent::GaveUp(reason)) => assert_eq!(reason, "no remote"),
+        other => panic!("expected to give up, got {other:?}"),
+    }
+    assert!(frames.recv_blocking().is_err());
+    stream.stop();
+    stream.stop();
+}
+
+/// Test sources share memory, which ends DMA-BUF sharing rather than
+/// leaving it waiting.
+#[test]
+fn share_dma_bufs_closes_on_memfd() {
+    require_pipewire!();
+    let _source = TestSource::start(TestSourceConfig {
+        name: "screencast-share-memfd".to_owned(),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let mut stream = PipewireStream::create();
+    let frames = stream.start_direct(Target::Name("screencast-share-memfd".to_owned()));
+    let dma_bufs = stream.share_dma_bufs();
+    let drain = std::thread::spawn({
+        let frames = frames.clone();
+        move || while frames.recv_blocking().is_ok() {}
+    });
+    assert!(dma_bufs.recv_blocking().is_err());
+
+    stream.stop();
+    drain.join().unwrap();
+}
+
+/// Commands change the capture without restarting the stream.
+#[test]
+fn runtime_commands() {
+    require_pipewire!();
+    let _source = TestSource::start(TestSourceConfig {
+        name: "screencast-commands-test".to_owned(),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let mut stream = PipewireStream::create();
+    let frames = stream.start_direct(Target::Name("screencast-commands-test".to_owned()));
+    receive_frame(&frames);
+
+    // Keep the frame channel drained while waiting for replies
+    let drain = std::thread::spawn({
+        let frames = frames.clone();
+        move || while frames.recv_blocking().is_ok() {}
+    });
+
+    let rect = "160x120+10+20".parse::<Rect>().unwrap();
+    assert_eq!(stream.set_crop(Some(rect)).wait(), Ok(()));
+    let snapshot = stream.snapshot().wait().unwrap();
+    assert_eq!((snapshot.width(), snapshot.height()), (160, 120));
+
+    assert_eq!(stream.set_output_size(Some((64, 48))).wait(), Ok(()));
+    let snapshot = stream.snapshot().wait().unwrap();
+    assert_eq!((snapshot.width(), snapshot.height()), (64, 48));
+
+    assert_eq!(
+        stream.set_max_fps(Some(0)).wait(),
+        Err(CommandError::InvalidArgument("fps"))
+    );
+    assert_eq!(stream.set_max_fps(Some(5)).wait(), Ok(()));
+
+    assert_eq!(stream.pause().wait(), Ok(()));
+    assert_eq!(stream.snapshot().wait().err(), Some(CommandError::Paused));
+    assert_eq!(stream.resume().wait(), Ok(()));
+    assert!(stream.snapshot().wait().is_ok());
+
+    stream.stop();
+    drain.join().unwrap();
+}
+
+/// Cropped frames re-published as a Video/Source node capture like any
+/// other source.
+#[test]
+fn republished_frames_capture_again() {
+    require_pipewire!();
+    let _source = TestSource::start(TestSourceConfig {
+        name: "screencast-republish-source".to_owned(),
+        pattern: Pattern::Bars,
+        ..Default::default()
+    })
+    .unwrap();
+
+    let mut stream = PipewireStream::create();
+    let frames = stream.start_direct(Target::Name("screencast-republish-source".to_owned()));
+    receive_frame(&frames);
+    let drain = std::thread::spawn({
+        let frames = frames.clone();
+        move || while frames.recv_blocking().is_ok() {}
+    });
+    let rect = "160x120+10+20".parse::<Rect>().unwrap();
+    assert_eq!(stream.set_crop(Some(rect)).wait(), Ok(()));
+    let plain = stream.snapshot().wait().unwrap();
+
+    // Blurred and overlaid before re-publishing
+    let mut logo = Frame::new(8, 8);
+    logo.make_mut_slice()
+        .fill(slint::Rgba8Pixel::new(255, 0, 255, 255));
+    let overlay = Overlay {
+        image: logo,
+        x: 4,
+        y: 4,
+    };
+    assert_eq!(stream.set_overlay(Some(overlay)).wait(), Ok(()));
+    assert_eq!(
+        stream.set_blur(Some("0x4+0+0".parse().unwrap())).wait(),
+        Err(CommandError::InvalidArgument("blur"))
+    );
+    // Across the edge between two bars
+    let blur = "40x40+50+40".parse::<Rect>().unwrap();
+    assert_eq!(stream.set_blur(Some(blur)).wait(), Ok(()));
+    let expected = stream.snapshot().wait().unwrap();
+    let pixel = |frame: &Frame, x: u32, y: u32| frame.as_slice()[(y * 160 + x) as usize];
+    assert_eq!(
+        pixel(&expected, 5, 5),
+        slint::Rgba8Pixel::new(255, 0, 255, 255)
+    );
+    assert_eq!(pixel(&expected, 20, 20), pixel(&plain, 20, 20));
+    assert_eq!(pixel(&expected, 100, 60), pixel(&plain, 100, 60));
+    assert_ne!(pixel(&expected, 68, 60), pixel(&plain, 68, 60));
+
+    let node_id = stream
+        .republish(RepublishConfig {
+            name: "screencast-republish-test".to_owned(),
+            ..Default::default()
+        })
+        .wait()
+        .unwrap();
+    let mut again = PipewireStream::create();
+    let republished = again.start_direct(Target::Id(node_id));
+    let frame = receive_frame(&republished);
+    assert_eq!((frame.width(), frame.height()), (160, 120));
+    assert_eq!(frame.as_bytes(), expected.as_bytes());
+
+    // Follows the new size with a new format
+    assert_eq!(stream.set_output_size(Some((80, 60))).wait(), Ok(()));
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while receive_frame(&republished).width() != 80 {
+        assert!(Instant::now() < deadline, "size not renegotiated");
+    }
+
+    again.stop();
+    stream.stop();
+    drain.join().unwrap();
+}
+
+/// Frames are stamped from the source's buffer timestamps, which count from
+/// zero rather than on the PipeWire clock.
+#[test]
+fn frames_are_timestamped() {
+    require_pipewire!();
+    let _source = TestSource::start(TestSourceConfig {
+        name: "screencast-clock-test".to_owned(),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let mut stream = PipewireStream::create();
+    let frames = stream.start_direct(Target::Name("screencast-clock-test".to_owned()));
+    let mut last = receive_timed_frame(&frames).pts;
+    for _ in 0..5 {
+        let pts = receive_timed_frame(&frames).pts;
+        assert!(pts > last, "{pts:?} after {last:?}");
+        last = pts;
+    }
+    assert!(last <= stream.clock().now());
+
+    frames.close();
+    let stats = stream.clock_stats().wait().unwrap();
+    assert!(stats.video.samples >= 6);
+    assert_eq!(stats.audio, None);
+    stream.stop();
+}
+
+/// Audio shares the capture loop, a consumer that never reads frames must
+/// not hold it up.
+#[test]
+fn audio_flows_while_frames_stall() {
+    require_pipewire!();
+    let _source = TestSource::start(TestSourceConfig {
+        name: "screencast-stall-test".to_owned(),
+        ..Default::default()
+    })
+    .unwrap();
+    let _tone = TestAudioSource::start("screencast-stall-tone").unwrap();
+
+    let mut stream = PipewireStream::create();
+    let _frames = stream.start_direct(Target::Name("screencast-stall-test".to_owned()));
+    let audio = stream.capture_audio(AudioSource::Node(Target::Name(
+        "screencast-stall-tone".to_owned(),
+    )));
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut chunks = 0;
+    while chunks < 50 {
+        assert!(Instant::now() < deadline, "{chunks} audio chunks received");
+        match audio.try_recv() {
+            Ok(_) => chunks += 1,
+            Err(_) => std::thread::sleep(Duration::from_millis(10)),
+        }
+    }
+
+    let stats = stream.clock_stats().wait().unwrap();
+    assert!(stats.audio.is_some());
+    stream.stop();
+}
+
+#[test]
+fn capture_one_frame() {
+    require_pipewire!();
+    let _source = TestSource::start(TestSourceConfig {
+        name: "screencast-screenshot-test".to_owned(),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let frame = capture_one(&CaptureOptions {
+        node: Some(Target::Name("screencast-screenshot-test".to_owned())),
+        crop: Some("100x50+0+0".parse().unwrap()),
+        size: Some((50, 25)),
+        ..Default::default()
+    })
+    .unwrap();
+    assert_eq!((frame.width(), frame.height()), (50, 25));
+}
+
+/// Padded and RGBA buffers are converted by their stride and format, into the
+/// same frame as the golden image.
+#[test]
+fn padded_and_rgba_sources_match_golden() {
+    require_pipewire!();
+    let golden = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/bars.qoi");
+    let golden = read_qoi(&mut std::fs::File::open(golden).unwrap()).unwrap();
+
+    for (name, format, stride_padding) in [
+        ("screencast-padded-test", VideoFormat::BGRx, 64),
+        ("screencast-rgba-test", VideoFormat::RGBA, 0),
+    ] {
+        let _source = TestSource::start(TestSourceConfig {
+            name: name.to_owned(),
+            pattern: Pattern::Bars,
+            format,
+            width: 64,
+            height: 48,
+            stride_padding,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let mut stream = PipewireStream::create();
+        let frames = stream.start_direct(Target::Name(name.to_owned()));
+        let frame = receive_frame(&frames);
+        assert_eq!((frame.width(), frame.height()), (64, 48));
+        assert!(frame.as_bytes() == golden.as_bytes(), "{name} differs");
+
+        stream.stop();
+    }
+}
+
+/// YUY2, as cameras produce it, is converted to RGBA.
+#[test]
+fn yuy2_source_converts() {
+    require_pipewire!();
+    let golden = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/bars.qoi");
+    let golden = read_qoi(&mut std::fs::File::open(golden).unwrap()).unwrap();
+    let _source = TestSource::start(TestSourceConfig {
+        name: "screencast-yuy2-test".to_owned(),
+        pattern: Pattern::Bars,
+        format: VideoFormat::YUY2,
+        width: 64,
+        height: 48,
+        stride_padding: 16,
+        ..Default::default()
+    })
+    .unwrap();
+
+    let mut stream = PipewireStream::create();
+    let frames = stream.start_direct(Target::Name("screencast-yuy2-test".to_owned()));
+    let frame = receive_frame(&frames);
+    assert_eq!((frame.width(), frame.height()), (64, 48));
+    // Limited range YUV rounds a little
+    for (pixel, expected) in frame.as_bytes().iter().zip(golden.as_bytes()) {
+        assert!(pixel.abs_diff(*expected) <= 3, "{pixel} != {expected}");
+    }
+
+    stream.stop();
+}
@@ -0,0 +1,271 @@
+mod common;
+
+use common::mock_portal::{MockConfig, MockPortal, MockStream};
+use screencast::portal::{CursorMode, PersistMode, Portal, PortalError, SourceOptions, SourceType};
+use screencast::remote_desktop::{
+    Axis, DeviceOptions, DeviceType, RemoteDesktop, StreamSpace, BTN_LEFT,
+};
+use screencast::restore_tokens::TokenStore;
+
+fn portal(daemon: &common::DbusDaemon) -> Portal {
+    Portal::with_connection(daemon.connect()).unwrap()
+}
+
+fn token_store(name: &str) -> TokenStore {
+    let dir = std::env::temp_dir().join(format!("screencast-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    TokenStore::open(dir.join("restore_tokens"))
+}
+
+#[test]
+fn start_returns_stream_metadata() {
+    let daemon = require_dbus!();
+    let mock = MockPortal::start(
+        daemon.address(),
+        MockConfig {
+            streams: vec![
+                MockStream::monitor(42, (0, 0), (1920, 1080)),
+                MockStream::monitor(43, (1920, 0), (1280, 1024)),
+            ],
+            ..Default::default()
+        },
+    );
+    let portal = portal(&daemon);
+
+    assert_eq!(portal.version().unwrap(), 5);
+    assert!(portal
+        .available_cursor_modes()
+        .unwrap()
+        .contains(CursorMode::METADATA));
+
+    let session = portal.create_session().unwrap();
+    session
+        .select_sources(&SourceOptions {
+            types: SourceType::MONITOR,
+            multiple: true,
+            cursor_mode: Some(CursorMode::EMBEDDED),
+            ..Default::default()
+        })
+        .unwrap();
+    let started = session.start("").unwrap();
+    let _pipewire_fd = session.open_pipewire_remote().unwrap();
+
+    assert_eq!(started.streams.len(), 2);
+    let second = &started.streams[1];
+    assert_eq!(second.node_id, 43);
+    assert_eq!(second.position, Some((1920, 0)));
+    assert_eq!(second.size, Some((1280, 1024)));
+    assert_eq!(second.source_type, Some(SourceType::MONITOR));
+    assert_eq!(second.mapping_id.as_deref(), Some("monitor-43"));
+    assert_eq!(started.restore_token, None);
+
+    drop(session);
+    assert_eq!(
+        mock.call_names(),
+        [
+            "Properties.Get",
+            "Properties.Get",
+            "ScreenCast.CreateSession",
+            "ScreenCast.SelectSources",
+            "ScreenCast.Start",
+            "ScreenCast.OpenPipeWireRemote",
+            "Session.Close",
+        ]
+    );
+    mock.with_calls(|calls| {
+        let select = calls.iter().find(|c| c.member == "SelectSources").unwrap();
+        let options = select.options();
+        assert_eq!(options["types"].0.as_u64(), Some(1));
+        assert_eq!(options["cursor_mode"].0.as_u64(), Some(2));
+        assert_eq!(options["multiple"].0.as_u64(), Some(1));
+        assert!(!options.contains_key("persist_mode"));
+    });
+}
+
+#[test]
+fn start_cancelled() {
+    let daemon = require_dbus!();
+    let _mock = MockPortal::start(
+        daemon.address(),
+        MockConfig {
+            start_response: 1,
+            ..Default::default()
+        },
+    );
+    let portal = portal(&daemon);
+
+    let result = portal.start_session(&SourceOptions::default(), "");
+    assert!(matches!(result, Err(PortalError::Cancelled)));
+}
+
+#[test]
+fn restore_token_is_saved_and_reused() {
+    let daemon = require_dbus!();
+    let mock = MockPortal::start(daemon.address(), MockConfig::default());
+    let portal = portal(&daemon);
+    let store = token_store("reuse");
+    let options = SourceOptions {
+        types: SourceType::MONITOR,
+        ..Default::default()
+    };
+
+    let (_, started) = portal
+        .start_persisted("main", &store, &options, "")
+        .unwrap();
+    assert_eq!(started.restore_token.as_deref(), Some("mock-token-1"));
+    assert_eq!(store.load("main").as_deref(), Some("mock-token-1"));
+
+    let (_, started) = portal
+        .start_persisted("main", &store, &options, "")
+        .unwrap();
+    assert_eq!(store.load("main").as_deref(), Some("mock-token-2"));
+    assert_eq!(started.streams[0].node_id, 42);
+
+    mock.with_calls(|calls| {
+        let selects = calls
+            .iter()
+            .filter(|c| c.member == "SelectSources")
+            .map(|c| c.options())
+            .collect::<Vec<_>>();
+        assert_eq!(selects.len(), 2);
+        assert!(!selects[0].contains_key("restore_token"));
+        assert_eq!(selects[1]["restore_token"].0.as_str(), Some("mock-token-1"));
+        assert_eq!(
+            selects[1]["persist_mode"].0.as_u64(),
+            Some(PersistMode::Persistent as u64)
+        );
+    });
+}
+
+#[test]
+fn rejected_restore_token_falls_back_to_dialog() {
+    let daemon = require_dbus!();
+    let mock = MockPortal::start(daemon.address(), MockConfig::default());
+    let portal = portal(&daemon);
+    let store = token_store("rejected");
+    store.save("main", "stale-token").unwrap();
+
+    let (_, started) = portal
+        .start_persisted("main", &store, &SourceOptions::default(), "")
+        .unwrap();
+    assert_eq!(started.streams.len(), 1);
+    assert_eq!(store.load("main").as_deref(), Some("mock-token-1"));
+
+    let starts = mock
+        .call_names()
+        .into_iter()
+        .filter(|c| c == "ScreenCast.Start")
+        .count();
+    assert_eq!(starts, 2);
+}
+
+#[test]
+fn remote_desktop_input() {
+    let daemon = require_dbus!();
+    let mock = MockPortal::start(
+        daemon.address(),
+        MockConfig {
+            streams: vec![MockStream::monitor(42, (0, 0), (1280, 720))],
+            ..Default::default()
+        },
+    );
+    let portal = portal(&daemon);
+    assert!(portal
+        .available_device_types()
+        .unwrap()
+        .contains(DeviceType::KEYBOARD | DeviceType::POINTER));
+
+    // Input is refused until the session is started
+    let remote = RemoteDesktop::create(&portal).unwrap();
+    assert!(matches!(
+        remote.notify_keyboard_keycode(30, true),
+        Err(PortalError::DBus(_))
+    ));
+
+    remote
+        .select_devices(&DeviceOptions {
+            types: DeviceType::KEYBOARD | DeviceType::POINTER,
+            ..Default::default()
+        })
+        .unwrap();
+    remote
+        .session()
+        .select_sources(&SourceOptions {
+            types: SourceType::MONITOR,
+            ..Default::default()
+        })
+        .unwrap();
+    let started = remote.start("").unwrap();
+    assert_eq!(started.devices, DeviceType::KEYBOARD | DeviceType::POINTER);
+    assert_eq!(started.streams[0].node_id, 42);
+
+    // Frames are captured at 1.5x the logical size
+    let space = StreamSpace::new(&started.streams[0], (1920, 1080));
+    remote.move_pointer(&space, 960.0, 540.0).unwrap();
+    remote.notify_pointer_button(BTN_LEFT, true).unwrap();
+    remote.notify_pointer_button(BTN_LEFT, false).unwrap();
+    remote.notify_pointer_axis(0.0, 12.5, true).unwrap();
+    remote
+        .notify_pointer_axis_discrete(Axis::Vertical, -2)
+        .unwrap();
+    remote.notify_keyboard_keycode(30, true).unwrap();
+    remote.notify_keyboard_keysym(0x61, false).unwrap();
+
+    let names = mock.call_names();
+    assert_eq!(
+        names[names.len() - 10..],
+        [
+            "RemoteDesktop.SelectDevices",
+            "ScreenCast.SelectSources",
+            "RemoteDesktop.Start",
+            "RemoteDesktop.NotifyPointerMotionAbsolute",
+            "RemoteDesktop.NotifyPointerButton",
+            "RemoteDesktop.NotifyPointerButton",
+            "RemoteDesktop.NotifyPointerAxis",
+            "RemoteDesktop.NotifyPointerAxisDiscrete",
+            "RemoteDesktop.NotifyKeyboardKeycode",
+            "RemoteDesktop.NotifyKeyboardKeysym",
+        ]
+    );
+    mock.with_calls(|calls| {
+        let call = |member: &'static str| calls.iter().filter(move |c| c.member == member);
+        let select = call("SelectDevices").next().unwrap().options();
+        assert_eq!(select["types"].0.as_u64(), Some(3));
+
+        let motion = call("NotifyPointerMotionAbsolute").next().unwrap();
+        let (session, _, stream, x, y): (dbus::Path, dbus::arg::PropMap, u32, f64, f64) =
+            motion.message.read5().unwrap();
+        assert_eq!(&session, remote.session().handle());
+        assert_eq!((stream, x, y), (42, 640.0, 360.0));
+
+        let buttons = call("NotifyPointerButton")
+            .map(|c| {
+                c.message
+                    .read4::<dbus::Path, dbus::arg::PropMap, i32, u32>()
+                    .unwrap()
+            })
+            .map(|(_, _, button, state)| (button, state))
+            .collect::<Vec<_>>();
+        assert_eq!(buttons, [(BTN_LEFT, 1), (BTN_LEFT, 0)]);
+
+        let axis = call("NotifyPointerAxis").next().unwrap();
+        assert_eq!(axis.options()["finish"].0.as_u64(), Some(1));
+        let (_, _, dx, dy): (dbus::Path, dbus::arg::PropMap, f64, f64) =
+            axis.message.read4().unwrap();
+        assert_eq!((dx, dy), (0.0, 12.5));
+
+        let discrete = call("NotifyPointerAxisDiscrete").next().unwrap();
+        let (_, _, axis, steps): (dbus::Path, dbus::arg::PropMap, u32, i32) =
+            discrete.message.read4().unwrap();
+        assert_eq!((axis, steps), (0, -2));
+
+        let key = call("NotifyKeyboardKeycode").next().unwrap();
+        let (_, _, keycode, state): (dbus::Path, dbus::arg::PropMap, i32, u32) =
+            key.message.read4().unwrap();
+        assert_eq!((keycode, state), (30, 1));
+        let keysym = call("NotifyKeyboardKeysym").next().unwrap();
+        let (_, _, keysym, state): (dbus::Path, dbus::arg::PropMap, i32, u32) =
+            keysym.message.read4().unwrap();
+        assert_eq!((keysym, state), (0x61, 0));
+    });
+}
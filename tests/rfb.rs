@@ -0,0 +1,486 @@
+use miniz_oxide::inflate::stream::InflateState;
+use miniz_oxide::{DataFormat, MZFlush};
+use screencast::frame_transform::Rect;
+use screencast::pipewire_stream::{Frame, TimedFrame};
+use screencast::rfb::{
+    InputEvent, RfbConfig, RfbServer, ENCODING_DESKTOP_SIZE, ENCODING_HEXTILE, ENCODING_RAW,
+    ENCODING_ZRLE,
+};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+/// Minimal RFB viewer keeping the framebuffer in the server's default
+/// format, 32 bit little-endian xRGB.
+struct Viewer {
+    stream: TcpStream,
+    width: u32,
+    height: u32,
+    name: String,
+    pixels: Vec<u32>,
+    zlib: Box<InflateState>,
+}
+
+impl Viewer {
+    fn connect(addr: SocketAddr, version: &[u8; 12]) -> Self {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut server_version = [0; 12];
+        stream.read_exact(&mut server_version).unwrap();
+        assert_eq!(&server_version, b"RFB 003.008\n");
+        stream.write_all(version).unwrap();
+        if version == b"RFB 003.003\n" {
+            let mut security = [0; 4];
+            stream.read_exact(&mut security).unwrap();
+            assert_eq!(u32::from_be_bytes(security), 1);
+        } else {
+            let mut types = [0; 2];
+            stream.read_exact(&mut types).unwrap();
+            assert_eq!(types, [1, 1]);
+            stream.write_all(&[1]).unwrap();
+            // 3.7 has no SecurityResult for None
+            if version == b"RFB 003.008\n" {
+                let mut result = [0; 4];
+                stream.read_exact(&mut result).unwrap();
+                assert_eq!(result, [0; 4]);
+            }
+        }
+        stream.write_all(&[1]).unwrap();
+
+        let mut init = [0; 24];
+        stream.read_exact(&mut init).unwrap();
+        let width = u16::from_be_bytes([init[0], init[1]]) as u32;
+        let height = u16::from_be_bytes([init[2], init[3]]) as u32;
+        // 32 bpp, depth 24, little-endian true colour, xRGB
+        assert_eq!(init[4..8], [32, 24, 0, 1]);
+        assert_eq!(init[14..17], [16, 8, 0]);
+        let mut name = vec![0; u32::from_be_bytes(init[20..24].try_into().unwrap()) as usize];
+        stream.read_exact(&mut name).unwrap();
+        Self {
+            stream,
+            width,
+            height,
+            name: String::from_utf8(name).unwrap(),
+            pixels: vec![0; (width * height) as usize],
+            zlib: InflateState::new_boxed(DataFormat::Zlib),
+        }
+    }
+
+    fn read<const N: usize>(&mut self) -> [u8; N] {
+        let mut bytes = [0; N];
+        self.stream.read_exact(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn read_u16(&mut self) -> u32 {
+        u16::from_be_bytes(self.read()) as u32
+    }
+
+    fn read_pixel(&mut self) -> u32 {
+        u32::from_le_bytes(self.read())
+    }
+
+    fn set_encodings(&mut self, encodings: &[i32]) {
+        let mut message = vec![2, 0];
+        message.extend((encodings.len() as u16).to_be_bytes());
+        for encoding in encodings {
+            message.extend(encoding.to_be_bytes());
+        }
+        self.stream.write_all(&message).unwrap();
+    }
+
+    fn request(&mut self, incremental: bool) {
+        let mut message = vec![3, incremental as u8, 0, 0, 0, 0];
+        message.extend((self.width as u16).to_be_bytes());
+        message.extend((self.height as u16).to_be_bytes());
+        self.stream.write_all(&message).unwrap();
+    }
+
+    fn key(&mut self, keysym: u32, down: bool) {
+        let mut message = vec![4, down as u8, 0, 0];
+        message.extend(keysym.to_be_bytes());
+        self.stream.write_all(&message).unwrap();
+    }
+
+    fn pointer(&mut self, x: u16, y: u16, buttons: u8) {
+        let mut message = vec![5, buttons];
+        message.extend(x.to_be_bytes());
+        message.extend(y.to_be_bytes());
+        self.stream.write_all(&message).unwrap();
+    }
+
+    /// Reads a FramebufferUpdate into the framebuffer, returning its
+    /// rectangles and encodings.
+    fn update(&mut self) -> Vec<(Rect, i32)> {
+        let [kind, _] = self.read();
+        assert_eq!(kind, 0);
+        let count = self.read_u16();
+        let mut rects = Vec::new();
+        for _ in 0..count {
+            let rect = Rect {
+                x: self.read_u16(),
+                y: self.read_u16(),
+                width: self.read_u16(),
+                height: self.read_u16(),
+            };
+            let encoding = i32::from_be_bytes(self.read());
+            let pixels = match encoding {
+                ENCODING_DESKTOP_SIZE => {
+                    (self.width, self.height) = (rect.width, rect.height);
+                    self.pixels = vec![0; (rect.width * rect.height) as usize];
+                    rects.push((rect, encoding));
+                    continue;
+                }
+                ENCODING_RAW => (0..rect.width * rect.height)
+                    .map(|_| self.read_pixel())
+                    .collect(),
+                ENCODING_HEXTILE => self.hextile(rect),
+                ENCODING_ZRLE => self.zrle(rect),
+                other => panic!("unexpected encoding {other}"),
+            };
+            for y in 0..rect.height {
+                for x in 0..rect.width {
+                    self.pixels[((rect.y + y) * self.width + rect.x + x) as usize] =
+                        pixels[(y * rect.width + x) as usize];
+                }
+            }
+            rects.push((rect, encoding));
+        }
+        rects
+    }
+
+    fn hextile(&mut self, rect: Rect) -> Vec<u32> {
+        let mut pixels = vec![0; (rect.width * rect.height) as usize];
+        let (mut background, mut foreground) = (0, 0);
+        for ty in (0..rect.height).step_by(16) {
+            for tx in (0..rect.width).step_by(16) {
+                let (w, h) = (16.min(rect.width - tx), 16.min(rect.height - ty));
+                let mut set = |x: u32, y: u32, pixel: u32| {
+                    pixels[((ty + y) * rect.width + tx + x) as usize] = pixel
+                };
+                let [flags] = self.read();
+                if flags & 1 != 0 {
+                    for y in 0..h {
+                        for x in 0..w {
+                            set(x, y, self.read_pixel());
+                        }
+                    }
+                    continue;
+                }
+                if flags & 2 != 0 {
+                    background = self.read_pixel();
+                }
+                if flags & 4 != 0 {
+                    foreground = self.read_pixel();
+                }
+                for y in 0..h {
+                    for x in 0..w {
+                        set(x, y, background);
+                    }
+                }
+                if flags & 8 == 0 {
+                    continue;
+                }
+                let [count] = self.read();
+                for _ in 0..count {
+                    let colour = match flags & 16 != 0 {
+                        true => self.read_pixel(),
+                        false => foreground,
+                    };
+                    let [xy, wh] = self.read();
+                    let (sx, sy) = ((xy >> 4) as u32, (xy & 15) as u32);
+                    let (sw, sh) = ((wh >> 4) as u32 + 1, (wh & 15) as u32 + 1);
+                    for y in sy..sy + sh {
+                        for x in sx..sx + sw {
+                            set(x, y, colour);
+                        }
+                    }
+                }
+            }
+        }
+        pixels
+    }
+
+    fn zrle(&mut self, rect: Rect) -> Vec<u32> {
+        let length = u32::from_be_bytes(self.read());
+        let mut compressed = vec![0; length as usize];
+        self.stream.read_exact(&mut compressed).unwrap();
+        let mut data = Vec::new();
+        let mut chunk = vec![0; 64 * 1024];
+        let mut input = &compressed[..];
+        loop {
+            let result = miniz_oxide::inflate::stream::inflate(
+                &mut self.zlib,
+                input,
+                &mut chunk,
+                MZFlush::Sync,
+            );
+            data.extend_from_slice(&chunk[..result.bytes_written]);
+            input = &input[result.bytes_consumed..];
+            if result.status.is_err() || (input.is_empty() && result.bytes_written < chunk.len()) {
+                break;
+            }
+        }
+        assert!(input.is_empty());
+
+        let mut data = data.into_iter();
+        let mut byte = || data.next().expect("ZRLE data ends early");
+        let cpixel = |byte: &mut dyn FnMut() -> u8| u32::from_le_bytes([byte(), byte(), byte(), 0]);
+        let mut pixels = vec![0; (rect.width * rect.height) as usize];
+        for ty in (0..rect.height).step_by(64) {
+            for tx in (0..rect.width).step_by(64) {
+                let (w, h) = (64.min(rect.width - tx), 64.min(rect.height - ty));
+                let mut tile = Vec::with_capacity((w * h) as usize);
+                match byte() {
+                    0 => tile.extend((0..w * h).map(|_| cpixel(&mut byte))),
+                    1 => tile.resize((w * h) as usize, cpixel(&mut byte)),
+                    size @ 2..=16 => {
+                        let palette = (0..size).map(|_| cpixel(&mut byte)).collect::<Vec<_>>();
+                        let bits = match size {
+                            2 => 1,
+                            3 | 4 => 2,
+                            _ => 4,
+                        };
+                        for _ in 0..h {
+                            let (mut current, mut left) = (0u8, 0);
+                            for _ in 0..w {
+                                if left == 0 {
+                                    (current, left) = (byte(), 8);
+                                }
+                                left -= bits;
+                                let index = (current >> left) & ((1 << bits) - 1);
+                                tile.push(palette[index as usize]);
+                            }
+                        }
+                    }
+                    kind @ (128 | 130..=255) => {
+                        let palette = (0..kind.saturating_sub(128))
+                            .map(|_| cpixel(&mut byte))
+                            .collect::<Vec<_>>();
+                        while tile.len() < (w * h) as usize {
+                            let (colour, run) = if palette.is_empty() {
+                                (cpixel(&mut byte), true)
+                            } else {
+                                let index = byte();
+                                (palette[(index & 127) as usize], index & 128 != 0)
+                            };
+                            let mut length = 1;
+                            if run {
+                                loop {
+                                    let next = byte();
+                                    length += next as usize;
+                                    if next != 255 {
+                                        break;
+                                    }
+                                }
+                            }
+                            tile.extend(std::iter::repeat_n(colour, length));
+                        }
+                    }
+                    other => panic!("unexpected ZRLE subencoding {other}"),
+                }
+                assert_eq!(tile.len(), (w * h) as usize);
+                for y in 0..h {
+                    for x in 0..w {
+                        pixels[((ty + y) * rect.width + tx + x) as usize] =
+                            tile[(y * w + x) as usize];
+                    }
+                }
+            }
+        }
+        pixels
+    }
+}
+
+/// Flat areas, a two-coloured pattern and a gradient, so that every kind of
+/// tile shows up.
+fn test_frame(width: u32, height: u32, seed: u8) -> Frame {
+    let mut frame = Frame::new(width, height);
+    let pixels = frame.make_mut_slice();
+    for y in 0..height {
+        for x in 0..width {
+            let (r, g, b) = if x < width / 3 {
+                (40, 40, seed)
+            } else if x < 2 * width / 3 {
+                match (x / 3 + y / 5) % 2 {
+                    0 => (255, 255, 255),
+                    _ => (200, 0, seed),
+                }
+            } else {
+                (
+                    (x * 7) as u8,
+                    (y * 3) as u8,
+                    seed.wrapping_add((x ^ y) as u8),
+                )
+            };
+            pixels[(y * width + x) as usize] = slint::Rgba8Pixel::new(r, g, b, 255);
+        }
+    }
+    frame
+}
+
+fn expected(frame: &Frame) -> Vec<u32> {
+    frame
+        .as_slice()
+        .iter()
+        .map(|p| (p.r as u32) << 16 | (p.g as u32) << 8 | p.b as u32)
+        .collect()
+}
+
+fn timed(frame: Frame, damage: Option<Vec<Rect>>) -> TimedFrame {
+    TimedFrame {
+        frame,
+        pts: Duration::ZERO,
+        damage,
+    }
+}
+
+fn bind() -> RfbServer {
+    bind_with_input(false)
+}
+
+fn bind_with_input(input: bool) -> RfbServer {
+    RfbServer::bind(RfbConfig {
+        bind: SocketAddr::from(([127, 0, 0, 1], 0)),
+        name: "rfb test".to_owned(),
+        view_only: !input,
+    })
+    .unwrap()
+}
+
+#[test]
+fn encodings_match_the_frame() {
+    let server = bind();
+    let frame = test_frame(150, 90, 0);
+    server.publish(&timed(frame.clone(), None));
+
+    for encoding in [ENCODING_RAW, ENCODING_HEXTILE, ENCODING_ZRLE] {
+        let mut viewer = Viewer::connect(server.local_addr(), b"RFB 003.008\n");
+        assert_eq!((viewer.width, viewer.height), (150, 90));
+        assert_eq!(viewer.name, "rfb test");
+        viewer.set_encodings(&[encoding]);
+        viewer.request(false);
+        let rects = viewer.update();
+        assert!(rects.iter().all(|(_, e)| *e == encoding), "{rects:?}");
+        assert!(viewer.pixels == expected(&frame), "encoding {encoding}");
+
+        // The second update continues the same zlib stream
+        viewer.request(false);
+        viewer.update();
+        assert!(viewer.pixels == expected(&frame), "encoding {encoding}");
+    }
+}
+
+#[test]
+fn incremental_updates_follow_damage() {
+    let server = bind();
+    let frame = test_frame(130, 70, 0);
+    server.publish(&timed(frame.clone(), None));
+    let mut viewer = Viewer::connect(server.local_addr(), b"RFB 003.007\n");
+    viewer.set_encodings(&[ENCODING_ZRLE, ENCODING_RAW]);
+    viewer.request(false);
+    viewer.update();
+
+    // Only the damaged area is sent
+    let damage = Rect {
+        x: 10,
+        y: 5,
+        width: 30,
+        height: 20,
+    };
+    let mut changed = frame.clone();
+    for y in damage.y..damage.y + damage.height {
+        for x in damage.x..damage.x + damage.width {
+            changed.make_mut_slice()[(y * 130 + x) as usize] = slint::Rgba8Pixel::new(1, 2, 3, 255);
+        }
+    }
+    server.publish(&timed(changed.clone(), Some(vec![damage])));
+    viewer.request(true);
+    let rects = viewer.update();
+    assert_eq!(rects, [(damage, ENCODING_ZRLE)]);
+    assert!(viewer.pixels == expected(&changed));
+
+    // Without damage, the changed tiles are found by comparing frames
+    let mut changed_again = changed.clone();
+    changed_again.make_mut_slice()[65 * 130 + 100] = slint::Rgba8Pixel::new(9, 9, 9, 255);
+    server.publish(&timed(changed_again.clone(), None));
+    viewer.request(true);
+    let rects = viewer.update();
+    assert!(viewer.pixels == expected(&changed_again));
+    let tile = Rect {
+        x: 64,
+        y: 64,
+        width: 64,
+        height: 6,
+    };
+    assert_eq!(rects, [(tile, ENCODING_ZRLE)]);
+
+    // Nothing changed, nothing sent until the next frame
+    viewer.request(true);
+    viewer
+        .stream
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let mut byte = [0];
+    assert!(viewer.stream.read_exact(&mut byte).is_err());
+}
+
+#[test]
+fn desktop_size_changes() {
+    let server = bind();
+    server.publish(&timed(test_frame(64, 48, 0), None));
+    let mut viewer = Viewer::connect(server.local_addr(), b"RFB 003.003\n");
+    viewer.set_encodings(&[ENCODING_HEXTILE, ENCODING_DESKTOP_SIZE]);
+    viewer.request(false);
+    viewer.update();
+
+    let larger = test_frame(100, 60, 3);
+    server.publish(&timed(larger.clone(), None));
+    viewer.request(true);
+    let rects = viewer.update();
+    assert_eq!(rects[0].1, ENCODING_DESKTOP_SIZE);
+    assert_eq!((viewer.width, viewer.height), (100, 60));
+    assert!(viewer.pixels == expected(&larger));
+    server.stop();
+}
+
+#[test]
+fn input_unless_view_only() {
+    for input in [true, false] {
+        let server = bind_with_input(input);
+        server.publish(&timed(test_frame(80, 60, 0), None));
+        let mut viewer = Viewer::connect(server.local_addr(), b"RFB 003.008\n");
+        viewer.key(0xff0d, true);
+        viewer.pointer(40, 30, 0b1001);
+        // Messages are handled in order, so the input was seen once the
+        // update arrives
+        viewer.request(false);
+        viewer.update();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut events = Vec::new();
+        while input && events.len() < 2 && Instant::now() < deadline {
+            events.extend(server.try_input());
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        events.extend(server.try_input());
+        let expected = [
+            InputEvent::Key {
+                keysym: 0xff0d,
+                down: true,
+            },
+            InputEvent::Pointer {
+                x: 40,
+                y: 30,
+                buttons: 0b1001,
+                frame_size: (80, 60),
+            },
+        ];
+        match input {
+            true => assert_eq!(events, expected),
+            false => assert_eq!(events, []),
+        }
+    }
+}
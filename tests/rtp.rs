@@ -0,0 +1,279 @@
+use screencast::export::ImageView;
+use screencast::jpeg::JpegEncoder;
+use screencast::pipewire_stream::{Frame, TimedFrame};
+use screencast::rtp::{RtpCodec, RtpConfig, RtpHeader, RtpSink, JPEG_PAYLOAD_TYPE};
+use std::net::UdpSocket;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Receives on a loopback port and reassembles RFC 2435 frames.
+struct Receiver {
+    socket: UdpSocket,
+    /// Sequence number of the last packet.
+    sequence: Option<u16>,
+}
+
+/// A reassembled JPEG frame.
+#[derive(Debug)]
+struct JpegFrame {
+    timestamp: u32,
+    ssrc: u32,
+    packets: usize,
+    type_: u8,
+    q: u8,
+    width: u32,
+    height: u32,
+    tables: Option<Vec<u8>>,
+    scan: Vec<u8>,
+}
+
+impl Receiver {
+    fn bind() -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        Self {
+            socket,
+            sequence: None,
+        }
+    }
+
+    fn packet(&mut self) -> Option<(RtpHeader, Vec<u8>)> {
+        let mut buffer = [0; 65536];
+        let len = self.socket.recv(&mut buffer).ok()?;
+        let (header, payload) = RtpHeader::parse(&buffer[..len]).unwrap();
+        if let Some(last) = self.sequence {
+            assert_eq!(header.sequence, last.wrapping_add(1), "Lost or reordered");
+        }
+        self.sequence = Some(header.sequence);
+        Some((header, payload.to_vec()))
+    }
+
+    fn jpeg_frame(&mut self) -> JpegFrame {
+        let mut frame = None::<JpegFrame>;
+        loop {
+            let (header, payload) = self.packet().expect("No packet");
+            assert_eq!(header.payload_type, JPEG_PAYLOAD_TYPE);
+            let offset = u32::from_be_bytes([0, payload[1], payload[2], payload[3]]) as usize;
+            let (type_, q) = (payload[4], payload[5]);
+            let (width, height) = (payload[6] as u32 * 8, payload[7] as u32 * 8);
+            let mut data = &payload[8..];
+            let frame = match &mut frame {
+                None => {
+                    assert_eq!(offset, 0);
+                    let tables = (q >= 128).then(|| {
+                        let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+                        let tables = data[4..4 + length].to_vec();
+                        data = &data[4 + length..];
+                        tables
+                    });
+                    frame.insert(JpegFrame {
+                        timestamp: header.timestamp,
+                        ssrc: header.ssrc,
+                        packets: 0,
+                        type_,
+                        q,
+                        width,
+                        height,
+                        tables,
+                        scan: Vec::new(),
+                    })
+                }
+                Some(frame) => {
+                    assert_eq!(header.timestamp, frame.timestamp);
+                    assert_eq!(header.ssrc, frame.ssrc);
+                    assert_eq!((type_, q), (frame.type_, frame.q));
+                    assert_eq!((width, height), (frame.width, frame.height));
+                    frame
+                }
+            };
+            assert_eq!(offset, frame.scan.len(), "Fragments not contiguous");
+            frame.scan.extend_from_slice(data);
+            frame.packets += 1;
+            if header.marker {
+                break;
+            }
+        }
+        frame.unwrap()
+    }
+}
+
+fn test_frame(width: u32, height: u32) -> Frame {
+    let mut frame = Frame::new(width, height);
+    let pixels = frame.make_mut_slice();
+    for y in 0..height {
+        for x in 0..width {
+            pixels[(y * width + x) as usize] =
+                slint::Rgba8Pixel::new((x * 5) as u8, (y * 3) as u8, (x ^ y) as u8, 255);
+        }
+    }
+    frame
+}
+
+fn timed(frame: Frame, pts: Duration) -> TimedFrame {
+    TimedFrame {
+        frame,
+        pts,
+        damage: None,
+    }
+}
+
+fn sdp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("screencast-{}-{name}.sdp", std::process::id()))
+}
+
+#[test]
+fn jpeg_fragments_reassemble() {
+    let mut receiver = Receiver::bind();
+    let mut sink = RtpSink::new(RtpConfig {
+        destination: receiver.socket.local_addr().unwrap(),
+        quality: 100,
+        mtu: 300,
+        max_fps: None,
+        ..Default::default()
+    })
+    .unwrap();
+
+    let frame = test_frame(100, 50);
+    assert!(sink.send(&timed(frame.clone(), Duration::ZERO)).unwrap());
+    let first = receiver.jpeg_frame();
+    assert_eq!(first.ssrc, sink.ssrc());
+    assert!(first.packets > 2);
+    assert_eq!((first.type_, first.q), (1, 255));
+    // Cut down to multiples of 8
+    assert_eq!((first.width, first.height), (96, 48));
+
+    let encoder = JpegEncoder::new(100);
+    assert_eq!(
+        first.tables.unwrap(),
+        encoder.quantization_tables().concat()
+    );
+    let image = ImageView {
+        width: 96,
+        height: 48,
+        ..ImageView::from(&frame)
+    };
+    assert_eq!(first.scan, encoder.encode_scan(&image));
+
+    assert!(sink.send(&timed(frame, Duration::from_millis(40))).unwrap());
+    let second = receiver.jpeg_frame();
+    assert_eq!(second.timestamp.wrapping_sub(first.timestamp), 3600);
+}
+
+#[test]
+fn jpeg_header_and_sdp() {
+    let mut receiver = Receiver::bind();
+    let destination = receiver.socket.local_addr().unwrap();
+    let sdp = sdp_path("jpeg");
+    let mut sink = RtpSink::new(RtpConfig {
+        destination,
+        quality: 50,
+        max_fps: Some(10),
+        sdp: Some(sdp.clone()),
+        ..Default::default()
+    })
+    .unwrap();
+    let description = std::fs::read_to_string(&sdp).unwrap();
+    std::fs::remove_file(&sdp).unwrap();
+    assert!(description.contains(&format!("m=video {} RTP/AVP 26\r\n", destination.port())));
+    assert!(description.contains("c=IN IP4 127.0.0.1\r\n"));
+
+    // Too wide for the header, scaled to 2040 x 97
+    let frame = test_frame(2100, 100);
+    assert!(sink.send(&timed(frame.clone(), Duration::ZERO)).unwrap());
+    let received = receiver.jpeg_frame();
+    assert_eq!(received.q, 50);
+    assert!(received.tables.is_none());
+    assert_eq!((received.width, received.height), (2040, 96));
+
+    // Within the fps limit
+    assert!(!sink
+        .send(&timed(frame.clone(), Duration::from_millis(50)))
+        .unwrap());
+    assert!(sink
+        .send(&timed(frame, Duration::from_millis(100)))
+        .unwrap());
+    receiver.jpeg_frame();
+    sink.finish().unwrap();
+}
+
+#[test]
+fn h264_through_ffmpeg() {
+    let config = RtpConfig::default();
+    if std::process::Command::new(&config.ffmpeg)
+        .arg("-version")
+        .output()
+        .is_err()
+    {
+        println!("ffmpeg not installed, skipping");
+        return;
+    }
+    let mut receiver = Receiver::bind();
+    let sdp = sdp_path("h264");
+    let mut sink = RtpSink::new(RtpConfig {
+        destination: receiver.socket.local_addr().unwrap(),
+        codec: RtpCodec::H264,
+        mtu: 500,
+        max_fps: None,
+        sdp: Some(sdp.clone()),
+        ..config
+    })
+    .unwrap();
+    let ssrc = sink.ssrc();
+    for i in 0..10 {
+        let pts = Duration::from_millis(40 * i);
+        assert!(sink.send(&timed(test_frame(320, 240), pts)).unwrap());
+        std::thread::sleep(Duration::from_millis(40));
+    }
+    sink.finish().unwrap();
+
+    receiver
+        .socket
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    let mut nal_types = Vec::new();
+    let mut fragment = None::<u8>;
+    let mut frames = 0;
+    while let Some((header, payload)) = receiver.packet() {
+        assert_eq!((header.payload_type, header.ssrc), (96, ssrc));
+        assert!(payload.len() <= 500 - 12);
+        match payload[0] & 0x1f {
+            // FU-A: start and end bits around a run of fragments
+            28 => {
+                let (start, end, type_) = (payload[1] & 0x80, payload[1] & 0x40, payload[1] & 0x1f);
+                match fragment {
+                    None => assert_ne!(start, 0),
+                    Some(current) => assert_eq!((start, type_), (0, current)),
+                }
+                fragment = (end == 0).then_some(type_);
+                if start != 0 {
+                    nal_types.push(type_);
+                }
+            }
+            // STAP-A: sizes and small NAL units, like the parameter sets
+            24 => {
+                assert_eq!(fragment, None, "Unfinished FU-A");
+                let mut rest = &payload[1..];
+                while rest.len() > 2 {
+                    let size = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+                    nal_types.push(rest[2] & 0x1f);
+                    rest = &rest[2 + size..];
+                }
+            }
+            type_ => {
+                assert_eq!(fragment, None, "Unfinished FU-A");
+                nal_types.push(type_);
+            }
+        }
+        frames += header.marker as usize;
+    }
+    assert_eq!(frames, 10);
+    // Parameter sets and an IDR slice
+    for type_ in [7, 8, 5] {
+        assert!(nal_types.contains(&type_), "No NAL unit of type {type_}");
+    }
+    let description = std::fs::read_to_string(&sdp).unwrap();
+    std::fs::remove_file(&sdp).unwrap();
+    assert!(description.contains("a=rtpmap:96 H264/90000"));
+}